@@ -0,0 +1,115 @@
+use crate::config::CONFIG;
+use core::sync::atomic::{AtomicBool, Ordering};
+use embassy_executor::Spawner;
+use embassy_rp::peripherals::{PIN_26, PIN_27, PWM_SLICE5};
+use embassy_rp::pwm::{Config, Pwm};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Timer};
+
+// This module drives the speaker on the PicoCalc carrier board,
+// which is wired to GP26 (left) and GP27 (right). Both pins
+// belong to PWM slice 5, so we generate a square wave on both
+// channels at once. The lcd and keyboard backlights are driven
+// by the keyboard MCU, so there is no contention for the slice.
+//
+// Not every unit has a speaker fitted, so this is only enabled
+// when the `beep` config key is set to `on`.
+
+const DEFAULT_FREQ: u32 = 1000;
+const DEFAULT_MS: u32 = 100;
+const MIN_FREQ: u32 = 20;
+const MAX_FREQ: u32 = 20_000;
+const MAX_MS: u32 = 5000;
+
+static BEEP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// (frequency in Hz, duration in ms)
+static BEEPS: Channel<CriticalSectionRawMutex, (u32, u32), 4> = Channel::new();
+
+pub async fn init_beep(spawner: &Spawner, slice: PWM_SLICE5, left: PIN_26, right: PIN_27) {
+    let enabled = CONFIG
+        .get()
+        .lock()
+        .await
+        .fetch("beep")
+        .await
+        .ok()
+        .flatten()
+        .map(|value| value.as_str() == "on")
+        .unwrap_or(false);
+    if !enabled {
+        return;
+    }
+
+    let pwm = Pwm::new_output_ab(slice, left, right, silent_config());
+    BEEP_ENABLED.store(true, Ordering::Relaxed);
+    spawner.must_spawn(beeper(pwm));
+}
+
+fn silent_config() -> Config {
+    let mut config = Config::default();
+    config.compare_a = 0;
+    config.compare_b = 0;
+    config
+}
+
+/// Computes a PWM configuration that produces a square wave
+/// of approximately `freq` Hz
+fn tone_config(freq: u32) -> Config {
+    let clk = embassy_rp::clocks::clk_sys_freq();
+    // Pick the smallest integer divider that lets the period
+    // fit into the 16-bit counter, for the best resolution
+    let divider = clk.div_ceil(freq * 65536).clamp(1, 255);
+    let top = (clk / (divider * freq)).saturating_sub(1).min(65535) as u16;
+
+    let mut config = Config::default();
+    config.divider = (divider as u8).into();
+    config.top = top;
+    config.compare_a = top / 2;
+    config.compare_b = top / 2;
+    config
+}
+
+#[embassy_executor::task]
+async fn beeper(mut pwm: Pwm<'static>) {
+    loop {
+        let (freq, ms) = BEEPS.receive().await;
+        pwm.set_config(&tone_config(freq));
+        Timer::after(Duration::from_millis(ms as u64)).await;
+        pwm.set_config(&silent_config());
+    }
+}
+
+/// Sounds the terminal bell, if the speaker is enabled.
+/// Does not block; the bell is dropped if several are
+/// already queued up.
+pub fn bell() {
+    if BEEP_ENABLED.load(Ordering::Relaxed) {
+        BEEPS.try_send((DEFAULT_FREQ, DEFAULT_MS)).ok();
+    }
+}
+
+pub async fn beep_command(args: &[&str]) {
+    if !BEEP_ENABLED.load(Ordering::Relaxed) {
+        print!("beep is disabled; use `config set beep on` and reboot to enable it\r\n");
+        return;
+    }
+
+    let parse = |arg: Option<&&str>, default: u32| match arg {
+        Some(value) => value.parse::<u32>().ok(),
+        None => Some(default),
+    };
+
+    let (Some(freq), Some(ms)) = (
+        parse(args.get(1), DEFAULT_FREQ),
+        parse(args.get(2), DEFAULT_MS),
+    ) else {
+        print!("Usage: beep [FREQ_HZ] [DURATION_MS]\r\n");
+        return;
+    };
+
+    BEEPS
+        .send((freq.clamp(MIN_FREQ, MAX_FREQ), ms.min(MAX_MS)))
+        .await;
+}
@@ -0,0 +1,626 @@
+use crate::keyboard::{Key, KeyReport, Modifiers};
+use crate::screen::SharedScreen;
+use crate::transcript::Transcript;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use embassy_futures::select::{Either, select};
+use embassy_futures::yield_now;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Instant, with_timeout};
+use embedded_io_async::{Read, Write};
+
+extern crate alloc;
+
+// This module connects a byte stream, such as an ssh channel, to
+// a screen: output from the remote end is parsed into the screen,
+// and keys typed by the user are encoded and sent back, along with
+// any replies to queries made by the remote end.
+
+const TIMEOUT_DURATION: Duration = Duration::from_secs(10);
+/// Output is parsed into the screen this many bytes at a time,
+/// releasing the screen in between, so that a burst of output
+/// doesn't hold up key handling and the painter for long.
+/// The parser keeps its state between calls, so sequences that
+/// span chunks are unaffected.
+const PARSE_CHUNK_SIZE: usize = 128;
+
+/// Input from the user that is to be sent to the remote end
+pub enum TerminalInput {
+    Key(KeyReport),
+    /// Text pasted from the computer attached to the serial console
+    Paste(String),
+}
+
+pub type KeyChannel = Channel<CriticalSectionRawMutex, TerminalInput, 4>;
+
+/// What the Enter key sends. A pty expects CR, as that is what a
+/// terminal sends, and translates it to LF itself when the program
+/// reading from it wants lines; a program that has put the pty into
+/// raw mode sees exactly what is sent, and most expect CR.
+/// Cr is the default on that basis; it is unverified against a
+/// live login shell, so Lf remains available via `ssh_enter`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum EnterKey {
+    #[default]
+    Cr,
+    Lf,
+    CrLf,
+}
+
+impl EnterKey {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Cr => "\r",
+            Self::Lf => "\n",
+            Self::CrLf => "\r\n",
+        }
+    }
+}
+
+impl core::str::FromStr for EnterKey {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cr" => Ok(Self::Cr),
+            "lf" => Ok(Self::Lf),
+            "crlf" => Ok(Self::CrLf),
+            _ => Err("expected one of cr, lf or crlf"),
+        }
+    }
+}
+
+/// Encodes keys into the bytes that a terminal would send for them,
+/// following xterm.
+#[derive(Default)]
+pub struct KeyEncoder {
+    enter: EnterKey,
+}
+
+impl KeyEncoder {
+    /// Returns the bytes to send for `report`, or None if the key
+    /// has no encoding. `app_cursor_keys` is whether the remote end
+    /// has asked for application cursor keys (DECCKM).
+    pub fn encode(&self, report: &KeyReport, app_cursor_keys: bool) -> Option<String> {
+        let Key::Char(c) = report.key else {
+            return encode_special_key(report, self.enter, app_cursor_keys);
+        };
+
+        if report.modifiers == Modifiers::CTRL {
+            if let Some(mapped) = ctrl_mapping(c) {
+                log::trace!(
+                    "doing mapped ctrl {} -> {}",
+                    c.escape_debug(),
+                    mapped.escape_debug()
+                );
+                return Some(mapped.to_string());
+            }
+        }
+
+        if report.modifiers == Modifiers::ALT {
+            // Alt sends escape first
+            log::trace!("ALT -> send escape first");
+            return Some(alloc::format!("\u{1b}{c}"));
+        }
+
+        log::trace!("just sending {} as-is", c.escape_debug());
+        Some(c.to_string())
+    }
+
+    /// Encodes pasted text, wrapping it in bracketed paste markers if
+    /// the remote end asked for them. Line breaks are sent as the
+    /// Enter key is. Escapes are removed from bracketed text, so that
+    /// the paste can't end the bracket early and have the rest of it
+    /// taken as typed commands.
+    pub fn encode_paste(&self, text: &str, bracketed: bool) -> String {
+        let mut encoded = String::new();
+        if bracketed {
+            encoded.push_str("\u{1b}[200~");
+        }
+        let mut lines = text.split('\n').peekable();
+        while let Some(line) = lines.next() {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            if bracketed {
+                encoded.extend(line.chars().filter(|&c| c != '\u{1b}'));
+            } else {
+                encoded.push_str(line);
+            }
+            if lines.peek().is_some() {
+                encoded.push_str(self.enter.as_str());
+            }
+        }
+        if bracketed {
+            encoded.push_str("\u{1b}[201~");
+        }
+        encoded
+    }
+}
+
+pub struct TerminalBridge {
+    keys: Arc<KeyChannel>,
+    screen: SharedScreen,
+    encoder: KeyEncoder,
+    /// The longest that parsing a chunk has held the screen
+    worst_hold: Cell<Duration>,
+    transcript: Option<Transcript>,
+}
+
+impl TerminalBridge {
+    pub fn new(keys: Arc<KeyChannel>, screen: SharedScreen) -> Self {
+        Self {
+            keys,
+            screen,
+            encoder: KeyEncoder::default(),
+            worst_hold: Cell::new(Duration::from_ticks(0)),
+            transcript: None,
+        }
+    }
+
+    /// Sets what the Enter key sends
+    pub fn with_enter_key(mut self, enter: EnterKey) -> Self {
+        self.encoder.enter = enter;
+        self
+    }
+
+    /// Records the output from the remote end in `transcript`
+    pub fn with_transcript(mut self, transcript: Option<Transcript>) -> Self {
+        self.transcript = transcript;
+        self
+    }
+
+    /// Parses `bytes` into the screen a chunk at a time, returning
+    /// any replies to queries that they contained
+    async fn parse_output(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut responses = Vec::new();
+        for chunk in bytes.chunks(PARSE_CHUNK_SIZE) {
+            let mut screen = self.screen.lock().await;
+            let start = Instant::now();
+            screen.parse_bytes(chunk);
+            responses.extend(screen.take_responses());
+            drop(screen);
+
+            let held = start.elapsed();
+            if held > self.worst_hold.get() {
+                self.worst_hold.set(held);
+                log::debug!(
+                    "TerminalBridge: screen held for {}us parsing {} bytes",
+                    held.as_micros(),
+                    chunk.len()
+                );
+            }
+            // Let anything that was waiting for the screen have it
+            yield_now().await;
+        }
+        responses
+    }
+
+    /// Parses output read from `io` into the screen, and writes
+    /// encoded keys and replies to queries to `io`, until the
+    /// remote end closes the stream or an error occurs
+    pub async fn run<T: Read + Write>(&mut self, io: &mut T) {
+        self.pump(io).await;
+        if let Some(transcript) = self.transcript.as_mut() {
+            transcript.flush().await;
+        }
+    }
+
+    async fn pump<T: Read + Write>(&mut self, io: &mut T) {
+        log::info!("TerminalBridge waiting for output");
+
+        loop {
+            let mut buf = [0u8; 1024];
+
+            let output = io.read(&mut buf);
+            let input = self.keys.receive();
+
+            match select(output, input).await {
+                Either::First(read_result) => match read_result {
+                    Ok(n) => {
+                        if n == 0 {
+                            log::warn!("TerminalBridge: EOF");
+                            return;
+                        }
+                        if let Some(transcript) = self.transcript.as_mut() {
+                            transcript.record(&buf[0..n]).await;
+                        }
+                        let responses = self.parse_output(&buf[0..n]).await;
+                        if !responses.is_empty() {
+                            send_input_bytes(io, &responses).await;
+                        }
+                    }
+                    Err(err) => {
+                        print!("\u{1b}[1mTerminalBridge: {err:?}\r\n");
+                        return;
+                    }
+                },
+                Either::Second(TerminalInput::Key(key_report)) => {
+                    let app_cursor_keys = self.screen.lock().await.application_cursor_keys();
+                    if let Some(text) = self.encoder.encode(&key_report, app_cursor_keys) {
+                        log::trace!("{key_report:?} -> {}", text.escape_debug());
+                        send_input_bytes(io, text.as_bytes()).await;
+                    }
+                }
+                Either::Second(TerminalInput::Paste(text)) => {
+                    let bracketed = self.screen.lock().await.bracketed_paste();
+                    log::debug!(
+                        "TerminalBridge: pasting {} bytes, bracketed={bracketed}",
+                        text.len()
+                    );
+                    let encoded = self.encoder.encode_paste(&text, bracketed);
+                    send_input_bytes(io, encoded.as_bytes()).await;
+                }
+            }
+        }
+    }
+}
+
+/// Sends the encoded form of a keypress, or a response to a
+/// terminal query, to the remote end.
+/// Only failures are worth logging at info level; successful
+/// writes happen on every keystroke and would flood the log.
+async fn send_input_bytes<T: Write>(io: &mut T, bytes: &[u8]) {
+    match with_timeout(TIMEOUT_DURATION, io.write_all(bytes)).await {
+        Ok(Ok(())) => log::trace!("sent {bytes:x?}"),
+        Ok(Err(err)) => log::error!("failed to send input: {err:?}"),
+        Err(_) => log::error!("timed out sending input"),
+    }
+}
+
+/// Encodes a key that doesn't produce a character, following xterm.
+/// Cursor and editing keys that are held with modifiers have the
+/// modifiers encoded as a parameter, eg: Shift+Del -> `\x1b[3;2~`.
+/// In application cursor key mode, the unmodified arrow keys, Home
+/// and End are sent with SS3 rather than CSI, eg: Up -> `\x1bOA`.
+fn encode_special_key(
+    report: &KeyReport,
+    enter: EnterKey,
+    app_cursor_keys: bool,
+) -> Option<String> {
+    enum Seq {
+        /// Sent as-is, preceded by escape if Alt is held
+        Raw(&'static str),
+        /// `CSI X`, or `CSI 1 ; mods X` when modified, or `SS3 X`
+        /// when unmodified in application cursor key mode
+        Csi(char),
+        /// `CSI n ~`, or `CSI n ; mods ~` when modified
+        Tilde(u8),
+    }
+
+    let seq = match report.key {
+        Key::Enter => Seq::Raw(enter.as_str()),
+        Key::BackSpace => Seq::Raw("\u{7f}"),
+        Key::Tab => Seq::Raw("\t"),
+        Key::Escape => Seq::Raw("\u{1b}"),
+        // There's no standard encoding for Break; treat it as an
+        // interrupt, which is what it is most likely wanted for
+        Key::Break => Seq::Raw("\u{3}"),
+        Key::Up | Key::JoyUp => Seq::Csi('A'),
+        Key::Down | Key::JoyDown => Seq::Csi('B'),
+        Key::Right | Key::JoyRight => Seq::Csi('C'),
+        Key::Left | Key::JoyLeft => Seq::Csi('D'),
+        Key::Home => Seq::Csi('H'),
+        Key::End => Seq::Csi('F'),
+        Key::Insert => Seq::Tilde(2),
+        Key::Del => Seq::Tilde(3),
+        Key::PageUp => Seq::Tilde(5),
+        Key::PageDown => Seq::Tilde(6),
+        _ => return None,
+    };
+
+    let mods = report.modifiers;
+    let mut param = 1;
+    if mods.intersects(Modifiers::LSHIFT | Modifiers::RSHIFT) {
+        param += 1;
+    }
+    if mods.contains(Modifiers::ALT) {
+        param += 2;
+    }
+    if mods.contains(Modifiers::CTRL) {
+        param += 4;
+    }
+
+    Some(match seq {
+        Seq::Raw(text) if mods.contains(Modifiers::ALT) => alloc::format!("\u{1b}{text}"),
+        Seq::Raw(text) => text.to_string(),
+        Seq::Csi(c) if param == 1 && app_cursor_keys => alloc::format!("\u{1b}O{c}"),
+        Seq::Csi(c) if param == 1 => alloc::format!("\u{1b}[{c}"),
+        Seq::Csi(c) => alloc::format!("\u{1b}[1;{param}{c}"),
+        Seq::Tilde(n) if param == 1 => alloc::format!("\u{1b}[{n}~"),
+        Seq::Tilde(n) => alloc::format!("\u{1b}[{n};{param}~"),
+    })
+}
+
+/// Taken from wezterm-input-types
+/// Map c to its Ctrl equivalent.
+/// In theory, this mapping is simply translating alpha characters
+/// to upper case and then masking them by 0x1f, but xterm inherits
+/// some built-in translation from legacy X11 so that are some
+/// aliased mappings and a couple that might be technically tied
+/// to US keyboard layout (particularly the punctuation characters
+/// produced in combination with SHIFT) that may not be 100%
+/// the right thing to do here for users with non-US layouts.
+fn ctrl_mapping(c: char) -> Option<char> {
+    Some(match c {
+        '@' | '`' | ' ' | '2' => '\x00',
+        'A' | 'a' => '\x01',
+        'B' | 'b' => '\x02',
+        'C' | 'c' => '\x03',
+        'D' | 'd' => '\x04',
+        'E' | 'e' => '\x05',
+        'F' | 'f' => '\x06',
+        'G' | 'g' => '\x07',
+        'H' | 'h' => '\x08',
+        'I' | 'i' => '\x09',
+        'J' | 'j' => '\x0a',
+        'K' | 'k' => '\x0b',
+        'L' | 'l' => '\x0c',
+        'M' | 'm' => '\x0d',
+        'N' | 'n' => '\x0e',
+        'O' | 'o' => '\x0f',
+        'P' | 'p' => '\x10',
+        'Q' | 'q' => '\x11',
+        'R' | 'r' => '\x12',
+        'S' | 's' => '\x13',
+        'T' | 't' => '\x14',
+        'U' | 'u' => '\x15',
+        'V' | 'v' => '\x16',
+        'W' | 'w' => '\x17',
+        'X' | 'x' => '\x18',
+        'Y' | 'y' => '\x19',
+        'Z' | 'z' => '\x1a',
+        '[' | '3' | '{' => '\x1b',
+        '\\' | '4' | '|' => '\x1c',
+        ']' | '5' | '}' => '\x1d',
+        '^' | '6' | '~' => '\x1e',
+        '_' | '7' | '/' => '\x1f',
+        '8' | '?' => '\x7f', // `Delete`
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::KeyState;
+
+    fn press(key: Key, modifiers: Modifiers) -> KeyReport {
+        KeyReport {
+            state: KeyState::Pressed,
+            key,
+            modifiers,
+        }
+    }
+
+    fn encode(key: Key, modifiers: Modifiers) -> Option<String> {
+        KeyEncoder::default().encode(&press(key, modifiers), false)
+    }
+
+    // These are the bytes that ssh_channel_task sent for each key
+    // before the encoder was split out of it
+
+    #[test]
+    fn plain_characters_are_sent_as_is() {
+        assert_eq!(
+            encode(Key::Char('a'), Modifiers::NONE).as_deref(),
+            Some("a")
+        );
+        assert_eq!(
+            encode(Key::Char('A'), Modifiers::LSHIFT).as_deref(),
+            Some("A")
+        );
+        assert_eq!(
+            encode(Key::Char('é'), Modifiers::NONE).as_deref(),
+            Some("é")
+        );
+    }
+
+    #[test]
+    fn ctrl_maps_to_control_characters() {
+        assert_eq!(
+            encode(Key::Char('c'), Modifiers::CTRL).as_deref(),
+            Some("\u{3}")
+        );
+        assert_eq!(
+            encode(Key::Char('['), Modifiers::CTRL).as_deref(),
+            Some("\u{1b}")
+        );
+        assert_eq!(
+            encode(Key::Char(' '), Modifiers::CTRL).as_deref(),
+            Some("\u{0}")
+        );
+        // Characters without a mapping are sent unchanged
+        assert_eq!(
+            encode(Key::Char('='), Modifiers::CTRL).as_deref(),
+            Some("=")
+        );
+    }
+
+    #[test]
+    fn alt_sends_escape_first() {
+        assert_eq!(
+            encode(Key::Char('x'), Modifiers::ALT).as_deref(),
+            Some("\u{1b}x")
+        );
+    }
+
+    #[test]
+    fn unmodified_special_keys() {
+        assert_eq!(
+            encode(Key::BackSpace, Modifiers::NONE).as_deref(),
+            Some("\u{7f}")
+        );
+        assert_eq!(encode(Key::Tab, Modifiers::NONE).as_deref(), Some("\t"));
+        assert_eq!(
+            encode(Key::Escape, Modifiers::NONE).as_deref(),
+            Some("\u{1b}")
+        );
+        assert_eq!(
+            encode(Key::Up, Modifiers::NONE).as_deref(),
+            Some("\u{1b}[A")
+        );
+        assert_eq!(
+            encode(Key::Down, Modifiers::NONE).as_deref(),
+            Some("\u{1b}[B")
+        );
+        assert_eq!(
+            encode(Key::Right, Modifiers::NONE).as_deref(),
+            Some("\u{1b}[C")
+        );
+        assert_eq!(
+            encode(Key::Left, Modifiers::NONE).as_deref(),
+            Some("\u{1b}[D")
+        );
+        assert_eq!(
+            encode(Key::JoyUp, Modifiers::NONE).as_deref(),
+            Some("\u{1b}[A")
+        );
+        assert_eq!(
+            encode(Key::Home, Modifiers::NONE).as_deref(),
+            Some("\u{1b}[H")
+        );
+        assert_eq!(
+            encode(Key::End, Modifiers::NONE).as_deref(),
+            Some("\u{1b}[F")
+        );
+        assert_eq!(
+            encode(Key::PageUp, Modifiers::NONE).as_deref(),
+            Some("\u{1b}[5~")
+        );
+        assert_eq!(
+            encode(Key::PageDown, Modifiers::NONE).as_deref(),
+            Some("\u{1b}[6~")
+        );
+    }
+
+    #[test]
+    fn enter_follows_the_setting() {
+        let report = press(Key::Enter, Modifiers::NONE);
+        for (enter, expected) in [
+            (EnterKey::Cr, "\r"),
+            (EnterKey::Lf, "\n"),
+            (EnterKey::CrLf, "\r\n"),
+        ] {
+            let encoder = KeyEncoder { enter };
+            assert_eq!(encoder.encode(&report, false).as_deref(), Some(expected));
+        }
+    }
+
+    #[test]
+    fn paste_sends_line_breaks_as_enter() {
+        let encoder = KeyEncoder::default();
+        assert_eq!(
+            encoder.encode_paste("one\r\ntwo\nthree", false),
+            "one\rtwo\rthree"
+        );
+    }
+
+    #[test]
+    fn bracketed_paste_is_wrapped_and_loses_escapes() {
+        let encoder = KeyEncoder::default();
+        assert_eq!(
+            encoder.encode_paste("ls\u{1b}[201~\nrm", true),
+            "\u{1b}[200~ls[201~\rrm\u{1b}[201~"
+        );
+    }
+
+    #[test]
+    fn editing_keys_and_break() {
+        assert_eq!(
+            encode(Key::Insert, Modifiers::NONE).as_deref(),
+            Some("\u{1b}[2~")
+        );
+        assert_eq!(
+            encode(Key::Del, Modifiers::NONE).as_deref(),
+            Some("\u{1b}[3~")
+        );
+        assert_eq!(
+            encode(Key::Break, Modifiers::NONE).as_deref(),
+            Some("\u{3}")
+        );
+    }
+
+    #[test]
+    fn modified_special_keys_carry_a_parameter() {
+        assert_eq!(
+            encode(Key::Del, Modifiers::LSHIFT).as_deref(),
+            Some("\u{1b}[3;2~")
+        );
+        assert_eq!(
+            encode(Key::Insert, Modifiers::RSHIFT).as_deref(),
+            Some("\u{1b}[2;2~")
+        );
+        assert_eq!(
+            encode(Key::PageUp, Modifiers::CTRL).as_deref(),
+            Some("\u{1b}[5;5~")
+        );
+        assert_eq!(
+            encode(Key::Up, Modifiers::LSHIFT).as_deref(),
+            Some("\u{1b}[1;2A")
+        );
+        assert_eq!(
+            encode(Key::Left, Modifiers::ALT).as_deref(),
+            Some("\u{1b}[1;3D")
+        );
+        assert_eq!(
+            encode(Key::Right, Modifiers::CTRL).as_deref(),
+            Some("\u{1b}[1;5C")
+        );
+        assert_eq!(
+            encode(Key::Home, Modifiers::CTRL | Modifiers::LSHIFT).as_deref(),
+            Some("\u{1b}[1;6H")
+        );
+        assert_eq!(
+            encode(
+                Key::End,
+                Modifiers::CTRL | Modifiers::ALT | Modifiers::LSHIFT
+            )
+            .as_deref(),
+            Some("\u{1b}[1;8F")
+        );
+    }
+
+    #[test]
+    fn alt_prefixes_raw_special_keys_with_escape() {
+        assert_eq!(
+            encode(Key::BackSpace, Modifiers::ALT).as_deref(),
+            Some("\u{1b}\u{7f}")
+        );
+        assert_eq!(
+            encode(Key::Break, Modifiers::ALT).as_deref(),
+            Some("\u{1b}\u{3}")
+        );
+    }
+
+    #[test]
+    fn keys_without_an_encoding() {
+        assert_eq!(encode(Key::F1, Modifiers::NONE), None);
+    }
+
+    #[test]
+    fn application_cursor_keys_send_ss3() {
+        let encoder = KeyEncoder::default();
+        for (key, expected) in [
+            (Key::Up, "\u{1b}OA"),
+            (Key::Down, "\u{1b}OB"),
+            (Key::Right, "\u{1b}OC"),
+            (Key::Left, "\u{1b}OD"),
+            (Key::JoyUp, "\u{1b}OA"),
+            (Key::Home, "\u{1b}OH"),
+            (Key::End, "\u{1b}OF"),
+        ] {
+            let report = press(key, Modifiers::NONE);
+            assert_eq!(encoder.encode(&report, true).as_deref(), Some(expected));
+        }
+        // Modified keys carry their parameter with CSI as usual,
+        // and the editing keys aren't affected
+        let report = press(Key::Up, Modifiers::CTRL);
+        assert_eq!(
+            encoder.encode(&report, true).as_deref(),
+            Some("\u{1b}[1;5A")
+        );
+        let report = press(Key::Del, Modifiers::NONE);
+        assert_eq!(encoder.encode(&report, true).as_deref(), Some("\u{1b}[3~"));
+    }
+}
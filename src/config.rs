@@ -1,4 +1,6 @@
 use crate::fixed_str::FixedString;
+use crate::process::{PromptKind, prompt_for_input};
+use core::ops::Range;
 use embassy_rp::flash::{
     Async, ERASE_SIZE, Error as FlashError, Flash as RpFlash, PAGE_SIZE, WRITE_SIZE,
 };
@@ -6,15 +8,25 @@ use embassy_rp::peripherals::{DMA_CH3, FLASH};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::lazy_lock::LazyLock;
 use embassy_sync::mutex::Mutex;
+use embassy_time::{Duration, Timer};
 use embedded_io::ErrorKind;
 use heapless::FnvIndexMap;
 use sequential_storage::cache::NoCache;
 use sequential_storage::erase_all;
 use sequential_storage::map::{fetch_all_items, fetch_item, remove_item, store_item};
 
+extern crate alloc;
+
 const PICO2_FLASH_SIZE: usize = 4 * 1024 * 1024;
 pub const CONFIG_SIZE: u32 = ERASE_SIZE as u32 * 2;
 pub const CONFIG_BASE: u32 = PICO2_FLASH_SIZE as u32 - CONFIG_SIZE;
+/// A copy of the config made by `config backup` is kept in the
+/// region immediately before the config
+const BACKUP_BASE: u32 = CONFIG_BASE - CONFIG_SIZE;
+/// Factory defaults, written by `config write-defaults`, are kept
+/// in the region before the backup. They are consulted for keys
+/// that aren't set in the config, and survive `config format`.
+const DEFAULTS_BASE: u32 = BACKUP_BASE - CONFIG_SIZE;
 const SCRATCH_SIZE: usize = PAGE_SIZE * 2;
 
 pub static CONFIG: LazyLock<Mutex<CriticalSectionRawMutex, Configuration>> =
@@ -23,6 +35,9 @@ pub static CONFIG: LazyLock<Mutex<CriticalSectionRawMutex, Configuration>> =
 #[derive(Debug, Default)]
 pub struct Configuration {
     flash: Option<Flash>,
+    /// Set when the config was unreadable at boot and the
+    /// backup is being used in its place
+    using_backup: bool,
 }
 
 pub type StrKey = FixedString<32>;
@@ -33,22 +48,39 @@ impl Configuration {
         self.flash.replace(flash);
     }
 
+    /// Fetches `key` from the config. If it isn't set there,
+    /// the factory defaults are consulted.
     pub async fn fetch(
         &mut self,
         key: &str,
+    ) -> Result<Option<StrValue>, sequential_storage::Error<embassy_rp::flash::Error>> {
+        if let Some(value) = self.fetch_in(self.range(), key).await? {
+            return Ok(Some(value));
+        }
+        // The defaults region may never have been written, so
+        // treat it being unreadable as there being no defaults
+        match self
+            .fetch_in(DEFAULTS_BASE..DEFAULTS_BASE + CONFIG_SIZE, key)
+            .await
+        {
+            Ok(value) => Ok(value),
+            Err(err) => {
+                log::debug!("config defaults are unreadable: {err:?}");
+                Ok(None)
+            }
+        }
+    }
+
+    async fn fetch_in(
+        &mut self,
+        range: Range<u32>,
+        key: &str,
     ) -> Result<Option<StrValue>, sequential_storage::Error<embassy_rp::flash::Error>> {
         match &mut self.flash {
             Some(flash) => {
                 let key: StrKey = key.try_into()?;
                 let mut buf = [0u8; SCRATCH_SIZE];
-                fetch_item(
-                    &mut flash.flash,
-                    CONFIG_BASE..CONFIG_BASE + CONFIG_SIZE,
-                    &mut NoCache::new(),
-                    &mut buf,
-                    &key,
-                )
-                .await
+                fetch_item(&mut flash.flash, range, &mut NoCache::new(), &mut buf, &key).await
             }
             None => {
                 todo!();
@@ -56,22 +88,39 @@ impl Configuration {
         }
     }
 
+    /// Fetches `key` and parses it as a `T`.
+    /// Returns None if the key isn't set or cannot be parsed,
+    /// logging the reason for the latter.
+    pub async fn fetch_parsed<T: core::str::FromStr>(&mut self, key: &str) -> Option<T>
+    where
+        T::Err: core::fmt::Debug,
+    {
+        match self.fetch(key).await {
+            Ok(Some(value)) => match value.parse() {
+                Ok(value) => Some(value),
+                Err(err) => {
+                    log::error!("config {key}={value} is invalid: {err:?}");
+                    None
+                }
+            },
+            Ok(None) => None,
+            Err(err) => {
+                log::error!("config fetch {key}: {err:?}");
+                None
+            }
+        }
+    }
+
     pub async fn remove(
         &mut self,
         key: &str,
     ) -> Result<(), sequential_storage::Error<embassy_rp::flash::Error>> {
+        let range = self.range();
         match &mut self.flash {
             Some(flash) => {
                 let key: StrKey = key.try_into()?;
                 let mut buf = [0u8; SCRATCH_SIZE];
-                remove_item(
-                    &mut flash.flash,
-                    CONFIG_BASE..CONFIG_BASE + CONFIG_SIZE,
-                    &mut NoCache::new(),
-                    &mut buf,
-                    &key,
-                )
-                .await
+                remove_item(&mut flash.flash, range, &mut NoCache::new(), &mut buf, &key).await
             }
             None => {
                 todo!();
@@ -83,6 +132,15 @@ impl Configuration {
         &mut self,
         key: &str,
         value: StrValue,
+    ) -> Result<(), sequential_storage::Error<embassy_rp::flash::Error>> {
+        self.store_in(self.range(), key, value).await
+    }
+
+    async fn store_in(
+        &mut self,
+        range: Range<u32>,
+        key: &str,
+        value: StrValue,
     ) -> Result<(), sequential_storage::Error<embassy_rp::flash::Error>> {
         match &mut self.flash {
             Some(flash) => {
@@ -90,7 +148,7 @@ impl Configuration {
                 let mut buf = [0u8; SCRATCH_SIZE];
                 store_item(
                     &mut flash.flash,
-                    CONFIG_BASE..CONFIG_BASE + CONFIG_SIZE,
+                    range,
                     &mut NoCache::new(),
                     &mut buf,
                     &key,
@@ -104,13 +162,22 @@ impl Configuration {
         }
     }
 
+    /// Erases the config, and stops using the backup if the
+    /// config was found to be corrupt at boot
     pub async fn format(
         &mut self,
+    ) -> Result<(), sequential_storage::Error<embassy_rp::flash::Error>> {
+        self.erase(CONFIG_BASE..CONFIG_BASE + CONFIG_SIZE).await?;
+        self.using_backup = false;
+        Ok(())
+    }
+
+    async fn erase(
+        &mut self,
+        range: Range<u32>,
     ) -> Result<(), sequential_storage::Error<embassy_rp::flash::Error>> {
         match &mut self.flash {
-            Some(flash) => {
-                erase_all(&mut flash.flash, CONFIG_BASE..CONFIG_BASE + CONFIG_SIZE).await
-            }
+            Some(flash) => erase_all(&mut flash.flash, range).await,
             None => {
                 todo!();
             }
@@ -122,18 +189,24 @@ impl Configuration {
     ) -> Result<
         FnvIndexMap<StrKey, StrValue, 32>,
         sequential_storage::Error<embassy_rp::flash::Error>,
+    > {
+        self.get_all_in(self.range()).await
+    }
+
+    async fn get_all_in(
+        &mut self,
+        range: Range<u32>,
+    ) -> Result<
+        FnvIndexMap<StrKey, StrValue, 32>,
+        sequential_storage::Error<embassy_rp::flash::Error>,
     > {
         match &mut self.flash {
             Some(flash) => {
                 let mut buf = [0u8; SCRATCH_SIZE];
                 let mut cache = NoCache::new();
-                let mut iter = fetch_all_items::<StrKey, _, _>(
-                    &mut flash.flash,
-                    CONFIG_BASE..CONFIG_BASE + CONFIG_SIZE,
-                    &mut cache,
-                    &mut buf,
-                )
-                .await?;
+                let mut iter =
+                    fetch_all_items::<StrKey, _, _>(&mut flash.flash, range, &mut cache, &mut buf)
+                        .await?;
 
                 let mut map = FnvIndexMap::new();
 
@@ -150,6 +223,359 @@ impl Configuration {
             }
         }
     }
+
+    /// The region that holds the config in use: normally the main
+    /// config, or the backup if the main config was found to be
+    /// corrupt at boot
+    fn range(&self) -> Range<u32> {
+        let base = if self.using_backup {
+            BACKUP_BASE
+        } else {
+            CONFIG_BASE
+        };
+        base..base + CONFIG_SIZE
+    }
+
+    /// Called at boot to check that the config can be read.
+    /// If it can't, but the backup can, the backup is used instead
+    /// until the config is restored or formatted.
+    pub async fn check(&mut self) {
+        let Err(err) = self
+            .get_all_in(CONFIG_BASE..CONFIG_BASE + CONFIG_SIZE)
+            .await
+        else {
+            return;
+        };
+        log::error!("config is unreadable: {err:?}");
+        match self
+            .get_all_in(BACKUP_BASE..BACKUP_BASE + CONFIG_SIZE)
+            .await
+        {
+            Ok(_) => {
+                self.using_backup = true;
+                print!(
+                    "Config is corrupt; using the backup. Run `config restore` to repair it.\r\n"
+                );
+            }
+            Err(err) => {
+                log::error!("config backup is unreadable: {err:?}");
+                print!("Config is corrupt and there is no usable backup. Run `config format`.\r\n");
+            }
+        }
+    }
+
+    pub fn using_backup(&self) -> bool {
+        self.using_backup
+    }
+
+    /// Returns every key in `range` with its current value. Unlike
+    /// get_all, this isn't limited to 32 keys, so that copying a
+    /// region can't silently lose some of them.
+    async fn items_in(
+        &mut self,
+        range: Range<u32>,
+    ) -> Result<
+        alloc::vec::Vec<(StrKey, StrValue)>,
+        sequential_storage::Error<embassy_rp::flash::Error>,
+    > {
+        let Some(flash) = &mut self.flash else {
+            return Err(sequential_storage::Error::Storage {
+                value: FlashError::Other,
+            });
+        };
+        let mut buf = [0u8; SCRATCH_SIZE];
+        let mut cache = NoCache::new();
+        let mut iter =
+            fetch_all_items::<StrKey, _, _>(&mut flash.flash, range, &mut cache, &mut buf).await?;
+
+        // A key that was stored more than once is seen each time,
+        // and the last is the one in use
+        let mut items: alloc::vec::Vec<(StrKey, StrValue)> = alloc::vec::Vec::new();
+        while let Some((key, value)) = iter.next::<StrKey, StrValue>(&mut buf).await? {
+            match items.iter_mut().find(|(k, _)| *k == key) {
+                Some(item) => item.1 = value,
+                None => items.push((key, value)),
+            }
+        }
+        Ok(items)
+    }
+
+    /// Copies every item from one region to another, which is
+    /// erased first. Returns the number of items copied.
+    async fn copy_region(
+        &mut self,
+        from: Range<u32>,
+        to: Range<u32>,
+    ) -> Result<usize, sequential_storage::Error<embassy_rp::flash::Error>> {
+        let items = self.items_in(from).await?;
+        self.copy_items(&items, to).await
+    }
+
+    async fn copy_items(
+        &mut self,
+        items: &[(StrKey, StrValue)],
+        to: Range<u32>,
+    ) -> Result<usize, sequential_storage::Error<embassy_rp::flash::Error>> {
+        self.erase(to.clone()).await?;
+        for (key, value) in items {
+            self.store_in(to.clone(), key.as_str(), value.clone())
+                .await?;
+        }
+        Ok(items.len())
+    }
+
+    /// Replaces the backup with a copy of the config
+    pub async fn backup(
+        &mut self,
+    ) -> Result<usize, sequential_storage::Error<embassy_rp::flash::Error>> {
+        self.copy_region(
+            CONFIG_BASE..CONFIG_BASE + CONFIG_SIZE,
+            BACKUP_BASE..BACKUP_BASE + CONFIG_SIZE,
+        )
+        .await
+    }
+
+    /// Reads raw bytes from the config region, starting `offset`
+    /// bytes into it
+    pub async fn read_raw(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
+        match &mut self.flash {
+            Some(flash) => flash.read(CONFIG_BASE + offset, bytes).await,
+            // Flash is assigned at boot, so this only happens if
+            // that failed
+            None => Err(FlashError::Other),
+        }
+    }
+
+    /// Counts how many items in the config region can be parsed,
+    /// returning the error that stopped the count, if any
+    pub async fn count_items(
+        &mut self,
+    ) -> (
+        usize,
+        Option<sequential_storage::Error<embassy_rp::flash::Error>>,
+    ) {
+        let Some(flash) = &mut self.flash else {
+            return (
+                0,
+                Some(sequential_storage::Error::Storage {
+                    value: FlashError::Other,
+                }),
+            );
+        };
+        let mut buf = [0u8; SCRATCH_SIZE];
+        let mut cache = NoCache::new();
+        let mut iter = match fetch_all_items::<StrKey, _, _>(
+            &mut flash.flash,
+            CONFIG_BASE..CONFIG_BASE + CONFIG_SIZE,
+            &mut cache,
+            &mut buf,
+        )
+        .await
+        {
+            Ok(iter) => iter,
+            Err(err) => return (0, Some(err)),
+        };
+
+        let mut count = 0;
+        loop {
+            match iter.next::<StrKey, StrValue>(&mut buf).await {
+                Ok(Some(_)) => count += 1,
+                Ok(None) => return (count, None),
+                Err(err) => return (count, Some(err)),
+            }
+        }
+    }
+
+    /// Replaces the factory defaults with a copy of the config
+    pub async fn write_defaults(
+        &mut self,
+    ) -> Result<usize, sequential_storage::Error<embassy_rp::flash::Error>> {
+        self.copy_region(self.range(), DEFAULTS_BASE..DEFAULTS_BASE + CONFIG_SIZE)
+            .await
+    }
+
+    /// Replaces the config with a copy of the backup, and goes
+    /// back to using the config if the backup was in use. If the
+    /// backup is empty, as it is if none was ever made, the config
+    /// is left alone and 0 is returned.
+    pub async fn restore(
+        &mut self,
+    ) -> Result<usize, sequential_storage::Error<embassy_rp::flash::Error>> {
+        let items = self
+            .items_in(BACKUP_BASE..BACKUP_BASE + CONFIG_SIZE)
+            .await?;
+        if items.is_empty() {
+            return Ok(0);
+        }
+        let count = self
+            .copy_items(&items, CONFIG_BASE..CONFIG_BASE + CONFIG_SIZE)
+            .await?;
+        self.using_backup = false;
+        Ok(count)
+    }
+}
+
+/// Keys outside of the default namespace are stored with the
+/// namespace name and this separator as a prefix
+const NAMESPACE_SEPARATOR: char = '/';
+/// The most bytes of keys and values that a namespace other than
+/// the default may hold, so that one feature can't fill the
+/// config region at the expense of the others
+const NAMESPACE_QUOTA: usize = 1024;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Storage(sequential_storage::Error<embassy_rp::flash::Error>),
+    /// The key, with its namespace prefix, is too long
+    KeyTooLong,
+    /// A key in the default namespace contains the separator,
+    /// which would put it in another namespace
+    SeparatorInKey,
+    /// Storing the value would take the namespace over its quota
+    QuotaExceeded {
+        used: usize,
+        quota: usize,
+    },
+}
+
+impl From<sequential_storage::Error<embassy_rp::flash::Error>> for StoreError {
+    fn from(err: sequential_storage::Error<embassy_rp::flash::Error>) -> Self {
+        Self::Storage(err)
+    }
+}
+
+impl Configuration {
+    /// Returns a handle to the keys in namespace `name`.
+    /// The empty name is the default namespace, which holds
+    /// the settings that predate namespaces.
+    pub fn namespace<'a>(&'a mut self, name: &'a str) -> Namespace<'a> {
+        Namespace { config: self, name }
+    }
+}
+
+/// A view of the config that only sees the keys in one namespace.
+/// Keys are transparently prefixed with the namespace name.
+pub struct Namespace<'a> {
+    config: &'a mut Configuration,
+    name: &'a str,
+}
+
+impl Namespace<'_> {
+    fn is_default(&self) -> bool {
+        self.name.is_empty()
+    }
+
+    fn full_key(&self, key: &str) -> alloc::string::String {
+        if self.is_default() {
+            key.into()
+        } else {
+            alloc::format!("{}{NAMESPACE_SEPARATOR}{key}", self.name)
+        }
+    }
+
+    /// Returns `key` without the namespace prefix, or None
+    /// if it belongs to some other namespace
+    fn own_key<'k>(&self, key: &'k str) -> Option<&'k str> {
+        if self.is_default() {
+            (!key.contains(NAMESPACE_SEPARATOR)).then_some(key)
+        } else {
+            key.strip_prefix(self.name)?
+                .strip_prefix(NAMESPACE_SEPARATOR)
+        }
+    }
+
+    pub async fn fetch(
+        &mut self,
+        key: &str,
+    ) -> Result<Option<StrValue>, sequential_storage::Error<embassy_rp::flash::Error>> {
+        let key = self.full_key(key);
+        self.config.fetch(&key).await
+    }
+
+    pub async fn remove(
+        &mut self,
+        key: &str,
+    ) -> Result<(), sequential_storage::Error<embassy_rp::flash::Error>> {
+        let key = self.full_key(key);
+        self.config.remove(&key).await
+    }
+
+    /// Stores `key`, checking first that the namespace
+    /// has enough of its quota left for it
+    pub async fn store(&mut self, key: &str, value: StrValue) -> Result<(), StoreError> {
+        let full_key = self.full_key(key);
+        if StrKey::with_str(&full_key).is_err() {
+            return Err(StoreError::KeyTooLong);
+        }
+        if self.is_default() && key.contains(NAMESPACE_SEPARATOR) {
+            return Err(StoreError::SeparatorInKey);
+        }
+        if !self.is_default() {
+            let used = self.used(key).await?;
+            let needed = used + key.len() + value.len();
+            if needed > NAMESPACE_QUOTA {
+                return Err(StoreError::QuotaExceeded {
+                    used,
+                    quota: NAMESPACE_QUOTA,
+                });
+            }
+        }
+        Ok(self.config.store(&full_key, value).await?)
+    }
+
+    /// Returns the bytes of the keys and values in this namespace,
+    /// other than those of `except`. This reads every item, as
+    /// get_all holds no more than 32 keys.
+    async fn used(
+        &mut self,
+        except: &str,
+    ) -> Result<usize, sequential_storage::Error<embassy_rp::flash::Error>> {
+        let range = self.config.range();
+        let name = self.name;
+        let Some(flash) = &mut self.config.flash else {
+            return Err(sequential_storage::Error::Storage {
+                value: FlashError::Other,
+            });
+        };
+        let mut buf = [0u8; SCRATCH_SIZE];
+        let mut cache = NoCache::new();
+        let mut iter =
+            fetch_all_items::<StrKey, _, _>(&mut flash.flash, range, &mut cache, &mut buf).await?;
+
+        // A key that was stored more than once is seen each time,
+        // and the last is the one in use
+        let mut sizes = alloc::collections::BTreeMap::new();
+        while let Some((key, value)) = iter.next::<StrKey, StrValue>(&mut buf).await? {
+            let own = key
+                .as_str()
+                .strip_prefix(name)
+                .and_then(|k| k.strip_prefix(NAMESPACE_SEPARATOR));
+            if let Some(own) = own.filter(|&k| k != except) {
+                sizes.insert(alloc::string::String::from(own), own.len() + value.len());
+            }
+        }
+        Ok(sizes.values().sum())
+    }
+
+    /// Returns the keys in this namespace, without their prefix
+    pub async fn get_all(
+        &mut self,
+    ) -> Result<
+        FnvIndexMap<StrKey, StrValue, 32>,
+        sequential_storage::Error<embassy_rp::flash::Error>,
+    > {
+        let all = self.config.get_all().await?;
+        let mut map = FnvIndexMap::new();
+        for (key, value) in all {
+            if let Some(key) = self
+                .own_key(key.as_str())
+                .and_then(|k| StrKey::with_str(k).ok())
+            {
+                map.insert(key, value).ok();
+            }
+        }
+        Ok(map)
+    }
 }
 
 pub struct Flash {
@@ -174,7 +600,6 @@ impl Flash {
         Self { flash }
     }
 
-    #[allow(unused)]
     pub async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), FlashError> {
         self.flash.read(offset, bytes).await
     }
@@ -200,16 +625,102 @@ impl embedded_io::Error for EmbeddedFlashError {
     }
 }
 
+/// Checks that a value is acceptable for settings that have
+/// constraints beyond being parseable
+async fn validate_setting(key: &str, value: &str) -> Result<(), alloc::string::String> {
+    match key {
+        "scrollback_lines" => {
+            let lines: usize = value
+                .parse()
+                .map_err(|_| alloc::format!("{key} must be a number of lines"))?;
+            let max =
+                crate::screen::max_scrollback_lines(&*crate::screen::SCREEN.get().lock().await);
+            if lines > max {
+                return Err(alloc::format!(
+                    "{key} can be at most {max} with the memory that is available"
+                ));
+            }
+            Ok(())
+        }
+        "color_palette" => value
+            .parse::<crate::display::Palette>()
+            .map(|_| ())
+            .map_err(|()| {
+                alloc::format!("{key} must be one of default, xterm, solarized or dracula")
+            }),
+        _ => Ok(()),
+    }
+}
+
+/// Settings that can take effect without a reboot are applied here
+async fn apply_setting(key: &str) {
+    match key {
+        "scrollback_lines" => crate::screen::apply_scrollback_config().await,
+        "color_palette" => crate::display::apply_palette_config().await,
+        _ => {}
+    }
+}
+
 pub async fn config_command(args: &[&str]) {
-    match args {
+    // `config --ns NAME ...` operates on the keys in namespace NAME
+    let (ns, args) = match args {
+        ["config", "--ns", ns, rest @ ..] => (*ns, [&["config"][..], rest].concat()),
+        _ => ("", args.to_vec()),
+    };
+    match args.as_slice() {
         ["config", "format"] => {
             let mut config = CONFIG.get().lock().await;
-            let result = config.format().await;
-            print!("{result:?}");
+            match config.format().await {
+                Ok(()) => print!("OK\r\n"),
+                Err(err) => print!("{err:?}\r\n"),
+            }
+        }
+        ["config", "backup"] => {
+            let mut config = CONFIG.get().lock().await;
+            if config.using_backup() {
+                print!("The config is corrupt; use `config restore` instead\r\n");
+                return;
+            }
+            match config.backup().await {
+                Ok(count) => print!("Backed up {count} items\r\n"),
+                Err(err) => print!("{err:?}\r\n"),
+            }
+        }
+        ["config", "restore"] => {
+            let mut config = CONFIG.get().lock().await;
+            match config.restore().await {
+                Ok(0) => print!("The backup is empty; the config was left as it is\r\n"),
+                Ok(count) => print!("Restored {count} items; reboot to apply them\r\n"),
+                Err(err) => print!("{err:?}\r\n"),
+            }
+        }
+        ["config", "write-defaults"] => {
+            let answer = prompt_for_input(
+                "Replace the factory defaults with the current config? Type `yes`:",
+                PromptKind::Text,
+            )
+            .await;
+            if answer.as_deref() != Some("yes") {
+                print!("Cancelled\r\n");
+                return;
+            }
+            let mut config = CONFIG.get().lock().await;
+            match config.write_defaults().await {
+                Ok(count) => print!("Wrote {count} defaults\r\n"),
+                Err(err) => print!("{err:?}\r\n"),
+            }
+        }
+        ["config", "dump", rest @ ..] => dump(rest).await,
+        #[cfg(feature = "net")]
+        ["config", "apply-url", url, fingerprint @ ..] if fingerprint.len() <= 1 => {
+            if let Err(err) = apply_url(ns, url, fingerprint.first().copied()).await {
+                print!("{err}\r\n");
+                print!("Nothing was changed\r\n");
+            }
         }
         ["config", "list"] => {
             let mut config = CONFIG.get().lock().await;
-            match config.get_all().await {
+            match config.namespace(ns).get_all().await {
                 Ok(map) => {
                     for (k, v) in &map {
                         print!("{k}={v}\r\n");
@@ -222,15 +733,33 @@ pub async fn config_command(args: &[&str]) {
         }
         ["config", "get", key] => {
             let mut config = CONFIG.get().lock().await;
-            let value = config.fetch(key).await;
+            let value = config.namespace(ns).fetch(key).await;
             print!("{value:?}\r\n");
         }
         ["config", "rm", key] => {
             let mut config = CONFIG.get().lock().await;
-            let result = config.remove(key).await;
+            let result = config.namespace(ns).remove(key).await;
+            drop(config);
             print!("{result:?}\r\n");
+            if ns.is_empty() {
+                apply_setting(key).await;
+            }
         }
         ["config", "set", key, value] => {
+            if ns.is_empty() {
+                if let Err(err) = validate_setting(key, value).await {
+                    print!("{err}\r\n");
+                    return;
+                }
+            }
+            if value.len() > StrValue::CAPACITY {
+                print!(
+                    "value is {} bytes long; at most {} can be stored\r\n",
+                    value.len(),
+                    StrValue::CAPACITY
+                );
+                return;
+            }
             let value: StrValue = match (*value).try_into() {
                 Ok(v) => v,
                 Err(err) => {
@@ -239,17 +768,261 @@ pub async fn config_command(args: &[&str]) {
                 }
             };
             let mut config = CONFIG.get().lock().await;
-            match config.store(key, value).await {
+            match config.namespace(ns).store(key, value).await {
                 Ok(()) => {
+                    drop(config);
                     print!("OK\r\n");
+                    if ns.is_empty() {
+                        apply_setting(key).await;
+                    }
                 }
                 Err(err) => {
                     print!("{err:?}\r\n");
                 }
             }
         }
+        ["config", "set", _key, _, _, ..] => {
+            print!("a value that contains spaces must be quoted, as in\r\n");
+            print!("  config set wifi_ssid \"My Network\"\r\n");
+        }
         _ => {
             print!("invalid arguments\r\n");
         }
     }
 }
+
+/// The largest manifest that `config apply-url` will fetch, which
+/// is as much as could fit into the config
+#[cfg(feature = "net")]
+const MAX_MANIFEST_SIZE: usize = CONFIG_SIZE as usize;
+
+/// Returns true if `key` looks like it holds credentials, such as
+/// `wifi_pw`, so that its value shouldn't be shown or kept in files
+pub fn is_secret_key(key: &str) -> bool {
+    key.ends_with("_pw") || key.contains("pass") || key.contains("secret")
+}
+
+/// Masks the value of a key that looks like it holds credentials,
+/// for showing what `config apply-url` would change
+#[cfg(feature = "net")]
+fn masked<'a>(key: &str, value: &'a str) -> &'a str {
+    if is_secret_key(key) {
+        "********"
+    } else {
+        value
+    }
+}
+
+/// Fetches a manifest of `key=value` lines from `url` and, if its
+/// SHA-256 matches `fingerprint`, shows what it would change and
+/// applies it once confirmed. The fingerprint may instead be stored
+/// as `config_url_sha256`, or else it is asked for. Blank lines and
+/// lines starting with `#` are ignored. Nothing is changed unless
+/// the whole manifest checks out.
+#[cfg(feature = "net")]
+async fn apply_url(
+    ns: &str,
+    url: &str,
+    fingerprint: Option<&str>,
+) -> Result<(), alloc::string::String> {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use core::fmt::Write as _;
+    use sha2::{Digest, Sha256};
+
+    let fingerprint = match fingerprint {
+        Some(fingerprint) => String::from(fingerprint),
+        None => {
+            let stored = CONFIG.get().lock().await.fetch("config_url_sha256").await;
+            match stored {
+                Ok(Some(stored)) => String::from(stored.as_str()),
+                _ => prompt_for_input("SHA-256 of the manifest:", PromptKind::Text)
+                    .await
+                    .ok_or_else(|| String::from("Cancelled"))?,
+            }
+        }
+    };
+    let fingerprint: String = fingerprint
+        .chars()
+        .filter(|&c| c != ':')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+
+    print!("Fetching {url}\r\n");
+    let manifest = crate::net::http_get(url, MAX_MANIFEST_SIZE).await?;
+
+    let mut digest = String::new();
+    for byte in Sha256::digest(&manifest) {
+        write!(digest, "{byte:02x}").ok();
+    }
+    if digest != fingerprint {
+        return Err(alloc::format!(
+            "The manifest's SHA-256 is {digest}, which doesn't match"
+        ));
+    }
+
+    let manifest =
+        core::str::from_utf8(&manifest).map_err(|_| String::from("The manifest isn't text"))?;
+    let mut settings = Vec::new();
+    for (idx, line) in manifest.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(alloc::format!("line {}: expected key=value", idx + 1));
+        };
+        let (key, value) = (key.trim(), value.trim());
+        if ns.is_empty() {
+            validate_setting(key, value).await?;
+        }
+        let value = StrValue::with_str(value)
+            .map_err(|_| alloc::format!("line {}: the value of {key} is too long", idx + 1))?;
+        settings.push((key, value));
+    }
+
+    let mut changes = Vec::new();
+    {
+        let mut config = CONFIG.get().lock().await;
+        let mut config = config.namespace(ns);
+        for (key, value) in settings {
+            let current = config
+                .fetch(key)
+                .await
+                .map_err(|err| alloc::format!("{key}: {err:?}"))?;
+            if current.as_ref() == Some(&value) {
+                continue;
+            }
+            let new = masked(key, &value);
+            match &current {
+                Some(current) => print!("  {key}: {} -> {new}\r\n", masked(key, current)),
+                None => print!("+ {key}={new}\r\n"),
+            }
+            changes.push((key, value));
+        }
+    }
+    if changes.is_empty() {
+        print!("The config already matches the manifest\r\n");
+        return Ok(());
+    }
+
+    let answer = prompt_for_input("Apply these changes? Type `yes`:", PromptKind::Text).await;
+    if answer.as_deref() != Some("yes") {
+        return Err(String::from("Cancelled"));
+    }
+
+    for (key, value) in &changes {
+        let result = CONFIG
+            .get()
+            .lock()
+            .await
+            .namespace(ns)
+            .store(key, value.clone())
+            .await;
+        if let Err(err) = result {
+            print!("{key}: {err:?}\r\n");
+            continue;
+        }
+        if ns.is_empty() {
+            apply_setting(key).await;
+        }
+    }
+    print!(
+        "Applied {} settings; some take effect after a reboot\r\n",
+        changes.len()
+    );
+    Ok(())
+}
+
+/// Erases all of the config after asking for confirmation, then
+/// reboots so that everything starts over with its default settings
+pub async fn factory_reset_command(_args: &[&str]) {
+    print!("This erases all settings, including wifi and ssh credentials.\r\n");
+    let answer = prompt_for_input("Type `yes` to continue:", PromptKind::Text).await;
+    if answer.as_deref() != Some("yes") {
+        print!("Cancelled; nothing was erased\r\n");
+        return;
+    }
+
+    print!("Erasing config...\r\n");
+    let result = CONFIG.get().lock().await.format().await;
+    if let Err(err) = result {
+        print!("Failed to erase config: {err:?}\r\n");
+        return;
+    }
+
+    print!("Done. Rebooting with default settings...\r\n");
+    // Give the screen a chance to show the message
+    Timer::after(Duration::from_millis(500)).await;
+    crate::keyboard::reboot();
+}
+
+/// Hexdumps the raw bytes of the config region, marking the page and
+/// erase unit boundaries, followed by how many items could be parsed.
+/// This reads the flash directly, so that it works even when the
+/// config can't be parsed.
+async fn dump(args: &[&str]) {
+    let parse = |arg: Option<&&str>, default: u32| match arg {
+        Some(value) => match value.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => value.parse().ok(),
+        },
+        None => Some(default),
+    };
+    let (Some(offset), Some(len)) = (parse(args.first(), 0), parse(args.get(1), CONFIG_SIZE))
+    else {
+        print!("Usage: config dump [OFFSET] [LEN]\r\n");
+        return;
+    };
+    let offset = offset.min(CONFIG_SIZE);
+    let len = len.min(CONFIG_SIZE - offset);
+
+    // Read everything up front, so that the config isn't locked
+    // while the pager waits for keys
+    let mut data = alloc::vec![0u8; len as usize];
+    let (read, (count, err)) = {
+        let mut config = CONFIG.get().lock().await;
+        (
+            config.read_raw(offset, &mut data).await,
+            config.count_items().await,
+        )
+    };
+    if let Err(err) = read {
+        print!("Failed to read config flash: {err:?}\r\n");
+        return;
+    }
+
+    let mut pager = crate::pager::Pager::new().await;
+    let per_line = crate::memory::BYTES_PER_LINE as usize;
+    for (idx, bytes) in data.chunks(per_line).enumerate() {
+        let line_offset = offset + (idx * per_line) as u32;
+        let separator = if line_offset % ERASE_SIZE as u32 == 0 {
+            Some(alloc::format!(
+                "== erase unit {} ==",
+                line_offset / ERASE_SIZE as u32
+            ))
+        } else if line_offset % PAGE_SIZE as u32 == 0 {
+            Some(alloc::format!(
+                "-- page {} --",
+                line_offset / PAGE_SIZE as u32
+            ))
+        } else {
+            None
+        };
+        if let Some(separator) = separator {
+            if !pager.line(&separator).await {
+                return;
+            }
+        }
+        let line = crate::memory::hexdump_line(CONFIG_BASE + line_offset, bytes);
+        if !pager.line(&line).await {
+            return;
+        }
+    }
+
+    let summary = match err {
+        None => alloc::format!("{count} items parsed without error"),
+        Some(err) => alloc::format!("{count} items parsed before {err:?}"),
+    };
+    pager.line(&summary).await;
+}
@@ -0,0 +1,1189 @@
+use crate::PicoCalcDisplay;
+use crate::process::{current_proc, wait_for_render_request};
+use crate::screen::SharedScreen;
+use crate::terminal::{
+    Attributes, CellSurface, Cluster, Color, SCREEN_HEIGHT, SCREEN_WIDTH, VIDEO_RAM_HEIGHT,
+};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU8, Ordering};
+use embassy_futures::select::{Either, select};
+use embassy_futures::select::{Either3, select3};
+use embassy_rp::peripherals::SPI1;
+use embassy_rp::spi::{Blocking, Spi};
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::once_lock::OnceLock;
+use embassy_sync::signal::Signal;
+use embassy_time::{Delay, Duration, Instant, Ticker, Timer};
+use embedded_graphics::mono_font::{MonoFont, MonoTextStyleBuilder};
+use embedded_graphics::pixelcolor::raw::RawU16;
+use embedded_graphics::pixelcolor::{Rgb565, Rgb888};
+use embedded_graphics::prelude::*;
+use embedded_graphics::primitives::*;
+use embedded_graphics::text::Text;
+use mipidsi::options::{Orientation, Rotation};
+
+// This module renders the terminal model onto the ILI9488 display
+// of the PicoCalc, and owns the display via the screen_painter task.
+//
+// Rendering is normally directly onto the display, which means that
+// partially drawn rows can be seen while the display is refreshed.
+// If enabled, and there is enough PSRAM in the heap, rendering is
+// instead into a FrameBuffer, and the changed rows are then copied
+// to the display in a single transfer.
+
+extern crate alloc;
+
+/// The colors that this terminal has always used for the 16
+/// palette entries
+#[cfg(not(feature = "xterm-colors"))]
+const ANSI_COLOR_IDX: [Rgb888; 16] = [
+    // Black
+    Rgb888::new(0x00, 0x00, 0x00),
+    // Maroon
+    Rgb888::new(0xcc, 0x55, 0x55),
+    // Green
+    Rgb888::new(0x55, 0xcc, 0x55),
+    // Olive
+    Rgb888::new(0xcd, 0xcd, 0x55),
+    // Navy
+    Rgb888::new(0x54, 0x55, 0xcb),
+    // Purple
+    Rgb888::new(0xcc, 0x55, 0xcc),
+    // Teal
+    Rgb888::new(0x7a, 0xca, 0xca),
+    // Silver
+    Rgb888::new(0xcc, 0xcc, 0xcc),
+    // Grey
+    Rgb888::new(0x55, 0x55, 0x55),
+    // Red
+    Rgb888::new(0xff, 0x55, 0x55),
+    // Lime
+    Rgb888::new(0x55, 0xff, 0x55),
+    // Yellow
+    Rgb888::new(0xff, 0xff, 0x55),
+    // Blue
+    Rgb888::new(0x55, 0x55, 0xff),
+    // Fuchsia
+    Rgb888::new(0xff, 0x55, 0xff),
+    // Aqua
+    Rgb888::new(0x55, 0xff, 0xff),
+    // White
+    Rgb888::new(0xff, 0xff, 0xff),
+];
+
+/// With the `xterm-colors` feature, the built-in palette is the
+/// canonical xterm 256 color one: the 16 named colors, then a
+/// 6x6x6 color cube, then 24 shades of grey
+#[cfg(feature = "xterm-colors")]
+const ANSI_COLOR_IDX: [Rgb888; 256] = {
+    let mut colors = [Rgb888::new(0, 0, 0); 256];
+    let mut idx = 0;
+    while idx < 256 {
+        colors[idx] = xterm_color(idx as u8);
+        idx += 1;
+    }
+    colors
+};
+
+/// Returns entry `idx` of the xterm 256 color palette
+const fn xterm_color(idx: u8) -> Rgb888 {
+    const CUBE_LEVELS: [u8; 6] = [0x00, 0x5f, 0x87, 0xaf, 0xd7, 0xff];
+    match idx {
+        0..16 => XTERM_COLORS[idx as usize],
+        16..232 => {
+            let cube = (idx - 16) as usize;
+            Rgb888::new(
+                CUBE_LEVELS[cube / 36],
+                CUBE_LEVELS[(cube / 6) % 6],
+                CUBE_LEVELS[cube % 6],
+            )
+        }
+        232.. => {
+            let level = 8 + (idx - 232) * 10;
+            Rgb888::new(level, level, level)
+        }
+    }
+}
+
+/// The 16 named colors as xterm shows them
+const XTERM_COLORS: [Rgb888; 16] = [
+    // Black
+    Rgb888::new(0x00, 0x00, 0x00),
+    // Maroon
+    Rgb888::new(0xcd, 0x00, 0x00),
+    // Green
+    Rgb888::new(0x00, 0xcd, 0x00),
+    // Olive
+    Rgb888::new(0xcd, 0xcd, 0x00),
+    // Navy
+    Rgb888::new(0x00, 0x00, 0xee),
+    // Purple
+    Rgb888::new(0xcd, 0x00, 0xcd),
+    // Teal
+    Rgb888::new(0x00, 0xcd, 0xcd),
+    // Silver
+    Rgb888::new(0xe5, 0xe5, 0xe5),
+    // Grey
+    Rgb888::new(0x7f, 0x7f, 0x7f),
+    // Red
+    Rgb888::new(0xff, 0x00, 0x00),
+    // Lime
+    Rgb888::new(0x00, 0xff, 0x00),
+    // Yellow
+    Rgb888::new(0xff, 0xff, 0x00),
+    // Blue
+    Rgb888::new(0x5c, 0x5c, 0xff),
+    // Fuchsia
+    Rgb888::new(0xff, 0x00, 0xff),
+    // Aqua
+    Rgb888::new(0x00, 0xff, 0xff),
+    // White
+    Rgb888::new(0xff, 0xff, 0xff),
+];
+
+/// The 16 colors of the dark Solarized scheme
+const SOLARIZED_COLORS: [Rgb888; 16] = [
+    // Black
+    Rgb888::new(0x07, 0x36, 0x42),
+    // Maroon
+    Rgb888::new(0xdc, 0x32, 0x2f),
+    // Green
+    Rgb888::new(0x85, 0x99, 0x00),
+    // Olive
+    Rgb888::new(0xb5, 0x89, 0x00),
+    // Navy
+    Rgb888::new(0x26, 0x8b, 0xd2),
+    // Purple
+    Rgb888::new(0xd3, 0x36, 0x82),
+    // Teal
+    Rgb888::new(0x2a, 0xa1, 0x98),
+    // Silver
+    Rgb888::new(0xee, 0xe8, 0xd5),
+    // Grey
+    Rgb888::new(0x00, 0x2b, 0x36),
+    // Red
+    Rgb888::new(0xcb, 0x4b, 0x16),
+    // Lime
+    Rgb888::new(0x58, 0x6e, 0x75),
+    // Yellow
+    Rgb888::new(0x65, 0x7b, 0x83),
+    // Blue
+    Rgb888::new(0x83, 0x94, 0x96),
+    // Fuchsia
+    Rgb888::new(0x6c, 0x71, 0xc4),
+    // Aqua
+    Rgb888::new(0x93, 0xa1, 0xa1),
+    // White
+    Rgb888::new(0xfd, 0xf6, 0xe3),
+];
+
+/// The 16 colors of the Dracula scheme
+const DRACULA_COLORS: [Rgb888; 16] = [
+    // Black
+    Rgb888::new(0x21, 0x22, 0x2c),
+    // Maroon
+    Rgb888::new(0xff, 0x55, 0x55),
+    // Green
+    Rgb888::new(0x50, 0xfa, 0x7b),
+    // Olive
+    Rgb888::new(0xf1, 0xfa, 0x8c),
+    // Navy
+    Rgb888::new(0xbd, 0x93, 0xf9),
+    // Purple
+    Rgb888::new(0xff, 0x79, 0xc6),
+    // Teal
+    Rgb888::new(0x8b, 0xe9, 0xfd),
+    // Silver
+    Rgb888::new(0xf8, 0xf8, 0xf2),
+    // Grey
+    Rgb888::new(0x62, 0x72, 0xa4),
+    // Red
+    Rgb888::new(0xff, 0x6e, 0x6e),
+    // Lime
+    Rgb888::new(0x69, 0xff, 0x94),
+    // Yellow
+    Rgb888::new(0xff, 0xff, 0xa5),
+    // Blue
+    Rgb888::new(0xd6, 0xac, 0xff),
+    // Fuchsia
+    Rgb888::new(0xff, 0x92, 0xdf),
+    // Aqua
+    Rgb888::new(0xa4, 0xff, 0xff),
+    // White
+    Rgb888::new(0xff, 0xff, 0xff),
+];
+
+/// The set of colors used for the 16 palette entries, chosen by
+/// the `color_palette` config
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Palette {
+    /// The built-in `ANSI_COLOR_IDX`
+    Default = 0,
+    Xterm = 1,
+    Solarized = 2,
+    Dracula = 3,
+}
+
+impl core::str::FromStr for Palette {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "default" => Ok(Self::Default),
+            "xterm" => Ok(Self::Xterm),
+            "solarized" => Ok(Self::Solarized),
+            "dracula" => Ok(Self::Dracula),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Palette {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Xterm,
+            2 => Self::Solarized,
+            3 => Self::Dracula,
+            _ => Self::Default,
+        }
+    }
+}
+
+static PALETTE: AtomicU8 = AtomicU8::new(Palette::Default as u8);
+
+/// Returns the color for palette entry `idx`, which counts from 0.
+/// The 16 named colors come from the selected palette; the others
+/// are the xterm color cube and greys.
+fn indexed_color(idx: usize) -> Rgb888 {
+    let named = match Palette::from_u8(PALETTE.load(Ordering::Relaxed)) {
+        Palette::Default => None,
+        Palette::Xterm => Some(&XTERM_COLORS),
+        Palette::Solarized => Some(&SOLARIZED_COLORS),
+        Palette::Dracula => Some(&DRACULA_COLORS),
+    };
+    match named.and_then(|colors| colors.get(idx)) {
+        Some(color) => *color,
+        None => ANSI_COLOR_IDX
+            .get(idx)
+            .copied()
+            .unwrap_or_else(|| xterm_color(idx as u8)),
+    }
+}
+
+/// Selects the palette named by the `color_palette` config, and
+/// has the screen redrawn in it
+pub async fn apply_palette_config() {
+    let name = crate::config::CONFIG
+        .get()
+        .lock()
+        .await
+        .fetch("color_palette")
+        .await
+        .ok()
+        .flatten();
+    let palette = match name {
+        Some(name) => name.as_str().parse().unwrap_or_else(|()| {
+            log::error!("color_palette {name} is not one of default, xterm, solarized or dracula");
+            Palette::Default
+        }),
+        None => Palette::Default,
+    };
+    PALETTE.store(palette as u8, Ordering::Relaxed);
+    current_proc().screen().lock().await.request_full_repaint();
+    crate::process::request_render();
+}
+
+/// Moves each channel of `color` `percent`% of the way toward
+/// `target`; toward 0xff to brighten, or toward 0 to darken.
+fn blend_toward(color: Rgb888, target: u8, percent: u32) -> Rgb888 {
+    let blend = |channel: u8| {
+        let channel = channel as i32;
+        let delta = (target as i32 - channel) * percent.min(100) as i32 / 100;
+        (channel + delta) as u8
+    };
+    Rgb888::new(blend(color.r()), blend(color.g()), blend(color.b()))
+}
+
+/// How far bold text is brightened toward white
+const BOLD_PERCENT: u32 = 40;
+/// How far half-bright text is darkened toward black
+const HALF_BRIGHT_PERCENT: u32 = 50;
+
+/// Returns the RGB value of `color`, or None for the default color,
+/// which depends on whether it is the foreground or background
+fn resolve_color(color: Color) -> Option<Rgb888> {
+    match color {
+        Color::Default => None,
+        Color::Indexed(idx) => Some(indexed_color(idx as usize)),
+        Color::TrueColor(r, g, b) => Some(Rgb888::new(r, g, b)),
+    }
+}
+
+/// Returns the color to draw text of color `fg` in, adjusted for the
+/// intensity given in `attributes`
+fn text_color(fg: Color, attributes: Attributes) -> Rgb565 {
+    match resolve_color(fg) {
+        // The default foreground keeps its distinctive colors
+        // for intensity, as programs often rely on them alone
+        None => {
+            if attributes.contains(Attributes::HALF_BRIGHT) {
+                Rgb565::CSS_DARK_GREEN
+            } else if attributes.contains(Attributes::BOLD) {
+                Rgb565::CSS_SALMON
+            } else {
+                Rgb565::GREEN
+            }
+        }
+        Some(color) => {
+            if attributes.contains(Attributes::HALF_BRIGHT) {
+                blend_toward(color, 0, HALF_BRIGHT_PERCENT).into()
+            } else if attributes.contains(Attributes::BOLD) {
+                blend_toward(color, 0xff, BOLD_PERCENT).into()
+            } else {
+                color.into()
+            }
+        }
+    }
+}
+
+impl CellSurface for PicoCalcDisplay<'_> {
+    fn erase(&mut self) {
+        self.clear(Rgb565::BLACK).unwrap();
+    }
+
+    fn set_scroll_offset(&mut self, offset: u16) {
+        self.set_vertical_scroll_offset(offset).ok();
+    }
+
+    fn draw_cluster(&mut self, cluster: &Cluster<'_>, font: &MonoFont<'_>, row_y: u32) {
+        draw_cluster_on(self, cluster, font, row_y);
+    }
+
+    fn draw_pixels(&mut self, x: u32, y: u32, pixels: &[u16]) {
+        draw_pixels_on(self, x, y, pixels);
+    }
+}
+
+/// Draws a row of RGB565 pixels onto target, which is either the
+/// display itself or a framebuffer
+fn draw_pixels_on<D>(target: &mut D, x: u32, y: u32, pixels: &[u16])
+where
+    D: DrawTarget<Color = Rgb565>,
+    D::Error: core::fmt::Debug,
+{
+    let area = Rectangle::new(
+        Point::new(x as i32, y as i32),
+        Size::new(pixels.len() as u32, 1),
+    );
+    target
+        .fill_contiguous(
+            &area,
+            pixels.iter().map(|&pixel| Rgb565::from(RawU16::new(pixel))),
+        )
+        .unwrap();
+}
+
+/// Draws a run of cells onto target, which is either the display
+/// itself or a framebuffer that is later copied to the display
+fn draw_cluster_on<D>(target: &mut D, cluster: &Cluster<'_>, font: &MonoFont<'_>, row_y: u32)
+where
+    D: DrawTarget<Color = Rgb565>,
+    D::Error: core::fmt::Debug,
+{
+    let boundary_y = (VIDEO_RAM_HEIGHT / font.character_size.height) * font.character_size.height;
+    let boundary_height = VIDEO_RAM_HEIGHT - boundary_y;
+    let vram_height = VIDEO_RAM_HEIGHT as i32;
+
+    let fg_color = text_color(cluster.colors.fg, cluster.attributes);
+    let bg_color = resolve_color(cluster.colors.bg).map_or(Rgb565::BLACK, Rgb565::from);
+
+    let (fg_color, bg_color) = if cluster.attributes.contains(Attributes::REVERSE) {
+        (bg_color, fg_color)
+    } else {
+        (fg_color, bg_color)
+    };
+
+    // Underline and strike-through are drawn over the glyphs, at the
+    // offsets that the font specifies, in the text color; that is
+    // after any reverse video swap, so they always contrast
+    let mut style = MonoTextStyleBuilder::new()
+        .font(font)
+        .text_color(fg_color)
+        .background_color(bg_color);
+    if cluster.attributes.contains(Attributes::UNDERLINE) {
+        style = style.underline();
+    }
+    if cluster.attributes.contains(Attributes::STRIKE_THROUGH) {
+        style = style.strikethrough();
+    }
+    let style = style.build();
+
+    let cell_width = font.character_size.width + font.character_spacing;
+    let start_x = cluster.start_col as u32 * cell_width;
+    let end_x = cluster.end_col as u32 * cell_width;
+    let pixel_width = end_x - start_x;
+
+    target
+        .fill_solid(
+            &Rectangle::new(
+                Point::new(start_x as i32, row_y as i32 % vram_height),
+                Size::new(pixel_width, font.character_size.height as u32),
+            ),
+            bg_color,
+        )
+        .unwrap();
+
+    Text::new(
+        cluster.text,
+        Point::new(
+            start_x as i32,
+            (row_y as i32 + font.baseline as i32) % vram_height,
+        ),
+        style,
+    )
+    .draw(target)
+    .unwrap();
+
+    if row_y % VIDEO_RAM_HEIGHT >= boundary_y
+        || row_y % VIDEO_RAM_HEIGHT + font.character_size.height - 1 >= boundary_y
+    {
+        // Wrapping around end of framebuffer
+        // FIXME: This isn't quite right, but I've run out of patience
+        // to debug it at the moment!
+        log::info!("discontinuity at @ {row_y} vs {boundary_y} ****");
+        let offset = font.character_size.height as i32 - boundary_height as i32;
+        target
+            .fill_solid(
+                &Rectangle::new(
+                    Point::new(start_x as i32, (row_y as i32 + offset) % vram_height),
+                    Size::new(pixel_width, boundary_height),
+                ),
+                bg_color,
+            )
+            .unwrap();
+        Text::new(
+            cluster.text,
+            Point::new(
+                start_x as i32,
+                (row_y as i32 + font.baseline as i32 + offset) % vram_height,
+            ),
+            style,
+        )
+        .draw(target)
+        .unwrap();
+    }
+}
+
+/// A copy of the video ram of the display, in the heap
+struct FrameBuffer {
+    pixels: Vec<Rgb565>,
+    /// The range of pixel rows that have changed since the last flush
+    dirty: Option<(u32, u32)>,
+    /// A scroll offset to apply once the pixels have been flushed
+    scroll_offset: Option<u16>,
+}
+
+const FRAMEBUFFER_PIXELS: usize = SCREEN_WIDTH as usize * VIDEO_RAM_HEIGHT as usize;
+
+impl FrameBuffer {
+    /// Allocates a framebuffer, returning None if there is
+    /// not enough memory; it is too large for internal RAM, so
+    /// this only succeeds when there is PSRAM in the heap
+    fn new() -> Option<Self> {
+        let mut pixels = Vec::new();
+        pixels.try_reserve_exact(FRAMEBUFFER_PIXELS).ok()?;
+        pixels.resize(FRAMEBUFFER_PIXELS, Rgb565::BLACK);
+        Some(Self {
+            pixels,
+            dirty: None,
+            scroll_offset: None,
+        })
+    }
+
+    fn mark_dirty(&mut self, first: u32, last: u32) {
+        self.dirty = Some(match self.dirty {
+            Some((lo, hi)) => (lo.min(first), hi.max(last)),
+            None => (first, last),
+        });
+    }
+
+    /// Copies the changed rows to the display, then applies any
+    /// change to the scroll offset, so that the display never
+    /// shows rows that have only been partially drawn
+    fn flush(&mut self, display: &mut PicoCalcDisplay<'_>) {
+        if let Some((first, last)) = self.dirty.take() {
+            let width = SCREEN_WIDTH as usize;
+            let pixels = &self.pixels[first as usize * width..(last as usize + 1) * width];
+            if let Err(err) = display.set_pixels(
+                0,
+                first as u16,
+                SCREEN_WIDTH - 1,
+                last as u16,
+                pixels.iter().copied(),
+            ) {
+                log::error!("failed to flush framebuffer: {err:?}");
+            }
+        }
+        if let Some(offset) = self.scroll_offset.take() {
+            display.set_vertical_scroll_offset(offset).ok();
+        }
+    }
+}
+
+impl OriginDimensions for FrameBuffer {
+    fn size(&self) -> Size {
+        Size::new(SCREEN_WIDTH as u32, VIDEO_RAM_HEIGHT)
+    }
+}
+
+impl DrawTarget for FrameBuffer {
+    type Color = Rgb565;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Rgb565>>,
+    {
+        let width = SCREEN_WIDTH as i32;
+        for Pixel(point, color) in pixels {
+            if (0..width).contains(&point.x) && (0..VIDEO_RAM_HEIGHT as i32).contains(&point.y) {
+                self.pixels[(point.y * width + point.x) as usize] = color;
+                self.mark_dirty(point.y as u32, point.y as u32);
+            }
+        }
+        Ok(())
+    }
+
+    fn fill_solid(&mut self, area: &Rectangle, color: Rgb565) -> Result<(), Self::Error> {
+        let area = area.intersection(&self.bounding_box());
+        let Some(bottom_right) = area.bottom_right() else {
+            return Ok(());
+        };
+        let width = SCREEN_WIDTH as usize;
+        for y in area.top_left.y..=bottom_right.y {
+            let row = y as usize * width;
+            self.pixels[row + area.top_left.x as usize..=row + bottom_right.x as usize].fill(color);
+        }
+        self.mark_dirty(area.top_left.y as u32, bottom_right.y as u32);
+        Ok(())
+    }
+}
+
+impl CellSurface for FrameBuffer {
+    fn erase(&mut self) {
+        self.pixels.fill(Rgb565::BLACK);
+        self.mark_dirty(0, VIDEO_RAM_HEIGHT - 1);
+    }
+
+    fn set_scroll_offset(&mut self, offset: u16) {
+        self.scroll_offset = Some(offset);
+    }
+
+    fn draw_cluster(&mut self, cluster: &Cluster<'_>, font: &MonoFont<'_>, row_y: u32) {
+        draw_cluster_on(self, cluster, font, row_y);
+    }
+
+    fn draw_pixels(&mut self, x: u32, y: u32, pixels: &[u16]) {
+        draw_pixels_on(self, x, y, pixels);
+    }
+}
+
+/// Switches to rendering via a framebuffer if the
+/// `display_framebuffer` config is set to `on`. This must be
+/// called after any PSRAM has been added to the heap.
+pub async fn apply_framebuffer_config() {
+    let enabled = crate::config::CONFIG
+        .get()
+        .lock()
+        .await
+        .fetch("display_framebuffer")
+        .await
+        .ok()
+        .flatten()
+        .map(|value| value.as_str() == "on")
+        .unwrap_or(false);
+    if !enabled {
+        return;
+    }
+    // The framebuffer can only ever fit in PSRAM
+    if !crate::psram::psram_available() {
+        log::warn!("display: display_framebuffer needs PSRAM, which was not found");
+        return;
+    }
+    DISPLAY_COMMANDS.send(DisplayCommand::UseFramebuffer).await;
+}
+
+/// Returns the orientation for a rotation of `degrees`, or None if
+/// that isn't 0 or 180. The panel is mounted mirrored, so it is
+/// flipped horizontally to undo that, unless `mirror` is set for
+/// assemblies that show it the other way.
+/// 90 and 270 aren't offered: the painter addresses 480 rows of
+/// video ram and uses the panel's vertical scrolling, neither of
+/// which match the geometry once rows and columns are swapped.
+fn orientation(degrees: u16, mirror: bool) -> Option<Orientation> {
+    let rotation = match degrees {
+        0 => Rotation::Deg0,
+        180 => Rotation::Deg180,
+        _ => return None,
+    };
+    let orientation = Orientation::new().rotate(rotation);
+    Some(if mirror {
+        orientation
+    } else {
+        orientation.flip_horizontal()
+    })
+}
+
+/// Returns the orientation set by the `screen_rotation` and
+/// `screen_mirror` config
+pub async fn configured_orientation() -> Orientation {
+    let mut config = crate::config::CONFIG.get().lock().await;
+    let degrees = config
+        .fetch_parsed::<u16>("screen_rotation")
+        .await
+        .unwrap_or(0);
+    let mirror = config
+        .fetch("screen_mirror")
+        .await
+        .ok()
+        .flatten()
+        .is_some_and(|value| value.as_str() == "on");
+    orientation(degrees, mirror).unwrap_or_else(|| {
+        log::error!("screen_rotation {degrees} is not 0 or 180");
+        Orientation::new().flip_horizontal()
+    })
+}
+
+#[embassy_executor::task]
+pub async fn screen_painter(mut display: PicoCalcDisplay<'static>) {
+    display.clear(Rgb565::BLACK).unwrap();
+    if let Err(err) = display.set_vertical_scroll_region(0, 0) {
+        log::error!("failed to set_vertical_scroll_region: {err:?}");
+    }
+
+    // Display update takes ~128ms @ 40_000_000
+    let mut ticker = Ticker::every(Duration::from_millis(200));
+    let mut sleeping = false;
+    let mut framebuffer: Option<FrameBuffer> = None;
+    let mut shown: Option<SharedScreen> = None;
+    let mut showing_pattern = false;
+    // The notification being shown, and when it was first shown
+    let mut status: Option<(String, Instant)> = None;
+    loop {
+        // Show the screen of whichever process is current, redrawing
+        // it completely if it isn't the one that is on the display
+        let current = current_proc().screen();
+        if !shown
+            .as_ref()
+            .is_some_and(|shown| Arc::ptr_eq(shown, &current))
+        {
+            if let Some(prior) = shown.take() {
+                prior.lock().await.set_status_line(None);
+            }
+            current.lock().await.request_full_repaint();
+            shown = Some(current.clone());
+        }
+
+        if status
+            .as_ref()
+            .is_some_and(|(_, since)| since.elapsed() >= crate::notify::SHOW_FOR)
+        {
+            status = None;
+        }
+        if status.is_none() {
+            status = crate::notify::next_notification().map(|message| (message, Instant::now()));
+        }
+
+        if !sleeping && !showing_pattern {
+            let mut screen = current.lock().await;
+            screen.set_status_line(status.as_ref().map(|(message, _)| message.as_str()));
+            match framebuffer.as_mut() {
+                Some(fb) => {
+                    screen.paint(fb);
+                    drop(screen);
+                    fb.flush(&mut display);
+                }
+                None => screen.paint(&mut display),
+            }
+        }
+        // Wait for the next tick, or for a request to redraw the
+        // prompt, in which case we update the display right away
+        // so that typing feels responsive.
+        match select3(
+            ticker.next(),
+            wait_for_render_request(),
+            DISPLAY_COMMANDS.receive(),
+        )
+        .await
+        {
+            Either3::First(()) => {}
+            Either3::Second(()) => {
+                current_proc().render().await;
+            }
+            Either3::Third(DisplayCommand::Sleep) => {
+                if !sleeping {
+                    LCD_LEVEL_BEFORE_SLEEP
+                        .store(crate::keyboard::lcd_backlight_level(), Ordering::Relaxed);
+                    crate::keyboard::set_lcd_backlight(0).await;
+                    // Sends SLPIN (0x10)
+                    if let Err(err) = display.sleep(&mut Delay) {
+                        log::error!("failed to put display to sleep: {err:?}");
+                    }
+                    sleeping = true;
+                }
+            }
+            Either3::Third(DisplayCommand::Wake) => {
+                if sleeping {
+                    // Sends SLPOUT (0x11) and waits the 120ms that
+                    // the display needs before it can be used
+                    if let Err(err) = display.wake(&mut Delay) {
+                        log::error!("failed to wake display: {err:?}");
+                    }
+                    sleeping = false;
+                    crate::keyboard::set_lcd_backlight(
+                        LCD_LEVEL_BEFORE_SLEEP.load(Ordering::Relaxed),
+                    )
+                    .await;
+                }
+            }
+            Either3::Third(DisplayCommand::UseFramebuffer) => {
+                if framebuffer.is_none() {
+                    framebuffer = FrameBuffer::new();
+                    match framebuffer {
+                        Some(_) => {
+                            log::info!("display: rendering via framebuffer");
+                            current.lock().await.request_full_repaint();
+                        }
+                        None => log::warn!("display: not enough memory for framebuffer"),
+                    }
+                }
+            }
+            Either3::Third(DisplayCommand::Bench) => {
+                let area = Rectangle::new(
+                    Point::zero(),
+                    Size::new(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32),
+                );
+                let start = Instant::now();
+                for _ in 0..BENCH_ITERATIONS {
+                    display.fill_solid(&area, Rgb565::BLACK).unwrap();
+                }
+                BENCH_RESULT.signal(start.elapsed() / BENCH_ITERATIONS);
+                // We just scribbled over whatever was on screen
+                current.lock().await.request_full_repaint();
+            }
+            Either3::Third(DisplayCommand::ShowPattern(pattern)) => {
+                if let Err(err) = display.set_vertical_scroll_offset(0) {
+                    log::error!("failed to reset scroll offset: {err:?}");
+                }
+                pattern.draw(&mut display);
+                showing_pattern = true;
+            }
+            Either3::Third(DisplayCommand::EndPattern) => {
+                showing_pattern = false;
+                current.lock().await.request_full_repaint();
+            }
+            Either3::Third(DisplayCommand::SetOrientation(orientation)) => {
+                if let Err(err) = display.set_orientation(orientation) {
+                    log::error!("failed to set orientation: {err:?}");
+                }
+                // The scroll region is defined in terms of the panel's
+                // own rows, so set it up again for the new orientation
+                if let Err(err) = display.set_vertical_scroll_region(0, 0) {
+                    log::error!("failed to set_vertical_scroll_region: {err:?}");
+                }
+                current.lock().await.request_full_repaint();
+            }
+        }
+    }
+}
+
+/// Patterns for checking the display itself, drawn directly
+/// onto it rather than via the terminal model
+#[derive(Clone, Copy)]
+pub enum TestPattern {
+    /// A vertical bar of each of the 16 palette colors
+    ColorBar,
+    /// Bands of red, green, blue and grey, each running from
+    /// black on the left to full intensity on the right
+    Gradient,
+    /// A grid of single pixel lines
+    Crosshatch,
+    /// Alternating black and white pixels
+    Checkerboard,
+}
+
+impl TestPattern {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "colorbar" => Some(Self::ColorBar),
+            "gradient" => Some(Self::Gradient),
+            "crosshatch" => Some(Self::Crosshatch),
+            "checkerboard" => Some(Self::Checkerboard),
+            _ => None,
+        }
+    }
+
+    fn draw(self, display: &mut PicoCalcDisplay<'static>) {
+        let (width, height) = (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+        let mut fill = |x: u32, y: u32, w: u32, h: u32, color: Rgb565| {
+            let area = Rectangle::new(Point::new(x as i32, y as i32), Size::new(w, h));
+            display.fill_solid(&area, color).unwrap();
+        };
+
+        match self {
+            Self::ColorBar => {
+                let bars = 16;
+                for idx in 0..bars {
+                    let x = idx * width / bars;
+                    let next_x = (idx + 1) * width / bars;
+                    fill(x, 0, next_x - x, height, indexed_color(idx as usize).into());
+                }
+            }
+            Self::Gradient => {
+                let band = height / 4;
+                for x in 0..width {
+                    let level = (x * 0xff / (width - 1)) as u8;
+                    let colors = [
+                        Rgb888::new(level, 0, 0),
+                        Rgb888::new(0, level, 0),
+                        Rgb888::new(0, 0, level),
+                        Rgb888::new(level, level, level),
+                    ];
+                    for (idx, color) in colors.into_iter().enumerate() {
+                        fill(x, idx as u32 * band, 1, band, color.into());
+                    }
+                }
+            }
+            Self::Crosshatch => {
+                const SPACING: u32 = 16;
+                fill(0, 0, width, height, Rgb565::BLACK);
+                for x in (0..width).step_by(SPACING as usize) {
+                    fill(x, 0, 1, height, Rgb565::WHITE);
+                }
+                for y in (0..height).step_by(SPACING as usize) {
+                    fill(0, y, width, 1, Rgb565::WHITE);
+                }
+                // Close off the right and bottom edges
+                fill(width - 1, 0, 1, height, Rgb565::WHITE);
+                fill(0, height - 1, width, 1, Rgb565::WHITE);
+            }
+            Self::Checkerboard => {
+                let pixels = (0..height).flat_map(|y| {
+                    (0..width).map(move |x| {
+                        if (x + y) % 2 == 0 {
+                            Rgb565::WHITE
+                        } else {
+                            Rgb565::BLACK
+                        }
+                    })
+                });
+                if let Err(err) =
+                    display.set_pixels(0, 0, SCREEN_WIDTH - 1, SCREEN_HEIGHT - 1, pixels)
+                {
+                    log::error!("failed to draw checkerboard: {err:?}");
+                }
+            }
+        }
+    }
+}
+
+/// Requests for the screen_painter, which owns the display
+pub enum DisplayCommand {
+    /// Turn off the backlight and put the display to sleep
+    Sleep,
+    /// Wake the display and restore the backlight
+    Wake,
+    /// Time how long it takes to fill the screen
+    Bench,
+    /// Render via a framebuffer from now on, if there is memory for it
+    UseFramebuffer,
+    /// Draw a test pattern, and stop painting the screen until EndPattern
+    ShowPattern(TestPattern),
+    /// Go back to painting the screen
+    EndPattern,
+    /// Rotate or mirror the display, then redraw everything
+    SetOrientation(Orientation),
+}
+
+const BENCH_ITERATIONS: u32 = 4;
+static BENCH_RESULT: Signal<CriticalSectionRawMutex, Duration> = Signal::new();
+
+static DISPLAY_COMMANDS: Channel<CriticalSectionRawMutex, DisplayCommand, 2> = Channel::new();
+static LCD_LEVEL_BEFORE_SLEEP: AtomicU8 = AtomicU8::new(0x80);
+
+/// The SPI bus that the display is on. Nothing else shares it.
+pub static DISPLAY_SPI_BUS: OnceLock<
+    BlockingMutex<CriticalSectionRawMutex, RefCell<Spi<'static, SPI1, Blocking>>>,
+> = OnceLock::new();
+/// Used when `display_spi_freq_mhz` isn't set
+pub const DEFAULT_SPI_FREQ: u32 = 62_500_000;
+/// The SPI clock can be at most half of clk_peri, which runs at 150MHz
+const MAX_SPI_FREQ: u32 = 75_000_000;
+const MIN_SPI_FREQ: u32 = 1_000_000;
+/// How long confirm_pattern waits for the user to confirm that
+/// the display looks right
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Converts a `display_spi_freq_mhz` value into Hz, limited
+/// to what the SPI peripheral can do
+pub fn spi_freq_from_mhz(mhz: u32) -> u32 {
+    mhz.saturating_mul(1_000_000)
+        .clamp(MIN_SPI_FREQ, MAX_SPI_FREQ)
+}
+
+fn set_spi_freq(freq: u32) {
+    if let Some(bus) = DISPLAY_SPI_BUS.try_get() {
+        bus.lock(|spi| spi.borrow_mut().set_frequency(freq));
+    }
+    crate::sysinfo::DISPLAY_SPI_FREQ.store(freq, Ordering::Relaxed);
+    log::info!("display spi bus running at {freq}Hz");
+}
+
+pub async fn display_command(args: &[&str]) {
+    match args {
+        [_, "off"] => DISPLAY_COMMANDS.send(DisplayCommand::Sleep).await,
+        [_, "on"] => DISPLAY_COMMANDS.send(DisplayCommand::Wake).await,
+        [_, "bench"] => bench().await,
+        [_, "spi", mhz] => spi(mhz).await,
+        _ => print!("Usage: display [on|off|bench|spi MHZ]\r\n"),
+    }
+}
+
+/// Shows `pattern` and asks the user to confirm that it looks
+/// right by pressing `y`. Any other key, or waiting for longer
+/// than CONFIRM_TIMEOUT, is taken to mean that it doesn't.
+pub async fn confirm_pattern(pattern: TestPattern) -> bool {
+    let Some(capture) =
+        crate::process::KeyCapture::begin("Press y within 10s if this looks right").await
+    else {
+        return false;
+    };
+    DISPLAY_COMMANDS
+        .send(DisplayCommand::ShowPattern(pattern))
+        .await;
+    let confirmed = match select(capture.next_key(), Timer::after(CONFIRM_TIMEOUT)).await {
+        Either::First(key) => matches!(key.key, crate::keyboard::Key::Char('y' | 'Y')),
+        Either::Second(()) => false,
+    };
+    capture.end().await;
+    DISPLAY_COMMANDS.send(DisplayCommand::EndPattern).await;
+    confirmed
+}
+
+/// Switches the display SPI bus to `mhz` and shows a test pattern.
+/// The new frequency is only kept, and saved to the config, if the
+/// user confirms that the pattern looks right; otherwise the prior
+/// frequency is restored, so that a setting which corrupts the
+/// display can't leave it unusable.
+async fn spi(mhz: &str) {
+    let Ok(mhz) = mhz.parse::<u32>() else {
+        print!("frequency `{mhz}` must be a whole number of MHz\r\n");
+        return;
+    };
+    let prior = crate::sysinfo::DISPLAY_SPI_FREQ.load(Ordering::Relaxed);
+    let freq = spi_freq_from_mhz(mhz);
+    set_spi_freq(freq);
+
+    if !confirm_pattern(TestPattern::ColorBar).await {
+        set_spi_freq(prior);
+        print!("Reverted to {}kHz\r\n", prior / 1000);
+        return;
+    }
+
+    let Ok(value) = crate::config::StrValue::with_str(alloc::format!("{}", freq / 1_000_000))
+    else {
+        return;
+    };
+    let result = crate::config::CONFIG
+        .get()
+        .lock()
+        .await
+        .store("display_spi_freq_mhz", value)
+        .await;
+    match result {
+        Ok(()) => print!("Display SPI bus is now {}kHz\r\n", freq / 1000),
+        Err(err) => print!("failed to save display_spi_freq_mhz: {err:?}\r\n"),
+    }
+}
+
+pub async fn screen_command(args: &[&str]) {
+    match args {
+        [_, "font", rest @ ..] => return font(rest).await,
+        [_, "rotate", degrees] => return rotate(degrees).await,
+        [_, "mirror", setting @ ("on" | "off")] => {
+            return set_orientation_config("screen_mirror", setting).await;
+        }
+        _ => {}
+    }
+    let pattern = match args {
+        [_, "test"] => Some(TestPattern::ColorBar),
+        [_, "test", name] => TestPattern::parse(name),
+        _ => None,
+    };
+    let Some(pattern) = pattern else {
+        print!("Usage: screen test [colorbar|gradient|crosshatch|checkerboard]\r\n");
+        print!("       screen font [list|set N]\r\n");
+        print!("       screen rotate [0|180]\r\n");
+        print!("       screen mirror [on|off]\r\n");
+        return;
+    };
+
+    DISPLAY_COMMANDS
+        .send(DisplayCommand::ShowPattern(pattern))
+        .await;
+    crate::process::wait_for_key("").await;
+    DISPLAY_COMMANDS.send(DisplayCommand::EndPattern).await;
+}
+
+async fn rotate(degrees: &str) {
+    if !degrees
+        .parse::<u16>()
+        .is_ok_and(|degrees| orientation(degrees, false).is_some())
+    {
+        print!("rotation must be 0 or 180\r\n");
+        return;
+    }
+    set_orientation_config("screen_rotation", degrees).await;
+}
+
+/// Saves an orientation setting and applies it to the display
+async fn set_orientation_config(key: &str, value: &str) {
+    let Ok(value) = crate::config::StrValue::with_str(value) else {
+        return;
+    };
+    let result = crate::config::CONFIG
+        .get()
+        .lock()
+        .await
+        .store(key, value)
+        .await;
+    if let Err(err) = result {
+        print!("failed to save {key}: {err:?}\r\n");
+        return;
+    }
+    let orientation = configured_orientation().await;
+    DISPLAY_COMMANDS
+        .send(DisplayCommand::SetOrientation(orientation))
+        .await;
+}
+
+/// Lists the fonts, marking the current one with `*`, or
+/// selects one by its index in the list
+async fn font(args: &[&str]) {
+    match args {
+        [] | ["list"] => {
+            let current = crate::screen::SCREEN.get().lock().await.font_index();
+            for (idx, (name, width, height)) in crate::terminal::fonts().enumerate() {
+                let mark = if Some(idx) == current { '*' } else { ' ' };
+                print!("{mark}{idx} {name} ({width}x{height})\r\n");
+            }
+        }
+        ["set", idx] => {
+            let Ok(idx) = idx.parse::<usize>() else {
+                print!("font index `{idx}` must be a number\r\n");
+                return;
+            };
+            let screen = crate::screen::SCREEN.get();
+            if !screen.lock().await.set_font(idx) {
+                print!("There is no font {idx}; see `screen font list`\r\n");
+                return;
+            }
+            crate::screen::save_font(screen).await;
+        }
+        _ => print!("Usage: screen font [list|set N]\r\n"),
+    }
+}
+
+/// Measures the effective pixel fill rate of the display and
+/// compares it with what the SPI bus could deliver if it were
+/// never idle. The ILI9488 takes 3 bytes per pixel over SPI.
+async fn bench() {
+    BENCH_RESULT.reset();
+    DISPLAY_COMMANDS.send(DisplayCommand::Bench).await;
+    let elapsed = BENCH_RESULT.wait().await;
+
+    let pixels = SCREEN_WIDTH as u64 * SCREEN_HEIGHT as u64;
+    let micros = elapsed.as_micros().max(1);
+    let actual = pixels * 1_000_000 / micros;
+
+    let spi_freq = crate::sysinfo::DISPLAY_SPI_FREQ.load(Ordering::Relaxed) as u64;
+    let theoretical = spi_freq / (3 * 8);
+
+    print!(
+        "fill {SCREEN_WIDTH}x{SCREEN_HEIGHT}: {}.{:03}ms, {} px/s\r\n",
+        micros / 1000,
+        micros % 1000,
+        actual
+    );
+    if theoretical > 0 {
+        print!(
+            "max at {}kHz SPI: {} px/s, achieved {}%\r\n",
+            spi_freq / 1000,
+            theoretical,
+            actual * 100 / theoretical
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blend_toward_white_and_black() {
+        let color = Rgb888::new(100, 0, 200);
+        assert_eq!(blend_toward(color, 0xff, 40), Rgb888::new(162, 102, 222));
+        assert_eq!(blend_toward(color, 0, 50), Rgb888::new(50, 0, 100));
+    }
+
+    #[test]
+    fn blend_toward_limits() {
+        let color = Rgb888::new(100, 0, 200);
+        assert_eq!(blend_toward(color, 0xff, 0), color);
+        assert_eq!(blend_toward(color, 0xff, 100), Rgb888::WHITE);
+        assert_eq!(blend_toward(color, 0, 250), Rgb888::BLACK);
+        assert_eq!(blend_toward(Rgb888::WHITE, 0xff, 40), Rgb888::WHITE);
+        assert_eq!(blend_toward(Rgb888::BLACK, 0, 50), Rgb888::BLACK);
+    }
+
+    #[test]
+    fn default_foreground_keeps_its_colors() {
+        assert_eq!(text_color(Color::Default, Attributes::NONE), Rgb565::GREEN);
+        assert_eq!(
+            text_color(Color::Default, Attributes::BOLD),
+            Rgb565::CSS_SALMON
+        );
+        assert_eq!(
+            text_color(Color::Default, Attributes::HALF_BRIGHT),
+            Rgb565::CSS_DARK_GREEN
+        );
+    }
+
+    #[test]
+    fn other_colors_are_scaled_for_intensity() {
+        let red = Color::TrueColor(200, 0, 0);
+        assert_eq!(
+            text_color(red, Attributes::NONE),
+            Rgb565::from(Rgb888::new(200, 0, 0))
+        );
+        assert_eq!(
+            text_color(red, Attributes::BOLD),
+            Rgb565::from(Rgb888::new(222, 102, 102))
+        );
+        assert_eq!(
+            text_color(red, Attributes::HALF_BRIGHT),
+            Rgb565::from(Rgb888::new(100, 0, 0))
+        );
+        // Reverse video doesn't change the color itself
+        assert_eq!(
+            text_color(red, Attributes::BOLD | Attributes::REVERSE),
+            text_color(red, Attributes::BOLD)
+        );
+    }
+}
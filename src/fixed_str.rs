@@ -36,6 +36,9 @@ impl<const N: usize> core::fmt::Debug for FixedString<N> {
 }
 
 impl<const N: usize> FixedString<N> {
+    /// The most bytes that the string can hold
+    pub const CAPACITY: usize = N;
+
     pub const fn new() -> Self {
         Self(String::new())
     }
@@ -139,4 +139,11 @@ pub async fn free_command(_args: &[&str]) {
         "{:<10} {qmi_total:>10} {qmi_used:>10} {qmi_free:>10}\r\n",
         "PSRAM (QMI)"
     );
+
+    let (lines, bytes, limit) = {
+        let screen = crate::screen::SCREEN.get().lock().await;
+        let (lines, bytes) = screen.scrollback_usage();
+        (lines, bytes, screen.scrollback_limit())
+    };
+    print!("Scrollback: {lines} of {limit} lines, {bytes} bytes\r\n");
 }
@@ -0,0 +1,222 @@
+use crate::config::{CONFIG, is_secret_key};
+use crate::pager::Pager;
+use crate::storage::{read_sd_file, write_sd_file};
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::lazy_lock::LazyLock;
+use embassy_sync::mutex::Mutex;
+
+extern crate alloc;
+
+// This module remembers the commands that were entered into the
+// shell. If the `history_save` config is set to `on`, the history
+// is also kept in HISTORY_PATH on the SD card, so that it survives
+// a reboot. The card usually isn't ready as early as the shell is,
+// so the file is read the first time that the history is used,
+// rather than at boot.
+//
+// In the shell, Up and Down walk through the history, by way of a
+// Recall that tracks where the walk has got to.
+//
+// Commands that set a config key holding credentials, such as
+// `config set wifi_pw ...`, are remembered until reboot but are
+// never written to the card, which anyone could remove and read.
+
+/// The number of commands that are remembered
+const HISTORY_LEN: usize = 32;
+/// The file is rewritten with just the remembered commands
+/// once it grows beyond this size
+const MAX_FILE_BYTES: u32 = 4096;
+const HISTORY_PATH: &str = "/HISTORY";
+
+pub static HISTORY: LazyLock<Mutex<CriticalSectionRawMutex, History>> =
+    LazyLock::new(|| Mutex::new(History::default()));
+
+#[derive(Default)]
+pub struct History {
+    /// Oldest first
+    entries: VecDeque<String>,
+    /// Whether the file has been read into entries
+    loaded: bool,
+}
+
+/// Returns true if `command` sets a config key that holds
+/// credentials, and so mustn't be saved to the card
+fn sets_secret(command: &str) -> bool {
+    let mut args = command.split_whitespace();
+    if args.next() != Some("config") {
+        return false;
+    }
+    let args: Vec<&str> = args.collect();
+    let args = match args.as_slice() {
+        ["--ns", _, rest @ ..] => rest,
+        args => args,
+    };
+    matches!(args, ["set", key, ..] if is_secret_key(key.trim_matches(['"', '\''])))
+}
+
+async fn save_enabled() -> bool {
+    CONFIG
+        .get()
+        .lock()
+        .await
+        .fetch("history_save")
+        .await
+        .ok()
+        .flatten()
+        .map(|value| value.as_str() == "on")
+        .unwrap_or(false)
+}
+
+impl History {
+    fn remember(&mut self, command: &str) {
+        if self.entries.back().map(|last| last.as_str()) == Some(command) {
+            return;
+        }
+        if self.entries.len() >= HISTORY_LEN {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(command.into());
+    }
+
+    /// Reads the saved history, if enabled and not already loaded.
+    /// Commands from the file are placed before any that were
+    /// entered since boot.
+    pub async fn load(&mut self) {
+        if self.loaded || !save_enabled().await {
+            return;
+        }
+        let data = match read_sd_file(HISTORY_PATH).await {
+            Ok(data) => data,
+            Err(err) => {
+                // Most likely, the card isn't ready yet, or the
+                // file hasn't been created. Try again next time.
+                log::debug!("history: {err}");
+                return;
+            }
+        };
+        self.loaded = true;
+
+        let recent = core::mem::take(&mut self.entries);
+        for line in String::from_utf8_lossy(&data).lines() {
+            if !line.trim().is_empty() {
+                self.remember(line);
+            }
+        }
+        for command in &recent {
+            self.remember(command);
+        }
+    }
+
+    /// Remembers a command, and appends it to the saved history
+    /// if that is enabled
+    pub async fn push(&mut self, command: &str) {
+        if command.trim().is_empty() {
+            return;
+        }
+        self.load().await;
+        self.remember(command);
+
+        if sets_secret(command) || !save_enabled().await {
+            return;
+        }
+        let line = alloc::format!("{command}\n");
+        match write_sd_file(HISTORY_PATH, line.as_bytes(), true).await {
+            Ok(len) if len > MAX_FILE_BYTES => self.rewrite().await,
+            Ok(_) => {}
+            Err(err) => log::warn!("history: failed to save: {err}"),
+        }
+    }
+
+    /// Returns a copy of the remembered commands, oldest first
+    pub fn entries(&self) -> Vec<String> {
+        self.entries.iter().cloned().collect()
+    }
+
+    /// Replaces the file with just the remembered commands,
+    /// which bounds its size
+    async fn rewrite(&self) {
+        let mut text = String::new();
+        for command in self.entries.iter().filter(|c| !sets_secret(c)) {
+            text.push_str(command);
+            text.push('\n');
+        }
+        if let Err(err) = write_sd_file(HISTORY_PATH, text.as_bytes(), false).await {
+            log::warn!("history: failed to rotate: {err}");
+        }
+    }
+}
+
+/// Tracks a walk through the history with Up and Down. What was
+/// typed before the walk began is kept, so that stepping past the
+/// newest entry gives it back. The recalled text is a copy, so
+/// editing it doesn't change the history.
+#[derive(Default)]
+pub struct Recall {
+    /// The index into History::entries of the entry being shown
+    pos: Option<usize>,
+    draft: String,
+}
+
+impl Recall {
+    /// Steps to the next older entry and returns it, or returns
+    /// None if already at the oldest one. `current` is the text
+    /// being edited, which is kept if the walk is just starting.
+    pub fn older(&mut self, history: &History, current: &str) -> Option<String> {
+        let pos = match self.pos {
+            None => {
+                let newest = history.entries.len().checked_sub(1)?;
+                self.draft = current.into();
+                newest
+            }
+            Some(0) => return None,
+            Some(pos) => pos - 1,
+        };
+        self.pos = Some(pos);
+        history.entries.get(pos).cloned()
+    }
+
+    /// Steps to the next newer entry and returns it. Stepping
+    /// past the newest entry returns the text from before the
+    /// walk began. Returns None if not walking the history.
+    pub fn newer(&mut self, history: &History) -> Option<String> {
+        let pos = self.pos?;
+        match history.entries.get(pos + 1) {
+            Some(entry) => {
+                self.pos = Some(pos + 1);
+                Some(entry.clone())
+            }
+            None => {
+                self.pos = None;
+                Some(core::mem::take(&mut self.draft))
+            }
+        }
+    }
+
+    /// Ends the walk, as when a command is entered
+    pub fn reset(&mut self) {
+        self.pos = None;
+        self.draft.clear();
+    }
+}
+
+pub async fn history_command(_args: &[&str]) {
+    let entries = {
+        let mut history = HISTORY.get().lock().await;
+        history.load().await;
+        history.entries()
+    };
+    // The lock isn't held while printing, as the pager may wait
+    // for a key, and Up and Down in the shell need the history
+    let mut pager = Pager::new().await;
+    for (idx, command) in entries.iter().enumerate() {
+        if !pager
+            .line(&alloc::format!("{:>3}  {command}", idx + 1))
+            .await
+        {
+            break;
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use alloc::vec::Vec;
+
+extern crate alloc;
+
+// Images sent by programs, using either sixel or the kitty graphics
+// protocol, are decoded into an Image, which the screen model
+// paints onto the display alongside the text.
+
+/// A decoded image
+pub struct Image {
+    /// Identifies the image to the lines that display it
+    pub id: u16,
+    /// The column of the left edge of the image
+    pub col: u8,
+    pub width: usize,
+    pub height: usize,
+    /// RGB565 pixels, row by row
+    pub pixels: Vec<u16>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, pixels: Vec<u16>) -> Self {
+        Self {
+            id: 0,
+            col: 0,
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// Returns the pixels of row `y`, if the image has that many rows
+    pub fn row(&self, y: usize) -> Option<&[u16]> {
+        let start = y.checked_mul(self.width)?;
+        self.pixels.get(start..start + self.width)
+    }
+}
+
+/// Allocates black pixels for an image of `width` by `height`,
+/// returning None if there isn't enough memory
+pub fn alloc_pixels(width: usize, height: usize) -> Option<Vec<u16>> {
+    let mut pixels = Vec::new();
+    if pixels.try_reserve_exact(width * height).is_err() {
+        log::warn!("image: not enough memory for {width}x{height} pixels");
+        return None;
+    }
+    pixels.resize(width * height, 0);
+    Some(pixels)
+}
+
+pub fn rgb565(red: u8, green: u8, blue: u8) -> u16 {
+    ((red as u16 & 0xf8) << 8) | ((green as u16 & 0xfc) << 3) | (blue as u16 >> 3)
+}
@@ -1,17 +1,34 @@
-use crate::process::current_proc;
-use crate::screen::SCREEN;
+use crate::process::{current_proc, request_render};
+use alloc::vec::Vec;
 use core::fmt::Formatter;
-use core::sync::atomic::{AtomicU8, Ordering};
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
 use embassy_rp::i2c::I2c;
 use embassy_rp::peripherals::I2C1;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::lazy_lock::LazyLock;
 use embassy_sync::mutex::Mutex;
 use embassy_time::{Duration, Instant, Ticker, with_timeout};
+extern crate alloc;
 
 static BATTERY_PCT: AtomicU8 = AtomicU8::new(0xff);
+static I2C_FREQ: AtomicU32 = AtomicU32::new(0);
+static KBD_READS: AtomicU32 = AtomicU32::new(0);
+static KBD_ERRORS: AtomicU32 = AtomicU32::new(0);
+static KBD_ADDR: AtomicU8 = AtomicU8::new(DEFAULT_KBD_ADDR);
 
-const KBD_ADDR: u8 = 0x1f;
+fn kbd_addr() -> u8 {
+    KBD_ADDR.load(Ordering::Relaxed)
+}
+
+/// Short enough that deliberately typing the same key twice
+/// is never mistaken for chatter
+const DEFAULT_DEBOUNCE_MS: u64 = 20;
+
+/// The address of the keyboard MCU in the stock firmware.
+/// Firmware variants may use another, which can be set via
+/// the `kbd_i2c_addr` config key.
+const DEFAULT_KBD_ADDR: u8 = 0x1f;
+const REG_ID_VER: u8 = 0x01;
 const REG_ID_BKL: u8 = 0x05;
 const REG_ID_FIF: u8 = 0x09;
 const REG_ID_BK2: u8 = 0x0a;
@@ -22,6 +39,8 @@ type I2cBus = I2c<'static, I2C1, embassy_rp::i2c::Async>;
 
 static I2C: LazyLock<Mutex<CriticalSectionRawMutex, Option<I2cBus>>> =
     LazyLock::new(|| Mutex::new(None));
+static BATTERY_HISTORY: LazyLock<Mutex<CriticalSectionRawMutex, BatteryHistory>> =
+    LazyLock::new(|| Mutex::new(BatteryHistory::default()));
 
 #[derive(Debug, Default, PartialEq, Clone, Copy)]
 #[repr(u8)]
@@ -151,6 +170,24 @@ pub struct KeyReport {
     pub modifiers: Modifiers,
 }
 
+impl KeyReport {
+    /// The keypress that types `c`, as for characters that arrive
+    /// over the serial console rather than from the keyboard
+    pub fn typed(c: char) -> Self {
+        Self {
+            state: KeyState::Pressed,
+            key: match c {
+                '\n' => Key::Enter,
+                '\u{7f}' => Key::BackSpace,
+                '\t' => Key::Tab,
+                '\u{1b}' => Key::Escape,
+                c => Key::Char(c),
+            },
+            modifiers: Modifiers::NONE,
+        }
+    }
+}
+
 bitflags::bitflags! {
     #[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
     pub struct Modifiers: u8 {
@@ -166,10 +203,80 @@ bitflags::bitflags! {
 #[derive(Default)]
 pub struct KeyBoardState {
     last_key: (KeyState, Key),
+    /// The modifier keys that are physically held down
     modifiers: Modifiers,
+    /// When true, tapping a modifier key latches it for the
+    /// next keypress, and tapping it twice locks it
+    sticky: bool,
+    /// Held modifiers that have been used to chord a key, and
+    /// thus shouldn't be treated as a tap when released
+    chorded: Modifiers,
+    /// Modifiers latched for the next non-modifier keypress
+    latched: Modifiers,
+    /// Modifiers locked on until tapped again
+    locked: Modifiers,
+    /// The key that consumed the latch, along with the latched
+    /// modifiers, so that its Hold and Released events carry
+    /// the same modifiers as its Pressed event
+    latched_key: Option<(Key, Modifiers)>,
+    /// Presses of the same key within this time of its release
+    /// are treated as contact chatter and ignored
+    debounce: Duration,
+    /// The most recently released key, and when
+    last_release: Option<(Key, Instant)>,
+    /// A chattering key press that was ignored; its release
+    /// must be ignored as well
+    suppressed: Option<Key>,
+}
+
+fn modifier_for_key(key: Key) -> Option<Modifiers> {
+    match key {
+        Key::ModAlt => Some(Modifiers::ALT),
+        Key::ModControl => Some(Modifiers::CTRL),
+        Key::ModShiftLeft => Some(Modifiers::LSHIFT),
+        Key::ModShiftRight => Some(Modifiers::RSHIFT),
+        Key::ModSymbol => Some(Modifiers::SYM),
+        _ => None,
+    }
 }
 
 impl KeyBoardState {
+    pub fn set_sticky(&mut self, sticky: bool) {
+        self.sticky = sticky;
+        self.latched = Modifiers::NONE;
+        self.locked = Modifiers::NONE;
+    }
+
+    pub fn set_debounce(&mut self, debounce: Duration) {
+        self.debounce = debounce;
+    }
+
+    /// Returns true if this event is the result of key chatter
+    /// and should be ignored
+    fn is_chatter(&mut self, state: KeyState, key: Key) -> bool {
+        match state {
+            KeyState::Pressed => {
+                if let Some((last_key, when)) = self.last_release {
+                    if last_key == key && when.elapsed() < self.debounce {
+                        log::debug!("debounce: ignoring chatter on {key:?}");
+                        self.suppressed = Some(key);
+                        return true;
+                    }
+                }
+                false
+            }
+            KeyState::Released => {
+                if self.suppressed == Some(key) {
+                    self.suppressed = None;
+                    return true;
+                }
+                self.last_release = Some((key, Instant::now()));
+                false
+            }
+            KeyState::Hold | KeyState::Idle => false,
+        }
+    }
+
     pub async fn process(&mut self) -> Option<KeyReport> {
         let key = read_keyboard().await.ok()?;
         if key == self.last_key {
@@ -178,41 +285,151 @@ impl KeyBoardState {
 
         self.last_key = key;
         let (state, key) = key;
-        match (state, key) {
-            (KeyState::Idle, Key::None) => return None,
-            (s @ KeyState::Hold | s @ KeyState::Released, Key::ModAlt) => {
-                self.modifiers.set(Modifiers::ALT, s == KeyState::Hold);
-            }
-            (s @ KeyState::Hold | s @ KeyState::Released, Key::ModControl) => {
-                self.modifiers.set(Modifiers::CTRL, s == KeyState::Hold);
-            }
-            (s @ KeyState::Hold | s @ KeyState::Released, Key::ModShiftLeft) => {
-                self.modifiers.set(Modifiers::LSHIFT, s == KeyState::Hold);
+        if (state, key) == (KeyState::Idle, Key::None) {
+            return None;
+        }
+        if self.is_chatter(state, key) {
+            return None;
+        }
+
+        if let Some(modifier) = modifier_for_key(key) {
+            match state {
+                KeyState::Pressed => {
+                    self.chorded.remove(modifier);
+                }
+                KeyState::Hold => {
+                    self.modifiers.insert(modifier);
+                }
+                KeyState::Released => {
+                    self.modifiers.remove(modifier);
+                    if self.sticky && !self.chorded.contains(modifier) {
+                        self.tap_modifier(modifier);
+                    }
+                }
+                KeyState::Idle => {}
             }
-            (s @ KeyState::Hold | s @ KeyState::Released, Key::ModShiftRight) => {
-                self.modifiers.set(Modifiers::RSHIFT, s == KeyState::Hold);
+            return Some(KeyReport {
+                state,
+                key,
+                modifiers: self.modifiers | self.latched | self.locked,
+            });
+        }
+
+        let mut modifiers = self.modifiers | self.locked;
+        match self.latched_key {
+            Some((latched_key, latched)) if latched_key == key => {
+                modifiers |= latched;
+                if state == KeyState::Released {
+                    self.latched_key = None;
+                }
             }
-            (s @ KeyState::Hold | s @ KeyState::Released, Key::ModSymbol) => {
-                self.modifiers.set(Modifiers::SYM, s == KeyState::Hold);
+            _ => {
+                if state == KeyState::Pressed {
+                    self.chorded |= self.modifiers;
+                    if !self.latched.is_empty() {
+                        modifiers |= self.latched;
+                        self.latched_key = Some((key, self.latched));
+                        self.latched = Modifiers::NONE;
+                    }
+                }
             }
-            _ => {}
         }
+
         Some(KeyReport {
             state,
             key,
-            modifiers: self.modifiers,
+            modifiers,
         })
     }
+
+    /// A modifier was pressed and released on its own
+    fn tap_modifier(&mut self, modifier: Modifiers) {
+        if self.locked.contains(modifier) {
+            self.locked.remove(modifier);
+            log::info!("sticky modifiers: unlocked {modifier:?}");
+        } else if self.latched.contains(modifier) {
+            self.latched.remove(modifier);
+            self.locked.insert(modifier);
+            log::info!("sticky modifiers: locked {modifier:?}");
+        } else {
+            self.latched.insert(modifier);
+            log::debug!("sticky modifiers: latched {modifier:?}");
+        }
+    }
+}
+
+/// The curve applied to lcd backlight levels, to compensate for
+/// the backlight being perceived as non-linear in brightness.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BacklightGamma {
+    /// The level is passed through unchanged
+    Linear = 0,
+    /// Spreads out the low levels, giving finer control over
+    /// the dim end of the range
+    Sqrt = 1,
+    /// Spreads out the high levels
+    Square = 2,
+}
+
+impl core::str::FromStr for BacklightGamma {
+    type Err = &'static str;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(Self::Linear),
+            "sqrt" => Ok(Self::Sqrt),
+            "square" => Ok(Self::Square),
+            _ => Err("expected one of linear, sqrt or square"),
+        }
+    }
+}
+
+static BACKLIGHT_GAMMA: AtomicU8 = AtomicU8::new(BacklightGamma::Linear as u8);
+/// The most recently requested lcd backlight level, before gamma
+static LCD_BACKLIGHT_LEVEL: AtomicU8 = AtomicU8::new(0);
+
+pub fn lcd_backlight_level() -> u8 {
+    LCD_BACKLIGHT_LEVEL.load(Ordering::Relaxed)
+}
+
+const fn gamma_table(gamma: BacklightGamma) -> [u8; 256] {
+    let mut table = [0u8; 256];
+    let mut i = 0;
+    while i < 256 {
+        let level = i as u32;
+        table[i] = match gamma {
+            BacklightGamma::Linear => level,
+            BacklightGamma::Sqrt => (level * 255).isqrt(),
+            BacklightGamma::Square => (level * level + 127) / 255,
+        } as u8;
+        i += 1;
+    }
+    table
+}
+
+static SQRT_GAMMA: [u8; 256] = gamma_table(BacklightGamma::Sqrt);
+static SQUARE_GAMMA: [u8; 256] = gamma_table(BacklightGamma::Square);
+
+fn apply_backlight_gamma(level: u8) -> u8 {
+    match BACKLIGHT_GAMMA.load(Ordering::Relaxed) {
+        1 => SQRT_GAMMA[level as usize],
+        2 => SQUARE_GAMMA[level as usize],
+        _ => level,
+    }
 }
 
 /// Control the lcd backlight brightness level.
 /// The firmware uses the value as a pwm signal at 10_000 Hz.
 /// https://github.com/clockworkpi/PicoCalc/blob/939b9bbad9030655a35ff07062024691abb12240/Code/picocalc_keyboard/backlight.ino#L20-L31
+/// The level is adjusted by the configured lcd_backlight_gamma
+/// before it is sent to the keyboard MCU.
 pub async fn set_lcd_backlight(level: u8) {
+    LCD_BACKLIGHT_LEVEL.store(level, Ordering::Relaxed);
+    let level = apply_backlight_gamma(level);
     let mut i2c_bus = I2C.get().lock().await;
     let i2c_bus = i2c_bus.as_mut().expect("bus configured");
     let _ = i2c_bus
-        .write_async(KBD_ADDR, [REG_ID_BKL | REG_WRITE, level])
+        .write_async(kbd_addr(), [REG_ID_BKL | REG_WRITE, level])
         .await;
 }
 
@@ -221,7 +438,7 @@ pub async fn get_lcd_backlight() -> Result<u8, embassy_rp::i2c::Error> {
     let i2c_bus = i2c_bus.as_mut().expect("bus configured");
     let mut buf = [0u8; 2];
     i2c_bus
-        .write_read_async(KBD_ADDR, [REG_ID_BKL], &mut buf)
+        .write_read_async(kbd_addr(), [REG_ID_BKL], &mut buf)
         .await?;
     Ok(buf[1])
 }
@@ -233,7 +450,7 @@ pub async fn set_keyboard_backlight(level: u8) {
     let mut i2c_bus = I2C.get().lock().await;
     let i2c_bus = i2c_bus.as_mut().expect("bus configured");
     let _ = i2c_bus
-        .write_async(KBD_ADDR, [REG_ID_BK2 | REG_WRITE, level])
+        .write_async(kbd_addr(), [REG_ID_BK2 | REG_WRITE, level])
         .await;
 }
 
@@ -242,17 +459,187 @@ pub async fn get_keyboard_backlight() -> Result<u8, embassy_rp::i2c::Error> {
     let i2c_bus = i2c_bus.as_mut().expect("bus configured");
     let mut buf = [0u8; 2];
     i2c_bus
-        .write_read_async(KBD_ADDR, [REG_ID_BK2], &mut buf)
+        .write_read_async(kbd_addr(), [REG_ID_BK2], &mut buf)
         .await?;
     Ok(buf[1])
 }
 
+async fn read_register(reg: u8) -> Result<u8, embassy_rp::i2c::Error> {
+    let mut i2c_bus = I2C.get().lock().await;
+    let i2c_bus = i2c_bus.as_mut().expect("bus configured");
+    let mut buf = [0u8; 2];
+    i2c_bus
+        .write_read_async(kbd_addr(), [reg], &mut buf)
+        .await?;
+    Ok(buf[1])
+}
+
+/// Reads the firmware version register of the keyboard MCU
+pub async fn read_version_register() -> Result<u8, embassy_rp::i2c::Error> {
+    read_register(REG_ID_VER).await
+}
+
+/// Reads the battery register of the keyboard MCU
+pub async fn read_battery_register() -> Result<u8, embassy_rp::i2c::Error> {
+    read_register(REG_ID_BAT).await
+}
+
+async fn write_register(reg: u8, value: u8) -> Result<(), embassy_rp::i2c::Error> {
+    let mut i2c_bus = I2C.get().lock().await;
+    let i2c_bus = i2c_bus.as_mut().expect("bus configured");
+    i2c_bus
+        .write_async(kbd_addr(), [reg | REG_WRITE, value])
+        .await
+}
+
+/// The keyboard MCU registers that we know about:
+/// (name, register, whether it is safe to write)
+const KNOWN_REGISTERS: &[(&str, u8, bool)] = &[
+    ("ver", REG_ID_VER, false),
+    ("bkl", REG_ID_BKL, true),
+    // Reading this consumes the next key from the FIFO
+    ("fif", REG_ID_FIF, false),
+    ("bk2", REG_ID_BK2, true),
+    ("bat", REG_ID_BAT, false),
+];
+
+/// Parses a register name or number, returning the register
+/// and whether it is known to be safe to write
+fn parse_register(reg: &str) -> Option<(u8, bool)> {
+    if let Some(&(_, reg, writable)) = KNOWN_REGISTERS
+        .iter()
+        .find(|(name, _, _)| name.eq_ignore_ascii_case(reg))
+    {
+        return Some((reg, writable));
+    }
+    let reg = parse_u8(reg)?;
+    // The top bit is the write flag, so isn't part of the register
+    if reg & REG_WRITE != 0 {
+        return None;
+    }
+    let writable = KNOWN_REGISTERS
+        .iter()
+        .any(|&(_, known, writable)| known == reg && writable);
+    Some((reg, writable))
+}
+
+/// Parses a decimal or 0x prefixed hex byte
+fn parse_u8(value: &str) -> Option<u8> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+pub async fn mcureg_command(args: &[&str]) {
+    let force = args.contains(&"--force");
+    let args: Vec<&str> = args
+        .iter()
+        .copied()
+        .filter(|arg| *arg != "--force")
+        .collect();
+
+    match args.as_slice() {
+        [_, "get", reg] => {
+            let Some((reg, _)) = parse_register(reg) else {
+                print!("unknown register {reg}\r\n");
+                return;
+            };
+            match read_register(reg).await {
+                Ok(value) => print!("0x{reg:02x} = 0x{value:02x} ({value})\r\n"),
+                Err(err) => print!("0x{reg:02x}: {err:?}\r\n"),
+            }
+        }
+        [_, "set", reg, value] => {
+            let Some((reg, writable)) = parse_register(reg) else {
+                print!("unknown register {reg}\r\n");
+                return;
+            };
+            let Some(value) = parse_u8(value) else {
+                print!("value must be between 0 and 255, or 0x00 and 0xff\r\n");
+                return;
+            };
+            if !writable && !force {
+                print!(
+                    "0x{reg:02x} is not known to be writable; use --force to write it anyway\r\n"
+                );
+                return;
+            }
+            if let Err(err) = write_register(reg, value).await {
+                print!("0x{reg:02x}: {err:?}\r\n");
+            }
+        }
+        _ => {
+            print!("Usage: mcureg get <reg>\r\n");
+            print!("       mcureg set <reg> <value> [--force]\r\n");
+            print!("Known registers:");
+            for (name, reg, _) in KNOWN_REGISTERS {
+                print!(" {name}=0x{reg:02x}");
+            }
+            print!("\r\n");
+        }
+    }
+}
+
+/// Returns true if a device at `addr` answers a read of the
+/// version register
+async fn probe(addr: u8) -> bool {
+    let mut i2c_bus = I2C.get().lock().await;
+    let i2c_bus = i2c_bus.as_mut().expect("bus configured");
+    let mut buf = [0u8; 2];
+    i2c_bus
+        .write_read_async(addr, [REG_ID_VER], &mut buf)
+        .await
+        .is_ok()
+}
+
+/// Uses the address from the `kbd_i2c_addr` config key, or the
+/// default. If nothing answers there, the bus is scanned and the
+/// first device that answers is assumed to be the keyboard.
+async fn locate_keyboard() {
+    let configured = crate::config::CONFIG
+        .get()
+        .lock()
+        .await
+        .fetch("kbd_i2c_addr")
+        .await
+        .ok()
+        .flatten();
+    let addr = match configured.as_ref().map(|value| parse_u8(value)) {
+        Some(Some(addr)) => addr,
+        Some(None) => {
+            log::error!("kbd_i2c_addr is not a valid address; using the default");
+            DEFAULT_KBD_ADDR
+        }
+        None => DEFAULT_KBD_ADDR,
+    };
+
+    if probe(addr).await {
+        KBD_ADDR.store(addr, Ordering::Relaxed);
+        log::info!("keyboard MCU at i2c address 0x{addr:02x}");
+        return;
+    }
+
+    log::warn!("no keyboard MCU at i2c address 0x{addr:02x}; scanning");
+    // 0x00-0x07 and 0x78-0x7f are reserved by the i2c spec
+    for candidate in 0x08..0x78 {
+        if candidate != addr && probe(candidate).await {
+            KBD_ADDR.store(candidate, Ordering::Relaxed);
+            log::info!("keyboard MCU found at i2c address 0x{candidate:02x}");
+            return;
+        }
+    }
+
+    KBD_ADDR.store(addr, Ordering::Relaxed);
+    log::error!("no keyboard MCU found; using i2c address 0x{addr:02x}");
+}
+
 async fn read_battery_pct() -> Result<u8, embassy_rp::i2c::Error> {
     let mut i2c_bus = I2C.get().lock().await;
     let i2c_bus = i2c_bus.as_mut().expect("bus configured");
     let mut buf = [0u8; 2];
     i2c_bus
-        .write_read_async(KBD_ADDR, [REG_ID_BAT], &mut buf)
+        .write_read_async(kbd_addr(), [REG_ID_BAT], &mut buf)
         .await?;
 
     Ok(buf[1])
@@ -262,10 +649,12 @@ async fn read_keyboard() -> Result<(KeyState, Key), embassy_rp::i2c::Error> {
     let mut buf = [0u8; 2];
     let mut i2c_bus = I2C.get().lock().await;
     let i2c_bus = i2c_bus.as_mut().expect("bus configured");
+    KBD_READS.fetch_add(1, Ordering::Relaxed);
     if let Err(err) = i2c_bus
-        .write_read_async(KBD_ADDR, [REG_ID_FIF], &mut buf)
+        .write_read_async(kbd_addr(), [REG_ID_FIF], &mut buf)
         .await
     {
+        KBD_ERRORS.fetch_add(1, Ordering::Relaxed);
         log::info!("read_keyboard: error: {err:?}");
         return Err(err);
     }
@@ -286,13 +675,45 @@ async fn read_keyboard() -> Result<(KeyState, Key), embassy_rp::i2c::Error> {
 #[embassy_executor::task]
 pub async fn keyboard_reader(
     i2c_bus: embassy_rp::i2c::I2c<'static, embassy_rp::peripherals::I2C1, embassy_rp::i2c::Async>,
+    i2c_frequency: u32,
 ) {
     I2C.get().lock().await.replace(i2c_bus);
+    I2C_FREQ.store(i2c_frequency, Ordering::Relaxed);
+    log::info!("keyboard i2c bus running at {i2c_frequency}Hz");
+
+    locate_keyboard().await;
+
+    if let Some(gamma) = crate::config::CONFIG
+        .get()
+        .lock()
+        .await
+        .fetch_parsed::<BacklightGamma>("lcd_backlight_gamma")
+        .await
+    {
+        BACKLIGHT_GAMMA.store(gamma as u8, Ordering::Relaxed);
+    }
 
     // Enable LCD backlight
     set_lcd_backlight(0x80).await;
 
     let mut keyboard = KeyBoardState::default();
+    if let Ok(Some(sticky)) = crate::config::CONFIG
+        .get()
+        .lock()
+        .await
+        .fetch("sticky_mods")
+        .await
+    {
+        keyboard.set_sticky(sticky.as_str() == "on");
+    }
+    let debounce_ms = crate::config::CONFIG
+        .get()
+        .lock()
+        .await
+        .fetch_parsed::<u64>("kbd_debounce_ms")
+        .await
+        .unwrap_or(DEFAULT_DEBOUNCE_MS);
+    keyboard.set_debounce(Duration::from_millis(debounce_ms));
 
     // First, drain any keys that might be buffered in its FIFO
     // prior to the last system reset. This prevents pending
@@ -308,6 +729,11 @@ pub async fn keyboard_reader(
     let mut last_battery_read = Instant::now();
     if let Ok(pct) = read_battery_pct().await {
         BATTERY_PCT.store(pct, Ordering::SeqCst);
+        BATTERY_HISTORY
+            .get()
+            .lock()
+            .await
+            .record(Instant::now(), BatteryStatus(pct));
     }
 
     // The keyboard MCU polls every 16ms, so let's match that
@@ -321,13 +747,23 @@ pub async fn keyboard_reader(
                 let prior = BATTERY_PCT.load(Ordering::SeqCst);
                 if pct != prior {
                     log::info!("Battery {} -> {}", BatteryStatus(prior), BatteryStatus(pct));
+                    if let Some(message) =
+                        battery_notification(&BatteryStatus(prior), &BatteryStatus(pct))
+                    {
+                        crate::notify::notify(message);
+                    }
                     BATTERY_PCT.store(pct, Ordering::SeqCst);
+                    BATTERY_HISTORY
+                        .get()
+                        .lock()
+                        .await
+                        .record(Instant::now(), BatteryStatus(pct));
                 }
             }
         }
 
         if let Some(key) = keyboard.process().await {
-            log::info!("key == {key:?}");
+            log::trace!("key == {key:?}");
             if key.state == KeyState::Pressed {
                 match key.key {
                     Key::F5 if key.modifiers == Modifiers::CTRL => {
@@ -345,22 +781,59 @@ pub async fn keyboard_reader(
                     Key::F4 if key.modifiers == Modifiers::CTRL => {
                         set_lcd_backlight(0xff).await;
                     }
+                    Key::F1 if key.modifiers == Modifiers::ALT => {
+                        crate::net::notify_ssh_stats();
+                    }
                     Key::Char('=') if key.modifiers == Modifiers::CTRL => {
-                        SCREEN.get().lock().await.increase_font();
+                        let screen = current_proc().screen();
+                        screen.lock().await.increase_font();
+                        crate::screen::save_font(&screen).await;
                     }
                     Key::Char('-') if key.modifiers == Modifiers::CTRL => {
-                        SCREEN.get().lock().await.decrease_font();
+                        let screen = current_proc().screen();
+                        screen.lock().await.decrease_font();
+                        crate::screen::save_font(&screen).await;
+                    }
+                    // Shift+PageUp/PageDown page through scrollback,
+                    // and Shift+End returns to the screen
+                    Key::PageUp | Key::PageDown | Key::End
+                        if key
+                            .modifiers
+                            .intersects(Modifiers::LSHIFT | Modifiers::RSHIFT) =>
+                    {
+                        let screen = current_proc().screen();
+                        let mut screen = screen.lock().await;
+                        let page = (screen.height as usize).saturating_sub(1).max(1);
+                        match key.key {
+                            Key::PageUp => screen.scroll_view_up(page),
+                            Key::PageDown => screen.scroll_view_down(page),
+                            _ => screen.reset_view(),
+                        }
+                        drop(screen);
+                        request_render();
                     }
                     _ => {
                         let proc = current_proc();
-                        if let Err(_) = with_timeout(Duration::from_millis(100), async {
-                            proc.key_input(key).await;
-                            proc.render().await;
-                        })
-                        .await
+                        // Any other key goes back to the screen, but
+                        // not a modifier, as that may be the start of
+                        // paging further
+                        let is_modifier = matches!(
+                            key.key,
+                            Key::ModAlt
+                                | Key::ModShiftLeft
+                                | Key::ModShiftRight
+                                | Key::ModSymbol
+                                | Key::ModControl
+                        );
+                        if !is_modifier {
+                            proc.screen().lock().await.reset_view();
+                        }
+                        if let Err(_) =
+                            with_timeout(Duration::from_millis(100), proc.key_input(key)).await
                         {
                             log::info!("timeout sending key to proc {}", proc.name());
                         }
+                        request_render();
                     }
                 }
             }
@@ -392,13 +865,120 @@ impl core::fmt::Display for BatteryStatus {
     }
 }
 
+/// As the battery runs down, a notification is shown when
+/// it reaches each of these percentages
+const BATTERY_WARNING_LEVELS: [u8; 3] = [20, 10, 5];
+
+/// Returns the notification to show for a change in the battery
+/// status, if it is worth interrupting for. Changes in the level
+/// are only worth it when it is getting low.
+fn battery_notification(
+    prior: &BatteryStatus,
+    now: &BatteryStatus,
+) -> Option<alloc::string::String> {
+    if now.is_charging() != prior.is_charging() {
+        return Some(if now.is_charging() {
+            alloc::format!("Battery {now}")
+        } else {
+            alloc::format!("Battery {now}, not charging")
+        });
+    }
+    let crossed = BATTERY_WARNING_LEVELS
+        .iter()
+        .any(|&level| prior.percentage() > level && now.percentage() <= level);
+    if crossed && !now.is_charging() {
+        return Some(alloc::format!("Battery low: {now}"));
+    }
+    None
+}
+
+/// The number of readings retained for the discharge estimate
+const BATTERY_HISTORY_LEN: usize = 10;
+/// The minimum number of readings before we'll make an estimate
+const BATTERY_HISTORY_MIN: usize = 3;
+
+/// Tracks the recent changes in battery percentage so that we
+/// can estimate how long is left before the battery is empty.
+/// Readings are only recorded when the percentage changes, and
+/// the history is discarded when the battery is charging, as
+/// the rate of discharge is meaningless in that situation.
+#[derive(Default)]
+struct BatteryHistory {
+    readings: heapless::Deque<(Instant, u8), BATTERY_HISTORY_LEN>,
+}
+
+impl BatteryHistory {
+    fn record(&mut self, when: Instant, status: BatteryStatus) {
+        if status.is_charging() {
+            self.readings.clear();
+            return;
+        }
+        if self.readings.is_full() {
+            self.readings.pop_front();
+        }
+        self.readings.push_back((when, status.percentage())).ok();
+    }
+
+    /// Returns the rate of discharge in percent per minute,
+    /// computed as the least squares fit over the history.
+    /// The result is positive when discharging.
+    fn discharge_rate(&self) -> Option<f32> {
+        let n = self.readings.len();
+        if n < BATTERY_HISTORY_MIN {
+            return None;
+        }
+        let (origin, _) = *self.readings.front()?;
+        let minutes = |when: Instant| (when.as_millis() - origin.as_millis()) as f32 / 60_000.0;
+
+        let mean_t = self.readings.iter().map(|&(t, _)| minutes(t)).sum::<f32>() / n as f32;
+        let mean_p = self.readings.iter().map(|&(_, p)| p as f32).sum::<f32>() / n as f32;
+
+        let mut cov = 0.0;
+        let mut var = 0.0;
+        for &(t, p) in self.readings.iter() {
+            let dt = minutes(t) - mean_t;
+            cov += dt * (p as f32 - mean_p);
+            var += dt * dt;
+        }
+        if var == 0.0 {
+            return None;
+        }
+
+        let rate = -cov / var;
+        (rate > 0.0).then_some(rate)
+    }
+
+    /// Estimates the minutes remaining until the battery is empty
+    fn minutes_remaining(&self, status: &BatteryStatus) -> Option<u32> {
+        if status.is_charging() {
+            return None;
+        }
+        let rate = self.discharge_rate()?;
+        Some((status.percentage() as f32 / rate) as u32)
+    }
+}
+
 pub fn get_battery() -> BatteryStatus {
     BatteryStatus(BATTERY_PCT.load(Ordering::SeqCst))
 }
 
+/// Returns the estimated number of minutes of battery life
+/// remaining, if we have enough history to make the estimate.
+pub async fn battery_minutes_remaining() -> Option<u32> {
+    let bat = get_battery();
+    BATTERY_HISTORY.get().lock().await.minutes_remaining(&bat)
+}
+
 pub async fn battery_command(_args: &[&str]) {
     let bat = get_battery();
-    print!("Battery: {bat}\r\n");
+    match battery_minutes_remaining().await {
+        Some(mins) => print!(
+            "Battery: {bat}, time remaining: ~{}h {}m\r\n",
+            mins / 60,
+            mins % 60
+        ),
+        None => print!("Battery: {bat}\r\n"),
+    }
 }
 
 // See rp2350 datasheet section 5.4.8.24. reboot
@@ -448,3 +1028,24 @@ pub async fn backlight_command(args: &[&str]) {
 
     print!("Keyboard: {kbd:?}\r\nLCD: {lcd:?}\r\n");
 }
+
+pub async fn kbd_command(args: &[&str]) {
+    match args {
+        ["kbd", "version"] => {
+            match read_register(REG_ID_VER).await {
+                Ok(ver) => print!("Keyboard MCU firmware: 0x{ver:02x}\r\n"),
+                Err(err) => print!("Keyboard MCU firmware: {err:?}\r\n"),
+            }
+            let reads = KBD_READS.load(Ordering::Relaxed);
+            let errors = KBD_ERRORS.load(Ordering::Relaxed);
+            print!(
+                "I2C: 0x{:02x} at {}kHz, {errors} errors in {reads} reads\r\n",
+                kbd_addr(),
+                I2C_FREQ.load(Ordering::Relaxed) / 1000
+            );
+        }
+        _ => {
+            print!("Usage: kbd version\r\n");
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::image::{Image, alloc_pixels, rgb565};
+use alloc::vec::Vec;
+use wezterm_escape_parser::apc::{
+    KittyImage, KittyImageCompression, KittyImageData, KittyImageFormat, KittyImageTransmit,
+};
+
+extern crate alloc;
+
+// Decodes images sent using the kitty graphics protocol. Only images
+// that are transmitted directly, as uncompressed RGB or RGBA pixels,
+// and displayed straight away are supported.
+
+/// The most base64 data that is collected for one image, which is
+/// a little more than is needed for an RGBA image the size of the screen
+const MAX_PAYLOAD_BYTES: usize = 320 * 320 * 4 * 4 / 3 + 4;
+
+/// An image whose data is arriving in chunks
+struct Pending {
+    format: Option<KittyImageFormat>,
+    width: Option<u32>,
+    height: Option<u32>,
+    compression: KittyImageCompression,
+    display: bool,
+    /// The base64 encoded data received so far
+    data: Vec<u8>,
+}
+
+/// Collects the chunks of kitty images until they are complete
+#[derive(Default)]
+pub struct KittyReceiver {
+    pending: Option<Pending>,
+}
+
+impl KittyReceiver {
+    /// Processes a kitty graphics command, returning an image
+    /// when one is complete and ready to be displayed, cropped to
+    /// `max_width` by `max_height` pixels
+    pub fn receive(
+        &mut self,
+        command: KittyImage,
+        max_width: usize,
+        max_height: usize,
+    ) -> Option<Image> {
+        let (transmit, display) = match command {
+            KittyImage::TransmitData { transmit, .. } => (transmit, false),
+            KittyImage::TransmitDataAndDisplay { transmit, .. } => (transmit, true),
+            unhandled => {
+                log::info!("kitty: unhandled {unhandled:?}");
+                return None;
+            }
+        };
+        let KittyImageTransmit {
+            format,
+            data,
+            width,
+            height,
+            compression,
+            more_data_follows,
+            ..
+        } = transmit;
+        let KittyImageData::Direct(payload) = data else {
+            log::info!("kitty: only direct transmission is supported");
+            self.pending = None;
+            return None;
+        };
+
+        // Only the first chunk describes the image
+        let pending = self.pending.get_or_insert_with(|| Pending {
+            format,
+            width,
+            height,
+            compression,
+            display,
+            data: Vec::new(),
+        });
+        if pending.data.len() + payload.len() > MAX_PAYLOAD_BYTES {
+            log::warn!("kitty: image is too large");
+            self.pending = None;
+            return None;
+        }
+        // Without PSRAM the heap may not have room for even
+        // a modest image; drop it rather than running out
+        if pending.data.try_reserve(payload.len()).is_err() {
+            log::warn!("kitty: not enough memory for image");
+            self.pending = None;
+            return None;
+        }
+        pending.data.extend_from_slice(payload.as_bytes());
+        if more_data_follows {
+            return None;
+        }
+
+        let pending = self.pending.take()?;
+        if !pending.display {
+            log::info!("kitty: keeping images to display later isn't supported");
+            return None;
+        }
+        pending.decode(max_width, max_height)
+    }
+}
+
+impl Pending {
+    fn decode(self, max_width: usize, max_height: usize) -> Option<Image> {
+        if !matches!(self.compression, KittyImageCompression::None) {
+            log::info!("kitty: compressed images aren't supported");
+            return None;
+        }
+        // RGBA is the default format
+        let bytes_per_pixel = match self.format {
+            None | Some(KittyImageFormat::Rgba) => 4,
+            Some(KittyImageFormat::Rgb) => 3,
+            Some(unhandled) => {
+                log::info!("kitty: {unhandled:?} images aren't supported");
+                return None;
+            }
+        };
+        let (Some(width), Some(height)) = (self.width, self.height) else {
+            log::info!("kitty: image has no size");
+            return None;
+        };
+        let (width, height) = (width as usize, height as usize);
+
+        let Some(bytes) = decode_base64(&self.data) else {
+            log::warn!("kitty: image data isn't valid base64");
+            return None;
+        };
+        let needed = width.checked_mul(height)?.checked_mul(bytes_per_pixel)?;
+        if bytes.len() < needed {
+            log::warn!("kitty: image data is too short for {width}x{height}");
+            return None;
+        }
+
+        let (crop_width, crop_height) = (width.min(max_width), height.min(max_height));
+        let mut pixels = alloc_pixels(crop_width, crop_height)?;
+        for y in 0..crop_height {
+            for x in 0..crop_width {
+                let idx = (y * width + x) * bytes_per_pixel;
+                let pixel = &bytes[idx..idx + bytes_per_pixel];
+                // Blend any transparency onto the black background
+                let alpha = pixel.get(3).copied().unwrap_or(0xff) as u16;
+                let blend = |value: u8| (value as u16 * alpha / 0xff) as u8;
+                pixels[y * crop_width + x] =
+                    rgb565(blend(pixel[0]), blend(pixel[1]), blend(pixel[2]));
+            }
+        }
+        Some(Image::new(crop_width, crop_height, pixels))
+    }
+}
+
+/// Decodes standard base64, ignoring any padding
+pub(crate) fn decode_base64(text: &[u8]) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+            b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let mut decoded = Vec::new();
+    decoded.try_reserve_exact(text.len() / 4 * 3 + 3).ok()?;
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for &c in text.iter().filter(|&&c| c != b'=') {
+        acc = (acc << 6) | value(c)?;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
@@ -1,6 +1,8 @@
-use crate::keyboard::{Key, KeyReport, KeyState, Modifiers};
+use crate::keyboard::KeyReport;
 use crate::process::current_proc;
 use crate::{Irqs, mk_static, static_bytes};
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::fmt::Write as _;
 use embassy_executor::Spawner;
 use embassy_futures::join::join;
@@ -8,10 +10,13 @@ use embassy_rp::peripherals::{PIN_0, PIN_1, PIN_8, PIN_9, UART0, UART1, USB};
 use embassy_rp::uart::{BufferedUart, BufferedUartRx, BufferedUartTx, Config as UartConfig};
 use embassy_rp::usb;
 use embassy_sync::pipe::Pipe;
+use embassy_time::{Duration, with_timeout};
 use embassy_usb_logger::UsbLogger;
 use embedded_io_async::{Read, Write as _};
 use log::{LevelFilter, Metadata, Record};
 
+extern crate alloc;
+
 // This module logs to both UART0 and to a USB CDC endpoint.
 // The former is routed via the host picocalc board and a CH340C
 // USB to serial chip.
@@ -35,7 +40,8 @@ pub async fn setup_logging(
         tx_pin,
         rx_pin,
         static_bytes!(128),
-        static_bytes!(8),
+        // Enough to hold pastes while the reader catches up
+        static_bytes!(256),
         UartConfig::default(),
     );
     let (mut tx0, rx0) = uart0.split();
@@ -44,6 +50,11 @@ pub async fn setup_logging(
         .write_all(b"\r\n\r\n *** WezTerm picocalc starting up ***\r\n\r\n")
         .await;
 
+    // Set the level here rather than in the log task, so that
+    // it can't clobber the level chosen by apply_log_level
+    unsafe {
+        log::set_max_level_racy(LevelFilter::Info);
+    }
     spawner.must_spawn(log(tx0, usb));
     spawner.must_spawn(uart_reader(rx0));
 
@@ -66,6 +77,25 @@ struct Logger {
     pipe: Pipe<CS, 1024>,
 }
 
+static LOGGER: Logger = Logger {
+    usb_logger: UsbLog::new(),
+    pipe: Pipe::new(),
+};
+
+/// Writes `line` to both the USB and serial logs as a single record,
+/// regardless of the log level. Each record is written in one go,
+/// so it can't be interleaved with other log lines; if there isn't
+/// room for all of it in the serial log, it is dropped from there
+/// rather than being written partially.
+pub fn write_record(line: &str) {
+    LOGGER
+        .usb_logger
+        .log(&Record::builder().args(format_args!("{line}")).build());
+    if LOGGER.pipe.free_capacity() >= line.len() + 2 {
+        let _ = write!(Writer(&LOGGER.pipe), "{line}\n");
+    }
+}
+
 impl Logger {
     /// Take data from the pipe, which is populated by the `log` crate,
     /// and feed it into the uart.
@@ -136,13 +166,8 @@ impl<'d, const N: usize> core::fmt::Write for Writer<'d, N> {
 
 #[embassy_executor::task]
 pub async fn log(uart: BufferedUartTx<'static, UART0>, driver: usb::Driver<'static, USB>) {
-    static LOGGER: Logger = Logger {
-        usb_logger: UsbLog::new(),
-        pipe: Pipe::new(),
-    };
-
     unsafe {
-        let _ = log::set_logger_racy(&LOGGER).map(|()| log::set_max_level_racy(LevelFilter::Info));
+        let _ = log::set_logger_racy(&LOGGER);
     }
 
     let _ = join(
@@ -154,17 +179,73 @@ pub async fn log(uart: BufferedUartTx<'static, UART0>, driver: usb::Driver<'stat
     .await;
 }
 
+/// The most bytes of an incomplete UTF-8 sequence that can be
+/// left over at the end of a read
+const MAX_CARRY: usize = 3;
+
+/// A read buffer that reassembles UTF-8 characters that are split
+/// across two reads. N must be at least MAX_CARRY larger than the
+/// amount that is to be read at a time.
+struct Utf8Carry<const N: usize> {
+    buf: [u8; N],
+    /// The start of the bytes that are carried over to the next read
+    start: usize,
+    /// The end of the bytes that have been read
+    len: usize,
+}
+
+impl<const N: usize> Utf8Carry<N> {
+    const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns the space to read into, which follows the start
+    /// of any incomplete character from the previous read
+    fn space(&mut self) -> &mut [u8] {
+        self.buf.copy_within(self.start..self.len, 0);
+        self.len -= self.start;
+        self.start = 0;
+        &mut self.buf[self.len..]
+    }
+
+    /// Decodes the `n` bytes that were read into `space()`.
+    /// An incomplete character at the end is held back until the
+    /// next read. Returns the bytes if they are not valid UTF-8.
+    fn decode(&mut self, n: usize) -> Result<&str, &[u8]> {
+        self.len += n;
+        match core::str::from_utf8(&self.buf[..self.len]) {
+            Ok(_) => {
+                self.start = self.len;
+            }
+            Err(err) if err.error_len().is_none() => {
+                self.start = err.valid_up_to();
+            }
+            Err(_) => {
+                self.start = self.len;
+                return Err(&self.buf[..self.len]);
+            }
+        }
+        Ok(core::str::from_utf8(&self.buf[..self.start]).unwrap_or(""))
+    }
+}
+
 #[embassy_executor::task]
 async fn mcu_uart_reader(mut rx: BufferedUart<'static, UART1>) {
+    let mut buf = Utf8Carry::<{ 128 + MAX_CARRY }>::new();
     loop {
-        let mut buf = [0; 128];
-        match rx.read(&mut buf).await {
-            Ok(n) => match core::str::from_utf8(&buf[0..n]) {
+        match rx.read(buf.space()).await {
+            Ok(n) => match buf.decode(n) {
                 Ok(s) => {
-                    log::info!("mcu_uart: {s}");
+                    if !s.is_empty() {
+                        log::info!("mcu_uart: {s}");
+                    }
                 }
-                Err(_) => {
-                    log::info!("mcu_uart: data not utf8: {:x?}", &buf[0..n]);
+                Err(data) => {
+                    log::info!("mcu_uart: data not utf8: {data:x?}");
                 }
             },
             Err(err) => {
@@ -174,38 +255,194 @@ async fn mcu_uart_reader(mut rx: BufferedUart<'static, UART1>) {
     }
 }
 
+/// Text can be pasted from the computer attached to UART0 by sending
+/// it as OSC 52, the sequence that terminals use to set the clipboard:
+/// `ESC ] 52 ; c ; <base64> BEL`, with ST (`ESC \`) also accepted
+/// in place of BEL. Longer payloads than this are discarded.
+const MAX_PASTE_ENCODED: usize = 8192;
+/// An escape that isn't followed by anything for this long was
+/// the Escape key, rather than the start of a paste
+const ESCAPE_TIMEOUT: Duration = Duration::from_millis(50);
+/// A paste that stalls for this long is abandoned, so that typing
+/// isn't swallowed by the remains of a truncated paste
+const PASTE_TIMEOUT: Duration = Duration::from_secs(1);
+
+enum ConsoleInput {
+    Key(KeyReport),
+    Paste(String),
+}
+
+#[derive(Default, PartialEq)]
+enum ConsoleState {
+    #[default]
+    Ground,
+    /// ESC has been received
+    Escape,
+    /// Collecting the body of an OSC
+    Osc,
+    /// ESC has been received within an OSC
+    OscEscape,
+}
+
+/// Turns the characters received from the serial console into keys
+/// and pastes. Anything malformed ends the paste, and the character
+/// that was unexpected is then treated as typed, so a garbled paste
+/// can't swallow the keys that follow it.
+#[derive(Default)]
+struct ConsoleDecoder {
+    state: ConsoleState,
+    osc: String,
+    overflowed: bool,
+}
+
+impl ConsoleDecoder {
+    /// How long to wait for more input before calling `expire`
+    fn timeout(&self) -> Option<Duration> {
+        match self.state {
+            ConsoleState::Ground => None,
+            ConsoleState::Escape => Some(ESCAPE_TIMEOUT),
+            ConsoleState::Osc | ConsoleState::OscEscape => Some(PASTE_TIMEOUT),
+        }
+    }
+
+    /// Called when nothing more arrived within `timeout`
+    fn expire(&mut self, inputs: &mut Vec<ConsoleInput>) {
+        if self.state == ConsoleState::Escape {
+            inputs.push(ConsoleInput::Key(KeyReport::typed('\u{1b}')));
+        } else {
+            log::warn!("paste: incomplete paste was abandoned");
+        }
+        self.state = ConsoleState::Ground;
+    }
+
+    fn feed(&mut self, c: char, inputs: &mut Vec<ConsoleInput>) {
+        match self.state {
+            ConsoleState::Ground => match c {
+                '\u{1b}' => self.state = ConsoleState::Escape,
+                '\r' => {}
+                c => {
+                    log::debug!("UART: char {c:?}");
+                    inputs.push(ConsoleInput::Key(KeyReport::typed(c)));
+                }
+            },
+            ConsoleState::Escape if c == ']' => {
+                self.state = ConsoleState::Osc;
+                self.osc.clear();
+                self.overflowed = false;
+            }
+            ConsoleState::Escape => {
+                inputs.push(ConsoleInput::Key(KeyReport::typed('\u{1b}')));
+                self.state = ConsoleState::Ground;
+                self.feed(c, inputs);
+            }
+            ConsoleState::Osc => match c {
+                '\u{7}' => self.finish(inputs),
+                '\u{1b}' => self.state = ConsoleState::OscEscape,
+                // base64 tools wrap their output
+                '\r' | '\n' => {}
+                c if c.is_ascii_graphic() => {
+                    if self.osc.len() < MAX_PASTE_ENCODED {
+                        self.osc.push(c);
+                    } else {
+                        self.overflowed = true;
+                    }
+                }
+                c => {
+                    log::warn!("paste: unexpected {c:?}; paste discarded");
+                    self.state = ConsoleState::Ground;
+                    self.feed(c, inputs);
+                }
+            },
+            ConsoleState::OscEscape if c == '\\' => self.finish(inputs),
+            ConsoleState::OscEscape => {
+                // The escape starts something new
+                log::warn!("paste: unterminated; paste discarded");
+                self.state = ConsoleState::Escape;
+                self.feed(c, inputs);
+            }
+        }
+    }
+
+    /// Decodes the OSC that has just been terminated
+    fn finish(&mut self, inputs: &mut Vec<ConsoleInput>) {
+        self.state = ConsoleState::Ground;
+        if self.overflowed {
+            log::warn!("paste: longer than {MAX_PASTE_ENCODED} bytes; paste discarded");
+            return;
+        }
+        let Some(("52", params)) = self.osc.split_once(';') else {
+            log::debug!("paste: ignoring OSC {}", self.osc);
+            return;
+        };
+        // The selection is ignored; there's only one place to paste
+        let Some((_selection, data)) = params.split_once(';') else {
+            log::warn!("paste: OSC 52 has no data; ignored");
+            return;
+        };
+        let text = crate::kitty::decode_base64(data.as_bytes())
+            .and_then(|bytes| String::from_utf8(bytes).ok());
+        match text {
+            Some(text) => inputs.push(ConsoleInput::Paste(text)),
+            None => log::warn!("paste: data isn't base64 encoded UTF-8; paste discarded"),
+        }
+    }
+}
+
 #[embassy_executor::task]
 async fn uart_reader(mut rx: BufferedUartRx<'static, UART0>) {
+    let mut buf = Utf8Carry::<{ 31 + MAX_CARRY }>::new();
+    let mut decoder = ConsoleDecoder::default();
+    let mut inputs = Vec::new();
     loop {
-        let mut buf = [0; 31];
-        if let Ok(n) = rx.read(&mut buf).await {
-            let proc = current_proc();
-            match core::str::from_utf8(&buf[0..n]) {
+        let read = rx.read(buf.space());
+        let read = match decoder.timeout() {
+            Some(timeout) => with_timeout(timeout, read).await.ok(),
+            None => Some(read.await),
+        };
+        match read {
+            None => decoder.expire(&mut inputs),
+            Some(Ok(n)) => match buf.decode(n) {
                 Ok(s) => {
                     for c in s.chars() {
-                        if c == '\r' {
-                            continue;
-                        }
-                        log::debug!("UART: char {c:?}");
-                        proc.key_input(KeyReport {
-                            state: KeyState::Pressed,
-                            key: match c {
-                                '\n' => Key::Enter,
-                                '\u{7f}' => Key::BackSpace,
-                                '\t' => Key::Tab,
-                                '\u{1b}' => Key::Escape,
-                                c => Key::Char(c),
-                            },
-                            modifiers: Modifiers::NONE,
-                        })
-                        .await;
+                        decoder.feed(c, &mut inputs);
                     }
                 }
-                Err(e) => {
-                    log::info!("not utf8: {e:?} {:x?}", &buf[0..n]);
+                Err(data) => {
+                    log::info!("not utf8: {data:x?}");
+                }
+            },
+            Some(Err(_)) => continue,
+        }
+
+        let proc = current_proc();
+        for input in inputs.drain(..) {
+            match input {
+                ConsoleInput::Key(key) => proc.key_input(key).await,
+                ConsoleInput::Paste(text) => {
+                    log::info!("UART: pasting {} bytes", text.len());
+                    proc.paste(&text).await;
                 }
             }
-            proc.render().await;
         }
+        crate::process::request_render();
+    }
+}
+
+/// Applies the `log_level` config setting, which may be one of
+/// `off`, `error`, `warn`, `info` (the default), `debug` or `trace`.
+/// Per-keystroke events are logged at `trace`, so that level is
+/// useful for debugging input handling, but is otherwise too noisy.
+pub async fn apply_log_level() {
+    let level = crate::config::CONFIG
+        .get()
+        .lock()
+        .await
+        .fetch_parsed::<LevelFilter>("log_level")
+        .await;
+    if let Some(level) = level {
+        unsafe {
+            log::set_max_level_racy(level);
+        }
+        log::info!("log level set to {level}");
     }
 }
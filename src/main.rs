@@ -9,7 +9,8 @@ use crate::screen::SCREEN;
 use crate::storage::init_storage;
 use core::cell::RefCell;
 use core::fmt::Write as _;
-use embassy_embedded_hal::shared_bus::blocking::spi::SpiDeviceWithConfig;
+use core::sync::atomic::Ordering;
+use embassy_embedded_hal::shared_bus::blocking::spi::SpiDevice;
 use embassy_executor::Spawner;
 use embassy_rp::block::ImageDef;
 use embassy_rp::gpio::{Level, Output};
@@ -19,41 +20,27 @@ use embassy_rp::uart::BufferedInterruptHandler;
 use embassy_rp::watchdog::Watchdog;
 use embassy_rp::{bind_interrupts, spi, usb};
 use embassy_sync::blocking_mutex::Mutex;
-use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_time::{Delay, Duration, Ticker, Timer};
 use mipidsi::Builder;
 use mipidsi::interface::SpiInterface;
 use mipidsi::models::ILI9488Rgb565;
-use mipidsi::options::{ColorInversion, ColorOrder, Orientation};
+use mipidsi::options::{ColorInversion, ColorOrder};
 use panic_persist as _;
 use static_cell::StaticCell;
 
 macro_rules! print {
     ($($args:tt)+) => {
-        {
-            use crate::screen::SCREEN;
-            use core::fmt::Write;
-            use crate::process::current_proc;
-            let proc = current_proc();
-            {
-                let mut screen = SCREEN.get().lock().await;
-                // Erase whatever prompt may have been printed
-                proc.un_prompt(&mut screen);
-                // write our text
-                write!(screen, $($args)+).ok();
-            }
-            // Get the shell to render its prompt again
-            proc.render().await;
-        }
+        crate::output::print_fmt(format_args!($($args)+)).await
     }
 }
 
 type PicoCalcDisplay<'a> = mipidsi::Display<
     SpiInterface<
         'a,
-        embassy_embedded_hal::shared_bus::blocking::spi::SpiDeviceWithConfig<
+        embassy_embedded_hal::shared_bus::blocking::spi::SpiDevice<
             'a,
-            NoopRawMutex,
+            CriticalSectionRawMutex,
             embassy_rp::spi::Spi<'a, SPI1, embassy_rp::spi::Blocking>,
             Output<'a>,
         >,
@@ -63,20 +50,39 @@ type PicoCalcDisplay<'a> = mipidsi::Display<
     Output<'a>,
 >;
 
+mod beep;
+#[cfg(feature = "net")]
+mod bridge;
 mod config;
+mod display;
 mod fixed_str;
 mod heap;
+mod history;
+mod image;
 mod keyboard;
+mod kitty;
 mod logging;
+mod memory;
+mod metrics;
+#[cfg(feature = "net")]
 mod net;
+mod notify;
+mod output;
+mod pager;
 mod process;
+mod progress;
 mod psram;
+mod ramdisk;
 mod rng;
 mod screen;
+mod selftest;
+mod sixel;
 mod storage;
+mod sysinfo;
+mod terminal;
 mod time;
-
-const MAX_SPI_FREQ: u32 = 62_500_000;
+#[cfg(feature = "net")]
+mod transcript;
 
 #[unsafe(link_section = ".start_block")]
 #[used]
@@ -167,23 +173,40 @@ async fn main(spawner: Spawner) {
         }
     );
 
-    if let Some(msg) = panic_persist::get_panic_message_utf8() {
+    let prior_panic = panic_persist::get_panic_message_utf8();
+    if let Some(msg) = prior_panic {
         // Give serial a chance to be ready to capture this info
         Timer::after(Duration::from_millis(100)).await;
         log::error!("prior panic: {msg}");
         let mut screen = SCREEN.get().lock().await;
-        write!(screen, "\u{1f}[1mPanic: ").ok();
-        for chunk in msg.lines() {
-            write!(screen, "{chunk}\r\n").ok();
-        }
-        write!(screen, "\u{1f}[0m").ok();
-        Timer::after(Duration::from_secs(5)).await;
+        write!(screen, "\u{1b}[1mPanic:\r\n").ok();
+        screen.print_wrapped(msg);
+        write!(screen, "\u{1b}[0m").ok();
     }
     spawner.must_spawn(watchdog_task(Watchdog::new(p.WATCHDOG)));
     crate::rng::init_rng(p.TRNG);
 
+    let flash = Flash::new(p.FLASH, p.DMA_CH3);
+    {
+        let mut config = CONFIG.get().lock().await;
+        config.assign_flash(flash);
+        config.check().await;
+    }
+    crate::logging::apply_log_level().await;
+    crate::metrics::init_metrics(&spawner).await;
+
+    // Some units have marginal I2C connections to the keyboard,
+    // so allow running the bus more slowly than the 400kHz default
+    let i2c_freq_khz = CONFIG
+        .get()
+        .lock()
+        .await
+        .fetch_parsed::<u32>("i2c_freq_khz")
+        .await
+        .unwrap_or(400)
+        .clamp(10, 400);
     let mut i2c_config = embassy_rp::i2c::Config::default();
-    i2c_config.frequency = 400_000;
+    i2c_config.frequency = i2c_freq_khz * 1000;
     let scl = p.PIN_7;
     let sda = p.PIN_6;
     let i2c_bus = embassy_rp::i2c::I2c::new_async(p.I2C1, scl, sda, Irqs, i2c_config);
@@ -196,20 +219,31 @@ async fn main(spawner: Spawner) {
     let rst = p.PIN_15;
 
     // create SPI
+    // Units with marginal connections to the display may need
+    // to run the SPI bus more slowly to avoid corruption
+    let display_spi_freq = CONFIG
+        .get()
+        .lock()
+        .await
+        .fetch_parsed::<u32>("display_spi_freq_mhz")
+        .await
+        .map(crate::display::spi_freq_from_mhz)
+        .unwrap_or(crate::display::DEFAULT_SPI_FREQ);
+    log::info!("display spi bus running at {display_spi_freq}Hz");
+    crate::sysinfo::DISPLAY_SPI_FREQ.store(display_spi_freq, Ordering::Relaxed);
+
     let mut display_config = spi::Config::default();
-    display_config.frequency = MAX_SPI_FREQ;
+    display_config.frequency = display_spi_freq;
     display_config.phase = spi::Phase::CaptureOnSecondTransition;
     display_config.polarity = spi::Polarity::IdleHigh;
 
-    static DISPLAY_SPI_BUS: StaticCell<
-        Mutex<NoopRawMutex, RefCell<Spi<SPI1, embassy_rp::spi::Blocking>>>,
-    > = StaticCell::new();
-    let spi = Spi::new_blocking(p.SPI1, sclk, mosi, miso, display_config.clone());
-
-    let display_spi = SpiDeviceWithConfig::new(
-        DISPLAY_SPI_BUS.init_with(|| Mutex::new(RefCell::new(spi))),
+    let spi = Spi::new_blocking(p.SPI1, sclk, mosi, miso, display_config);
+    // The bus is kept where `display spi` can change its frequency.
+    // That is why the device doesn't carry a config of its own, as
+    // it would otherwise set the frequency back on every transfer.
+    let display_spi = SpiDevice::new(
+        crate::display::DISPLAY_SPI_BUS.get_or_init(|| Mutex::new(RefCell::new(spi))),
         Output::new(display_cs, Level::High),
-        display_config,
     );
 
     let dcx = Output::new(dcx, Level::Low);
@@ -225,19 +259,37 @@ async fn main(spawner: Spawner) {
         DISPLAY_BUFFER.init_with(|| [0u8; DISPLAY_BUFFER_SIZE]),
     );
 
+    let orientation = crate::display::configured_orientation().await;
+
     // Define the display from the display interface and initialize it
     let display = Builder::new(ILI9488Rgb565, di)
         .color_order(ColorOrder::Bgr)
         .reset_pin(rst)
         .invert_colors(ColorInversion::Inverted)
-        .orientation(Orientation::new().flip_horizontal())
+        .orientation(orientation)
         .init(&mut Delay)
         .unwrap();
-    spawner.must_spawn(crate::screen::screen_painter(display));
-    spawner.must_spawn(crate::keyboard::keyboard_reader(i2c_bus));
-
-    let flash = Flash::new(p.FLASH, p.DMA_CH3);
-    CONFIG.get().lock().await.assign_flash(flash);
+    crate::screen::apply_font_config().await;
+    spawner.must_spawn(crate::display::screen_painter(display));
+    spawner.must_spawn(crate::keyboard::keyboard_reader(
+        i2c_bus,
+        i2c_config.frequency,
+    ));
+    spawner.must_spawn(crate::process::shell_task());
+
+    if prior_panic.is_some() {
+        // Now that the panic is on the display, give a chance
+        // to read it before boot messages scroll it away
+        if let Some(capture) = crate::process::KeyCapture::begin("Press any key to continue").await
+        {
+            embassy_futures::select::select(
+                capture.next_key(),
+                Timer::after(Duration::from_secs(5)),
+            )
+            .await;
+            capture.end().await;
+        }
+    }
 
     let psram = init_psram(
         p.PIO1, p.PIN_21, p.PIN_2, p.PIN_3, p.PIN_20, p.DMA_CH1, p.DMA_CH2,
@@ -248,6 +300,12 @@ async fn main(spawner: Spawner) {
     if psram_qmi_size > 0 {
         init_qmi_psram_heap(psram_qmi_size);
     }
+    crate::sysinfo::PSRAM_SIZE.store(psram.size, Ordering::Relaxed);
+    crate::sysinfo::PSRAM_QMI_SIZE.store(psram_qmi_size, Ordering::Relaxed);
+    // Now that we know how much heap there is
+    crate::screen::apply_scrollback_config().await;
+    crate::display::apply_framebuffer_config().await;
+    crate::display::apply_palette_config().await;
 
     {
         print!(
@@ -264,6 +322,9 @@ async fn main(spawner: Spawner) {
             // powering up the picocalc carrier board
             print!("\u{1b}[1mExternal PSRAM was NOT found!\u{1b}[0m\r\n");
         }
+        if !crate::psram::psram_available() {
+            print!("No PSRAM for the heap: scrollback is reduced and the framebuffer is off\r\n");
+        }
         print!(
             "Heap {} used, {} free\r\n",
             byte_size(HEAP.used()),
@@ -271,11 +332,15 @@ async fn main(spawner: Spawner) {
         );
     }
 
+    crate::ramdisk::init_ramdisk(psram).await;
+    crate::beep::init_beep(&spawner, p.PWM_SLICE5, p.PIN_26, p.PIN_27).await;
+
     init_storage(
         &spawner, p.PIN_16, p.PIN_17, p.PIN_18, p.PIN_19, p.PIN_22, p.SPI0,
     )
     .await;
 
+    #[cfg(feature = "net")]
     crate::net::setup_wifi(
         &spawner, p.PIN_23, p.PIN_24, p.PIN_25, p.PIN_29, p.PIO0, p.DMA_CH0,
     )
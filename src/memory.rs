@@ -0,0 +1,282 @@
+use crate::config::CONFIG;
+use crate::pager::Pager;
+use crate::sysinfo::PSRAM_QMI_SIZE;
+use alloc::string::String;
+use core::fmt::Write;
+use core::ops::Range;
+use core::sync::atomic::Ordering;
+
+extern crate alloc;
+
+// This module provides commands to read and write arbitrary memory,
+// including hardware registers, for debugging.
+//
+// Reading an address that isn't backed by anything causes a bus
+// fault, so both reads and writes are restricted to the regions
+// that are known to exist: the memories, and the blocks of the
+// address map in the RP2350 datasheet that hold peripherals.
+// Reading a few registers, such as the data register of a UART or
+// a SIO spinlock, takes data from a FIFO or claims something, so
+// peek refuses to read those. Memory is accessed a 32-bit word at
+// a time, as that is what most peripheral registers require.
+
+/// The most bytes that peek will show at once
+const MAX_PEEK_LEN: u32 = 4096;
+pub const BYTES_PER_LINE: u32 = 8;
+
+const ROM: Range<u32> = 0x0000_0000..0x0000_8000;
+const FLASH: Range<u32> = 0x1000_0000..0x1040_0000;
+const PSRAM_BASE: u32 = 0x1100_0000;
+const SRAM: Range<u32> = 0x2000_0000..0x2008_2000;
+
+/// The parts of the APB and AHB peripheral space that are occupied.
+/// APB peripherals are 32kB apart, from SYSINFO to TICKS; the gaps
+/// between these ranges have nothing in them.
+const PERIPHERALS: [Range<u32>; 13] = [
+    // SYSINFO .. TICKS
+    0x4000_0000..0x4011_0000,
+    // OTP and OTP_DATA
+    0x4012_0000..0x4014_0000,
+    // CoreSight peripherals
+    0x4014_0000..0x4014_8000,
+    // GLITCH_DETECTOR and TBMAN
+    0x4015_8000..0x4016_8000,
+    // DMA
+    0x5000_0000..0x5000_4000,
+    // USBCTRL_DPRAM and USBCTRL_REGS
+    0x5010_0000..0x5010_1000,
+    0x5011_0000..0x5011_4000,
+    // PIO0, PIO1 and PIO2
+    0x5020_0000..0x5020_4000,
+    0x5030_0000..0x5030_4000,
+    0x5040_0000..0x5040_4000,
+    // XIP_AUX, HSTX_FIFO and CORESIGHT_TRACE
+    0x5050_0000..0x5050_4000,
+    0x5060_0000..0x5060_4000,
+    0x5070_0000..0x5070_4000,
+];
+const SIO: Range<u32> = 0xd000_0000..0xd000_0200;
+
+/// Registers that change something when they are read, with the
+/// reason that peek gives for not reading them. Peripheral
+/// registers are also reached through the atomic XOR, set and
+/// clear aliases at 0x1000, 0x2000 and 0x3000 above them.
+const READ_SIDE_EFFECTS: &[(Range<u32>, &str)] = &[
+    (
+        0x4007_0000..0x4007_0004,
+        "UART0 DR would take from its FIFO",
+    ),
+    (
+        0x4007_8000..0x4007_8004,
+        "UART1 DR would take from its FIFO",
+    ),
+    (
+        0x4008_0008..0x4008_000c,
+        "SPI0 SSPDR would take from its FIFO",
+    ),
+    (
+        0x4008_8008..0x4008_800c,
+        "SPI1 SSPDR would take from its FIFO",
+    ),
+    (
+        0x4009_0010..0x4009_0014,
+        "I2C0 IC_DATA_CMD would take from its FIFO",
+    ),
+    (
+        0x4009_8010..0x4009_8014,
+        "I2C1 IC_DATA_CMD would take from its FIFO",
+    ),
+    (
+        0x400a_000c..0x400a_0010,
+        "ADC FIFO would take from its FIFO",
+    ),
+    (
+        0x400f_0114..0x400f_012c,
+        "TRNG EHR_DATA would use up random bits",
+    ),
+    (
+        0x5020_0020..0x5020_0030,
+        "PIO0 RXF would take from its FIFOs",
+    ),
+    (
+        0x5030_0020..0x5030_0030,
+        "PIO1 RXF would take from its FIFOs",
+    ),
+    (
+        0x5040_0020..0x5040_0030,
+        "PIO2 RXF would take from its FIFOs",
+    ),
+    (
+        0xd000_0058..0xd000_005c,
+        "SIO FIFO_RD would take from its FIFO",
+    ),
+    (
+        0xd000_0094..0xd000_00a0,
+        "SIO INTERP0 POP registers would update it",
+    ),
+    (
+        0xd000_00d4..0xd000_00e0,
+        "SIO INTERP1 POP registers would update it",
+    ),
+    (0xd000_0100..0xd000_0180, "SIO spinlocks would be claimed"),
+];
+
+/// Returns the regions that can be safely read, along with whether
+/// they can be written to. Flash and ROM can't be written through
+/// the XIP window.
+fn regions() -> impl Iterator<Item = (Range<u32>, bool)> {
+    let psram_size = PSRAM_QMI_SIZE.load(Ordering::Relaxed);
+    [
+        (ROM, false),
+        (FLASH, false),
+        (PSRAM_BASE..PSRAM_BASE + psram_size, true),
+        (SRAM, true),
+        (SIO, true),
+    ]
+    .into_iter()
+    .chain(PERIPHERALS.into_iter().map(|region| (region, true)))
+}
+
+/// Returns Some(writable) if all of `addr..addr + len` lies within
+/// a single known region
+fn check_range(addr: u32, len: u32) -> Option<bool> {
+    let end = addr.checked_add(len)?;
+    regions()
+        .find(|(region, _)| region.start <= addr && end <= region.end)
+        .map(|(_, writable)| writable)
+}
+
+/// Returns why reading the word at `addr` should be avoided, if it
+/// is a register that changes something when read
+fn read_side_effect(addr: u32) -> Option<&'static str> {
+    // Only peripherals have the atomic aliases; SIO is not among them
+    let addr = if (0x4000_0000..0x6000_0000).contains(&addr) {
+        addr & !0x3000
+    } else {
+        addr
+    };
+    READ_SIDE_EFFECTS
+        .iter()
+        .find(|(register, _)| register.contains(&addr))
+        .map(|(_, reason)| *reason)
+}
+
+/// Parses a decimal or 0x prefixed hex number
+fn parse_u32(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/// Formats up to BYTES_PER_LINE bytes in the style of `hexdump`,
+/// labelled with `addr`
+pub fn hexdump_line(addr: u32, bytes: &[u8]) -> String {
+    let mut line = String::new();
+    write!(line, "{addr:08x}:").ok();
+    for byte in bytes {
+        write!(line, " {byte:02x}").ok();
+    }
+    for _ in bytes.len()..BYTES_PER_LINE as usize {
+        line.push_str("   ");
+    }
+    line.push_str("  ");
+    for &byte in bytes {
+        line.push(if byte.is_ascii_graphic() || byte == b' ' {
+            byte as char
+        } else {
+            '.'
+        });
+    }
+    line
+}
+
+pub async fn peek_command(args: &[&str]) {
+    let (addr, len) = match args {
+        [_, addr] => (parse_u32(addr), Some(4)),
+        [_, addr, len] => (parse_u32(addr), parse_u32(len)),
+        _ => (None, None),
+    };
+    let (Some(addr), Some(len)) = (addr, len) else {
+        print!("Usage: peek ADDR [LEN]\r\n");
+        return;
+    };
+
+    // Whole words only
+    let start = addr & !3;
+    let end = addr
+        .saturating_add(len.clamp(1, MAX_PEEK_LEN))
+        .saturating_add(3)
+        & !3;
+    if check_range(start, end - start).is_none() {
+        print!("0x{start:08x}..0x{end:08x} is not in a known memory region\r\n");
+        return;
+    }
+    for word_addr in (start..end).step_by(4) {
+        if let Some(reason) = read_side_effect(word_addr) {
+            print!("Not reading 0x{word_addr:08x}: {reason}\r\n");
+            return;
+        }
+    }
+
+    let mut pager = Pager::new().await;
+    for line_addr in (start..end).step_by(BYTES_PER_LINE as usize) {
+        let line_end = (line_addr + BYTES_PER_LINE).min(end);
+        let mut bytes = [0u8; BYTES_PER_LINE as usize];
+        for (idx, word_addr) in (line_addr..line_end).step_by(4).enumerate() {
+            // SAFETY: the address is word aligned, and check_range
+            // confirmed that it is in a region that can be read
+            let word = unsafe { core::ptr::read_volatile(word_addr as *const u32) };
+            bytes[idx * 4..idx * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        let bytes = &bytes[..(line_end - line_addr) as usize];
+        if !pager.line(&hexdump_line(line_addr, bytes)).await {
+            break;
+        }
+    }
+}
+
+pub async fn poke_command(args: &[&str]) {
+    let [_, addr, value] = args else {
+        print!("Usage: poke ADDR VALUE\r\n");
+        return;
+    };
+    let (Some(addr), Some(value)) = (parse_u32(addr), parse_u32(value)) else {
+        print!("ADDR and VALUE must be decimal or 0x prefixed hex numbers\r\n");
+        return;
+    };
+
+    let allowed = CONFIG
+        .get()
+        .lock()
+        .await
+        .fetch("allow_poke")
+        .await
+        .ok()
+        .flatten()
+        .is_some_and(|value| value.as_str() == "on");
+    if !allowed {
+        print!("poke is disabled; use `config set allow_poke on` to enable it\r\n");
+        return;
+    }
+
+    if addr & 3 != 0 {
+        print!("ADDR must be a multiple of 4\r\n");
+        return;
+    }
+    match check_range(addr, 4) {
+        Some(true) => {}
+        Some(false) => {
+            print!("0x{addr:08x} is in read-only memory\r\n");
+            return;
+        }
+        None => {
+            print!("0x{addr:08x} is not in a known memory region\r\n");
+            return;
+        }
+    }
+
+    // SAFETY: none, really; the user asked for this. We've only
+    // checked that the address is aligned and can be written.
+    unsafe { core::ptr::write_volatile(addr as *mut u32, value) };
+}
@@ -0,0 +1,75 @@
+use crate::config::CONFIG;
+use crate::heap::HEAP;
+use crate::keyboard::get_battery;
+use alloc::format;
+use embassy_executor::Spawner;
+use embassy_time::{Duration, Instant, Ticker};
+
+extern crate alloc;
+
+// This module periodically writes a machine readable summary of the
+// state of the device to the log, so that a script on a host that
+// is attached via USB can track it over time. Each record is a line
+// of JSON, prefixed by METRICS_SENTINEL so that it can be picked out
+// from the other log lines.
+//
+// This is only enabled when the `usb_metrics` config key is `on`.
+
+const METRICS_SENTINEL: &str = "@METRICS@";
+/// Increment this whenever a field is removed or changes meaning
+const METRICS_SCHEMA: u32 = 1;
+const DEFAULT_INTERVAL_SECS: u64 = 10;
+
+pub async fn init_metrics(spawner: &Spawner) {
+    let (enabled, secs) = {
+        let mut config = CONFIG.get().lock().await;
+        let enabled = config
+            .fetch("usb_metrics")
+            .await
+            .ok()
+            .flatten()
+            .map(|value| value.as_str() == "on")
+            .unwrap_or(false);
+        let secs = config
+            .fetch_parsed::<u64>("usb_metrics_secs")
+            .await
+            .unwrap_or(DEFAULT_INTERVAL_SECS);
+        (enabled, secs)
+    };
+    if enabled {
+        spawner.must_spawn(metrics_reporter(Duration::from_secs(secs.max(1))));
+    }
+}
+
+#[embassy_executor::task]
+async fn metrics_reporter(interval: Duration) {
+    let mut ticker = Ticker::every(interval);
+    loop {
+        ticker.next().await;
+
+        let battery = get_battery();
+        #[cfg(feature = "net")]
+        let (ip, ssh_sessions) = (
+            match crate::net::ipv4_address().await {
+                Some(addr) => format!("\"{}\"", addr.address()),
+                None => "null".into(),
+            },
+            crate::net::ssh_session_count(),
+        );
+        // The schema is the same without networking, so
+        // that consumers of the records needn't care
+        #[cfg(not(feature = "net"))]
+        let (ip, ssh_sessions): (alloc::string::String, u32) = ("null".into(), 0);
+        let record = format!(
+            "{METRICS_SENTINEL}{{\"schema\":{METRICS_SCHEMA},\"uptime_s\":{},\
+             \"heap_used\":{},\"heap_free\":{},\"battery_pct\":{},\
+             \"charging\":{},\"ip\":{ip},\"ssh_sessions\":{ssh_sessions}}}",
+            Instant::now().as_secs(),
+            HEAP.used(),
+            HEAP.free(),
+            battery.percentage(),
+            battery.is_charging(),
+        );
+        crate::logging::write_record(&record);
+    }
+}
@@ -1,20 +1,25 @@
 use crate::Irqs;
+use crate::bridge::{EnterKey, KeyChannel, TerminalBridge, TerminalInput};
 use crate::config::CONFIG;
-use crate::keyboard::{Key, KeyReport, KeyState, Modifiers};
+use crate::keyboard::{KeyReport, KeyState};
 use crate::net::alloc::string::ToString;
-use crate::process::{LineEditor, Process, assign_proc, assign_proc_if};
+use crate::process::{Process, PromptKind, assign_proc, prompt_for_input};
 use crate::rng::WezTermRng;
-use crate::screen::{SCREEN, SCREEN_HEIGHT, SCREEN_WIDTH, Screen};
+use crate::screen::{SCREEN, SCREEN_HEIGHT, SCREEN_WIDTH, Screen, SharedScreen};
+use crate::transcript::Transcript;
 use alloc::boxed::Box;
 use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write as _;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use cyw43::Control;
 use cyw43_pio::{PioSpi, RM2_CLOCK_DIVIDER};
 use embassy_executor::Spawner;
 use embassy_futures::select::*;
 use embassy_net::dns::{DnsQueryType, DnsSocket};
 use embassy_net::tcp::TcpSocket;
-use embassy_net::{IpEndpoint, Stack};
+use embassy_net::{IpAddress, IpEndpoint, Stack};
 use embassy_rp::gpio::{Level, Output};
 use embassy_rp::peripherals::{DMA_CH0, PIO0};
 use embassy_rp::pio::Pio;
@@ -22,19 +27,19 @@ use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
 use embassy_sync::channel::Channel;
 use embassy_sync::lazy_lock::LazyLock;
 use embassy_sync::mutex::Mutex;
-use embassy_time::{Duration, with_timeout};
-use embedded_io_async::{Read, Write as _};
+use embassy_time::{Duration, Instant, with_timeout};
+use embedded_io_async::{ErrorType, Read, Write};
 use rand_core::RngCore;
 use static_cell::StaticCell;
 use sunset::{CliEvent, SessionCommand};
-use sunset_embassy::{ChanInOut, ProgressHolder, SSHClient};
+use sunset_embassy::{ProgressHolder, SSHClient};
 
 extern crate alloc;
 
-type CS = embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-
 static WIFI_CONTROL: LazyLock<Mutex<CriticalSectionRawMutex, Option<Control<'static>>>> =
     LazyLock::new(|| Mutex::new(None));
+/// The number of ssh sessions that are connected
+static SSH_SESSIONS: AtomicU32 = AtomicU32::new(0);
 static STACK: LazyLock<Mutex<CriticalSectionRawMutex, Option<Stack<'static>>>> =
     LazyLock::new(|| Mutex::new(None));
 
@@ -98,35 +103,21 @@ pub async fn setup_wifi(
     );
     spawner.must_spawn(net_runner(runner));
 
-    control
-        .set_power_management(cyw43::PowerManagementMode::None)
-        .await;
-
-    let (ssid, wifi_pw) = {
-        let mut config = CONFIG.get().lock().await;
-        let ssid = config.fetch("wifi_ssid").await;
-        let wifi_pw = config.fetch("wifi_pw").await;
-        (ssid, wifi_pw)
-    };
-    match (ssid, wifi_pw) {
-        (Ok(Some(ssid)), Ok(Some(wifi_pw))) => {
-            if !ssid.is_empty() {
-                print!("Connecting to \u{1b}[1m{ssid}\u{1b}[0m...\r\n");
-                match control
-                    .join(&ssid, cyw43::JoinOptions::new(wifi_pw.as_bytes()))
-                    .await
-                {
-                    Ok(_) => {}
-                    Err(err) => {
-                        log::error!("join failed with status={}", err.status);
-                        print!("Failed with status {}\r\n", err.status);
-                    }
-                }
-            }
-        }
-        _ => {
-            print!("wifi_ssid and/or wifi_pw are not set\r\n");
+    let wifi_pm = CONFIG.get().lock().await.fetch("wifi_pm").await;
+    let wifi_pm = wifi_pm.ok().flatten();
+    let pm_mode = match wifi_pm.as_ref().map(|pm| pm.as_str()) {
+        None | Some("none") => cyw43::PowerManagementMode::None,
+        Some("performance") => cyw43::PowerManagementMode::Performance,
+        Some("powersave") => cyw43::PowerManagementMode::PowerSave,
+        Some(other) => {
+            print!("wifi_pm `{other}` is not one of none, performance or powersave\r\n");
+            cyw43::PowerManagementMode::None
         }
+    };
+    log::info!("wifi power management mode {pm_mode:?}");
+    control.set_power_management(pm_mode).await;
+    if let Err(err) = join_wifi(&mut control).await {
+        print!("{err}\r\n");
     }
     WIFI_CONTROL.get().lock().await.replace(control);
 
@@ -139,399 +130,792 @@ pub async fn setup_wifi(
     }
 
     spawner.must_spawn(crate::time::time_sync(stack));
+    spawner.must_spawn(wifi_monitor(stack));
     STACK.get().lock().await.replace(stack);
 }
 
-const TIMEOUT_DURATION: Duration = Duration::from_secs(10);
+/// Joins the network named by the wifi_ssid config setting
+async fn join_wifi(control: &mut Control<'static>) -> Result<(), String> {
+    let (ssid, wifi_pw) = {
+        let mut config = CONFIG.get().lock().await;
+        let ssid = config.fetch("wifi_ssid").await;
+        let wifi_pw = config.fetch("wifi_pw").await;
+        (ssid, wifi_pw)
+    };
+    let (Ok(Some(ssid)), Ok(Some(wifi_pw))) = (ssid, wifi_pw) else {
+        return Err("wifi_ssid and/or wifi_pw are not set".into());
+    };
+    if ssid.is_empty() {
+        return Err("wifi_ssid is empty".into());
+    }
+    join_network(control, &ssid, &wifi_pw).await
+}
+
+/// Joins the network `ssid`, which is open if `password` is empty.
+/// The name is sent in the join request, rather than being looked
+/// up in a scan, so this works for hidden networks too.
+async fn join_network(
+    control: &mut Control<'static>,
+    ssid: &str,
+    password: &str,
+) -> Result<(), String> {
+    let options = if password.is_empty() {
+        cyw43::JoinOptions::new_open()
+    } else {
+        cyw43::JoinOptions::new(password.as_bytes())
+    };
+    print!("Connecting to \u{1b}[1m{ssid}\u{1b}[0m...\r\n");
+    control.join(ssid, options).await.map_err(|err| {
+        log::error!("join failed with status={}", err.status);
+        alloc::format!("Failed with status {}", err.status)
+    })
+}
 
-async fn ssh_channel_task(mut channel: ChanInOut<'_, '_>, key_rx: Arc<Channel<CS, KeyReport, 4>>) {
-    log::info!("ssh_channel_task waiting for output");
+/// Set by `net down`, so that wifi dropping out isn't reported,
+/// and isn't treated as something to recover from
+static WIFI_TURNED_OFF: AtomicBool = AtomicBool::new(false);
 
+/// Reports wifi dropping out and coming back on the status line,
+/// once it has first come up
+#[embassy_executor::task]
+async fn wifi_monitor(stack: Stack<'static>) {
+    // Until the network first comes up, it being down is expected
+    // rather than something to report
+    stack.wait_config_up().await;
     loop {
-        let mut buf = [0u8; 1024];
+        stack.wait_config_down().await;
+        if !WIFI_TURNED_OFF.load(Ordering::Relaxed) {
+            crate::notify::notify("Wi-Fi disconnected");
+        }
+        stack.wait_config_up().await;
+        let message = match stack.config_v4() {
+            Some(v4) => alloc::format!("Wi-Fi reconnected, IP Address {}", v4.address),
+            None => String::from("Wi-Fi reconnected"),
+        };
+        crate::notify::notify(message);
+    }
+}
 
-        let output = channel.read(&mut buf);
-        let input = key_rx.receive();
+/// How long `net up` waits for an address before reporting
+const NET_UP_TIMEOUT: Duration = Duration::from_secs(15);
 
-        match select(output, input).await {
-            Either::First(read_result) => match read_result {
-                Ok(n) => {
-                    if n == 0 {
-                        log::warn!("ssh_channel_task: EOF on ssh channel");
-                        return;
-                    }
-                    SCREEN.get().lock().await.parse_bytes(&buf[0..n]);
-                }
-                Err(err) => {
-                    print!("\u{1b}[1mssh_channel_task: {err:?}\r\n");
-                    return;
-                }
-            },
-            Either::Second(key_report) => {
-                // Encode a key with xterm style keyboard encoding.
-                // FIXME: woefully incomplete!
-
-                if key_report.modifiers == Modifiers::CTRL {
-                    if let Key::Char(c) = key_report.key {
-                        if let Some(mapped) = ctrl_mapping(c) {
-                            log::info!(
-                                "doing mapped ctrl {} -> {}",
-                                c.escape_debug(),
-                                mapped.escape_debug()
-                            );
-                            let mut buf = [0u8; 4];
-                            log::info!(
-                                "{:?}",
-                                with_timeout(
-                                    TIMEOUT_DURATION,
-                                    channel.write_all(mapped.encode_utf8(&mut buf).as_bytes()),
-                                )
-                                .await
-                            );
-                            continue;
-                        }
-                    }
-                }
+pub async fn net_command(args: &[&str]) {
+    match args {
+        [_] | [_, "status"] => net_status().await,
+        [_, "up"] => net_up().await,
+        [_, "down"] => net_down().await,
+        [_, "join"] => net_join().await,
+        _ => print!("Usage: net [status|up|down|join]\r\n"),
+    }
+}
 
-                if key_report.modifiers == Modifiers::ALT {
-                    // Alt sends escape first
-                    log::info!("ALT -> send escape first");
-                    log::info!(
-                        "{:?}",
-                        with_timeout(TIMEOUT_DURATION, channel.write_all(b"\x1b")).await
-                    );
-                }
+async fn net_status() {
+    if WIFI_TURNED_OFF.load(Ordering::Relaxed) {
+        print!("Wi-Fi is down; use `net up` to reconnect\r\n");
+        return;
+    }
+    match ipv4_address().await {
+        Some(address) => print!("Wi-Fi is up, IP Address {address}\r\n"),
+        None => print!("Wi-Fi is not connected\r\n"),
+    }
+}
 
-                if let Key::Char(c) = key_report.key {
-                    let mut buf = [0u8; 4];
-                    log::info!("just sending {} as-is", c.escape_debug());
-                    log::info!(
-                        "{:?}",
-                        with_timeout(
-                            TIMEOUT_DURATION,
-                            channel.write_all(c.encode_utf8(&mut buf).as_bytes()),
-                        )
-                        .await
-                    );
-                } else {
-                    let text = match key_report.key {
-                        Key::Enter => "\n",
-                        Key::BackSpace => "\u{7f}",
-                        Key::Tab => "\t",
-                        Key::Escape => "\u{1b}",
-                        Key::Up => "\u{1b}[A",
-                        Key::Down => "\u{1b}[B",
-                        Key::Right => "\u{1b}[C",
-                        Key::Left => "\u{1b}[D",
-                        Key::Home => "\u{1b}[H",
-                        Key::End => "\u{1b}[F",
-                        Key::PageUp => "\u{1b}[5~",
-                        Key::PageDown => "\u{1b}[6~",
-                        Key::None | Key::Char(_) => continue,
-                        _ => {
-                            continue;
-                        }
-                    };
-                    log::info!("{key_report:?} -> {}", text.escape_debug());
-                    log::info!(
-                        "{:?}",
-                        with_timeout(TIMEOUT_DURATION, channel.write_all(text.as_bytes())).await
-                    );
-                }
+/// Leaves the wifi network until `net up`
+async fn net_down() {
+    let mut control = WIFI_CONTROL.get().lock().await;
+    let Some(control) = control.as_mut() else {
+        print!("Wi-Fi has not been set up\r\n");
+        return;
+    };
+    WIFI_TURNED_OFF.store(true, Ordering::Relaxed);
+    control.leave().await;
+    print!("Wi-Fi is down\r\n");
+}
+
+/// Joins the configured wifi network again, and waits a while
+/// for it to be given an address
+async fn net_up() {
+    {
+        let mut control = WIFI_CONTROL.get().lock().await;
+        let Some(control) = control.as_mut() else {
+            print!("Wi-Fi has not been set up\r\n");
+            return;
+        };
+        WIFI_TURNED_OFF.store(false, Ordering::Relaxed);
+        if let Err(err) = join_wifi(control).await {
+            print!("{err}\r\n");
+            return;
+        }
+    }
+
+    // The stack is only published once it has first come up
+    let stack = STACK.get().lock().await.as_ref().copied();
+    let timed_out = match stack {
+        Some(stack) => with_timeout(NET_UP_TIMEOUT, stack.wait_config_up())
+            .await
+            .is_err(),
+        None => false,
+    };
+    if timed_out {
+        print!("Joined, but no address yet\r\n");
+        return;
+    }
+    net_status().await;
+}
+
+/// The most networks that `net join` lists
+const MAX_SCANNED: usize = 16;
+/// How long `net join` listens for networks
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest name that a wifi network can have, in bytes
+const MAX_SSID_LEN: usize = 32;
+
+/// Returns the names of the networks that can be seen, in the
+/// order that they were found. Hidden networks don't give a name,
+/// and so can't be listed.
+async fn scan_networks(control: &mut Control<'static>) -> Vec<String> {
+    let mut ssids: Vec<String> = Vec::new();
+    let mut scanner = control.scan(Default::default()).await;
+    let scan = async {
+        while let Some(bss) = scanner.next().await {
+            let len = (bss.ssid_len as usize).min(bss.ssid.len());
+            let ssid = &bss.ssid[..len];
+            if ssid.iter().all(|&b| b == 0) {
+                continue;
+            }
+            // The name has to be typed back exactly to join, so
+            // names that aren't UTF-8 are left out
+            let Ok(ssid) = core::str::from_utf8(ssid) else {
+                continue;
+            };
+            if ssids.len() < MAX_SCANNED && !ssids.iter().any(|s| s == ssid) {
+                ssids.push(ssid.into());
             }
         }
+    };
+    if with_timeout(SCAN_TIMEOUT, scan).await.is_err() {
+        log::info!("wifi scan timed out after {}s", SCAN_TIMEOUT.as_secs());
     }
+    ssids
 }
 
-#[embassy_executor::task]
-async fn ssh_session_task(host: String, command: Option<String>) {
-    let Some(stack) = STACK.get().lock().await.as_ref().copied() else {
-        print!("network is offline\r\n");
+/// Asks for the name and security of a network that wasn't listed,
+/// such as a hidden one, returning the name and whether it needs a
+/// password
+async fn prompt_for_other_network() -> Option<(String, bool)> {
+    let ssid = prompt_for_input("Network name:", PromptKind::Text).await?;
+    if ssid.is_empty() || ssid.len() > MAX_SSID_LEN {
+        print!("A network name is 1 to {MAX_SSID_LEN} bytes long\r\n");
+        return None;
+    }
+    print!("  1) WPA2\r\n  2) Open, with no password\r\n");
+    let security = prompt_for_input("Security:", PromptKind::Text).await?;
+    match security.trim() {
+        "1" => Some((ssid, true)),
+        "2" => Some((ssid, false)),
+        other => {
+            print!("`{other}` is not 1 or 2\r\n");
+            None
+        }
+    }
+}
+
+/// Lists the networks that can be seen, joins the one that is
+/// picked, and offers to save it as the one to join at boot
+async fn net_join() {
+    let ssids = {
+        let mut control = WIFI_CONTROL.get().lock().await;
+        let Some(control) = control.as_mut() else {
+            print!("Wi-Fi has not been set up\r\n");
+            return;
+        };
+        print!("Scanning...\r\n");
+        scan_networks(control).await
+    };
+    for (idx, ssid) in ssids.iter().enumerate() {
+        print!("{:>3}) {ssid}\r\n", idx + 1);
+    }
+    let other = ssids.len() + 1;
+    print!("{other:>3}) Other network...\r\n");
+
+    let Some(choice) = prompt_for_input("Network number:", PromptKind::Text).await else {
         return;
     };
+    let (ssid, secured) = match choice.trim().parse::<usize>() {
+        Ok(n) if n == other => match prompt_for_other_network().await {
+            Some(network) => network,
+            None => return,
+        },
+        // Whether a listed network needs a password isn't known,
+        // so an empty one is taken to mean that it is open
+        Ok(n) if (1..other).contains(&n) => (ssids[n - 1].clone(), true),
+        _ => {
+            print!("`{choice}` is not one of the numbers shown\r\n");
+            return;
+        }
+    };
+    let password = if secured {
+        match prompt_for_input("Password:", PromptKind::Password).await {
+            Some(password) => password,
+            None => return,
+        }
+    } else {
+        String::new()
+    };
 
-    let command = command.as_deref();
+    {
+        let mut control = WIFI_CONTROL.get().lock().await;
+        let Some(control) = control.as_mut() else {
+            return;
+        };
+        WIFI_TURNED_OFF.store(false, Ordering::Relaxed);
+        if let Err(err) = join_network(control, &ssid, &password).await {
+            print!("{err}\r\n");
+            return;
+        }
+    }
+    print!("Joined {ssid}\r\n");
 
-    let dns_client = DnsSocket::new(stack);
+    let answer = prompt_for_input("Join this network at boot? Type `yes`:", PromptKind::Text).await;
+    if answer.as_deref() != Some("yes") {
+        return;
+    }
+    let mut config = CONFIG.get().lock().await;
+    for (key, value) in [("wifi_ssid", &ssid), ("wifi_pw", &password)] {
+        let value = match value.as_str().try_into() {
+            Ok(value) => value,
+            Err(err) => {
+                print!("{key} can't be saved: {err:?}\r\n");
+                return;
+            }
+        };
+        if let Err(err) = config.store(key, value).await {
+            print!("{key} can't be saved: {err:?}\r\n");
+            return;
+        }
+    }
+    print!("Saved\r\n");
+}
 
-    match dns_client.query(&host, DnsQueryType::A).await {
-        Ok(addrs) => {
-            log::info!("{host} -> {addrs:?}");
-            let mut socket_tx_buf = [0u8; 8192];
-            let mut socket_rx_buf = [0u8; 8192];
-            let mut tcp_socket = TcpSocket::new(stack, &mut socket_tx_buf, &mut socket_rx_buf);
-
-            match tcp_socket
-                .connect(IpEndpoint {
-                    addr: addrs[0],
-                    port: 22,
-                })
-                .await
-            {
-                Ok(()) => {
-                    use embassy_futures::select::*;
-
-                    let key_channel = Arc::new(Channel::new());
-                    let ssh_proc = Arc::new(SshProcess {
-                        key_sender: key_channel.clone(),
-                    });
-                    let prior_proc = assign_proc(ssh_proc).await;
-
-                    print!("Connected to {host} {}:22\r\n", addrs[0]);
-                    let (mut read, mut write) = tcp_socket.split();
-                    let mut ssh_tx_buf = [0u8; 8192];
-                    let mut ssh_rx_buf = [0u8; 8192];
-                    let ssh_client = match SSHClient::new(&mut ssh_tx_buf, &mut ssh_rx_buf) {
-                        Ok(client) => client,
-                        Err(err) => {
-                            print!("SSHClient::new: {err:?}\r\n");
-                            return;
-                        }
-                    };
+pub fn ssh_session_count() -> u32 {
+    SSH_SESSIONS.load(Ordering::Relaxed)
+}
 
-                    let session_authd_chan =
-                        embassy_sync::channel::Channel::<NoopRawMutex, bool, 1>::new();
-                    let wait_for_auth = session_authd_chan.receiver();
+/// Returns the IPv4 address assigned by DHCP, if any
+pub async fn ipv4_address() -> Option<embassy_net::Ipv4Cidr> {
+    let stack = STACK.get().lock().await;
+    stack.as_ref()?.config_v4().map(|v4| v4.address)
+}
 
-                    let spawn_session_future = async {
-                        if wait_for_auth.receive().await {
-                            let channel = ssh_client.open_session_pty().await?;
-                            ssh_channel_task(channel, key_channel).await;
-                        }
-                        Ok::<(), sunset::Error>(())
-                    };
-
-                    let runner = ssh_client.run(&mut read, &mut write);
-                    let mut progress = ProgressHolder::new();
-                    let ssh_ticker = async {
-                        loop {
-                            match ssh_client.progress(&mut progress).await {
-                                Ok(event) => match event {
-                                    CliEvent::Hostkey(k) => {
-                                        log::info!("host key {:?}", k.hostkey());
-                                        k.accept().expect("accept hostkey");
-                                    }
-                                    CliEvent::Banner(b) => {
-                                        if let Ok(b) = b.banner() {
-                                            log::info!("banner: {b}");
-                                        }
-                                    }
-                                    CliEvent::Username(req) => {
-                                        match CONFIG.get().lock().await.fetch("ssh_user").await {
-                                            Ok(Some(pw)) => req.username(&pw),
-                                            _ => {
-                                                let user =
-                                                    prompt_for_input("login: ", PromptKind::Text)
-                                                        .await;
-                                                match user {
-                                                    Some(user) => req.username(&user),
-                                                    None => {
-                                                        print!("Cancelled\r\n");
-                                                        return Ok(());
-                                                    }
-                                                }
-                                            }
-                                        }
-                                        .expect("set user");
-                                    }
-                                    CliEvent::Password(req) => {
-                                        match CONFIG.get().lock().await.fetch("ssh_pw").await {
-                                            Ok(Some(pw)) => req.password(&pw),
-                                            _ => {
-                                                let user = prompt_for_input(
-                                                    "password: ",
-                                                    PromptKind::Password,
-                                                )
-                                                .await;
-                                                match user {
-                                                    Some(user) => req.password(&user),
-                                                    None => req.skip(),
-                                                }
-                                            }
-                                        }
-                                        .expect("set pw");
-                                    }
-                                    CliEvent::Pubkey(req) => {
-                                        req.skip().expect("skip pubkey");
-                                    }
-                                    CliEvent::AgentSign(req) => {
-                                        req.skip().expect("skip agentsign");
-                                    }
-                                    CliEvent::Authenticated => {
-                                        log::info!("Authenticated!");
-                                        session_authd_chan.sender().send(true).await;
-                                    }
-                                    CliEvent::SessionOpened(mut s) => {
-                                        log::info!("session opened channel {}", s.channel());
-
-                                        use heapless::{String, Vec};
-
-                                        let mut term = String::<32>::new();
-                                        let _ = term.push_str("xterm").unwrap();
-
-                                        let pty = {
-                                            let screen = SCREEN.get().lock().await;
-                                            let rows = screen.height;
-                                            let cols = screen.width;
-
-                                            sunset::Pty {
-                                                term,
-                                                rows: rows.into(),
-                                                cols: cols.into(),
-                                                width: SCREEN_WIDTH as u32,
-                                                height: SCREEN_HEIGHT as u32,
-                                                modes: Vec::new(),
-                                            }
-                                        };
-
-                                        log::info!("requesting pty {pty:?}");
-                                        if let Err(err) = s.pty(pty) {
-                                            print!("requesting pty failed {err:?}\r\n");
-                                            return Err(err);
-                                        }
-                                        log::info!("setting command");
-                                        match &command {
-                                            Some(cmd) => {
-                                                if let Err(err) = s.cmd(&SessionCommand::Exec(cmd))
-                                                {
-                                                    print!("command failed: {err:?}\r\n");
-                                                    return Err(err);
-                                                }
-                                            }
-                                            None => {
-                                                if let Err(err) = s.shell() {
-                                                    print!("shell failed: {err:?}\r\n");
-                                                    return Err(err);
-                                                }
-                                            }
-                                        }
-                                        log::info!("SessionOpened completed");
-                                    }
-                                    CliEvent::SessionExit(status) => {
-                                        print!("[ssh session exit with {status:?}]\r\n");
-                                        break;
-                                    }
-                                    CliEvent::Defunct => {
-                                        log::error!("ssh session terminated");
-                                        break;
-                                    }
-                                },
-                                Err(err) => {
-                                    print!("ssh progress error: {err:?}\r\n");
-                                    return Err(err);
-                                }
-                            }
-                        }
+const TIMEOUT_DURATION: Duration = Duration::from_secs(10);
 
-                        Ok::<(), sunset::Error>(())
-                    };
+/// Resolves `host` to its addresses. IP address literals are
+/// returned directly, without a DNS query, so that hosts can
+/// still be reached by address when DNS isn't working.
+/// The error is a message that is suitable to show to the user.
+pub async fn resolve(stack: Stack<'_>, host: &str) -> Result<Vec<IpAddress>, String> {
+    if let Some(addr) = parse_ip_literal(host)? {
+        return Ok(alloc::vec![addr]);
+    }
 
-                    let res = select(runner, select(ssh_ticker, spawn_session_future)).await;
-                    log::info!("ssh result is {res:?}");
-                    assign_proc(prior_proc).await;
-                }
-                Err(err) => {
-                    print!("failed to connect to port 22: {err:?}\r\n");
-                }
+    let dns_client = DnsSocket::new(stack);
+    let addrs = dns_client
+        .query(host, DnsQueryType::A)
+        .await
+        .map_err(|err| alloc::format!("failed to resolve {host}: {err:?}"))?;
+    log::info!("{host} -> {addrs:?}");
+    Ok(addrs.iter().copied().collect())
+}
+
+/// Parses `host` as a dotted-quad IPv4 address, returning None if it
+/// is a name. Anything made up only of digits and dots is treated as
+/// an address, so that malformed ones such as `999.1.1.1` or `10.0.0.5.`
+/// are reported as errors rather than being sent to DNS.
+fn parse_ip_literal(host: &str) -> Result<Option<IpAddress>, String> {
+    if !host.bytes().all(|c| c.is_ascii_digit() || c == b'.') {
+        return Ok(None);
+    }
+    host.parse()
+        .map(|addr| Some(IpAddress::Ipv4(addr)))
+        .map_err(|_| alloc::format!("{host} is not a valid IPv4 address"))
+}
+
+/// Resolves `host` and connects to `port` on the first of its
+/// addresses that accepts, using the provided socket buffers.
+/// Each attempt is abandoned after TIMEOUT_DURATION.
+/// The error is a message that is suitable to show to the user.
+async fn connect_tcp<'a>(
+    host: &str,
+    port: u16,
+    rx_buf: &'a mut [u8],
+    tx_buf: &'a mut [u8],
+) -> Result<(TcpSocket<'a>, IpAddress), String> {
+    let Some(stack) = STACK.get().lock().await.as_ref().copied() else {
+        return Err("network is offline".into());
+    };
+
+    let addrs = resolve(stack, host).await?;
+
+    let mut socket = TcpSocket::new(stack, rx_buf, tx_buf);
+    let mut last_error = alloc::format!("{host} has no addresses");
+    for &addr in &addrs {
+        match with_timeout(TIMEOUT_DURATION, socket.connect(IpEndpoint { addr, port })).await {
+            Ok(Ok(())) => return Ok((socket, addr)),
+            Ok(Err(err)) => {
+                last_error = alloc::format!("failed to connect to {addr} port {port}: {err:?}");
+            }
+            Err(_) => {
+                last_error = alloc::format!("timed out connecting to {addr} port {port}");
+                // Return the socket to the closed state for the next attempt
+                socket.abort();
             }
         }
-        Err(err) => {
-            print!("failed to resolve {host}: {err:?}\r\n");
-        }
+        log::warn!("{last_error}");
     }
+    Err(last_error)
 }
 
-#[derive(Copy, Clone)]
-enum PromptKind {
-    Text,
-    Password,
+/// Fetches `url` with an HTTP GET and returns the body of the
+/// response, which must be successful and no longer than `max_len`.
+/// There is no TLS, so only `http://` URLs are supported; anything
+/// fetched this way should be checked against a known digest.
+pub async fn http_get(url: &str, max_len: usize) -> Result<Vec<u8>, String> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        if url.starts_with("https://") {
+            return Err("https isn't supported; use http:// instead".into());
+        }
+        return Err(alloc::format!("{url} is not an http:// URL"));
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => rest.split_at(idx),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => match port.parse::<u16>() {
+            Ok(port) => (host, port),
+            Err(_) => return Err(alloc::format!("invalid port in {url}")),
+        },
+        None => (authority, 80),
+    };
+
+    let mut rx_buf = [0u8; 2048];
+    let mut tx_buf = [0u8; 512];
+    let (mut socket, _addr) = connect_tcp(host, port, &mut rx_buf, &mut tx_buf).await?;
+    let request =
+        alloc::format!("GET {path} HTTP/1.0\r\nHost: {authority}\r\nConnection: close\r\n\r\n");
+    match with_timeout(TIMEOUT_DURATION, socket.write_all(request.as_bytes())).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => return Err(alloc::format!("failed to send request: {err:?}")),
+        Err(_) => return Err("timed out sending request".into()),
+    }
+
+    // Allow some room for the headers
+    let limit = max_len + 1024;
+    let mut response = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let n = match with_timeout(TIMEOUT_DURATION, socket.read(&mut buf)).await {
+            Ok(Ok(n)) => n,
+            Ok(Err(err)) => return Err(alloc::format!("failed to read response: {err:?}")),
+            Err(_) => return Err("timed out reading response".into()),
+        };
+        if n == 0 {
+            break;
+        }
+        if response.len() + n > limit {
+            socket.abort();
+            return Err(alloc::format!("response is larger than {max_len} bytes"));
+        }
+        response.extend_from_slice(&buf[..n]);
+    }
+    socket.close();
+
+    let Some(header_end) = response.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Err("response has no headers".into());
+    };
+    let status_line = response[..header_end]
+        .split(|&b| b == b'\r')
+        .next()
+        .and_then(|line| core::str::from_utf8(line).ok())
+        .unwrap_or("");
+    match status_line.split(' ').nth(1) {
+        Some("200") => {}
+        _ => return Err(alloc::format!("request failed: {status_line}")),
+    }
+    let body = response.split_off(header_end + 4);
+    if body.len() > max_len {
+        return Err(alloc::format!("response is larger than {max_len} bytes"));
+    }
+    Ok(body)
 }
 
-async fn prompt_for_input(prompt: &str, kind: PromptKind) -> Option<String> {
-    use crate::process::{Mutex, ProcHandle};
-    use core::fmt::Write;
+/// Writes to the ssh channel that wait at least this long are
+/// counted as stalls by `ssh stats`
+const STALL_THRESHOLD: Duration = Duration::from_millis(100);
+/// Writes that wait at least this long are logged
+const STALL_WARNING: Duration = Duration::from_secs(1);
+
+/// Counters for the ssh session, shown by `ssh stats`. Only one
+/// session runs at a time, so there is a single set of them.
+/// sunset doesn't expose the channel window, but a write waits
+/// when the window is used up, so the time spent waiting in
+/// writes is measured instead.
+struct SessionStats {
+    /// Uptime in seconds when the session started
+    started: AtomicU32,
+    rx_bytes: AtomicU32,
+    tx_bytes: AtomicU32,
+    /// Writes that waited for at least STALL_THRESHOLD
+    stalls: AtomicU32,
+    longest_stall_ms: AtomicU32,
+}
 
-    let channel = Arc::new(Channel::<CS, Option<String>, 1>::new());
+static SESSION_STATS: SessionStats = SessionStats {
+    started: AtomicU32::new(0),
+    rx_bytes: AtomicU32::new(0),
+    tx_bytes: AtomicU32::new(0),
+    stalls: AtomicU32::new(0),
+    longest_stall_ms: AtomicU32::new(0),
+};
 
-    struct PromptProc {
-        prompt: String,
-        input: Mutex<LineEditor>,
-        channel: Arc<Channel<CS, Option<String>, 1>>,
-        kind: PromptKind,
+impl SessionStats {
+    fn reset(&self) {
+        self.started
+            .store(Instant::now().as_secs() as u32, Ordering::Relaxed);
+        self.rx_bytes.store(0, Ordering::Relaxed);
+        self.tx_bytes.store(0, Ordering::Relaxed);
+        self.stalls.store(0, Ordering::Relaxed);
+        self.longest_stall_ms.store(0, Ordering::Relaxed);
     }
 
-    impl Drop for PromptProc {
-        fn drop(&mut self) {
-            self.channel.try_send(None).ok();
+    fn record_write(&self, len: usize, waited: Duration) {
+        self.tx_bytes.fetch_add(len as u32, Ordering::Relaxed);
+        if waited < STALL_THRESHOLD {
+            return;
+        }
+        let waited_ms = waited.as_millis() as u32;
+        self.stalls.fetch_add(1, Ordering::Relaxed);
+        self.longest_stall_ms
+            .fetch_max(waited_ms, Ordering::Relaxed);
+        if waited >= STALL_WARNING {
+            log::warn!("ssh: write of {len} bytes waited {waited_ms}ms for the channel");
         }
     }
+}
 
-    #[async_trait::async_trait(?Send)]
-    impl Process for PromptProc {
-        fn name(&self) -> &str {
-            "prompt"
-        }
-        async fn render(&self) {
-            let mut screen = SCREEN.get().lock().await;
-            match self.kind {
-                PromptKind::Text => {
-                    let input = self.input.lock().await;
-                    write!(screen, "\r{} {}\u{1b}[K", self.prompt, input.input()).ok();
-                }
-                PromptKind::Password => {
-                    write!(screen, "\r{}\u{1b}[K", self.prompt).ok();
-                }
+/// Wraps the ssh channel, counting the bytes that pass through
+/// it and how long writes wait, in SESSION_STATS
+struct MeteredChannel<T> {
+    inner: T,
+}
+
+impl<T: ErrorType> ErrorType for MeteredChannel<T> {
+    type Error = T::Error;
+}
+
+impl<T: Read> Read for MeteredChannel<T> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let n = self.inner.read(buf).await?;
+        SESSION_STATS
+            .rx_bytes
+            .fetch_add(n as u32, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<T: Write> Write for MeteredChannel<T> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let start = Instant::now();
+        let n = self.inner.write(buf).await?;
+        SESSION_STATS.record_write(n, start.elapsed());
+        Ok(n)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush().await
+    }
+}
+
+/// `ssh stats` shows the counters for the active session
+async fn ssh_stats() {
+    if ssh_session_count() == 0 {
+        print!("ssh: no session is connected\r\n");
+        return;
+    }
+    let stats = &SESSION_STATS;
+    let started = stats.started.load(Ordering::Relaxed);
+    print!(
+        "connected for {}s\r\n\
+         received {} bytes, sent {} bytes\r\n\
+         {} writes waited over {}ms, the longest for {}ms\r\n",
+        (Instant::now().as_secs() as u32).saturating_sub(started),
+        stats.rx_bytes.load(Ordering::Relaxed),
+        stats.tx_bytes.load(Ordering::Relaxed),
+        stats.stalls.load(Ordering::Relaxed),
+        STALL_THRESHOLD.as_millis(),
+        stats.longest_stall_ms.load(Ordering::Relaxed),
+    );
+}
+
+/// Shows a summary of SESSION_STATS on the status line, so that
+/// they can be seen while the session has the screen and keyboard
+pub fn notify_ssh_stats() {
+    if ssh_session_count() == 0 {
+        crate::notify::notify("ssh: no session is connected");
+        return;
+    }
+    let stats = &SESSION_STATS;
+    let started = stats.started.load(Ordering::Relaxed);
+    crate::notify::notify(alloc::format!(
+        "ssh {}s rx {} tx {} waits {} max {}ms",
+        (Instant::now().as_secs() as u32).saturating_sub(started),
+        stats.rx_bytes.load(Ordering::Relaxed),
+        stats.tx_bytes.load(Ordering::Relaxed),
+        stats.stalls.load(Ordering::Relaxed),
+        stats.longest_stall_ms.load(Ordering::Relaxed),
+    ));
+}
+
+#[embassy_executor::task]
+async fn ssh_session_task(host: String, command: Option<String>, record: bool) {
+    let command = command.as_deref();
+
+    let mut socket_tx_buf = [0u8; 8192];
+    let mut socket_rx_buf = [0u8; 8192];
+    let (mut tcp_socket, addr) =
+        match connect_tcp(&host, 22, &mut socket_rx_buf, &mut socket_tx_buf).await {
+            Ok(connected) => connected,
+            Err(err) => {
+                print!("{err}\r\n");
+                return;
             }
+        };
+
+    let key_channel = Arc::new(Channel::new());
+    let session_screen = Screen::new_shared().await;
+    let ssh_proc = Arc::new(SshProcess {
+        key_sender: key_channel.clone(),
+        screen: session_screen.clone(),
+    });
+    let prior_proc = assign_proc(ssh_proc).await;
+
+    write!(
+        session_screen.lock().await,
+        "Connected to {host} {addr}:22\r\n"
+    )
+    .ok();
+    let (mut read, mut write) = tcp_socket.split();
+    let mut ssh_tx_buf = [0u8; 8192];
+    let mut ssh_rx_buf = [0u8; 8192];
+    let ssh_client = match SSHClient::new(&mut ssh_tx_buf, &mut ssh_rx_buf) {
+        Ok(client) => client,
+        Err(err) => {
+            print!("SSHClient::new: {err:?}\r\n");
+            return;
         }
+    };
+
+    let session_authd_chan = embassy_sync::channel::Channel::<NoopRawMutex, bool, 1>::new();
+    let wait_for_auth = session_authd_chan.receiver();
 
-        fn un_prompt(&self, screen: &mut Screen) {
-            write!(screen, "\r\u{1b}[K").ok();
+    let spawn_session_future = async {
+        if wait_for_auth.receive().await {
+            let mut channel = MeteredChannel {
+                inner: ssh_client.open_session_pty().await?,
+            };
+            let transcript = if record {
+                Some(Transcript::begin(&host).await)
+            } else {
+                None
+            };
+            let enter = CONFIG
+                .get()
+                .lock()
+                .await
+                .fetch_parsed::<EnterKey>("ssh_enter")
+                .await
+                .unwrap_or_default();
+            TerminalBridge::new(key_channel, session_screen.clone())
+                .with_enter_key(enter)
+                .with_transcript(transcript)
+                .run(&mut channel)
+                .await;
         }
+        Ok::<(), sunset::Error>(())
+    };
 
-        async fn key_input(&self, key: KeyReport) {
-            if key.state != KeyState::Pressed {
-                return;
-            }
-            use crate::keyboard::Modifiers;
-            match (key.modifiers, key.key) {
-                (Modifiers::CTRL, Key::Char('c' | 'C' | 'd' | 'D')) | (_, Key::Escape) => {
-                    self.channel.send(None).await;
-                }
-                _ => {
-                    if let Some(command) = self.input.lock().await.apply_key(key) {
-                        write!(SCREEN.get().lock().await, "\r\n").ok();
-                        self.channel.send(Some(command)).await;
+    let runner = ssh_client.run(&mut read, &mut write);
+    let mut progress = ProgressHolder::new();
+    let ssh_ticker = async {
+        loop {
+            match ssh_client.progress(&mut progress).await {
+                Ok(event) => match event {
+                    CliEvent::Hostkey(k) => {
+                        log::info!("host key {:?}", k.hostkey());
+                        k.accept().expect("accept hostkey");
+                    }
+                    CliEvent::Banner(b) => {
+                        if let Ok(b) = b.banner() {
+                            log::info!("banner: {b}");
+                        }
                     }
+                    CliEvent::Username(req) => {
+                        match CONFIG.get().lock().await.fetch("ssh_user").await {
+                            Ok(Some(pw)) => req.username(&pw),
+                            _ => {
+                                let user = prompt_for_input("login: ", PromptKind::Text).await;
+                                match user {
+                                    Some(user) => req.username(&user),
+                                    None => {
+                                        print!("Cancelled\r\n");
+                                        return Ok(());
+                                    }
+                                }
+                            }
+                        }
+                        .expect("set user");
+                    }
+                    CliEvent::Password(req) => {
+                        match CONFIG.get().lock().await.fetch("ssh_pw").await {
+                            Ok(Some(pw)) => req.password(&pw),
+                            _ => {
+                                let user =
+                                    prompt_for_input("password: ", PromptKind::Password).await;
+                                match user {
+                                    Some(user) => req.password(&user),
+                                    None => req.skip(),
+                                }
+                            }
+                        }
+                        .expect("set pw");
+                    }
+                    CliEvent::Pubkey(req) => {
+                        req.skip().expect("skip pubkey");
+                    }
+                    CliEvent::AgentSign(req) => {
+                        req.skip().expect("skip agentsign");
+                    }
+                    CliEvent::Authenticated => {
+                        log::info!("Authenticated!");
+                        session_authd_chan.sender().send(true).await;
+                    }
+                    CliEvent::SessionOpened(mut s) => {
+                        log::info!("session opened channel {}", s.channel());
+
+                        use heapless::{String, Vec};
+
+                        let mut term = String::<32>::new();
+                        let _ = term.push_str("xterm").unwrap();
+
+                        let pty = {
+                            let screen = session_screen.lock().await;
+                            let rows = screen.height;
+                            let cols = screen.width;
+
+                            sunset::Pty {
+                                term,
+                                rows: rows.into(),
+                                cols: cols.into(),
+                                width: SCREEN_WIDTH as u32,
+                                height: SCREEN_HEIGHT as u32,
+                                modes: Vec::new(),
+                            }
+                        };
+
+                        log::info!("requesting pty {pty:?}");
+                        if let Err(err) = s.pty(pty) {
+                            print!("requesting pty failed {err:?}\r\n");
+                            return Err(err);
+                        }
+                        log::info!("setting command");
+                        match &command {
+                            Some(cmd) => {
+                                if let Err(err) = s.cmd(&SessionCommand::Exec(cmd)) {
+                                    print!("command failed: {err:?}\r\n");
+                                    return Err(err);
+                                }
+                            }
+                            None => {
+                                if let Err(err) = s.shell() {
+                                    print!("shell failed: {err:?}\r\n");
+                                    return Err(err);
+                                }
+                            }
+                        }
+                        log::info!("SessionOpened completed");
+                    }
+                    CliEvent::SessionExit(status) => {
+                        print!("[ssh session exit with {status:?}]\r\n");
+                        break;
+                    }
+                    CliEvent::Defunct => {
+                        log::error!("ssh session terminated");
+                        break;
+                    }
+                },
+                Err(err) => {
+                    print!("ssh progress error: {err:?}\r\n");
+                    return Err(err);
                 }
             }
         }
-    }
 
-    let prompt_proc: ProcHandle = Arc::new(PromptProc {
-        prompt: prompt.to_string(),
-        input: Mutex::new(LineEditor::default()),
-        channel: channel.clone(),
-        kind,
-    });
+        Ok::<(), sunset::Error>(())
+    };
 
-    let prior = assign_proc(prompt_proc.clone()).await;
-    let response = channel.receive().await;
-    let _ = assign_proc_if(prior, |current| Arc::ptr_eq(current, &prompt_proc)).await;
-    response
+    SESSION_STATS.reset();
+    SSH_SESSIONS.fetch_add(1, Ordering::Relaxed);
+    let res = select(runner, select(ssh_ticker, spawn_session_future)).await;
+    SSH_SESSIONS.fetch_sub(1, Ordering::Relaxed);
+    log::info!("ssh result is {res:?}");
+    {
+        // Keep what the session showed, such as the output of a
+        // command, on the shell screen rather than discarding it
+        let mut screen = SCREEN.get().lock().await;
+        screen.append_screen(&session_screen.lock().await);
+        // and bring the prompt back in the default attributes and
+        // modes, however the session left things
+        screen.soft_reset();
+    }
+    assign_proc(prior_proc).await;
 }
 
 pub async fn ssh_command(args: &[&str]) {
-    if args.len() > 1 {
-        let hostname = args[1].to_string();
+    if let [_, "stats"] = args {
+        ssh_stats().await;
+        return;
+    }
 
-        let command: Option<String> = if args.len() > 2 {
-            Some(args[2..].join(" "))
-        } else {
+    // `-o` records the output of the session, as does ssh_transcript=on
+    let (record, args) = match args {
+        [_, "-o", rest @ ..] => (true, rest),
+        [_, rest @ ..] => (false, rest),
+        [] => (false, args),
+    };
+    let record = record
+        || CONFIG
+            .get()
+            .lock()
+            .await
+            .fetch("ssh_transcript")
+            .await
+            .ok()
+            .flatten()
+            .is_some_and(|value| value.as_str() == "on");
+
+    if let [hostname, command @ ..] = args {
+        let hostname = hostname.to_string();
+
+        let command: Option<String> = if command.is_empty() {
             None
+        } else {
+            // As with OpenSSH, the words are joined with spaces for the
+            // remote shell to split, so `ssh host 'ls -l /tmp'` works
+            Some(command.join(" "))
         };
         let spawn_result = {
             let spawner = Spawner::for_current_executor().await;
-            spawner.spawn(ssh_session_task(hostname, command))
+            spawner.spawn(ssh_session_task(hostname, command, record))
         };
         match spawn_result {
             Ok(_) => {}
@@ -542,11 +926,12 @@ pub async fn ssh_command(args: &[&str]) {
         return;
     }
 
-    print!("Usage: ssh [hostname] [command]\r\n");
+    print!("Usage: ssh [-o] [hostname] [command] | ssh stats\r\n");
 }
 
 struct SshProcess {
-    key_sender: Arc<Channel<CS, KeyReport, 4>>,
+    key_sender: Arc<KeyChannel>,
+    screen: SharedScreen,
 }
 
 #[async_trait::async_trait(?Send)]
@@ -554,13 +939,22 @@ impl Process for SshProcess {
     fn name(&self) -> &str {
         "ssh"
     }
+    fn screen(&self) -> SharedScreen {
+        self.screen.clone()
+    }
     async fn render(&self) {}
     fn un_prompt(&self, _screen: &mut Screen) {}
     async fn key_input(&self, key: KeyReport) {
         if key.state != KeyState::Pressed {
             return;
         }
-        self.key_sender.send(key).await;
+        self.key_sender.send(TerminalInput::Key(key)).await;
+    }
+
+    async fn paste(&self, text: &str) {
+        self.key_sender
+            .send(TerminalInput::Paste(text.to_string()))
+            .await;
     }
 }
 
@@ -593,50 +987,40 @@ async fn wifi_scanner(mut control: Control<'static>) {
 }
 */
 
-/// Taken from wezterm-input-types
-/// Map c to its Ctrl equivalent.
-/// In theory, this mapping is simply translating alpha characters
-/// to upper case and then masking them by 0x1f, but xterm inherits
-/// some built-in translation from legacy X11 so that are some
-/// aliased mappings and a couple that might be technically tied
-/// to US keyboard layout (particularly the punctuation characters
-/// produced in combination with SHIFT) that may not be 100%
-/// the right thing to do here for users with non-US layouts.
-fn ctrl_mapping(c: char) -> Option<char> {
-    Some(match c {
-        '@' | '`' | ' ' | '2' => '\x00',
-        'A' | 'a' => '\x01',
-        'B' | 'b' => '\x02',
-        'C' | 'c' => '\x03',
-        'D' | 'd' => '\x04',
-        'E' | 'e' => '\x05',
-        'F' | 'f' => '\x06',
-        'G' | 'g' => '\x07',
-        'H' | 'h' => '\x08',
-        'I' | 'i' => '\x09',
-        'J' | 'j' => '\x0a',
-        'K' | 'k' => '\x0b',
-        'L' | 'l' => '\x0c',
-        'M' | 'm' => '\x0d',
-        'N' | 'n' => '\x0e',
-        'O' | 'o' => '\x0f',
-        'P' | 'p' => '\x10',
-        'Q' | 'q' => '\x11',
-        'R' | 'r' => '\x12',
-        'S' | 's' => '\x13',
-        'T' | 't' => '\x14',
-        'U' | 'u' => '\x15',
-        'V' | 'v' => '\x16',
-        'W' | 'w' => '\x17',
-        'X' | 'x' => '\x18',
-        'Y' | 'y' => '\x19',
-        'Z' | 'z' => '\x1a',
-        '[' | '3' | '{' => '\x1b',
-        '\\' | '4' | '|' => '\x1c',
-        ']' | '5' | '}' => '\x1d',
-        '^' | '6' | '~' => '\x1e',
-        '_' | '7' | '/' => '\x1f',
-        '8' | '?' => '\x7f', // `Delete`
-        _ => return None,
-    })
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_literals_are_parsed() {
+        assert_eq!(
+            parse_ip_literal("10.0.0.5"),
+            Ok(Some(IpAddress::Ipv4("10.0.0.5".parse().unwrap())))
+        );
+        assert_eq!(
+            parse_ip_literal("255.255.255.255"),
+            Ok(Some(IpAddress::Ipv4("255.255.255.255".parse().unwrap())))
+        );
+    }
+
+    #[test]
+    fn names_are_left_for_dns() {
+        assert_eq!(parse_ip_literal("example.com"), Ok(None));
+        assert_eq!(parse_ip_literal("pi4"), Ok(None));
+        assert_eq!(parse_ip_literal("10.0.0.5.nip.io"), Ok(None));
+    }
+
+    #[test]
+    fn malformed_ip_literals_are_errors() {
+        for host in [
+            "999.1.1.1",
+            "10.0.0.5.",
+            "10.0.0",
+            "10..0.5",
+            "1.2.3.4.5",
+            "12345",
+        ] {
+            assert!(parse_ip_literal(host).is_err(), "{host} should be rejected");
+        }
+    }
 }
@@ -0,0 +1,34 @@
+use alloc::string::String;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_time::Duration;
+
+extern crate alloc;
+
+// Events that happen in the background, such as the SD card being
+// inserted or wifi dropping out, are reported here rather than being
+// printed, as printing would interrupt whatever is on the screen.
+// The painter shows each one in turn over the top line of the
+// screen for a few seconds, then puts the line back as it was.
+
+/// How long each notification is shown for
+pub const SHOW_FOR: Duration = Duration::from_secs(4);
+/// Notifications that arrive while this many are waiting are
+/// dropped, so that a burst of events can't back up for minutes
+const MAX_QUEUED: usize = 4;
+
+static NOTIFICATIONS: Channel<CriticalSectionRawMutex, String, MAX_QUEUED> = Channel::new();
+
+/// Queues `message` to be shown on the status line
+pub fn notify(message: impl Into<String>) {
+    let message = message.into();
+    log::info!("notify: {message}");
+    if NOTIFICATIONS.try_send(message).is_err() {
+        log::warn!("notify: too many notifications are waiting; dropped");
+    }
+}
+
+/// Takes the next notification to show, if any
+pub fn next_notification() -> Option<String> {
+    NOTIFICATIONS.try_receive().ok()
+}
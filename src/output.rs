@@ -0,0 +1,136 @@
+use crate::process::current_proc;
+use crate::screen::SCREEN;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::cell::RefCell;
+use core::fmt::Write;
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
+
+extern crate alloc;
+
+// The output of print! normally goes to the screen of the local
+// shell. It can be redirected to an OutputSink instead, so that a
+// command can be run and its output inspected rather than shown.
+// There is only one redirection at a time, and it applies to all
+// tasks, so anything printed in the background while it is active,
+// such as the first NTP sync, is caught along with the command's
+// output. Output that is written straight to the screen, rather
+// than through print!, is not redirected.
+
+/// Somewhere other than the screen that print! can write to
+pub trait OutputSink: Send {
+    fn write_output(&mut self, args: core::fmt::Arguments<'_>);
+}
+
+static REDIRECT: CriticalSectionMutex<RefCell<Option<Box<dyn OutputSink>>>> =
+    CriticalSectionMutex::new(RefCell::new(None));
+
+/// Sends the output of print! to `sink` until `end_redirect`
+/// is called. Fails, handing back `sink`, if the output is
+/// already being redirected.
+pub fn redirect(sink: Box<dyn OutputSink>) -> Result<(), Box<dyn OutputSink>> {
+    REDIRECT.lock(|current| {
+        let mut current = current.borrow_mut();
+        if current.is_some() {
+            return Err(sink);
+        }
+        *current = Some(sink);
+        Ok(())
+    })
+}
+
+/// Sends the output of print! back to the screen
+pub fn end_redirect() {
+    REDIRECT.lock(|current| current.borrow_mut().take());
+}
+
+/// Returns true if the output of print! is being redirected, in
+/// which case there is nobody to answer a prompt about it
+pub fn is_redirected() -> bool {
+    REDIRECT.lock(|current| current.borrow().is_some())
+}
+
+/// The implementation of print!
+pub async fn print_fmt(args: core::fmt::Arguments<'_>) {
+    let redirected = REDIRECT.lock(|current| match current.borrow_mut().as_mut() {
+        Some(sink) => {
+            sink.write_output(args);
+            true
+        }
+        None => false,
+    });
+    if redirected {
+        return;
+    }
+
+    let proc = current_proc();
+    {
+        let mut screen = SCREEN.get().lock().await;
+        // Erase whatever prompt may have been printed
+        proc.un_prompt(&mut screen);
+        // write our text
+        screen.write_fmt(args).ok();
+    }
+    // Have the painter render the prompt again
+    crate::process::request_render();
+}
+
+struct Captured {
+    text: String,
+    limit: usize,
+    truncated: bool,
+}
+
+/// Collects output into a string of up to `limit` bytes. Anything
+/// beyond that is dropped, and a marker is added to show that it
+/// was. Clones share the same string, so that one can be handed to
+/// `redirect` while another is kept to take the output from.
+#[derive(Clone)]
+pub struct CaptureSink {
+    captured: Arc<CriticalSectionMutex<RefCell<Captured>>>,
+}
+
+impl CaptureSink {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            captured: Arc::new(CriticalSectionMutex::new(RefCell::new(Captured {
+                text: String::new(),
+                limit,
+                truncated: false,
+            }))),
+        }
+    }
+
+    /// Takes the output collected so far
+    pub fn take(&self) -> String {
+        self.captured.lock(|captured| {
+            let mut captured = captured.borrow_mut();
+            let mut text = core::mem::take(&mut captured.text);
+            if core::mem::take(&mut captured.truncated) {
+                text.push_str("[output truncated]\r\n");
+            }
+            text
+        })
+    }
+}
+
+impl Write for Captured {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            if self.text.len() + c.len_utf8() > self.limit {
+                self.truncated = true;
+                break;
+            }
+            self.text.push(c);
+        }
+        Ok(())
+    }
+}
+
+impl OutputSink for CaptureSink {
+    fn write_output(&mut self, args: core::fmt::Arguments<'_>) {
+        self.captured
+            .lock(|captured| captured.borrow_mut().write_fmt(args).ok());
+    }
+}
@@ -0,0 +1,93 @@
+use crate::keyboard::Key;
+use crate::process::wait_for_key;
+use crate::screen::SCREEN;
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_time::Instant;
+
+/// The total time, in milliseconds, that has been spent waiting
+/// for the user at --More--, so that timing a command can leave
+/// it out. It wraps, so only the difference between two readings
+/// is meaningful.
+static WAITED_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Returns the running total of time spent at --More--
+pub fn waited_ms() -> u32 {
+    WAITED_MS.load(Ordering::Relaxed)
+}
+
+/// Returns the number of cells that `line` takes on the screen,
+/// leaving out any CSI sequences, such as those setting colors
+fn visible_len(line: &str) -> usize {
+    let mut len = 0;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // Skip the `[`, parameters and final byte
+            if chars.next() == Some('[') {
+                for c in chars.by_ref() {
+                    if ('\u{40}'..='\u{7e}').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+        len += 1;
+    }
+    len
+}
+
+/// Feeds lines of output to the screen one screenful at a time,
+/// prompting the user to continue once the screen fills up,
+/// much like `more`.
+pub struct Pager {
+    rows: usize,
+    max_rows: usize,
+    width: usize,
+    quit: bool,
+}
+
+impl Pager {
+    pub async fn new() -> Self {
+        let screen = SCREEN.get().lock().await;
+        Self {
+            rows: 0,
+            // Leave a row spare for the --More-- prompt
+            max_rows: (screen.height as usize).saturating_sub(1).max(1),
+            width: (screen.width as usize).max(1),
+            quit: false,
+        }
+    }
+
+    /// Prints line, waiting for the user if the screen is full.
+    /// Returns false if the user asked to stop, in which case
+    /// the caller should stop producing output.
+    pub async fn line(&mut self, line: &str) -> bool {
+        if self.quit {
+            return false;
+        }
+
+        let rows = visible_len(line).div_ceil(self.width).max(1);
+        if self.rows + rows > self.max_rows {
+            let start = Instant::now();
+            // When the output is captured there's nobody to ask
+            if let Some(key) = wait_for_key("--More--").await {
+                WAITED_MS.fetch_add(start.elapsed().as_millis() as u32, Ordering::Relaxed);
+                match key.key {
+                    Key::Char('q') | Key::Escape => {
+                        self.quit = true;
+                        return false;
+                    }
+                    // Advance by a single line
+                    Key::Enter => self.rows = self.max_rows.saturating_sub(rows),
+                    // Advance by a page
+                    _ => self.rows = 0,
+                }
+            }
+        }
+
+        print!("{line}\r\n");
+        self.rows += rows;
+        true
+    }
+}
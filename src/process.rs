@@ -1,6 +1,7 @@
 use crate::SCREEN;
+use crate::config::CONFIG;
 use crate::keyboard::{Key, KeyReport, KeyState};
-use crate::screen::Screen;
+use crate::screen::{Screen, SharedScreen};
 use crate::storage::ls_command;
 use alloc::boxed::Box;
 use alloc::string::String;
@@ -8,9 +9,14 @@ use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::cell::RefCell;
 use core::fmt::Write;
+use core::sync::atomic::{AtomicU8, Ordering};
+use embassy_futures::select::{Either, select};
 use embassy_sync::blocking_mutex::CriticalSectionMutex;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Channel, TrySendError};
 use embassy_sync::lazy_lock::LazyLock;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
 extern crate alloc;
 
 pub type Mutex<T> = embassy_sync::mutex::Mutex<CriticalSectionRawMutex, T>;
@@ -20,6 +26,26 @@ pub static SHELL: LazyLock<ProcHandle> = LazyLock::new(LocalShell::new);
 static CURRENT: LazyLock<CriticalSectionMutex<RefCell<Arc<dyn Process + Send + Sync>>>> =
     LazyLock::new(|| CriticalSectionMutex::new(RefCell::new(Arc::clone(SHELL.get()))));
 
+/// Commands entered into the shell are queued here and run by
+/// the shell_task, rather than from within key_input, so that
+/// the keyboard task remains free to deliver input to commands
+/// that want to wait for a keypress.
+static COMMANDS: Channel<CriticalSectionRawMutex, String, 1> = Channel::new();
+
+/// Signalled when the prompt of the current process needs to be
+/// rendered. The screen_painter takes care of this, so that code
+/// handling input or producing output doesn't have to wait for
+/// the screen in order to redraw the prompt.
+static RENDER: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn request_render() {
+    RENDER.signal(());
+}
+
+pub async fn wait_for_render_request() {
+    RENDER.wait().await
+}
+
 pub async fn assign_proc_if(
     proc: ProcHandle,
     func: impl FnOnce(&ProcHandle) -> bool,
@@ -51,6 +77,179 @@ pub fn current_proc() -> ProcHandle {
     CURRENT.get().lock(|cell| Arc::clone(&*cell.borrow()))
 }
 
+/// Temporarily takes over keyboard input from the current process,
+/// so that a command can wait for the user to press a key.
+/// The prompt is displayed while the capture is active.
+/// `end` must be called to hand input back to the prior process.
+pub struct KeyCapture {
+    proc: ProcHandle,
+    keys: Arc<Channel<CriticalSectionRawMutex, KeyReport, 4>>,
+    prior: ProcHandle,
+}
+
+struct KeyCaptureProc {
+    prompt: String,
+    keys: Arc<Channel<CriticalSectionRawMutex, KeyReport, 4>>,
+}
+
+#[async_trait::async_trait(?Send)]
+impl Process for KeyCaptureProc {
+    fn name(&self) -> &str {
+        "key"
+    }
+
+    async fn render(&self) {
+        let mut screen = SCREEN.get().lock().await;
+        write!(screen, "\r{}\u{1b}[K", self.prompt).ok();
+    }
+
+    fn un_prompt(&self, screen: &mut Screen) {
+        write!(screen, "\r\u{1b}[K").ok();
+    }
+
+    async fn key_input(&self, key: KeyReport) {
+        if key.state == KeyState::Pressed {
+            self.keys.try_send(key).ok();
+        }
+    }
+}
+
+impl KeyCapture {
+    /// Shows `prompt` and takes the keys that follow, until `end`.
+    /// Returns None if the output is redirected, as then nobody is
+    /// looking at the prompt.
+    pub async fn begin(prompt: &str) -> Option<Self> {
+        if crate::output::is_redirected() {
+            return None;
+        }
+        let keys = Arc::new(Channel::new());
+        let proc: ProcHandle = Arc::new(KeyCaptureProc {
+            prompt: prompt.into(),
+            keys: keys.clone(),
+        });
+        let prior = assign_proc(proc.clone()).await;
+        Some(Self { proc, keys, prior })
+    }
+
+    pub async fn next_key(&self) -> KeyReport {
+        self.keys.receive().await
+    }
+
+    pub async fn end(self) {
+        let proc = self.proc;
+        assign_proc_if(self.prior, |current| Arc::ptr_eq(current, &proc)).await;
+    }
+}
+
+/// Displays prompt and waits for the next keypress, returning
+/// None straight away if the output is redirected
+pub async fn wait_for_key(prompt: &str) -> Option<KeyReport> {
+    let capture = KeyCapture::begin(prompt).await?;
+    let key = capture.next_key().await;
+    capture.end().await;
+    Some(key)
+}
+
+#[derive(Copy, Clone)]
+pub enum PromptKind {
+    Text,
+    Password,
+}
+
+/// Displays prompt and reads a line of input, returning None
+/// if the user cancels with Escape, CTRL-C or CTRL-D, or if the
+/// output is redirected, so that nobody can see the prompt
+pub async fn prompt_for_input(prompt: &str, kind: PromptKind) -> Option<String> {
+    if crate::output::is_redirected() {
+        return None;
+    }
+    let channel = Arc::new(Channel::<CriticalSectionRawMutex, Option<String>, 1>::new());
+
+    struct PromptProc {
+        prompt: String,
+        input: Mutex<LineEditor>,
+        channel: Arc<Channel<CriticalSectionRawMutex, Option<String>, 1>>,
+        kind: PromptKind,
+        area: PromptArea,
+    }
+
+    impl PromptProc {
+        fn render_input(&self, screen: &mut Screen, input: &str, cursor_x: usize) {
+            match self.kind {
+                PromptKind::Text => {
+                    let prompt = alloc::format!("{} ", self.prompt);
+                    self.area.render(screen, &prompt, input, cursor_x);
+                }
+                PromptKind::Password => {
+                    self.area.render(screen, &self.prompt, "", 0);
+                }
+            }
+        }
+    }
+
+    impl Drop for PromptProc {
+        fn drop(&mut self) {
+            self.channel.try_send(None).ok();
+        }
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl Process for PromptProc {
+        fn name(&self) -> &str {
+            "prompt"
+        }
+        async fn render(&self) {
+            let mut screen = SCREEN.get().lock().await;
+            let input = self.input.lock().await;
+            self.render_input(&mut screen, input.input(), input.cursor_x());
+        }
+
+        fn un_prompt(&self, screen: &mut Screen) {
+            self.area.erase(screen);
+        }
+
+        async fn key_input(&self, key: KeyReport) {
+            if key.state != KeyState::Pressed {
+                return;
+            }
+            use crate::keyboard::Modifiers;
+            match (key.modifiers, key.key) {
+                (Modifiers::CTRL, Key::Char('c' | 'C' | 'd' | 'D')) | (_, Key::Escape) => {
+                    self.channel.send(None).await;
+                }
+                _ => {
+                    let command = self.input.lock().await.apply_key(key);
+                    if let Some(command) = command {
+                        let mut screen = SCREEN.get().lock().await;
+                        self.render_input(&mut screen, &command, command.chars().count());
+                        self.area.forget();
+                        write!(screen, "\r\n").ok();
+                        drop(screen);
+                        self.channel.send(Some(command)).await;
+                    }
+                }
+            }
+        }
+
+        async fn paste(&self, text: &str) {
+            self.input.lock().await.insert_str(text);
+        }
+    }
+
+    let prompt_proc: ProcHandle = Arc::new(PromptProc {
+        prompt: prompt.into(),
+        input: Mutex::new(LineEditor::default()),
+        channel: channel.clone(),
+        kind,
+        area: PromptArea::default(),
+    });
+
+    let prior = assign_proc(prompt_proc.clone()).await;
+    let response = channel.receive().await;
+    let _ = assign_proc_if(prior, |current| Arc::ptr_eq(current, &prompt_proc)).await;
+    response
+}
+
 #[async_trait::async_trait(?Send)]
 pub trait Process {
     async fn key_input(&self, key: KeyReport);
@@ -58,8 +257,73 @@ pub trait Process {
 
     fn name(&self) -> &str;
 
+    /// Receives text pasted from the computer attached to the
+    /// serial console. By default it is typed in a key at a time.
+    async fn paste(&self, text: &str) {
+        for c in text.chars() {
+            self.key_input(KeyReport::typed(c)).await;
+        }
+    }
+
     // Erase whatever prompt may have been printed
     fn un_prompt(&self, _screen: &mut Screen) {}
+
+    /// The screen that is displayed while this process is current.
+    /// Most processes share the screen of the local shell.
+    fn screen(&self) -> SharedScreen {
+        SCREEN.get().clone()
+    }
+}
+
+/// Tracks the rows occupied by an interactive prompt, so that it
+/// can be erased and redrawn in place even when the input is
+/// long enough to wrap onto several rows.
+#[derive(Default)]
+pub struct PromptArea {
+    /// How many rows above the cursor the prompt begins
+    rows_above_cursor: AtomicU8,
+}
+
+impl PromptArea {
+    /// Moves to the start of the prompt and erases it, along
+    /// with any rows that it wrapped onto
+    pub fn erase(&self, screen: &mut Screen) {
+        let rows = self.rows_above_cursor.swap(0, Ordering::Relaxed);
+        if rows > 0 {
+            write!(screen, "\u{1b}[{rows}A").ok();
+        }
+        write!(screen, "\r\u{1b}[J").ok();
+    }
+
+    /// Leaves the prompt on screen as ordinary output, so that
+    /// a subsequent erase won't remove it
+    pub fn forget(&self) {
+        self.rows_above_cursor.store(0, Ordering::Relaxed);
+    }
+
+    /// Replaces any previously rendered prompt with `prompt`
+    /// followed by `input`, leaving the cursor at `cursor_x`
+    /// characters into `input`
+    pub fn render(&self, screen: &mut Screen, prompt: &str, input: &str, cursor_x: usize) {
+        self.erase(screen);
+        write!(screen, "{prompt}{input}").ok();
+
+        // The model wraps as soon as the last column is filled,
+        // so a prompt that exactly fills a row ends on the next
+        let width = (screen.width as usize).max(1);
+        let prompt_len = prompt.chars().count();
+        let end = prompt_len + input.chars().count();
+        let cursor = prompt_len + cursor_x;
+        let (end_row, cursor_row) = (end / width, cursor / width);
+        if end_row > cursor_row {
+            write!(screen, "\u{1b}[{}A", end_row - cursor_row).ok();
+        }
+        if cursor != end {
+            write!(screen, "\u{1b}[{}G", cursor % width + 1).ok();
+        }
+        self.rows_above_cursor
+            .store(cursor_row.min(u8::MAX as usize) as u8, Ordering::Relaxed);
+    }
 }
 
 #[derive(Default)]
@@ -75,17 +339,26 @@ impl LineEditor {
         }
         match key.key {
             Key::Char(c) => {
-                self.command.insert(self.cursor_x, c);
+                self.command.insert(self.byte_offset(self.cursor_x), c);
                 self.cursor_x += 1;
             }
             Key::BackSpace => {
-                if self.cursor_x == self.command.len() {
-                    self.command.pop();
-                    self.cursor_x = self.cursor_x.saturating_sub(1);
-                } else {
-                    self.command.remove(self.cursor_x);
+                // Removes the character before the cursor
+                if self.cursor_x > 0 {
+                    self.cursor_x -= 1;
+                    self.command.remove(self.byte_offset(self.cursor_x));
                 }
             }
+            Key::Del => {
+                // Removes the character under the cursor
+                if self.cursor_x < self.len() {
+                    self.command.remove(self.byte_offset(self.cursor_x));
+                }
+            }
+            Key::Left => self.cursor_x = self.cursor_x.saturating_sub(1),
+            Key::Right => self.cursor_x = (self.cursor_x + 1).min(self.len()),
+            Key::Home => self.cursor_x = 0,
+            Key::End => self.cursor_x = self.len(),
             Key::Enter => {
                 let cmd = self.command.clone();
                 self.command.clear();
@@ -99,42 +372,347 @@ impl LineEditor {
         None
     }
 
+    /// Inserts `text` at the cursor in one go, as for a paste.
+    /// The input is a single line, so line breaks and tabs become
+    /// spaces, except that a final line break is dropped rather than
+    /// running the command; other control characters are dropped.
+    pub fn insert_str(&mut self, text: &str) {
+        let text = text.trim_end_matches(['\r', '\n']);
+        let at = self.byte_offset(self.cursor_x);
+        let mut inserted = String::new();
+        for c in text.chars() {
+            match c {
+                '\r' | '\n' | '\t' => inserted.push(' '),
+                c if c.is_control() => {}
+                c => inserted.push(c),
+            }
+        }
+        self.command.insert_str(at, &inserted);
+        self.cursor_x += inserted.chars().count();
+    }
+
+    /// Returns the length of the input in characters
+    fn len(&self) -> usize {
+        self.command.chars().count()
+    }
+
+    /// Returns the offset in bytes of the character at `char_idx`.
+    /// cursor_x counts characters, which may take several bytes.
+    fn byte_offset(&self, char_idx: usize) -> usize {
+        self.command
+            .char_indices()
+            .nth(char_idx)
+            .map_or(self.command.len(), |(idx, _)| idx)
+    }
+
+    /// Replaces the text being edited with `text`, leaving the
+    /// cursor at the end of it, as when recalling history
+    pub fn set_input(&mut self, text: String) {
+        self.cursor_x = text.chars().count();
+        self.command = text;
+    }
+
     pub fn input(&self) -> &str {
         &self.command
     }
+
+    pub fn cursor_x(&self) -> usize {
+        self.cursor_x
+    }
 }
 
 pub struct LocalShell {
     command: Mutex<LineEditor>,
+    recall: Mutex<crate::history::Recall>,
+    prompt: PromptArea,
 }
 
 impl LocalShell {
     pub fn new() -> ProcHandle {
         Arc::new(Self {
             command: Mutex::new(LineEditor::default()),
+            recall: Mutex::new(crate::history::Recall::default()),
+            prompt: PromptArea::default(),
         })
     }
 
-    async fn dispatch_command(&self, command: &str) {
-        let argv: Vec<&str> = command.split(' ').collect();
-        let arg0 = argv[0];
-        match arg0 {
-            "bat" => crate::keyboard::battery_command(&argv).await,
-            "bl" => crate::keyboard::backlight_command(&argv).await,
-            "bootsel" => crate::keyboard::reboot_bootsel(),
-            "cls" => crate::screen::cls_command(&argv).await,
-            "config" => crate::config::config_command(&argv).await,
-            "free" => crate::heap::free_command(&argv).await,
-            "ls" => ls_command(&argv).await,
-            "reboot" => crate::keyboard::reboot(),
-            "ssh" => crate::net::ssh_command(&argv).await,
-            "time" => crate::time::time_command(&argv).await,
-            _ => {
-                let mut screen = SCREEN.get().lock().await;
-                write!(screen, "Unknown command: {arg0}\r\n").ok();
+    /// Replaces the command being edited with an older or newer
+    /// one from the history, for Up and Down
+    async fn recall_history(&self, older: bool) {
+        let mut history = crate::history::HISTORY.get().lock().await;
+        history.load().await;
+        let mut recall = self.recall.lock().await;
+        let mut cmd = self.command.lock().await;
+        let text = if older {
+            recall.older(&history, cmd.input())
+        } else {
+            recall.newer(&history)
+        };
+        if let Some(text) = text {
+            cmd.set_input(text);
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn shell_task() {
+    loop {
+        let command = COMMANDS.receive().await;
+        crate::history::HISTORY
+            .get()
+            .lock()
+            .await
+            .push(&command)
+            .await;
+        let elapsed = timed_dispatch(&command).await;
+
+        let slow_ms = CONFIG
+            .get()
+            .lock()
+            .await
+            .fetch_parsed::<u64>("slow_cmd_ms")
+            .await;
+        if slow_ms.is_some_and(|slow_ms| elapsed.as_millis() >= slow_ms) {
+            log::warn!("slow command: `{command}` took {}ms", elapsed.as_millis());
+        }
+    }
+}
+
+/// Runs `command` and returns how long it took, leaving out any
+/// time spent waiting for the user at a --More-- prompt
+pub async fn timed_dispatch(command: &str) -> Duration {
+    let waited_before = crate::pager::waited_ms();
+    let start = Instant::now();
+    // dispatch_command can lead back here, via `time`, so it
+    // must be boxed
+    Box::pin(dispatch_command(command)).await;
+    let elapsed = start.elapsed();
+    let waited = crate::pager::waited_ms().wrapping_sub(waited_before);
+    elapsed
+        .checked_sub(Duration::from_millis(waited as u64))
+        .unwrap_or(Duration::from_ticks(0))
+}
+
+/// Splits a command line into its arguments. Runs of whitespace
+/// separate arguments, and leading and trailing whitespace is
+/// ignored. An argument may be quoted with `'` or `"` to include
+/// whitespace, which is kept as it is, or to be empty; the quotes
+/// are removed. Within `"` quotes, and outside of quotes, `\`
+/// takes the next character literally, so that `\"` is a quote.
+/// Within `'` quotes, everything up to the closing `'` is literal.
+fn tokenize(command: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut chars = command.chars();
+    // The argument being collected, if one has been started
+    let mut arg: Option<String> = None;
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => args.extend(arg.take()),
+            '\'' => {
+                let arg = arg.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err("missing closing `'`".into()),
+                    }
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) => arg.push(c),
+                            None => return Err("missing closing `\"`".into()),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err("missing closing `\"`".into()),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                Some(c) => arg.get_or_insert_default().push(c),
+                None => return Err("nothing follows the final `\\`".into()),
+            },
+            c => arg.get_or_insert_default().push(c),
+        }
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+/// Joins `args` back into a command line that tokenize will split
+/// into the same arguments, quoting those that need it
+pub fn quote_args(args: &[&str]) -> String {
+    let mut command = String::new();
+    for (idx, arg) in args.iter().enumerate() {
+        if idx > 0 {
+            command.push(' ');
+        }
+        let needs_quotes = arg.is_empty()
+            || arg
+                .chars()
+                .any(|c| c.is_whitespace() || matches!(c, '\'' | '"' | '\\'));
+        if !needs_quotes {
+            command.push_str(arg);
+            continue;
+        }
+        command.push('"');
+        for c in arg.chars() {
+            if matches!(c, '"' | '\\') {
+                command.push('\\');
             }
+            command.push(c);
+        }
+        command.push('"');
+    }
+    command
+}
+
+/// How a command finished. Commands report their own errors in
+/// their output, so this only says whether a command was run.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    Ran,
+    UnknownCommand,
+    /// The output was already being captured, so nothing was run
+    CaptureBusy,
+}
+
+/// The most output that dispatch_command_captured keeps
+const MAX_CAPTURED_OUTPUT: usize = 4096;
+
+/// Runs `command` with the output of print! captured, rather than
+/// shown, and returns it. Output beyond MAX_CAPTURED_OUTPUT is
+/// dropped, and marked as truncated. Captures can't be nested.
+pub async fn dispatch_command_captured(command: &str) -> (ExitStatus, String) {
+    let sink = crate::output::CaptureSink::new(MAX_CAPTURED_OUTPUT);
+    if crate::output::redirect(Box::new(sink.clone())).is_err() {
+        return (ExitStatus::CaptureBusy, String::new());
+    }
+    // dispatch_command can lead back here, so it must be boxed
+    let status = Box::pin(dispatch_command(command)).await;
+    crate::output::end_redirect();
+    (status, sink.take())
+}
+
+pub async fn dispatch_command(command: &str) -> ExitStatus {
+    let argv = match tokenize(command) {
+        Ok(argv) => argv,
+        Err(err) => {
+            print!("{err}\r\n");
+            return ExitStatus::Ran;
+        }
+    };
+    let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+    let Some(&arg0) = argv.first() else {
+        // A blank line; the prompt will be rendered again
+        return ExitStatus::Ran;
+    };
+    match arg0 {
+        "ansi" | "sgrtest" => crate::screen::sgrtest_command(&argv).await,
+        "attrib" => crate::storage::attrib_command(&argv).await,
+        "bat" => crate::keyboard::battery_command(&argv).await,
+        "beep" => crate::beep::beep_command(&argv).await,
+        "bl" => crate::keyboard::backlight_command(&argv).await,
+        "bootsel" => crate::keyboard::reboot_bootsel(),
+        "cat" => crate::storage::cat_command(&argv).await,
+        "cls" => crate::screen::cls_command(&argv).await,
+        "color" | "colors" => crate::screen::color_command(&argv).await,
+        "config" => crate::config::config_command(&argv).await,
+        "cp" => crate::storage::cp_command(&argv).await,
+        "display" => crate::display::display_command(&argv).await,
+        "du" => crate::storage::du_command(&argv).await,
+        "factory-reset" => crate::config::factory_reset_command(&argv).await,
+        "find" => crate::storage::find_command(&argv).await,
+        "free" => crate::heap::free_command(&argv).await,
+        "history" => crate::history::history_command(&argv).await,
+        "kbd" => crate::keyboard::kbd_command(&argv).await,
+        "ls" => ls_command(&argv).await,
+        "mcureg" => crate::keyboard::mcureg_command(&argv).await,
+        #[cfg(feature = "net")]
+        "net" => crate::net::net_command(&argv).await,
+        "panic" => panic_command(&argv),
+        "peek" => crate::memory::peek_command(&argv).await,
+        "poke" => crate::memory::poke_command(&argv).await,
+        "psram" => crate::psram::psram_command(&argv).await,
+        "ramdisk" => crate::ramdisk::ramdisk_command(&argv).await,
+        "reboot" => crate::keyboard::reboot(),
+        "rm" => crate::storage::rm_command(&argv).await,
+        "screen" => crate::display::screen_command(&argv).await,
+        "selftest" => crate::selftest::selftest_command(&argv).await,
+        #[cfg(feature = "net")]
+        "ssh" => crate::net::ssh_command(&argv).await,
+        "sysinfo" => crate::sysinfo::sysinfo_command(&argv).await,
+        "time" => crate::time::time_command(&argv).await,
+        "tree" => crate::storage::tree_command(&argv).await,
+        "watch" => watch_command(&argv).await,
+        _ => {
+            print!("Unknown command: {arg0}\r\n");
+            return ExitStatus::UnknownCommand;
+        }
+    }
+    ExitStatus::Ran
+}
+
+/// `panic test` deliberately panics, so that the way a panic
+/// is persisted and shown at the next boot can be checked.
+/// This is left out of the README, as it isn't for everyday use.
+fn panic_command(args: &[&str]) {
+    match args {
+        [_, "test"] => panic!(
+            "panic test requested from the shell. This message is long enough \
+             that it has to be wrapped across several rows of the screen."
+        ),
+        _ => log::warn!("panic: only `panic test` is supported"),
+    }
+}
+
+/// Runs a command every few seconds, clearing the screen before
+/// each run, until a key is pressed
+pub async fn watch_command(args: &[&str]) {
+    let [_, interval, command @ ..] = args else {
+        print!("Usage: watch SECONDS COMMAND [ARGS...]\r\n");
+        return;
+    };
+    let Some(&name) = command.first() else {
+        print!("Usage: watch SECONDS COMMAND [ARGS...]\r\n");
+        return;
+    };
+    let secs = match interval.parse::<u64>() {
+        Ok(secs) if secs > 0 => secs,
+        _ => {
+            print!("interval `{interval}` must be a whole number of seconds\r\n");
+            return;
+        }
+    };
+    if name == "watch" {
+        print!("watch cannot run watch\r\n");
+        return;
+    }
+    let command = quote_args(command);
+
+    let Some(capture) = KeyCapture::begin("Press any key to stop watching").await else {
+        print!("watch needs the screen, so its output can't be redirected\r\n");
+        return;
+    };
+    loop {
+        // Capture the output first, so that the screen is only
+        // cleared once there is something to replace it with
+        let (_, output) = dispatch_command_captured(&command).await;
+        SCREEN.get().lock().await.clear();
+        print!("Every {secs}s: {command}\r\n\r\n{output}");
+
+        // A key pressed while the command was running is
+        // already queued, and stops us straight away
+        let wait = Timer::after(Duration::from_secs(secs));
+        if let Either::First(_) = select(capture.next_key(), wait).await {
+            break;
         }
     }
+    capture.end().await;
 }
 
 #[async_trait::async_trait(?Send)]
@@ -145,17 +723,28 @@ impl Process for LocalShell {
     async fn render(&self) {
         let mut screen = SCREEN.get().lock().await;
         let command = self.command.lock().await;
-        write!(screen, "\r$ {}\u{1b}[K", command.command.as_str()).ok();
+        self.prompt
+            .render(&mut screen, "$ ", command.input(), command.cursor_x());
     }
 
     fn un_prompt(&self, screen: &mut Screen) {
-        write!(screen, "\r\u{1b}[K").ok();
+        self.prompt.erase(screen);
     }
 
     async fn key_input(&self, key: KeyReport) {
         if key.state != KeyState::Pressed {
             return;
         }
+        if matches!(key.key, Key::Up | Key::Down) {
+            self.recall_history(key.key == Key::Up).await;
+            return;
+        }
+        // While a command is already waiting for the running one to
+        // finish, Enter leaves the line being edited as it is, so
+        // that it can be entered once there is room for it
+        if key.key == Key::Enter && COMMANDS.is_full() {
+            return;
+        }
 
         // Take care with the scoping, as the write! call
         // below can call through to un_prompt and render
@@ -166,8 +755,131 @@ impl Process for LocalShell {
         };
 
         if let Some(command) = command {
-            write!(SCREEN.get().lock().await, "\r\n").ok();
-            self.dispatch_command(&command).await;
+            self.recall.lock().await.reset();
+            let mut screen = SCREEN.get().lock().await;
+            // Leave the command on screen, with the cursor after
+            // the end of it, rather than part way through
+            self.prompt
+                .render(&mut screen, "$ ", &command, command.chars().count());
+            self.prompt.forget();
+            write!(screen, "\r\n").ok();
+            // The serial console may have taken the room since the
+            // check above; if so, keep the line to be entered again
+            if let Err(TrySendError::Full(command)) = COMMANDS.try_send(command) {
+                self.command.lock().await.set_input(command);
+            }
+        }
+    }
+
+    async fn paste(&self, text: &str) {
+        self.command.lock().await.insert_str(text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn press(editor: &mut LineEditor, key: Key) -> Option<String> {
+        editor.apply_key(KeyReport {
+            state: KeyState::Pressed,
+            key,
+            ..Default::default()
+        })
+    }
+
+    fn type_str(editor: &mut LineEditor, text: &str) {
+        for c in text.chars() {
+            press(editor, Key::Char(c));
+        }
+    }
+
+    #[test]
+    fn editing_in_the_middle_of_multi_byte_characters() {
+        let mut editor = LineEditor::default();
+        type_str(&mut editor, "héé");
+        press(&mut editor, Key::Left);
+        press(&mut editor, Key::BackSpace);
+        assert_eq!((editor.input(), editor.cursor_x()), ("hé", 1));
+        type_str(&mut editor, "ö");
+        assert_eq!((editor.input(), editor.cursor_x()), ("höé", 2));
+        press(&mut editor, Key::Del);
+        assert_eq!((editor.input(), editor.cursor_x()), ("hö", 2));
+    }
+
+    #[test]
+    fn cursor_stays_within_the_input() {
+        let mut editor = LineEditor::default();
+        press(&mut editor, Key::BackSpace);
+        press(&mut editor, Key::Left);
+        assert_eq!((editor.input(), editor.cursor_x()), ("", 0));
+
+        type_str(&mut editor, "ab");
+        press(&mut editor, Key::Right);
+        press(&mut editor, Key::Del);
+        assert_eq!((editor.input(), editor.cursor_x()), ("ab", 2));
+
+        press(&mut editor, Key::Home);
+        press(&mut editor, Key::BackSpace);
+        assert_eq!((editor.input(), editor.cursor_x()), ("ab", 0));
+        press(&mut editor, Key::End);
+        assert_eq!(editor.cursor_x(), 2);
+        assert_eq!(press(&mut editor, Key::Enter).as_deref(), Some("ab"));
+        assert_eq!((editor.input(), editor.cursor_x()), ("", 0));
+    }
+
+    #[test]
+    fn tokenize_splits_on_whitespace() {
+        assert_eq!(tokenize("ls  -l\t/tmp ").unwrap(), ["ls", "-l", "/tmp"]);
+        assert!(tokenize("").unwrap().is_empty());
+        assert!(tokenize(" \t ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn tokenize_quoting() {
+        assert_eq!(
+            tokenize("echo 'a b' \"c d\"").unwrap(),
+            ["echo", "a b", "c d"]
+        );
+        // Quotes join onto the text next to them
+        assert_eq!(tokenize("a'b c'd").unwrap(), ["ab cd"]);
+        // Single quotes keep backslashes and double quotes as they are
+        assert_eq!(tokenize("'a\\b\"'").unwrap(), ["a\\b\""]);
+        assert_eq!(tokenize("\"it's\"").unwrap(), ["it's"]);
+    }
+
+    #[test]
+    fn tokenize_escapes() {
+        assert_eq!(tokenize("a\\ b").unwrap(), ["a b"]);
+        assert_eq!(tokenize("\\'x").unwrap(), ["'x"]);
+        assert_eq!(tokenize("\"a\\\"b\\\\\"").unwrap(), ["a\"b\\"]);
+    }
+
+    #[test]
+    fn tokenize_empty_quotes_are_an_argument() {
+        assert_eq!(tokenize("set key ''").unwrap(), ["set", "key", ""]);
+        assert_eq!(tokenize("\"\" x").unwrap(), ["", "x"]);
+    }
+
+    #[test]
+    fn tokenize_unterminated() {
+        assert!(tokenize("echo 'a b").is_err());
+        assert!(tokenize("echo \"a b").is_err());
+        assert!(tokenize("echo \"a\\").is_err());
+        assert!(tokenize("echo a\\").is_err());
+    }
+
+    #[test]
+    fn quote_args_round_trips() {
+        for args in [
+            &["ls", "-l"][..],
+            &["echo", "a b", ""],
+            &["x", "it's", "say \"hi\"", "back\\slash"],
+            &["tab\there", "é ö"],
+        ] {
+            let quoted = quote_args(args);
+            assert_eq!(tokenize(&quoted).unwrap(), args, "{quoted}");
         }
+        assert_eq!(quote_args(&["ls", "-l"]), "ls -l");
     }
 }
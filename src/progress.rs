@@ -0,0 +1,109 @@
+use crate::byte_size;
+use crate::keyboard::KeyReport;
+use crate::process::{
+    ProcHandle, Process, PromptArea, assign_proc, assign_proc_if, request_render,
+};
+use crate::screen::{SCREEN, Screen};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_futures::yield_now;
+use embassy_time::{Duration, Instant};
+
+extern crate alloc;
+
+/// The display is updated at most this often, so that a fast
+/// transfer isn't slowed down by redrawing the progress line
+const MIN_UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Shows a line at the bottom of the screen with the progress
+/// of a long running transfer. Output that is printed during the
+/// transfer appears above it. `end` must be called to remove it,
+/// even if the transfer fails.
+pub struct Progress {
+    proc: Arc<ProgressProc>,
+    prior: ProcHandle,
+    last_update: Instant,
+}
+
+struct ProgressProc {
+    label: String,
+    done: AtomicU32,
+    /// 0 if unknown
+    total: AtomicU32,
+    area: PromptArea,
+}
+
+#[async_trait::async_trait(?Send)]
+impl Process for ProgressProc {
+    fn name(&self) -> &str {
+        "progress"
+    }
+
+    async fn render(&self) {
+        let done = self.done.load(Ordering::Relaxed);
+        let total = self.total.load(Ordering::Relaxed);
+        let status = if total > 0 {
+            alloc::format!(
+                "{}: {} of {} ({}%)",
+                self.label,
+                byte_size(done),
+                byte_size(total),
+                done as u64 * 100 / total as u64
+            )
+        } else {
+            alloc::format!("{}: {}", self.label, byte_size(done))
+        };
+        let mut screen = SCREEN.get().lock().await;
+        self.area.render(&mut screen, "", &status, status.len());
+    }
+
+    fn un_prompt(&self, screen: &mut Screen) {
+        self.area.erase(screen);
+    }
+
+    // Input is ignored until the transfer completes
+    async fn key_input(&self, _key: KeyReport) {}
+}
+
+impl Progress {
+    pub async fn begin(label: &str) -> Self {
+        let proc = Arc::new(ProgressProc {
+            label: label.into(),
+            done: AtomicU32::new(0),
+            total: AtomicU32::new(0),
+            area: PromptArea::default(),
+        });
+        let prior = assign_proc(proc.clone()).await;
+        Self {
+            proc,
+            prior,
+            last_update: Instant::now(),
+        }
+    }
+
+    /// Sets the size of the transfer, once it is known, so
+    /// that a percentage can be shown
+    pub fn set_total(&self, total: u32) {
+        self.proc.total.store(total, Ordering::Relaxed);
+    }
+
+    /// Records that `done` bytes have been transferred so far
+    pub async fn update(&mut self, done: u32) {
+        self.proc.done.store(done, Ordering::Relaxed);
+        if self.last_update.elapsed() >= MIN_UPDATE_INTERVAL {
+            self.last_update = Instant::now();
+            request_render();
+            // Some transfers don't otherwise wait for anything,
+            // which would leave the painter no chance to run
+            yield_now().await;
+        }
+    }
+
+    /// Removes the progress line and restores the prior process
+    pub async fn end(self) {
+        let proc: ProcHandle = self.proc;
+        assign_proc_if(self.prior, |current| Arc::ptr_eq(current, &proc)).await;
+    }
+}
@@ -1,4 +1,5 @@
 use crate::Irqs;
+use embassy_futures::join::join;
 use embassy_futures::yield_now;
 use embassy_rp::PeripheralRef;
 use embassy_rp::clocks::clk_peri_freq;
@@ -10,6 +11,8 @@ use embassy_time::{Duration, Instant, Timer};
 use fixed::FixedU32;
 use fixed::types::extra::U8;
 
+extern crate alloc;
+
 // The physical connections in the picocalc schematic are:
 // LABEL     PICO      ESP-PSRAM64H
 // RAM_CS  - PIN_20    CE                    (pulled up to 3v3 via 10kOhm)
@@ -38,56 +41,171 @@ const PSRAM_CMD_NOOP: u8 = 0xFF;
 #[allow(unused)]
 const PSRAM_KNOWN_GOOD_DIE_PASS: u8 = 0x5d;
 
+/// The command byte plus a 24-bit address
+const CMD_ADDR_BYTES: usize = 4;
+/// Fast reads wait 8 cycles after the address before data is returned
+const FAST_READ_WAIT_BYTES: usize = 1;
+
+/// Computes the bit count that the PIO program expects at the start
+/// of each command from the number of bytes to write or read.
+/// The program takes each count as a u8, so larger counts are
+/// rejected; at compile time when used in a const context.
+const fn bit_count(bytes: usize) -> u8 {
+    assert!(
+        bytes * 8 <= u8::MAX as usize,
+        "too many bits for the PIO program"
+    );
+    (bytes * 8) as u8
+}
+
+/// The largest read that can be described to the PIO program,
+/// which takes the number of bits to read as a u8
+pub const MAX_READ_CHUNK: usize = 31;
+/// The read chunk size that has historically been reliable
+const DEFAULT_READ_CHUNK: usize = 4;
+/// The largest write that can be described to the PIO program:
+/// 32 bits of command and address, plus the data, as a u8
+pub const MAX_WRITE_CHUNK: usize = 27;
+/// The write chunk size that has historically been reliable
+const DEFAULT_WRITE_CHUNK: usize = 24;
+
+const _: u8 = bit_count(MAX_READ_CHUNK);
+const _: u8 = bit_count(CMD_ADDR_BYTES + MAX_WRITE_CHUNK);
+const _: u8 = bit_count(CMD_ADDR_BYTES + FAST_READ_WAIT_BYTES);
+
+#[derive(Clone, Copy, Debug)]
+pub enum ChunkKind {
+    Read,
+    Write,
+}
+
 pub struct PsRam {
     sm: embassy_rp::pio::StateMachine<'static, PIO1, 0>,
     tx_ch: PeripheralRef<'static, DMA_CH1>,
     rx_ch: PeripheralRef<'static, DMA_CH2>,
     pub size: u32,
+    /// The number of bytes requested by each read command
+    max_read_chunk: usize,
+    /// The number of bytes sent by each write command
+    max_write_chunk: usize,
 }
 
 impl PsRam {
+    /// Sends `cmd` to the PIO program and reads `out.len()` bytes of
+    /// response. `cmd` begins with the number of bits to write and the
+    /// number of bits to read, followed by the bytes to write.
     pub async fn send_command(&mut self, cmd: &[u8], out: &mut [u8]) {
+        // A mismatch would leave the state machine waiting for data
+        // that never comes, or send the remainder as a new command
+        debug_assert!(
+            cmd.len() >= 2 && cmd[0] == bit_count(cmd.len() - 2) && cmd[1] == bit_count(out.len()),
+            "psram command bit counts don't match its length"
+        );
         if out.is_empty() {
             self.sm
                 .tx()
                 .dma_push(self.tx_ch.reborrow(), cmd, false)
                 .await;
         } else {
+            // Start draining the RX FIFO while the command is still
+            // being sent, rather than afterwards. The FIFO only holds
+            // 4 entries, and the state machine would otherwise stall
+            // mid-read once it fills up, with CS still asserted.
             let (rx, tx) = self.sm.rx_tx();
-            tx.dma_push(self.tx_ch.reborrow(), cmd, false).await;
-            rx.dma_pull(self.rx_ch.reborrow(), out, false).await;
+            join(
+                tx.dma_push(self.tx_ch.reborrow(), cmd, false),
+                rx.dma_pull(self.rx_ch.reborrow(), out, false),
+            )
+            .await;
+        }
+    }
+
+    pub fn max_chunk(&self, kind: ChunkKind) -> usize {
+        match kind {
+            ChunkKind::Read => self.max_read_chunk,
+            ChunkKind::Write => self.max_write_chunk,
         }
     }
 
+    pub fn set_max_chunk(&mut self, kind: ChunkKind, chunk: usize) {
+        match kind {
+            ChunkKind::Read => self.max_read_chunk = chunk.clamp(1, MAX_READ_CHUNK),
+            ChunkKind::Write => self.max_write_chunk = chunk.clamp(1, MAX_WRITE_CHUNK),
+        }
+    }
+
+    /// Finds the largest chunk size of the given kind for which every
+    /// chunk size up to and including it reads back the data that was
+    /// written. `scratch` is an address with `len` bytes that may be
+    /// overwritten. The chunk size is left set to the result.
+    /// The other kind of chunk is left at its current size, so the
+    /// write size should be calibrated with a conservative read size.
+    pub async fn calibrate(&mut self, kind: ChunkKind, scratch: u32, len: usize) -> usize {
+        const CYCLES: u8 = 4;
+        let mut expect = alloc::vec![0u8; len];
+        let mut got = alloc::vec![0u8; len];
+        let mut reliable = 0;
+        let limit = match kind {
+            ChunkKind::Read => MAX_READ_CHUNK,
+            ChunkKind::Write => MAX_WRITE_CHUNK,
+        };
+
+        'chunk: for chunk in 1..=limit {
+            self.set_max_chunk(kind, chunk);
+            for cycle in 0..CYCLES {
+                // Vary the pattern between cycles so that stale
+                // data from a prior cycle can't pass for good data
+                for (idx, byte) in expect.iter_mut().enumerate() {
+                    *byte = (idx as u8).wrapping_mul(31) ^ (idx >> 8) as u8 ^ (cycle * 0x55);
+                }
+                self.write(scratch, &expect).await;
+                self.read(scratch, &mut got).await;
+                if got != expect {
+                    let idx = got.iter().zip(&expect).position(|(a, b)| a != b);
+                    log::warn!("psram: {kind:?} chunk size {chunk} failed at offset {idx:?}");
+                    break 'chunk;
+                }
+                yield_now().await;
+            }
+            reliable = chunk;
+        }
+
+        self.set_max_chunk(kind, reliable);
+        reliable
+    }
+
     pub async fn write(&mut self, mut addr: u32, mut data: &[u8]) {
-        // I haven't seen this work reliably over 24 bytes
-        const MAX_CHUNK: usize = 24;
+        // I haven't seen this work reliably over 24 bytes, which is
+        // the default; the chunk size is adjustable so that longer
+        // writes can be checked with `psram calibrate`
         while data.len() > 0 {
-            let to_write = data.len().min(MAX_CHUNK);
+            let to_write = data.len().min(self.max_write_chunk);
             //log::info!("writing {to_write} @ {addr}");
 
             #[rustfmt::skip]
             let mut to_send = [
-                32 + (to_write as u8 * 8), // write address + data
-                0,                         // read 0 bits
+                bit_count(CMD_ADDR_BYTES + to_write), // write address + data
+                bit_count(0),                         // read 0 bits
                 PSRAM_CMD_WRITE,
                 ((addr >> 16) & 0xff) as u8,
                 ((addr >> 8) & 0xff) as u8,
                 (addr & 0xff) as u8,
-                // This sequence must be MAX_CHUNK in length
+                // This sequence must be MAX_WRITE_CHUNK in length
                 0, 0, 0, 0,
                 0, 0, 0, 0,
                 0, 0, 0, 0,
                 0, 0, 0, 0,
                 0, 0, 0, 0,
                 0, 0, 0, 0,
+                0, 0, 0,
             ];
 
             for (src, dst) in data.iter().zip(to_send.iter_mut().skip(6)) {
                 *dst = *src;
             }
 
-            self.send_command(&to_send[0..6 + to_write], &mut []).await;
+            self.send_command(&to_send[0..2 + CMD_ADDR_BYTES + to_write], &mut [])
+                .await;
             addr += to_write as u32;
             data = &data[to_write..];
         }
@@ -98,8 +216,8 @@ impl PsRam {
         #[rustfmt::skip]
         self.send_command(
             &[
-                32,    // write 32 bits
-                3 * 8, // read 8 bytes = 64 bits
+                bit_count(CMD_ADDR_BYTES), // write 32 bits
+                bit_count(3),              // read 3 bytes = 24 bits
                 PSRAM_CMD_READ_ID,
                 // don't care: 24-bit "address"
                 0, 0, 0,
@@ -111,16 +229,16 @@ impl PsRam {
     }
 
     pub async fn read(&mut self, mut addr: u32, mut out: &mut [u8]) {
-        // Cannot get reliable reads above 4 bytes at a time.
-        // out[4] will always have a bit error
-        const MAX_CHUNK: usize = 4;
+        // Reads larger than 4 bytes at a time used to always have
+        // a bit error in out[4]; the chunk size is adjustable so
+        // that this can be checked with `psram calibrate`
         while out.len() > 0 {
-            let to_read = out.len().min(MAX_CHUNK);
+            let to_read = out.len().min(self.max_read_chunk);
             //log::info!("reading {to_read} @ {addr}");
             self.send_command(
                 &[
-                    40,                // write 40 bits
-                    to_read as u8 * 8, // read n bytes
+                    bit_count(CMD_ADDR_BYTES + FAST_READ_WAIT_BYTES), // write 40 bits
+                    bit_count(to_read),                               // read n bytes
                     PSRAM_CMD_FAST_READ,
                     ((addr >> 16) & 0xff) as u8,
                     ((addr >> 8) & 0xff) as u8,
@@ -140,8 +258,8 @@ impl PsRam {
         //log::info!("write8 addr {addr} <- {data:x}");
         self.send_command(
             &[
-                40, // write 40 bits
-                0,  // read 0 bits
+                bit_count(CMD_ADDR_BYTES + 1), // write 40 bits
+                bit_count(0),                  // read 0 bits
                 PSRAM_CMD_WRITE,
                 ((addr >> 16) & 0xff) as u8,
                 ((addr >> 8) & 0xff) as u8,
@@ -158,8 +276,8 @@ impl PsRam {
         let mut buf = [0u8];
         self.send_command(
             &[
-                40, // write 40 bits
-                8,  // read 8 bits
+                bit_count(CMD_ADDR_BYTES + FAST_READ_WAIT_BYTES), // write 40 bits
+                bit_count(1),                                     // read 8 bits
                 PSRAM_CMD_FAST_READ,
                 ((addr >> 16) & 0xff) as u8,
                 ((addr >> 8) & 0xff) as u8,
@@ -173,6 +291,85 @@ impl PsRam {
     }
 }
 
+/// Returns true if PSRAM was added to the heap during boot.
+/// Without it the heap is only the small region of internal
+/// RAM, so features that need a lot of memory check this to
+/// choose smaller buffers, or to not try at all.
+/// The RAM disk uses the PIO-attached PSRAM instead, and checks
+/// for that itself.
+pub fn psram_available() -> bool {
+    crate::sysinfo::PSRAM_QMI_SIZE.load(core::sync::atomic::Ordering::Relaxed) > 0
+}
+
+pub async fn psram_command(args: &[&str]) {
+    let mut ramdisk = crate::ramdisk::RAMDISK.get().lock().await;
+    let Some(disk) = ramdisk.as_mut() else {
+        print!("PSRAM is not available\r\n");
+        return;
+    };
+
+    let chunk_kind = |name: &str| match name {
+        "max_read_chunk" => Some((ChunkKind::Read, MAX_READ_CHUNK)),
+        "max_write_chunk" => Some((ChunkKind::Write, MAX_WRITE_CHUNK)),
+        _ => None,
+    };
+
+    match args {
+        [_, name] if chunk_kind(name).is_some() => {
+            let (kind, _) = chunk_kind(name).unwrap();
+            print!("{name} is {}\r\n", disk.psram().max_chunk(kind));
+        }
+        [_, name, n] if chunk_kind(name).is_some() => {
+            let (kind, limit) = chunk_kind(name).unwrap();
+            match n.parse::<usize>() {
+                Ok(n) if (1..=limit).contains(&n) => disk.psram().set_max_chunk(kind, n),
+                _ => print!("{name} must be between 1 and {limit}\r\n"),
+            }
+        }
+        [_, "calibrate"] => {
+            // Test in a block that the RAM disk isn't using, so
+            // that the contents of the RAM disk are preserved
+            let Some((scratch, len)) = disk.reserve_block() else {
+                print!("ram: is full; free up some space before calibrating\r\n");
+                return;
+            };
+            print!("Calibrating...\r\n");
+            let psram = disk.psram();
+            // Check writes using single byte reads, which are the
+            // least likely to fail, then check reads using the
+            // write size that was found
+            let read_chunk = psram.max_chunk(ChunkKind::Read);
+            psram.set_max_chunk(ChunkKind::Read, 1);
+            let write_chunk = psram
+                .calibrate(ChunkKind::Write, scratch, len as usize)
+                .await;
+            psram.set_max_chunk(ChunkKind::Read, read_chunk);
+            let read_chunk = if write_chunk == 0 {
+                0
+            } else {
+                psram
+                    .calibrate(ChunkKind::Read, scratch, len as usize)
+                    .await
+            };
+            disk.release_block(scratch);
+
+            for (name, chunk) in [("write", write_chunk), ("read", read_chunk)] {
+                if chunk == 0 {
+                    print!("No reliable {name} chunk size was found!\r\n");
+                } else {
+                    print!("max_{name}_chunk is now {chunk}\r\n");
+                }
+            }
+            print!("Use `config set psram_write_chunk N` to keep the write size\r\n");
+        }
+        _ => {
+            print!("Usage: psram max_read_chunk [N]\r\n");
+            print!("       psram max_write_chunk [N]\r\n");
+            print!("       psram calibrate\r\n");
+        }
+    }
+}
+
 pub async fn init_psram(
     pio_1: PIO1,
     sclk: PIN_21,
@@ -269,12 +466,18 @@ done:
         tx_ch: dma_ch1,
         rx_ch: dma_ch2,
         size: 0,
+        max_read_chunk: DEFAULT_READ_CHUNK,
+        max_write_chunk: DEFAULT_WRITE_CHUNK,
     };
 
     // Issue a reset command
-    psram.send_command(&[8, 0, PSRAM_CMD_RSTEN], &mut []).await;
+    psram
+        .send_command(&[bit_count(1), bit_count(0), PSRAM_CMD_RSTEN], &mut [])
+        .await;
     Timer::after(Duration::from_micros(50)).await;
-    psram.send_command(&[8, 0, PSRAM_CMD_RST], &mut []).await;
+    psram
+        .send_command(&[bit_count(1), bit_count(0), PSRAM_CMD_RST], &mut [])
+        .await;
     Timer::after(Duration::from_micros(100)).await;
 
     log::info!("Verifying 1 byte write and read...");
@@ -347,6 +550,19 @@ done:
         log::info!("psram is {size} Mbits, {} bytes", psram.size);
     }
 
+    // Applied after the tests above, which are known to pass with
+    // the default, so that they still serve as a baseline check
+    if let Some(chunk) = crate::config::CONFIG
+        .get()
+        .lock()
+        .await
+        .fetch_parsed::<usize>("psram_write_chunk")
+        .await
+    {
+        psram.set_max_chunk(ChunkKind::Write, chunk);
+        log::info!("psram write chunk is {}", psram.max_write_chunk);
+    }
+
     psram
 }
 
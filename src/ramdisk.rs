@@ -0,0 +1,261 @@
+use crate::byte_size;
+use crate::psram::PsRam;
+use alloc::string::String;
+use alloc::vec::Vec;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::lazy_lock::LazyLock;
+use embassy_sync::mutex::Mutex;
+
+extern crate alloc;
+
+// This module implements a simple RAM disk in the PIO-attached
+// PSRAM, for use as scratch space that works even without an
+// SD card. There is no persistence: the contents are lost
+// when the device is reset or loses power.
+//
+// The disk is a flat directory of files. Each file is a name,
+// a length and a list of extents in PSRAM. Space is handed out
+// in BLOCK_SIZE units, with adjacent blocks merged into a single
+// extent, so a file written in one go is typically contiguous.
+// The directory itself lives on the heap.
+
+/// The unit in which PSRAM space is allocated to files
+const BLOCK_SIZE: u32 = 4096;
+
+pub static RAMDISK: LazyLock<Mutex<CriticalSectionRawMutex, Option<RamDisk>>> =
+    LazyLock::new(|| Mutex::new(None));
+
+#[derive(Clone, Copy)]
+struct Extent {
+    /// Address in PSRAM
+    start: u32,
+    /// Allocated bytes, a multiple of BLOCK_SIZE
+    len: u32,
+}
+
+pub struct RamFile {
+    pub name: String,
+    pub len: u32,
+    extents: Vec<Extent>,
+}
+
+impl RamFile {
+    fn capacity(&self) -> u32 {
+        self.extents.iter().map(|ext| ext.len).sum()
+    }
+
+    /// Maps an offset within the file to a PSRAM address and the
+    /// number of contiguous bytes that are available from there.
+    fn locate(&self, mut offset: u32) -> Option<(u32, u32)> {
+        for ext in &self.extents {
+            if offset < ext.len {
+                return Some((ext.start + offset, ext.len - offset));
+            }
+            offset -= ext.len;
+        }
+        None
+    }
+}
+
+pub struct RamDisk {
+    psram: PsRam,
+    /// One entry per block; true if the block is in use
+    used: Vec<bool>,
+    files: Vec<RamFile>,
+}
+
+impl RamDisk {
+    pub fn new(psram: PsRam) -> Self {
+        let num_blocks = (psram.size / BLOCK_SIZE) as usize;
+        Self {
+            psram,
+            used: alloc::vec![false; num_blocks],
+            files: Vec::new(),
+        }
+    }
+
+    pub fn total_bytes(&self) -> u32 {
+        self.used.len() as u32 * BLOCK_SIZE
+    }
+
+    pub fn used_bytes(&self) -> u32 {
+        self.used.iter().filter(|used| **used).count() as u32 * BLOCK_SIZE
+    }
+
+    pub fn psram(&mut self) -> &mut PsRam {
+        &mut self.psram
+    }
+
+    /// Temporarily takes a free block out of circulation, returning
+    /// its address and size, so that it can be used for testing
+    /// the PSRAM without disturbing any files
+    pub fn reserve_block(&mut self) -> Option<(u32, u32)> {
+        let block = self.used.iter().position(|used| !used)?;
+        self.used[block] = true;
+        Some((block as u32 * BLOCK_SIZE, BLOCK_SIZE))
+    }
+
+    /// Returns a block obtained from reserve_block
+    pub fn release_block(&mut self, addr: u32) {
+        self.used[(addr / BLOCK_SIZE) as usize] = false;
+    }
+
+    pub fn files(&self) -> &[RamFile] {
+        &self.files
+    }
+
+    fn find(&self, name: &str) -> Option<usize> {
+        self.files
+            .iter()
+            .position(|file| file.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn len(&self, name: &str) -> Option<u32> {
+        self.find(name).map(|idx| self.files[idx].len)
+    }
+
+    fn free_extents(&mut self, extents: &[Extent]) {
+        for ext in extents {
+            let first = (ext.start / BLOCK_SIZE) as usize;
+            let count = (ext.len / BLOCK_SIZE) as usize;
+            for used in &mut self.used[first..first + count] {
+                *used = false;
+            }
+        }
+    }
+
+    /// Creates an empty file named `name`, replacing any prior
+    /// file with the same name.
+    pub fn create(&mut self, name: &str) -> Result<(), String> {
+        if name.is_empty() || name.contains('/') {
+            return Err(alloc::format!("`{name}` is not a valid ram: file name"));
+        }
+        if let Some(idx) = self.find(name) {
+            let file = self.files.remove(idx);
+            self.free_extents(&file.extents);
+        }
+        self.files.push(RamFile {
+            name: name.into(),
+            len: 0,
+            extents: Vec::new(),
+        });
+        self.files.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<(), String> {
+        let idx = self
+            .find(name)
+            .ok_or_else(|| alloc::format!("ram:{name} not found"))?;
+        let file = self.files.remove(idx);
+        self.free_extents(&file.extents);
+        Ok(())
+    }
+
+    /// Grows the allocation for the file at `idx` by a block
+    fn grow(&mut self, idx: usize) -> Result<(), String> {
+        let Some(block) = self.used.iter().position(|used| !used) else {
+            return Err(alloc::format!(
+                "ram: is full ({} in use)",
+                byte_size(self.used_bytes())
+            ));
+        };
+        self.used[block] = true;
+        let start = block as u32 * BLOCK_SIZE;
+
+        let extents = &mut self.files[idx].extents;
+        match extents.last_mut() {
+            Some(last) if last.start + last.len == start => last.len += BLOCK_SIZE,
+            _ => extents.push(Extent {
+                start,
+                len: BLOCK_SIZE,
+            }),
+        }
+        Ok(())
+    }
+
+    /// Appends `data` to the end of the file
+    pub async fn append(&mut self, name: &str, mut data: &[u8]) -> Result<(), String> {
+        let idx = self
+            .find(name)
+            .ok_or_else(|| alloc::format!("ram:{name} not found"))?;
+
+        while !data.is_empty() {
+            if self.files[idx].len == self.files[idx].capacity() {
+                self.grow(idx)?;
+            }
+            let file = &self.files[idx];
+            let (addr, avail) = file.locate(file.len).expect("capacity was checked");
+            let to_write = data.len().min(avail as usize);
+            self.psram.write(addr, &data[..to_write]).await;
+            self.files[idx].len += to_write as u32;
+            data = &data[to_write..];
+        }
+        Ok(())
+    }
+
+    /// Reads from the file starting at `offset` into `buf`,
+    /// returning the number of bytes read. 0 indicates the
+    /// end of the file.
+    pub async fn read_at(
+        &mut self,
+        name: &str,
+        mut offset: u32,
+        buf: &mut [u8],
+    ) -> Result<usize, String> {
+        let idx = self
+            .find(name)
+            .ok_or_else(|| alloc::format!("ram:{name} not found"))?;
+
+        let mut total = 0;
+        while total < buf.len() && offset < self.files[idx].len {
+            let file = &self.files[idx];
+            let Some((addr, avail)) = file.locate(offset) else {
+                break;
+            };
+            let to_read = (buf.len() - total)
+                .min(avail as usize)
+                .min((file.len - offset) as usize);
+            self.psram
+                .read(addr, &mut buf[total..total + to_read])
+                .await;
+            total += to_read;
+            offset += to_read as u32;
+        }
+        Ok(total)
+    }
+}
+
+/// Hands the PIO PSRAM over to the RAM disk
+pub async fn init_ramdisk(psram: PsRam) {
+    if psram.size == 0 {
+        return;
+    }
+    let disk = RamDisk::new(psram);
+    log::info!("ram: disk has {} blocks", disk.used.len());
+    RAMDISK.get().lock().await.replace(disk);
+}
+
+pub async fn ramdisk_command(args: &[&str]) {
+    match args {
+        [_, "status"] => {
+            let ramdisk = RAMDISK.get().lock().await;
+            let Some(disk) = ramdisk.as_ref() else {
+                print!("ram: is not available (no PSRAM was found)\r\n");
+                return;
+            };
+            let total = disk.total_bytes();
+            let used = disk.used_bytes();
+            print!(
+                "ram: {} files, {} used, {} free of {}\r\n",
+                disk.files().len(),
+                byte_size(used),
+                byte_size(total - used),
+                byte_size(total),
+            );
+        }
+        _ => {
+            print!("Usage: ramdisk status\r\n");
+        }
+    }
+}
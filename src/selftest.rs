@@ -0,0 +1,202 @@
+use crate::config::{CONFIG, StrValue};
+use crate::display::{TestPattern, confirm_pattern};
+use alloc::string::String;
+use embassy_futures::yield_now;
+use rand_core::RngCore;
+
+extern crate alloc;
+
+// `selftest` checks each of the subsystems in turn and reports
+// PASS or FAIL for each, ending with an overall result, so that
+// newly assembled units can be checked in one go.
+
+/// The scratch key that is written to the config
+const CONFIG_KEY: &str = "selftest";
+/// The scratch file that is written to the SD card
+const SD_PATH: &str = "SELFTEST.TMP";
+/// How many bytes of PSRAM are checked
+const PSRAM_TEST_BYTES: usize = 256;
+/// How many words are taken from the TRNG to check it
+const TRNG_WORDS: usize = 64;
+/// NTP is re-synchronized at least this often once it is
+/// running, so an older sync means that it has stopped
+#[cfg(feature = "net")]
+const NTP_MAX_AGE: embassy_time::Duration = embassy_time::Duration::from_secs(60 * 60);
+
+type TestResult = Result<String, String>;
+
+pub async fn selftest_command(_args: &[&str]) {
+    let mut failed = 0;
+    let mut passed = 0;
+
+    macro_rules! check {
+        ($name:expr, $test:expr) => {{
+            let result: TestResult = $test.await;
+            match result {
+                Ok(detail) => {
+                    passed += 1;
+                    print!("PASS {:<8} {detail}\r\n", $name);
+                }
+                Err(reason) => {
+                    failed += 1;
+                    print!("\u{1b}[1mFAIL {:<8} {reason}\u{1b}[0m\r\n", $name);
+                }
+            }
+            // Each test runs to completion before the next starts;
+            // let the other tasks, including the watchdog, catch up
+            yield_now().await;
+        }};
+    }
+
+    check!("config", test_config());
+    check!("psram", test_psram());
+    check!("sd", test_sd());
+    check!("kbd", test_keyboard());
+    check!("battery", test_battery());
+    check!("trng", test_trng());
+    // These can't pass without networking, so
+    // are left out rather than always failing
+    #[cfg(feature = "net")]
+    check!("wifi", test_wifi());
+    #[cfg(feature = "net")]
+    check!("ntp", test_ntp());
+    check!("display", test_display());
+
+    if failed == 0 {
+        print!("selftest: PASS, all {passed} tests passed\r\n");
+    } else {
+        print!(
+            "\u{1b}[1mselftest: FAIL, {failed} of {} tests failed\u{1b}[0m\r\n",
+            passed + failed
+        );
+    }
+}
+
+async fn test_config() -> TestResult {
+    let value = alloc::format!("{:08x}", crate::rng::WezTermRng.next_u32());
+    let stored = StrValue::with_str(&value).map_err(|()| String::from("value too long"))?;
+
+    let mut config = CONFIG.get().lock().await;
+    config
+        .store(CONFIG_KEY, stored)
+        .await
+        .map_err(|err| alloc::format!("store: {err:?}"))?;
+    let fetched = config.fetch(CONFIG_KEY).await;
+    let removed = config.remove(CONFIG_KEY).await;
+
+    match fetched {
+        Ok(Some(fetched)) if fetched.as_str() == value => {}
+        Ok(fetched) => return Err(alloc::format!("read back {fetched:?}, not {value}")),
+        Err(err) => return Err(alloc::format!("fetch: {err:?}")),
+    }
+    removed.map_err(|err| alloc::format!("remove: {err:?}"))?;
+    Ok(String::from("scratch key written, read back and removed"))
+}
+
+async fn test_psram() -> TestResult {
+    let mut ramdisk = crate::ramdisk::RAMDISK.get().lock().await;
+    let disk = ramdisk.as_mut().ok_or("PSRAM is not available")?;
+    // Test in a block that the RAM disk isn't using, so
+    // that the contents of the RAM disk are preserved
+    let (scratch, _len) = disk
+        .reserve_block()
+        .ok_or("the RAM disk is full, so there is nowhere to test")?;
+
+    let mut errors = 0;
+    for pattern in [0x55u8, 0xaa, 0x00] {
+        let mut data = [0u8; PSRAM_TEST_BYTES];
+        for (idx, byte) in data.iter_mut().enumerate() {
+            // 0x00 stands in for an incrementing pattern
+            *byte = if pattern == 0 { idx as u8 } else { pattern };
+        }
+        let mut readback = [0u8; PSRAM_TEST_BYTES];
+        disk.psram().write(scratch, &data).await;
+        disk.psram().read(scratch, &mut readback).await;
+        errors += data.iter().zip(&readback).filter(|(a, b)| a != b).count();
+    }
+    disk.release_block(scratch);
+
+    if errors > 0 {
+        return Err(alloc::format!("{errors} bytes read back incorrectly"));
+    }
+    Ok(alloc::format!(
+        "{PSRAM_TEST_BYTES} bytes written and read back with 3 patterns"
+    ))
+}
+
+async fn test_sd() -> TestResult {
+    let data = b"picocalc selftest\r\n";
+    crate::storage::write_sd_file(SD_PATH, data, false).await?;
+    let readback = crate::storage::read_sd_file(SD_PATH).await;
+    crate::storage::remove_file(SD_PATH, true).await?;
+    if readback? != data {
+        return Err(String::from("file read back incorrectly"));
+    }
+    Ok(alloc::format!("{SD_PATH} written, read back and removed"))
+}
+
+async fn test_keyboard() -> TestResult {
+    let version = crate::keyboard::read_version_register()
+        .await
+        .map_err(|err| alloc::format!("{err:?}"))?;
+    Ok(alloc::format!("firmware version {version:#04x}"))
+}
+
+async fn test_battery() -> TestResult {
+    let value = crate::keyboard::read_battery_register()
+        .await
+        .map_err(|err| alloc::format!("{err:?}"))?;
+    let percent = value & 0x7f;
+    if percent > 100 {
+        return Err(alloc::format!("implausible level {percent}%"));
+    }
+    Ok(alloc::format!("{percent}%"))
+}
+
+/// Checks that the TRNG isn't stuck, and that its output is
+/// roughly balanced between ones and zeros
+async fn test_trng() -> TestResult {
+    let mut rng = crate::rng::WezTermRng;
+    let mut words = [0u32; TRNG_WORDS];
+    for word in &mut words {
+        *word = rng.next_u32();
+    }
+    if words.iter().all(|&word| word == words[0]) {
+        return Err(alloc::format!("stuck at {:#010x}", words[0]));
+    }
+    let ones: u32 = words.iter().map(|word| word.count_ones()).sum();
+    let total = TRNG_WORDS as u32 * 32;
+    let percent = ones * 100 / total;
+    if !(40..=60).contains(&percent) {
+        return Err(alloc::format!("{percent}% of bits are set"));
+    }
+    Ok(alloc::format!("{percent}% of {total} bits are set"))
+}
+
+#[cfg(feature = "net")]
+async fn test_wifi() -> TestResult {
+    match crate::net::ipv4_address().await {
+        Some(addr) => Ok(alloc::format!("associated, address {addr}")),
+        None => Err(String::from("not associated, or no address from DHCP")),
+    }
+}
+
+#[cfg(feature = "net")]
+async fn test_ntp() -> TestResult {
+    let Some(last_sync) = crate::time::last_sync().await else {
+        return Err(String::from("never synchronized"));
+    };
+    let age = last_sync.elapsed();
+    if age > NTP_MAX_AGE {
+        return Err(alloc::format!("last synchronized {}s ago", age.as_secs()));
+    }
+    Ok(alloc::format!("synchronized {}s ago", age.as_secs()))
+}
+
+async fn test_display() -> TestResult {
+    if confirm_pattern(TestPattern::ColorBar).await {
+        Ok(String::from("test pattern confirmed"))
+    } else {
+        Err(String::from("test pattern not confirmed"))
+    }
+}
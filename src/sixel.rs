@@ -0,0 +1,104 @@
+use crate::image::{Image, alloc_pixels, rgb565};
+use wezterm_escape_parser::{Sixel, SixelData};
+
+extern crate alloc;
+
+// Decodes sixel images into RGB565 pixels, so that they can be
+// painted onto the display alongside the text of the screen.
+
+/// Sixel data is sent in bands of this many pixel rows
+const BAND_HEIGHT: usize = 6;
+/// The number of color registers. Programs rarely use more.
+const NUM_REGISTERS: usize = 256;
+/// Color registers start out white, so that images which don't
+/// define their colors are still visible
+const DEFAULT_COLOR: u16 = 0xffff;
+
+/// Converts a sixel HSL color to RGB565. Sixel measures hue from
+/// blue rather than red, and saturation and lightness as percentages.
+fn hsl_to_rgb565(hue: u16, saturation: u8, lightness: u8) -> u16 {
+    let hue = (hue as f32 + 240.0) % 360.0;
+    let saturation = saturation.min(100) as f32 / 100.0;
+    let lightness = lightness.min(100) as f32 / 100.0;
+
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let h = hue / 60.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let scale = |v: f32| ((v + m) * 255.0) as u8;
+    rgb565(scale(r), scale(g), scale(b))
+}
+
+/// Decodes `sixel`, cropping it to `max_width` by `max_height`
+/// pixels. Returns None if there isn't memory for the pixels.
+pub fn decode(sixel: &Sixel, max_width: usize, max_height: usize) -> Option<Image> {
+    let (width, height) = sixel.dimensions();
+    let width = (width as usize).min(max_width);
+    let height = (height as usize).min(max_height);
+
+    let mut pixels = alloc_pixels(width, height)?;
+
+    let mut registers = [DEFAULT_COLOR; NUM_REGISTERS];
+    let mut current = 0;
+    let mut x = 0;
+    let mut band_y = 0;
+
+    let mut put = |x: usize, band_y: usize, bits: u8, color: u16| {
+        if x >= width {
+            return;
+        }
+        for bit in 0..BAND_HEIGHT {
+            let y = band_y + bit;
+            if bits & (1 << bit) != 0 && y < height {
+                pixels[y * width + x] = color;
+            }
+        }
+    };
+
+    for data in &sixel.data {
+        match data {
+            SixelData::Data(bits) => {
+                put(x, band_y, *bits, registers[current]);
+                x = x.saturating_add(1);
+            }
+            SixelData::Repeat { repeat_count, data } => {
+                let count = *repeat_count as usize;
+                for x in x..(x + count).min(width) {
+                    put(x, band_y, *data, registers[current]);
+                }
+                x = x.saturating_add(count);
+            }
+            SixelData::DefineColorMapRGB { color_number, rgb } => {
+                let (red, green, blue) = rgb.to_tuple_rgb8();
+                registers[*color_number as usize % NUM_REGISTERS] = rgb565(red, green, blue);
+            }
+            SixelData::DefineColorMapHSL {
+                color_number,
+                hue_angle,
+                saturation,
+                lightness,
+            } => {
+                registers[*color_number as usize % NUM_REGISTERS] =
+                    hsl_to_rgb565(*hue_angle, *saturation, *lightness);
+            }
+            SixelData::SelectColorMapEntry(number) => {
+                current = *number as usize % NUM_REGISTERS;
+            }
+            SixelData::CarriageReturn => x = 0,
+            SixelData::NewLine => {
+                x = 0;
+                band_y += BAND_HEIGHT;
+            }
+        }
+    }
+
+    Some(Image::new(width, height, pixels))
+}
@@ -1,18 +1,26 @@
 use crate::byte_size;
+use crate::progress::Progress;
+use crate::ramdisk::{RAMDISK, RamFile};
+use crate::screen::SCREEN;
 use crate::time::WezTermTimeSource;
+use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
+#[cfg(feature = "net")]
+use core::cell::RefCell;
 use core::fmt::Write;
 use embassy_embedded_hal::SetConfig;
 use embassy_executor::Spawner;
 use embassy_rp::gpio::{Input, Level, Output, Pull};
 use embassy_rp::peripherals::{PIN_16, PIN_17, PIN_18, PIN_19, PIN_22, SPI0};
+#[cfg(feature = "net")]
+use embassy_sync::blocking_mutex::CriticalSectionMutex;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::lazy_lock::LazyLock;
 use embassy_sync::mutex::Mutex;
 use embassy_time::{Delay, Duration, Timer};
 use embedded_hal_bus::spi::{ExclusiveDevice, NoDelay};
-use embedded_sdmmc::{DirEntry, SdCard, VolumeIdx, VolumeManager};
+use embedded_sdmmc::{DirEntry, Mode, SdCard, ShortFileName, Timestamp, VolumeIdx, VolumeManager};
 
 extern crate alloc;
 
@@ -59,6 +67,11 @@ impl Storage {
         }
     }
 
+    /// Returns the capacity of the card in bytes, if it is initialized
+    pub fn card_size(&mut self) -> Option<u64> {
+        self.vol_mgr()?.device(|card| card.num_bytes().ok())
+    }
+
     /// Returns the VolMgr only if the card is initialized
     pub fn vol_mgr(&mut self) -> Option<&mut VolMgr> {
         match self {
@@ -68,7 +81,9 @@ impl Storage {
     }
 }
 
-async fn check_card(sd_detect: &Input<'_>) {
+/// Brings the state of the card up to date with whether it is
+/// inserted. The error is a message to show to the user.
+async fn check_card(sd_detect: &Input<'_>) -> Result<(), String> {
     let sd_is_present = sd_detect.get_level() == Level::Low;
     let mut storage = STORAGE.get().lock().await;
     match &mut *storage {
@@ -76,7 +91,7 @@ async fn check_card(sd_detect: &Input<'_>) {
         Storage::NotPlugged(sdcard) => {
             if !sd_is_present {
                 sdcard.mark_card_uninit();
-                return;
+                return Ok(());
             }
             match sdcard.num_bytes() {
                 Ok(size) => {
@@ -96,7 +111,7 @@ async fn check_card(sd_detect: &Input<'_>) {
                     storage.mark_loaded(volume_mgr);
                 }
                 Err(err) => {
-                    print!("\u{1b}[1mSD Card error: {err:?}\u{1b}[0m\r\n",);
+                    return Err(format!("SD Card error: {err:?}"));
                 }
             }
         }
@@ -117,12 +132,13 @@ async fn check_card(sd_detect: &Input<'_>) {
                     }
                     Err(err) => {
                         *storage = Storage::Unplugged(volmgr);
-                        print!("\u{1b}[1mSD Card error: {err:?}\u{1b}[0m\r\n",);
+                        return Err(format!("SD Card error: {err:?}"));
                     }
                 }
             }
         }
     }
+    Ok(())
 }
 
 pub async fn init_storage(
@@ -146,7 +162,9 @@ pub async fn init_storage(
 
     *STORAGE.get().lock().await = Storage::NotPlugged(sdcard);
 
-    check_card(&sd_detect).await;
+    if let Err(err) = check_card(&sd_detect).await {
+        print!("\u{1b}[1m{err}\u{1b}[0m\r\n");
+    }
     spawner.must_spawn(sdcard_hot_plug(sd_detect));
 
     let mut storage = STORAGE.get().lock().await;
@@ -184,13 +202,111 @@ async fn sdcard_hot_plug(mut sd_detect: Input<'static>) {
         // Give it a moment to de-bounce
         Timer::after(Duration::from_millis(1000)).await;
 
-        // now see whether it is in or out
-        check_card(&sd_detect).await;
+        // now see whether it is in or out, and say so on the
+        // status line rather than interrupting the screen
+        let message = match check_card(&sd_detect).await {
+            Err(err) => err,
+            Ok(()) if sd_detect.get_level() == Level::Low => "SD card inserted".into(),
+            Ok(()) => "SD card removed".into(),
+        };
+        crate::notify::notify(message);
+    }
+}
+
+type Dir<'a> =
+    embedded_sdmmc::Directory<'a, CardType, WezTermTimeSource, MAX_DIRS, MAX_FILES, MAX_VOLUMES>;
+
+type Vol<'a> =
+    embedded_sdmmc::Volume<'a, CardType, WezTermTimeSource, MAX_DIRS, MAX_FILES, MAX_VOLUMES>;
+
+type SdFile<'a> =
+    embedded_sdmmc::File<'a, CardType, WezTermTimeSource, MAX_DIRS, MAX_FILES, MAX_VOLUMES>;
+
+/// Where a path refers to: either the RAM disk or the SD card
+pub enum Location<'a> {
+    Ram(&'a str),
+    Sd(&'a str),
+}
+
+/// Paths with a `ram:` prefix refer to the RAM disk, everything
+/// else refers to the SD card.
+pub fn resolve_path(path: &str) -> Location<'_> {
+    match path.strip_prefix("ram:") {
+        Some(name) => Location::Ram(name.trim_start_matches('/')),
+        None => Location::Sd(path),
+    }
+}
+
+/// Returns the `/` separated components of `path`, skipping the
+/// empty and `.` components produced by leading, trailing or
+/// repeated separators.
+fn path_components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter(|comp| !comp.is_empty() && *comp != ".")
+}
+
+/// Splits `path` into its directory portion and the name of
+/// the final entry within that directory.
+fn split_path(path: &str) -> (&str, &str) {
+    let path = path.trim_end_matches('/');
+    match path.rsplit_once('/') {
+        Some((dirs, name)) => (dirs, name),
+        None => ("", path),
+    }
+}
+
+/// embedded_sdmmc only deals in 8.3 short names; it doesn't decode
+/// the VFAT long file name entries that a PC may have written.
+/// Check names up front so that the user gets a more helpful
+/// message than a bare `FilenameError`.
+fn check_short_name(name: &str) -> Result<(), String> {
+    if name == ".." {
+        return Ok(());
+    }
+    ShortFileName::create_from_str(name)
+        .map(|_| ())
+        .map_err(|err| {
+            format!("`{name}` is not a valid 8.3 name ({err:?}). Long file names are not supported")
+        })
+}
+
+/// Walks `dir` down through each of the components of `path`
+fn change_dir_path(dir: &mut Dir<'_>, path: &str) -> Result<(), String> {
+    for comp in path_components(path) {
+        check_short_name(comp)?;
+        dir.change_dir(comp)
+            .map_err(|err| format!("Failed to open {comp} in /{path}: {err:?}"))?;
     }
+    Ok(())
 }
 
 pub async fn ls_command(args: &[&str]) {
     log::debug!("invoked ls with {args:?}\r\n");
+
+    let mut long_format = false;
+    let mut show_hidden = false;
+    let mut path = "";
+    for arg in args.iter().skip(1) {
+        match *arg {
+            "-l" => long_format = true,
+            "-a" => show_hidden = true,
+            "-la" | "-al" => {
+                long_format = true;
+                show_hidden = true;
+            }
+            arg if arg.starts_with('-') => {
+                print!("ls: unknown option {arg}\r\nUsage: ls [-a] [-l] [path]\r\n");
+                return;
+            }
+            arg => path = arg,
+        }
+    }
+
+    if let Location::Ram(name) = resolve_path(path) {
+        ls_ram(name, long_format).await;
+        return;
+    }
+
     let mut storage = STORAGE.get().lock().await;
     let Some(mgr) = storage.vol_mgr() else {
         print!("No SD card is present\r\n");
@@ -213,37 +329,29 @@ pub async fn ls_command(args: &[&str]) {
         }
     };
 
-    let path = args.get(1).copied().unwrap_or("");
-    let (dirs, entry_name) = match path.rsplit_once('/') {
-        Some((dirs, entry_name)) => (Some(dirs), entry_name),
-        None => (None, path),
-    };
-
-    if let Some(dirs) = dirs {
-        for comp in dirs.split('/') {
-            match dir.change_dir(comp) {
-                Ok(()) => {}
-                Err(err) => {
-                    print!("Failed to open {comp} in {dirs}: {err:?}\r\n");
-                    return;
-                }
-            }
-        }
+    let (dirs, entry_name) = split_path(path);
+    if let Err(err) = change_dir_path(&mut dir, dirs) {
+        print!("{err}\r\n");
+        return;
     }
 
     async fn print_entry(entry: &DirEntry) {
-        let mut attrs = String::new();
-        write!(attrs, "{:?}", entry.attributes).ok();
         let mut size = String::new();
         write!(size, "{}", byte_size(entry.size)).ok();
         let (size, unit) = size.split_once(' ').unwrap_or((&size, ""));
         let mut name = String::new();
         write!(name, "{}", entry.name).ok();
+        let flags = attribute_flags(entry);
+        let time = format_time(&entry.mtime);
 
-        print!("{attrs:<3} {size:>7} {unit:<3} {name}\r\n");
+        print!("{flags} {size:>7} {unit:<3} {time} {name}\r\n");
     }
 
     if !entry_name.is_empty() {
+        if let Err(err) = check_short_name(entry_name) {
+            print!("{err}\r\n");
+            return;
+        }
         match dir.find_directory_entry(entry_name) {
             Ok(entry) => {
                 if entry.attributes.is_directory() {
@@ -254,7 +362,7 @@ pub async fn ls_command(args: &[&str]) {
                 }
             }
             Err(err) => {
-                print!("Failed to find {entry_name} in {dirs:?}: {err:?}\r\n");
+                print!("Failed to find {entry_name} in /{dirs}: {err:?}\r\n");
                 return;
             }
         }
@@ -263,11 +371,897 @@ pub async fn ls_command(args: &[&str]) {
     // Just iterate the directory
     let mut dirs = Vec::new();
     dir.iterate_dir(|entry| {
-        dirs.push(entry.clone());
+        if show_hidden || !entry.attributes.is_hidden() {
+            dirs.push(entry.clone());
+        }
     })
     .ok();
     dirs.sort_by(|a, b| a.name.base_name().cmp(b.name.base_name()));
-    for entry in dirs {
-        print_entry(&entry).await;
+    if long_format {
+        for entry in dirs {
+            print_entry(&entry).await;
+        }
+    } else {
+        let names: Vec<String> = dirs
+            .iter()
+            .map(|entry| {
+                let mut name = String::new();
+                write!(name, "{}", entry.name).ok();
+                if entry.attributes.is_directory() {
+                    name.push('/');
+                }
+                name
+            })
+            .collect();
+        let width = SCREEN.get().lock().await.width as usize;
+        print_columns(&names, width).await;
+    }
+}
+
+/// Prints `names` packed into as many columns as will fit into
+/// `width` cells. The entries are ordered down each column and
+/// then across, like `ls` on a terminal.
+async fn print_columns(names: &[String], width: usize) {
+    let Some(longest) = names.iter().map(|name| name.len()).max() else {
+        return;
+    };
+    let col_width = longest + 2;
+    let num_cols = (width / col_width).max(1);
+    let num_rows = names.len().div_ceil(num_cols);
+
+    for row in 0..num_rows {
+        let mut line = String::new();
+        for col in 0..num_cols {
+            let Some(name) = names.get(col * num_rows + row) else {
+                break;
+            };
+            write!(line, "{name:<col_width$}").ok();
+        }
+        print!("{}\r\n", line.trim_end());
+    }
+}
+
+/// How deep du, find and tree will descend. FAT has no links that
+/// could form a cycle, but a corrupt filesystem could, so this
+/// guards against that as well as against running for too long.
+const MAX_WALK_DEPTH: usize = 8;
+
+/// Returns the entries in `path`, sorted by name, excluding
+/// the `.` and `..` entries and the volume label.
+/// The directory handle is closed before returning so that
+/// walking a tree never needs more than one open directory.
+fn list_dir(vol: &mut Vol<'_>, path: &str) -> Result<Vec<DirEntry>, String> {
+    let mut dir = vol
+        .open_root_dir()
+        .map_err(|err| format!("Failed to open root dir on vol0: {err:?}"))?;
+    change_dir_path(&mut dir, path)?;
+
+    let mut entries = Vec::new();
+    dir.iterate_dir(|entry| {
+        let name = entry.name.base_name();
+        if name != b"." && name != b".." && !entry.attributes.is_volume() {
+            entries.push(entry.clone());
+        }
+    })
+    .map_err(|err| format!("Failed to list /{path}: {err:?}"))?;
+    entries.sort_by(|a, b| a.name.base_name().cmp(b.name.base_name()));
+    Ok(entries)
+}
+
+struct WalkItem {
+    /// 0 for entries directly within the starting directory
+    depth: usize,
+    /// true if this is the final entry within its directory
+    last: bool,
+    /// path relative to the starting directory
+    path: String,
+    entry: DirEntry,
+}
+
+struct WalkFrame {
+    path: String,
+    entries: Vec<DirEntry>,
+    next: usize,
+}
+
+/// Walks the tree rooted at a directory depth first, an entry at
+/// a time, so that only the listings of the directories leading
+/// to the current entry are held rather than the whole tree.
+/// An explicit stack is used rather than recursion, and each
+/// directory is closed once it has been listed, so that we stay
+/// within the MAX_DIRS limit of the volume manager.
+struct TreeWalk {
+    root: String,
+    stack: Vec<WalkFrame>,
+}
+
+impl TreeWalk {
+    fn new(vol: &mut Vol<'_>, path: &str) -> Result<Self, String> {
+        let entries = list_dir(vol, path)?;
+        let mut stack = Vec::new();
+        stack.push(WalkFrame {
+            path: String::new(),
+            entries,
+            next: 0,
+        });
+        Ok(Self {
+            root: path.into(),
+            stack,
+        })
     }
+
+    /// Returns the next entry, or None once every entry has
+    /// been visited
+    async fn next(&mut self, vol: &mut Vol<'_>) -> Result<Option<WalkItem>, String> {
+        while let Some(frame) = self.stack.last_mut() {
+            let Some(entry) = frame.entries.get(frame.next).cloned() else {
+                self.stack.pop();
+                continue;
+            };
+            frame.next += 1;
+            let last = frame.next == frame.entries.len();
+
+            let mut item_path = frame.path.clone();
+            if !item_path.is_empty() {
+                item_path.push('/');
+            }
+            write!(item_path, "{}", entry.name).ok();
+
+            let depth = self.stack.len() - 1;
+            if entry.attributes.is_directory() && depth + 1 < MAX_WALK_DEPTH {
+                let entries = list_dir(vol, &format!("{}/{item_path}", self.root))?;
+                self.stack.push(WalkFrame {
+                    path: item_path.clone(),
+                    entries,
+                    next: 0,
+                });
+                // Large cards can take a while to walk; give the
+                // watchdog and other tasks a chance to run
+                embassy_futures::yield_now().await;
+            }
+            return Ok(Some(WalkItem {
+                depth,
+                last,
+                path: item_path,
+                entry,
+            }));
+        }
+        Ok(None)
+    }
+}
+
+pub async fn du_command(args: &[&str]) {
+    let path = match args {
+        [_] => "",
+        [_, path] => path,
+        _ => {
+            print!("Usage: du [path]\r\n");
+            return;
+        }
+    };
+
+    let (subdirs, total) = match sum_sizes(path).await {
+        Ok(sizes) => sizes,
+        Err(err) => {
+            print!("{err}\r\n");
+            return;
+        }
+    };
+
+    let mut pager = crate::pager::Pager::new().await;
+    for (name, size) in subdirs {
+        let line = format!("{:>10} {name}/", format!("{}", byte_size(size)));
+        if !pager.line(&line).await {
+            return;
+        }
+    }
+    let line = format!("{:>10} total", format!("{}", byte_size(total)));
+    pager.line(&line).await;
+}
+
+/// Returns the size of the files beneath each directory within
+/// `path`, along with the size of all of the files beneath it
+async fn sum_sizes(path: &str) -> Result<(Vec<(String, u64)>, u64), String> {
+    let mut storage = STORAGE.get().lock().await;
+    let mgr = storage.vol_mgr().ok_or_else(no_card)?;
+    let mut vol = open_volume(mgr)?;
+    let mut walk = TreeWalk::new(&mut vol, path)?;
+
+    let mut subdirs: Vec<(String, u64)> = Vec::new();
+    let mut total = 0u64;
+    while let Some(item) = walk.next(&mut vol).await? {
+        if item.depth == 0 && item.entry.attributes.is_directory() {
+            subdirs.push((item.path.clone(), 0));
+        }
+        if item.entry.attributes.is_directory() {
+            continue;
+        }
+        let size = item.entry.size as u64;
+        total += size;
+        if item.depth > 0 {
+            let top = item.path.split('/').next().unwrap_or("");
+            if let Some((_, dir_size)) = subdirs.iter_mut().find(|(name, _)| name == top) {
+                *dir_size += size;
+            }
+        }
+    }
+    Ok((subdirs, total))
+}
+
+/// `find [path] NAME` lists the entries beneath `path` whose
+/// names contain NAME. Short names are upper case, so the match
+/// ignores case.
+pub async fn find_command(args: &[&str]) {
+    let (path, needle) = match args {
+        [_, needle] => ("", *needle),
+        [_, path, needle] => (*path, *needle),
+        _ => {
+            print!("Usage: find [path] NAME\r\n");
+            return;
+        }
+    };
+
+    match find_entries(path, &needle.to_ascii_uppercase()).await {
+        Ok(true) => {}
+        Ok(false) => print!("Nothing matching `{needle}` was found\r\n"),
+        Err(err) => print!("{err}\r\n"),
+    }
+}
+
+/// Prints the entries beneath `path` whose upper cased names
+/// contain `needle`, returning whether there were any
+async fn find_entries(path: &str, needle: &str) -> Result<bool, String> {
+    let mut storage = STORAGE.get().lock().await;
+    let mgr = storage.vol_mgr().ok_or_else(no_card)?;
+    let mut vol = open_volume(mgr)?;
+    let mut walk = TreeWalk::new(&mut vol, path)?;
+
+    let base = path.trim_matches('/');
+    let mut pager = crate::pager::Pager::new().await;
+    let mut found = false;
+    while let Some(item) = walk.next(&mut vol).await? {
+        let mut name = String::new();
+        write!(name, "{}", item.entry.name).ok();
+        if !name.to_ascii_uppercase().contains(needle) {
+            continue;
+        }
+        found = true;
+        let mut line = String::from("/");
+        if !base.is_empty() {
+            line.push_str(base);
+            line.push('/');
+        }
+        line.push_str(&item.path);
+        if item.entry.attributes.is_directory() {
+            line.push('/');
+        }
+        if !pager.line(&line).await {
+            break;
+        }
+    }
+    Ok(found)
+}
+
+pub async fn tree_command(args: &[&str]) {
+    let path = match args {
+        [_] => "",
+        [_, path] => path,
+        _ => {
+            print!("Usage: tree [path]\r\n");
+            return;
+        }
+    };
+
+    if let Err(err) = print_tree(path).await {
+        print!("{err}\r\n");
+    }
+}
+
+async fn print_tree(path: &str) -> Result<(), String> {
+    let mut storage = STORAGE.get().lock().await;
+    let mgr = storage.vol_mgr().ok_or_else(no_card)?;
+    let mut vol = open_volume(mgr)?;
+    let mut walk = TreeWalk::new(&mut vol, path)?;
+
+    let mut pager = crate::pager::Pager::new().await;
+    if !pager.line(&format!("/{}", path.trim_matches('/'))).await {
+        return Ok(());
+    }
+
+    // `open` tracks whether each ancestor has further siblings
+    // still to come, and thus needs a vertical line drawn
+    let mut open: Vec<bool> = Vec::new();
+    while let Some(item) = walk.next(&mut vol).await? {
+        open.truncate(item.depth);
+        let mut line = String::new();
+        for &more in &open {
+            line.push_str(if more { "│   " } else { "    " });
+        }
+        line.push_str(if item.last {
+            "└── "
+        } else {
+            "├── "
+        });
+        write!(line, "{}", item.entry.name).ok();
+        if item.entry.attributes.is_directory() {
+            line.push('/');
+        }
+        if !pager.line(&line).await {
+            break;
+        }
+        open.push(!item.last);
+    }
+    Ok(())
+}
+
+async fn ls_ram(name: &str, long_format: bool) {
+    let ramdisk = RAMDISK.get().lock().await;
+    let Some(disk) = ramdisk.as_ref() else {
+        print!("ram: is not available (no PSRAM was found)\r\n");
+        return;
+    };
+
+    let files: Vec<&RamFile> = disk
+        .files()
+        .iter()
+        .filter(|file| name.is_empty() || file.name.eq_ignore_ascii_case(name))
+        .collect();
+    if !name.is_empty() && files.is_empty() {
+        print!("ram:{name} not found\r\n");
+        return;
+    }
+
+    if long_format {
+        for file in files {
+            let mut size = String::new();
+            write!(size, "{}", byte_size(file.len)).ok();
+            let (size, unit) = size.split_once(' ').unwrap_or((&size, ""));
+            print!("{:<2} {:<3} {size:>7} {unit:<3} {}\r\n", "", "", file.name);
+        }
+    } else {
+        let names: Vec<String> = files.iter().map(|file| file.name.clone()).collect();
+        let width = SCREEN.get().lock().await.width as usize;
+        print_columns(&names, width).await;
+    }
+}
+
+fn open_volume(mgr: &VolMgr) -> Result<Vol<'_>, String> {
+    mgr.open_volume(VolumeIdx(0))
+        .map_err(|err| format!("Failed to open vol0: {err:?}"))
+}
+
+/// Opens the directory that contains `path`, returning it along
+/// with the name of the final entry in `path`.
+fn open_parent<'a, 'p>(vol: &mut Vol<'a>, path: &'p str) -> Result<(Dir<'a>, &'p str), String> {
+    let (dirs, name) = split_path(path);
+    if name.is_empty() {
+        return Err(format!("`{path}` is not a file name"));
+    }
+    check_short_name(name)?;
+    let mut dir = vol
+        .open_root_dir()
+        .map_err(|err| format!("Failed to open root dir on vol0: {err:?}"))?;
+    change_dir_path(&mut dir, dirs)?;
+    Ok((dir, name))
+}
+
+fn open_sd_file<'a>(dir: &Dir<'a>, name: &str, mode: Mode) -> Result<SdFile<'a>, String> {
+    dir.open_file_in_dir(name, mode)
+        .map_err(|err| format!("Failed to open {name}: {err:?}"))
+}
+
+/// Reads the whole of a file on the SD card, for files such
+/// as the command history that are small enough to hold in memory
+pub async fn read_sd_file(path: &str) -> Result<Vec<u8>, String> {
+    let mut storage = STORAGE.get().lock().await;
+    let mgr = storage.vol_mgr().ok_or_else(no_card)?;
+    let mut vol = open_volume(mgr)?;
+    let (dir, name) = open_parent(&mut vol, path)?;
+    let mut file = open_sd_file(&dir, name, Mode::ReadOnly)?;
+    let mut data = Vec::new();
+    let mut buf = [0u8; 512];
+    while !file.is_eof() {
+        let n = file
+            .read(&mut buf)
+            .map_err(|err| format!("Failed to read {name}: {err:?}"))?;
+        data.extend_from_slice(&buf[..n]);
+    }
+    Ok(data)
+}
+
+/// Writes data to a file on the SD card, creating it if needed.
+/// If `append` is true, data is added to the end of the file,
+/// otherwise the file is replaced. Returns the new file size.
+pub async fn write_sd_file(path: &str, data: &[u8], append: bool) -> Result<u32, String> {
+    let mut storage = STORAGE.get().lock().await;
+    let mgr = storage.vol_mgr().ok_or_else(no_card)?;
+    let mut vol = open_volume(mgr)?;
+    let (dir, name) = open_parent(&mut vol, path)?;
+    check_writable(mgr, &dir, name, false)?;
+    let mode = if append {
+        Mode::ReadWriteCreateOrAppend
+    } else {
+        Mode::ReadWriteCreateOrTruncate
+    };
+    let mut file = open_sd_file(&dir, name, mode)?;
+    file.write(data)
+        .map_err(|err| format!("Failed to write {name}: {err:?}"))?;
+    let len = file.length();
+    file.close()
+        .map_err(|err| format!("Failed to close {name}: {err:?}"))?;
+    #[cfg(feature = "net")]
+    stamp_later(path);
+    Ok(len)
+}
+
+/// The most files that are remembered for fix_timestamps; any
+/// more that are written before the clock is set keep their time
+#[cfg(feature = "net")]
+const MAX_UNSTAMPED: usize = 16;
+
+/// Files on the SD card that were written before the clock was set
+#[cfg(feature = "net")]
+static UNSTAMPED: CriticalSectionMutex<RefCell<Vec<String>>> =
+    CriticalSectionMutex::new(RefCell::new(Vec::new()));
+
+/// Called after writing `path` on the SD card. If the clock hasn't
+/// been set yet, the file was stamped with a time shortly after
+/// the Unix Epoch, so `path` is remembered for fix_timestamps.
+#[cfg(feature = "net")]
+fn stamp_later(path: &str) {
+    if crate::time::clock_is_set() {
+        return;
+    }
+    UNSTAMPED.lock(|unstamped| {
+        let mut unstamped = unstamped.borrow_mut();
+        if unstamped.len() < MAX_UNSTAMPED && !unstamped.iter().any(|p| p == path) {
+            unstamped.push(path.into());
+        }
+    });
+}
+
+/// Once the clock has been set, gives the files that were written
+/// before then the current time as their modification time.
+/// embedded_sdmmc has no way to set that directly, but it stamps a
+/// file with the current time when one that was written to is
+/// closed, so an empty write is made to each of them.
+#[cfg(feature = "net")]
+pub async fn fix_timestamps() {
+    let paths = UNSTAMPED.lock(|unstamped| core::mem::take(&mut *unstamped.borrow_mut()));
+    if paths.is_empty() {
+        return;
+    }
+    let mut storage = STORAGE.get().lock().await;
+    let Some(mgr) = storage.vol_mgr() else {
+        return;
+    };
+    for path in &paths {
+        let touch = || -> Result<(), String> {
+            let mut vol = open_volume(mgr)?;
+            let (dir, name) = open_parent(&mut vol, path)?;
+            // Not creating it, in case it was removed since
+            let mut file = open_sd_file(&dir, name, Mode::ReadWriteAppend)?;
+            file.write(&[])
+                .map_err(|err| format!("Failed to write {name}: {err:?}"))?;
+            file.close()
+                .map_err(|err| format!("Failed to close {name}: {err:?}"))
+        };
+        match touch() {
+            Ok(()) => log::info!("storage: fixed the timestamp of {path}"),
+            Err(err) => log::warn!("storage: can't fix the timestamp of {path}: {err}"),
+        }
+    }
+}
+
+/// Returns true if `time` can't be right, because it was stamped
+/// before the clock was set. FAT can't record a time before 1980,
+/// so such files show as 1970 or 1980, depending on what wrote them.
+fn is_bogus_time(time: &Timestamp) -> bool {
+    time.year_since_1970 <= 10
+}
+
+/// Formats `time` for `ls -l`, marking it with `?` if it is bogus
+fn format_time(time: &Timestamp) -> String {
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}{}",
+        1970 + time.year_since_1970 as u32,
+        time.zero_indexed_month + 1,
+        time.zero_indexed_day + 1,
+        time.hours,
+        time.minutes,
+        if is_bogus_time(time) { '?' } else { ' ' },
+    )
+}
+
+fn no_card() -> String {
+    String::from("No SD card is present")
+}
+
+fn no_ramdisk() -> String {
+    String::from("ram: is not available (no PSRAM was found)")
+}
+
+/// Prints a text file a chunk at a time, translating newlines.
+/// A character that is split between chunks is held back until
+/// the rest of it arrives, rather than shown as two replacement
+/// characters.
+#[derive(Default)]
+struct TextPrinter {
+    /// The start of a character that the last chunk ended with
+    partial: Vec<u8>,
+}
+
+impl TextPrinter {
+    async fn print(&mut self, data: &[u8]) {
+        let mut bytes = core::mem::take(&mut self.partial);
+        bytes.extend_from_slice(data);
+        let mut text = String::new();
+        let mut rest = &bytes[..];
+        while let Err(err) = core::str::from_utf8(rest) {
+            let (valid, invalid) = rest.split_at(err.valid_up_to());
+            text.push_str(core::str::from_utf8(valid).unwrap_or_default());
+            match err.error_len() {
+                Some(len) => {
+                    text.push(char::REPLACEMENT_CHARACTER);
+                    rest = &invalid[len..];
+                }
+                None => {
+                    // The chunk ends part way through a character
+                    self.partial = invalid.to_vec();
+                    rest = &[];
+                }
+            }
+        }
+        text.push_str(core::str::from_utf8(rest).unwrap_or_default());
+        print!("{}", text.replace('\n', "\r\n"));
+    }
+
+    /// Shows a character that the file ended part way through
+    async fn finish(self) {
+        if !self.partial.is_empty() {
+            print!("{}", char::REPLACEMENT_CHARACTER);
+        }
+    }
+}
+
+pub async fn cat_command(args: &[&str]) {
+    let [_, path] = args else {
+        print!("Usage: cat <path>\r\n");
+        return;
+    };
+    let mut progress = Progress::begin(path).await;
+    let result = cat_file(path, &mut progress).await;
+    progress.end().await;
+    if let Err(err) = result {
+        print!("cat: {err}\r\n");
+    }
+}
+
+async fn cat_file(path: &str, progress: &mut Progress) -> Result<(), String> {
+    let mut buf = [0u8; 512];
+    let mut total = 0u32;
+    let mut printer = TextPrinter::default();
+    match resolve_path(path) {
+        Location::Ram(name) => {
+            let mut ramdisk = RAMDISK.get().lock().await;
+            let disk = ramdisk.as_mut().ok_or_else(no_ramdisk)?;
+            progress.set_total(disk.len(name).unwrap_or(0));
+            loop {
+                let n = disk.read_at(name, total, &mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                printer.print(&buf[..n]).await;
+                total += n as u32;
+                progress.update(total).await;
+            }
+        }
+        Location::Sd(path) => {
+            let mut storage = STORAGE.get().lock().await;
+            let mgr = storage.vol_mgr().ok_or_else(no_card)?;
+            let mut vol = open_volume(mgr)?;
+            let (dir, name) = open_parent(&mut vol, path)?;
+            let mut file = open_sd_file(&dir, name, Mode::ReadOnly)?;
+            progress.set_total(file.length());
+            while !file.is_eof() {
+                let n = file
+                    .read(&mut buf)
+                    .map_err(|err| format!("Failed to read {name}: {err:?}"))?;
+                printer.print(&buf[..n]).await;
+                total += n as u32;
+                progress.update(total).await;
+            }
+        }
+    }
+    printer.finish().await;
+    // Make sure the prompt starts on a fresh line
+    print!("\r\n");
+    Ok(())
+}
+
+pub async fn cp_command(args: &[&str]) {
+    let (force, src, dest) = match args {
+        [_, src, dest] if !src.starts_with('-') => (false, *src, *dest),
+        [_, "-f", src, dest] => (true, *src, *dest),
+        _ => {
+            print!("Usage: cp [-f] <src> <dest>\r\n");
+            return;
+        }
+    };
+    let mut progress = Progress::begin(src).await;
+    let result = copy_file(src, dest, force, &mut progress).await;
+    progress.end().await;
+    if let Err(err) = result {
+        print!("cp: {err}\r\n");
+    }
+}
+
+/// Copies `src` to `dest`. A read-only `dest` on the SD card is
+/// only overwritten if `force` is set.
+async fn copy_file(
+    src: &str,
+    dest: &str,
+    force: bool,
+    progress: &mut Progress,
+) -> Result<(), String> {
+    let src = resolve_path(src);
+    let src_name = match src {
+        Location::Ram(name) => name,
+        Location::Sd(path) => split_path(path).1,
+    };
+
+    // Allow `cp foo ram:` and `cp ram:foo dir/` to keep the name
+    let dest_path;
+    let dest = match resolve_path(dest) {
+        Location::Ram("") => Location::Ram(src_name),
+        Location::Sd(path) if path.is_empty() || path.ends_with('/') => {
+            dest_path = format!("{path}{src_name}");
+            Location::Sd(&dest_path)
+        }
+        dest => dest,
+    };
+
+    // Always lock storage before the ramdisk, so that we can't
+    // deadlock with another user of both
+    let mut storage = STORAGE.get().lock().await;
+    let mut ramdisk = RAMDISK.get().lock().await;
+    let mut buf = [0u8; 512];
+    let mut total = 0u32;
+
+    match (src, dest) {
+        (Location::Ram(src), Location::Ram(dest)) => {
+            if src.eq_ignore_ascii_case(dest) {
+                return Err(format!("ram:{src} and ram:{dest} are the same file"));
+            }
+            let disk = ramdisk.as_mut().ok_or_else(no_ramdisk)?;
+            let len = disk
+                .len(src)
+                .ok_or_else(|| format!("ram:{src} not found"))?;
+            progress.set_total(len);
+            disk.create(dest)?;
+            loop {
+                let n = disk.read_at(src, total, &mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                disk.append(dest, &buf[..n]).await?;
+                total += n as u32;
+                progress.update(total).await;
+            }
+        }
+        (Location::Sd(src), Location::Ram(dest)) => {
+            let disk = ramdisk.as_mut().ok_or_else(no_ramdisk)?;
+            let mgr = storage.vol_mgr().ok_or_else(no_card)?;
+            let mut vol = open_volume(mgr)?;
+            let (dir, name) = open_parent(&mut vol, src)?;
+            let mut file = open_sd_file(&dir, name, Mode::ReadOnly)?;
+            progress.set_total(file.length());
+            disk.create(dest)?;
+            while !file.is_eof() {
+                let n = file
+                    .read(&mut buf)
+                    .map_err(|err| format!("Failed to read {name}: {err:?}"))?;
+                disk.append(dest, &buf[..n]).await?;
+                total += n as u32;
+                progress.update(total).await;
+            }
+        }
+        (Location::Ram(src), Location::Sd(dest)) => {
+            let disk = ramdisk.as_mut().ok_or_else(no_ramdisk)?;
+            let len = disk
+                .len(src)
+                .ok_or_else(|| format!("ram:{src} not found"))?;
+            progress.set_total(len);
+            let mgr = storage.vol_mgr().ok_or_else(no_card)?;
+            let mut vol = open_volume(mgr)?;
+            let (dir, name) = open_parent(&mut vol, dest)?;
+            check_writable(mgr, &dir, name, force)?;
+            let mut file = open_sd_file(&dir, name, Mode::ReadWriteCreateOrTruncate)?;
+            loop {
+                let n = disk.read_at(src, total, &mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                file.write(&buf[..n])
+                    .map_err(|err| format!("Failed to write {name}: {err:?}"))?;
+                total += n as u32;
+                progress.update(total).await;
+            }
+            file.close()
+                .map_err(|err| format!("Failed to close {name}: {err:?}"))?;
+            #[cfg(feature = "net")]
+            stamp_later(dest);
+        }
+        (Location::Sd(src), Location::Sd(dest)) => {
+            let mgr = storage.vol_mgr().ok_or_else(no_card)?;
+            let mut vol = open_volume(mgr)?;
+            let (src_dir, src_name) = open_parent(&mut vol, src)?;
+            let (dest_dir, dest_name) = open_parent(&mut vol, dest)?;
+            check_writable(mgr, &dest_dir, dest_name, force)?;
+            let mut src_file = open_sd_file(&src_dir, src_name, Mode::ReadOnly)?;
+            progress.set_total(src_file.length());
+            let mut dest_file =
+                open_sd_file(&dest_dir, dest_name, Mode::ReadWriteCreateOrTruncate)?;
+            while !src_file.is_eof() {
+                let n = src_file
+                    .read(&mut buf)
+                    .map_err(|err| format!("Failed to read {src_name}: {err:?}"))?;
+                dest_file
+                    .write(&buf[..n])
+                    .map_err(|err| format!("Failed to write {dest_name}: {err:?}"))?;
+                total += n as u32;
+                progress.update(total).await;
+            }
+            dest_file
+                .close()
+                .map_err(|err| format!("Failed to close {dest_name}: {err:?}"))?;
+            #[cfg(feature = "net")]
+            stamp_later(dest);
+        }
+    }
+
+    print!("Copied {}\r\n", byte_size(total));
+    Ok(())
+}
+
+pub async fn rm_command(args: &[&str]) {
+    let (force, path) = match args {
+        [_, path] if !path.starts_with('-') => (false, *path),
+        [_, "-f", path] => (true, *path),
+        _ => {
+            print!("Usage: rm [-f] <path>\r\n");
+            return;
+        }
+    };
+    if let Err(err) = remove_file(path, force).await {
+        print!("rm: {err}\r\n");
+    }
+}
+
+pub async fn remove_file(path: &str, force: bool) -> Result<(), String> {
+    match resolve_path(path) {
+        Location::Ram(name) => {
+            let mut ramdisk = RAMDISK.get().lock().await;
+            ramdisk.as_mut().ok_or_else(no_ramdisk)?.remove(name)
+        }
+        Location::Sd(path) => {
+            let mut storage = STORAGE.get().lock().await;
+            let mgr = storage.vol_mgr().ok_or_else(no_card)?;
+            let mut vol = open_volume(mgr)?;
+            let (dir, name) = open_parent(&mut vol, path)?;
+            let entry = dir
+                .find_directory_entry(name)
+                .map_err(|err| format!("Failed to find {name}: {err:?}"))?;
+            if entry.attributes.is_read_only() && !force {
+                return Err(format!("{name} is read-only; use rm -f to remove it"));
+            }
+            dir.delete_file_in_dir(name)
+                .map_err(|err| format!("Failed to remove {name}: {err:?}"))
+        }
+    }
+}
+
+/// Returns the attributes of an entry as `drhsa` flags, for
+/// directory, read-only, hidden, system and archive, with `-`
+/// in place of each attribute that isn't set
+fn attribute_flags(entry: &DirEntry) -> String {
+    let attributes = &entry.attributes;
+    [
+        (attributes.is_directory(), 'd'),
+        (attributes.is_read_only(), 'r'),
+        (attributes.is_hidden(), 'h'),
+        (attributes.is_system(), 's'),
+        (attributes.is_archive(), 'a'),
+    ]
+    .iter()
+    .map(|&(set, flag)| if set { flag } else { '-' })
+    .collect()
+}
+
+/// Checks that `name` in `dir` may be overwritten, which it can't
+/// be if it is read-only, unless `force` is set, in which case the
+/// read-only attribute is cleared. A file that doesn't exist yet
+/// can always be written.
+fn check_writable(mgr: &VolMgr, dir: &Dir<'_>, name: &str, force: bool) -> Result<(), String> {
+    let entry = match dir.find_directory_entry(name) {
+        Ok(entry) => entry,
+        Err(embedded_sdmmc::Error::NotFound) => return Ok(()),
+        Err(err) => return Err(format!("Failed to find {name}: {err:?}")),
+    };
+    if !entry.attributes.is_read_only() {
+        return Ok(());
+    }
+    if !force {
+        return Err(format!("{name} is read-only; use -f to overwrite it"));
+    }
+    update_attributes(mgr, &entry, 0, ATTR_READ_ONLY)
+}
+
+// FAT directory entries are 32 bytes, with the attributes in byte 11
+const DIR_ENTRY_ATTR_OFFSET: usize = 11;
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_HIDDEN: u8 = 0x02;
+
+/// embedded_sdmmc can report the attributes of an entry but has
+/// no way to change them, so we patch the directory entry on the
+/// card directly. VolumeManager::device drops its cached block
+/// when handing out the card, so it won't see stale data later.
+fn update_attributes(mgr: &VolMgr, entry: &DirEntry, set: u8, clear: u8) -> Result<(), String> {
+    use embedded_sdmmc::{Block, BlockDevice};
+
+    mgr.device(|card| {
+        let mut blocks = [Block::new()];
+        card.read(&mut blocks, entry.entry_block)
+            .map_err(|err| format!("Failed to read directory entry: {err:?}"))?;
+        let attr = &mut blocks[0].contents[entry.entry_offset as usize + DIR_ENTRY_ATTR_OFFSET];
+        *attr = (*attr | set) & !clear;
+        card.write(&blocks, entry.entry_block)
+            .map_err(|err| format!("Failed to write directory entry: {err:?}"))
+    })
+}
+
+pub async fn attrib_command(args: &[&str]) {
+    let Some((path, changes)) = args.get(1).map(|path| (*path, &args[2..])) else {
+        print!("Usage: attrib <path> [+r|-r] [+h|-h]\r\n");
+        return;
+    };
+    if let Err(err) = attrib(path, changes).await {
+        print!("attrib: {err}\r\n");
+    }
+}
+
+async fn attrib(path: &str, changes: &[&str]) -> Result<(), String> {
+    let mut set = 0;
+    let mut clear = 0;
+    for change in changes {
+        match *change {
+            "+r" => set |= ATTR_READ_ONLY,
+            "-r" => clear |= ATTR_READ_ONLY,
+            "+h" => set |= ATTR_HIDDEN,
+            "-h" => clear |= ATTR_HIDDEN,
+            other => return Err(format!("unknown attribute change {other}")),
+        }
+    }
+
+    let Location::Sd(path) = resolve_path(path) else {
+        return Err(String::from("files on ram: have no attributes"));
+    };
+
+    let mut storage = STORAGE.get().lock().await;
+    let mgr = storage.vol_mgr().ok_or_else(no_card)?;
+    let mut vol = open_volume(mgr)?;
+    let (dir, name) = open_parent(&mut vol, path)?;
+    let find_entry = || {
+        dir.find_directory_entry(name)
+            .map_err(|err| format!("Failed to find {name}: {err:?}"))
+    };
+
+    let mut entry = find_entry()?;
+    if set != 0 || clear != 0 {
+        update_attributes(mgr, &entry, set, clear)?;
+        entry = find_entry()?;
+    }
+
+    print!("{} {}\r\n", attribute_flags(&entry), entry.name);
+    Ok(())
 }
@@ -0,0 +1,147 @@
+use crate::byte_size;
+use crate::heap::HEAP;
+use crate::keyboard::get_battery;
+use crate::pager::Pager;
+use crate::storage::STORAGE;
+use crate::time::{Rfc3339, UnixTime};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use embassy_time::Instant;
+
+extern crate alloc;
+
+/// The size of the PIO-attached PSRAM, recorded during boot
+pub static PSRAM_SIZE: AtomicU32 = AtomicU32::new(0);
+/// The size of the QMI-attached PSRAM, recorded during boot
+pub static PSRAM_QMI_SIZE: AtomicU32 = AtomicU32::new(0);
+/// The display SPI bus frequency in Hz, recorded during boot
+pub static DISPLAY_SPI_FREQ: AtomicU32 = AtomicU32::new(0);
+
+fn board_name() -> &'static str {
+    if cfg!(feature = "pimoroni2w") {
+        "Pimoroni Pico Plus 2 W (RP2350B)"
+    } else if cfg!(feature = "pico2w") {
+        "Raspberry Pi Pico 2 W (RP2350A)"
+    } else {
+        "RP2350"
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    let days = secs / 86400;
+    let hours = (secs / 3600) % 24;
+    let mins = (secs / 60) % 60;
+    let secs = secs % 60;
+    if days > 0 {
+        format!("{days}d {hours}h {mins}m {secs}s")
+    } else if hours > 0 {
+        format!("{hours}h {mins}m {secs}s")
+    } else {
+        format!("{mins}m {secs}s")
+    }
+}
+
+/// Summarizes the state of the whole device, in a form that is
+/// suitable for pasting into a bug report.
+pub async fn sysinfo_command(_args: &[&str]) {
+    let mut lines: Vec<String> = Vec::new();
+
+    lines.push(format!("Board:    {}", board_name()));
+    lines.push(format!("Firmware: {}", env!("WEZTERM_CI_TAG")));
+    lines.push(format!(
+        "Uptime:   {}",
+        format_duration(Instant::now().as_secs())
+    ));
+
+    lines.push(format!(
+        "RAM:      {} avail of 520KiB",
+        byte_size(crate::get_max_usable_stack())
+    ));
+    lines.push(format!(
+        "PSRAM:    {} (SLOW), {} (QMI)",
+        byte_size(PSRAM_SIZE.load(Ordering::Relaxed)),
+        byte_size(PSRAM_QMI_SIZE.load(Ordering::Relaxed)),
+    ));
+    lines.push(format!(
+        "Heap:     {} used, {} free",
+        byte_size(HEAP.used()),
+        byte_size(HEAP.free()),
+    ));
+
+    {
+        let screen = crate::screen::SCREEN.get().lock().await;
+        let (count, bytes) = screen.scrollback_usage();
+        lines.push(format!(
+            "Scroll:   {count} of {} lines, {}",
+            screen.scrollback_limit(),
+            byte_size(bytes)
+        ));
+    }
+    lines.push(format!(
+        "Display:  SPI {}MHz",
+        DISPLAY_SPI_FREQ.load(Ordering::Relaxed) as f32 / 1_000_000.0
+    ));
+
+    {
+        let mut storage = STORAGE.get().lock().await;
+        match storage.card_size() {
+            Some(size) => lines.push(format!("SD card:  {}", byte_size(size))),
+            None => lines.push(String::from("SD card:  not present")),
+        }
+    }
+
+    #[cfg(feature = "net")]
+    {
+        let ssid = crate::config::CONFIG
+            .get()
+            .lock()
+            .await
+            .fetch("wifi_ssid")
+            .await
+            .ok()
+            .flatten();
+        match ssid {
+            Some(ssid) if !ssid.is_empty() => lines.push(format!("Wi-Fi:    {ssid}")),
+            _ => lines.push(String::from("Wi-Fi:    not configured")),
+        }
+        match crate::net::ipv4_address().await {
+            Some(addr) => lines.push(format!("IP:       {addr}")),
+            None => lines.push(String::from("IP:       none")),
+        }
+    }
+    #[cfg(not(feature = "net"))]
+    lines.push(String::from("Wi-Fi:    not included in this build"));
+
+    match crate::keyboard::battery_minutes_remaining().await {
+        Some(mins) => lines.push(format!(
+            "Battery:  {}, ~{}h {}m remaining",
+            get_battery(),
+            mins / 60,
+            mins % 60
+        )),
+        None => lines.push(format!("Battery:  {}", get_battery())),
+    }
+
+    match crate::time::last_sync().await {
+        Some(when) => {
+            lines.push(format!(
+                "Time:     {}",
+                Rfc3339(UnixTime::now().as_chrono())
+            ));
+            lines.push(format!(
+                "NTP:      synced {} ago",
+                format_duration(when.elapsed().as_secs())
+            ));
+        }
+        None => lines.push(String::from("Time:     not yet synced")),
+    }
+
+    let mut pager = Pager::new().await;
+    for line in &lines {
+        if !pager.line(line).await {
+            break;
+        }
+    }
+}
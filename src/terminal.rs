@@ -0,0 +1,2254 @@
+use crate::image::Image;
+use crate::kitty::KittyReceiver;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::iter::{Copied, Enumerate, Peekable, Zip};
+use core::ops::Range;
+use core::slice::Iter;
+use embassy_time::Instant;
+use embedded_graphics::mono_font::MonoFont;
+use wezterm_escape_parser::color::ColorSpec;
+use wezterm_escape_parser::{Action, ControlCode, Esc, EscCode};
+
+extern crate alloc;
+
+// This module holds the terminal model: the grid of cells and the
+// interpretation of the escape sequences that modify it.
+// It knows nothing about the display hardware; instead, it paints
+// itself into a CellSurface, which is implemented by the renderer
+// in the display module.
+
+pub const SCREEN_HEIGHT: u16 = 320;
+pub const SCREEN_WIDTH: u16 = 320;
+
+/// The number of pixel rows in the video ram of the display.
+/// Scrolling is implemented by moving the start of the visible
+/// region through the video ram, so the pixel row positions
+/// passed to the CellSurface wrap around at this value.
+pub const VIDEO_RAM_HEIGHT: u32 = 480;
+
+/// Something that the terminal model can paint its cells into
+pub trait CellSurface {
+    /// Erase the whole surface, in preparation for a full repaint
+    fn erase(&mut self);
+
+    /// Paint a run of cells that share the same attributes.
+    /// `row_y` is the pixel row in video ram of the top of the line.
+    fn draw_cluster(&mut self, cluster: &Cluster<'_>, font: &MonoFont<'_>, row_y: u32);
+
+    /// Set the pixel row in video ram that is shown at the top
+    /// of the screen
+    fn set_scroll_offset(&mut self, offset: u16);
+
+    /// Paint a row of RGB565 pixels, starting at pixel column `x`
+    /// of pixel row `y` in video ram
+    fn draw_pixels(&mut self, x: u32, y: u32, pixels: &[u16]);
+}
+
+/// The fonts that can be selected, smallest first, along with the
+/// names by which `screen font list` shows them
+const FONT_LIST: [(&str, &MonoFont); 7] = [
+    ("PROFONT_7_POINT", &profont::PROFONT_7_POINT),
+    ("PROFONT_9_POINT", &profont::PROFONT_9_POINT),
+    ("PROFONT_10_POINT", &profont::PROFONT_10_POINT),
+    ("PROFONT_12_POINT", &profont::PROFONT_12_POINT),
+    ("PROFONT_14_POINT", &profont::PROFONT_14_POINT),
+    ("PROFONT_18_POINT", &profont::PROFONT_18_POINT),
+    ("PROFONT_24_POINT", &profont::PROFONT_24_POINT),
+];
+static FONTS: &[(&str, &MonoFont)] = &FONT_LIST;
+
+/// Returns the number of columns that fit across the screen in
+/// `font`, limited to the MAX_COLS that a Line can hold, so that
+/// the cursor can never index past the end of one
+const fn columns(font: &MonoFont) -> u8 {
+    let cols = SCREEN_WIDTH as u32 / (font.character_size.width + font.character_spacing);
+    if cols > MAX_COLS as u32 {
+        MAX_COLS as u8
+    } else {
+        cols as u8
+    }
+}
+
+/// Returns the number of rows that fit down the screen in `font`
+const fn rows(font: &MonoFont) -> u8 {
+    (SCREEN_HEIGHT as u32 / font.character_size.height) as u8
+}
+
+// The limit in `columns` is a backstop; a font that needs it would
+// leave part of the screen unused, so check that none of them do,
+// and that each fits in the lines that the model has
+const _: () = {
+    let mut idx = 0;
+    while idx < FONT_LIST.len() {
+        let font = FONT_LIST[idx].1;
+        assert!(
+            SCREEN_WIDTH as u32 / (font.character_size.width + font.character_spacing)
+                <= MAX_COLS as u32,
+            "a font is narrow enough to need more than MAX_COLS columns"
+        );
+        assert!(
+            (rows(font) as usize) < MAX_LINES,
+            "a font is short enough to need more than MAX_LINES rows"
+        );
+        idx += 1;
+    }
+};
+
+/// Returns the name and the character width and height, in
+/// pixels, of each of the fonts, in the order used by `set_font`
+pub fn fonts() -> impl Iterator<Item = (&'static str, u32, u32)> {
+    FONTS
+        .iter()
+        .map(|(name, font)| (*name, font.character_size.width, font.character_size.height))
+}
+
+/// The fonts only have ASCII glyphs, so box drawing characters,
+/// as used by `tree` and by full screen programs for borders, are
+/// shown as the nearest ASCII ones. Anything else is a space.
+fn ascii_fallback(c: char) -> u8 {
+    match c {
+        '─' | '━' | '┄' | '┅' | '┈' | '┉' | '╌' | '╍' | '═' | '╴' | '╶' | '╸' | '╺' | '╼' | '╾' => {
+            b'-'
+        }
+        '│' | '┃' | '┆' | '┇' | '┊' | '┋' | '╎' | '╏' | '║' | '╵' | '╷' | '╹' | '╻' | '╽' | '╿' => {
+            b'|'
+        }
+        '└' | '╰' => b'`',
+        '\u{2500}'..='\u{257f}' => b'+',
+        _ => b' ',
+    }
+}
+
+/// Converts the count of a cursor movement or an edit into a number
+/// of cells or lines. The parser supplies 1 when the parameter is
+/// missing, but an explicit 0 must also mean 1, as it does in xterm.
+fn movement_count(n: u32) -> u8 {
+    n.clamp(1, u8::MAX as u32) as u8
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct LogicalY(u8);
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct PhysicalY(u8);
+
+bitflags::bitflags! {
+    #[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
+    pub struct Attributes: u8 {
+        const NONE = 0;
+        const REVERSE = 1;
+        const BOLD = 2;
+        const HALF_BRIGHT = 4;
+        const UNDERLINE = 8;
+        const STRIKE_THROUGH = 16;
+    }
+}
+
+/// The shape of the cursor, as selected by DECSCUSR
+#[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CursorShape {
+    #[default]
+    Block,
+    Underline,
+    Bar,
+}
+
+/// A blinking cursor is shown, then hidden, for this long.
+/// The painter only runs every 200ms, so this is approximate.
+const CURSOR_BLINK_MS: u64 = 500;
+
+/// The bar cursor is drawn in the default foreground color,
+/// green, this many pixels wide
+const BAR_CURSOR_COLOR: u16 = 0x07e0;
+const BAR_CURSOR_WIDTH: usize = 2;
+
+const MAX_COLS: usize = 80;
+const _: () = assert!(
+    MAX_COLS <= u128::BITS as usize,
+    "tab stops are a u128 bitmask"
+);
+
+/// A tab stop every 8 columns
+const DEFAULT_TAB_STOPS: u128 = {
+    let mut stops = 0u128;
+    let mut x = 8;
+    while x < MAX_COLS {
+        stops |= 1 << x;
+        x += 8;
+    }
+    stops
+};
+
+/// The most bytes of responses that are held, waiting to be sent
+const MAX_RESPONSE_BYTES: usize = 256;
+
+/// A foreground or background color, as selected by SGR. This
+/// takes 4 bytes, where the parser's ColorSpec, which holds true
+/// colors as floats, takes 20.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Color {
+    /// The default color for the position
+    #[default]
+    Default,
+    /// An entry in the 256 color palette; the first 16 are the
+    /// named colors, which can be changed by `color_palette`
+    Indexed(u8),
+    /// A 24-bit color, which is reduced to RGB565 when it is drawn
+    TrueColor(u8, u8, u8),
+}
+
+impl Color {
+    fn from_spec(spec: ColorSpec) -> Self {
+        match spec {
+            ColorSpec::Default => Self::Default,
+            ColorSpec::PaletteIndex(idx) => Self::Indexed(idx),
+            ColorSpec::TrueColor(color) => {
+                let (r, g, b, _) = color.to_srgb_u8();
+                Self::TrueColor(r, g, b)
+            }
+        }
+    }
+}
+
+/// The colors of a cell
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CellColors {
+    pub fg: Color,
+    pub bg: Color,
+}
+
+#[derive(Copy, Clone)]
+pub struct Line {
+    pub ascii: [u8; MAX_COLS],
+    pub attributes: [Attributes; MAX_COLS],
+    pub colors: [CellColors; MAX_COLS],
+    needs_paint: bool,
+    /// One past the rightmost column that has been printed to
+    content_end: u8,
+    /// The part of an image that is shown on this line
+    image: Option<ImageSlice>,
+}
+
+/// Identifies the image shown on a line, and which of its pixel
+/// rows is at the top of the line
+#[derive(Copy, Clone)]
+struct ImageSlice {
+    id: u16,
+    top: u16,
+}
+
+#[derive(Debug)]
+pub struct Cluster<'a> {
+    pub text: &'a str,
+    pub attributes: Attributes,
+    pub colors: CellColors,
+    pub start_col: usize,
+    pub end_col: usize,
+}
+
+pub struct ClusterIter<'a> {
+    line: &'a Line,
+    last_attr: (Attributes, CellColors),
+    start_idx: Option<usize>,
+    attr_iter: Peekable<Enumerate<Zip<Copied<Iter<'a, Attributes>>, Copied<Iter<'a, CellColors>>>>>,
+    cursor_x: Option<usize>,
+    cursor_shape: CursorShape,
+}
+
+impl<'a> ClusterIter<'a> {
+    fn take_current(&mut self, end_col: usize) -> Option<Cluster<'a>> {
+        let start_col = self.start_idx.take()?;
+
+        let byte_slice = &self.line.ascii[start_col..end_col];
+        let text = core::str::from_utf8(byte_slice).unwrap_or("");
+
+        Some(Cluster {
+            text,
+            start_col,
+            end_col,
+            attributes: self.last_attr.0,
+            colors: self.last_attr.1,
+        })
+    }
+}
+
+impl<'a> Iterator for ClusterIter<'a> {
+    type Item = Cluster<'a>;
+
+    fn next(&mut self) -> Option<Cluster<'a>> {
+        loop {
+            if let Some(cursor_x) = self.cursor_x {
+                if let Some((idx, attr_tuple)) = self.attr_iter.peek() {
+                    if *idx == cursor_x {
+                        let idx = *idx;
+                        let attr_tuple = *attr_tuple;
+                        if let Some(cluster) = self.take_current(idx) {
+                            return Some(cluster);
+                        }
+
+                        // Consume the peeked cursor position
+                        self.attr_iter.next();
+
+                        // Stage an entry for the cursor, flipping it
+                        // to reverse its video attributes, or
+                        // underlining it. A bar cursor is drawn over
+                        // the cell afterwards, so the cell is unchanged.
+                        self.last_attr = attr_tuple;
+                        match self.cursor_shape {
+                            CursorShape::Block => self.last_attr.0.toggle(Attributes::REVERSE),
+                            CursorShape::Underline => {
+                                self.last_attr.0.insert(Attributes::UNDERLINE)
+                            }
+                            CursorShape::Bar => {}
+                        }
+                        self.start_idx = Some(idx);
+                    }
+                }
+            }
+
+            if let Some((idx, attr_tuple)) = self.attr_iter.next() {
+                match self.start_idx {
+                    Some(_) => {
+                        if attr_tuple == self.last_attr {
+                            continue;
+                        }
+
+                        let cluster = self.take_current(idx);
+                        self.last_attr = attr_tuple;
+                        self.start_idx = Some(idx);
+                        return cluster;
+                    }
+                    None => {
+                        self.start_idx = Some(idx);
+                        self.last_attr = attr_tuple;
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+
+        self.take_current(MAX_COLS - 1)
+    }
+}
+
+impl Line {
+    pub fn clear(&mut self) {
+        self.ascii.fill(0x20);
+        self.attributes.fill(Attributes::NONE);
+        self.colors.fill(CellColors::default());
+        self.needs_paint = true;
+        self.content_end = 0;
+        self.image = None;
+    }
+
+    pub fn cluster<'a>(
+        &'a self,
+        cursor_x: Option<u8>,
+        cursor_shape: CursorShape,
+    ) -> ClusterIter<'a> {
+        ClusterIter {
+            line: self,
+            last_attr: (Attributes::NONE, CellColors::default()),
+            start_idx: None,
+            attr_iter: self
+                .attributes
+                .iter()
+                .copied()
+                .zip(self.colors.iter().copied())
+                .enumerate()
+                .peekable(),
+            cursor_x: cursor_x.map(|x| x as usize),
+            cursor_shape,
+        }
+    }
+}
+
+impl Default for Line {
+    fn default() -> Line {
+        Line {
+            ascii: [0x20; MAX_COLS],
+            attributes: [Attributes::NONE; MAX_COLS],
+            colors: [CellColors::default(); MAX_COLS],
+            needs_paint: true,
+            content_end: 0,
+            image: None,
+        }
+    }
+}
+
+impl ScreenModel {
+    pub(crate) fn apply_action(&mut self, action: Action) {
+        match action {
+            Action::Print(c) => {
+                self.print(c);
+            }
+            Action::PrintString(s) => {
+                for c in s.chars() {
+                    self.print(c);
+                }
+            }
+            Action::Control(c) => {
+                match c {
+                    ControlCode::CarriageReturn => {
+                        self.cursor_x = 0;
+                        self.line_log_mut(self.cursor_y).unwrap().needs_paint = true;
+                    }
+                    ControlCode::LineFeed => {
+                        self.line_feed();
+                    }
+                    ControlCode::Backspace => {
+                        // FIXME: respect the left margin once we support margins
+                        if self.cursor_x == 0 {
+                            // Without reverse-wraparound, backspace stops
+                            // at the left edge, as it does in xterm
+                            if self.reverse_wrap && self.cursor_y.0 > 0 {
+                                self.line_log_mut(self.cursor_y).unwrap().needs_paint = true;
+                                self.cursor_y.0 -= 1;
+                                self.cursor_x = self.width - 1;
+                            }
+                        } else {
+                            self.cursor_x -= 1;
+                        }
+                        self.line_log_mut(self.cursor_y).unwrap().needs_paint = true;
+                    }
+                    ControlCode::Bell => {
+                        self.bell = true;
+                    }
+                    ControlCode::HorizontalTab => {
+                        // Move to the next tab stop, or the rightmost
+                        // column if there are no more
+                        let next = (self.cursor_x + 1..self.width)
+                            .find(|&x| self.tab_stops & (1 << x) != 0)
+                            .unwrap_or(self.width - 1);
+                        self.move_cursor(next, self.cursor_y.0);
+                    }
+                    unhandled => {
+                        log::info!("c0/c1: unhandled {unhandled:?}");
+                    }
+                }
+            }
+            Action::Esc(esc) => match esc {
+                unhandled @ Esc::Unspecified { .. } => {
+                    log::info!("esc: unhandled {unhandled:?}");
+                }
+                Esc::Code(EscCode::StringTerminator) => {}
+                Esc::Code(EscCode::Index) => {
+                    self.line_feed();
+                }
+                Esc::Code(EscCode::ReverseIndex) => {
+                    self.reverse_line_feed();
+                }
+                Esc::Code(EscCode::HorizontalTabSet) => {
+                    self.tab_stops |= 1 << self.cursor_x;
+                }
+                unhandled => {
+                    log::info!("esc: unhandled {unhandled:?}");
+                }
+            },
+            Action::CSI(csi) => {
+                use wezterm_escape_parser::csi::*;
+
+                match csi {
+                    CSI::Edit(Edit::EraseInLine(EraseInLine::EraseToEndOfLine)) => {
+                        self.erase_to_end_of_line();
+                    }
+                    CSI::Edit(Edit::EraseInDisplay(EraseInDisplay::EraseToEndOfDisplay)) => {
+                        self.erase_to_end_of_line();
+                        for y in self.cursor_y.0 + 1..self.height {
+                            if let Some(line) = self.line_log_mut(LogicalY(y)) {
+                                line.clear();
+                            }
+                        }
+                    }
+                    CSI::Edit(Edit::InsertCharacter(n)) => {
+                        self.insert_characters(movement_count(n));
+                    }
+                    CSI::Edit(Edit::DeleteCharacter(n)) => {
+                        self.delete_characters(movement_count(n));
+                    }
+                    CSI::Edit(Edit::EraseCharacter(n)) => {
+                        self.erase_characters(movement_count(n));
+                    }
+                    CSI::Edit(Edit::InsertLine(n)) => {
+                        self.insert_lines(movement_count(n));
+                    }
+                    CSI::Edit(Edit::DeleteLine(n)) => {
+                        self.delete_lines(movement_count(n));
+                    }
+                    CSI::Edit(Edit::Repeat(n)) => {
+                        // REP; more than a screenful would all be
+                        // scrolled away, so don't bother
+                        if let Some(c) = self.last_char {
+                            let cells = self.width as u32 * self.height as u32;
+                            for _ in 0..n.max(1).min(cells) {
+                                self.print(c);
+                            }
+                        }
+                    }
+                    CSI::Cursor(Cursor::Up(n)) => {
+                        self.move_cursor_up(self.cursor_x, n);
+                    }
+                    CSI::Cursor(Cursor::Down(n)) => {
+                        self.move_cursor_down(self.cursor_x, n);
+                    }
+                    CSI::Cursor(Cursor::SetTopAndBottomMargins { top, bottom }) => {
+                        // DECSTBM; `CSI r` resets to the whole screen,
+                        // which the parser gives as a huge bottom margin
+                        let top = top.as_zero_based().min(u8::MAX as u32) as u8;
+                        let bottom = bottom.as_zero_based().min(self.height as u32 - 1) as u8;
+                        if top < bottom {
+                            self.scroll_top = top;
+                            self.scroll_bottom = bottom;
+                            let home = if self.origin_mode { top } else { 0 };
+                            self.move_cursor(0, home);
+                        }
+                    }
+                    CSI::Cursor(Cursor::Left(n)) => {
+                        let x = self.cursor_x.saturating_sub(movement_count(n));
+                        self.move_cursor(x, self.cursor_y.0);
+                    }
+                    CSI::Cursor(Cursor::Right(n)) => {
+                        let x = self.cursor_x.saturating_add(movement_count(n));
+                        self.move_cursor(x, self.cursor_y.0);
+                    }
+                    // CUP and HVP; OneBased parameters are already
+                    // defaulted to 1
+                    CSI::Cursor(Cursor::Position { line, col })
+                    | CSI::Cursor(Cursor::CharacterAndLinePosition { line, col }) => {
+                        self.set_cursor_position(col.as_zero_based(), line.as_zero_based());
+                    }
+                    CSI::Cursor(Cursor::LinePositionAbsolute(line)) => {
+                        // VPA; the parameter is 1-based
+                        self.set_cursor_position(self.cursor_x as u32, line.saturating_sub(1));
+                    }
+                    CSI::Cursor(Cursor::NextLine(n)) => {
+                        self.move_cursor_down(0, n);
+                    }
+                    CSI::Cursor(Cursor::PrecedingLine(n)) => {
+                        self.move_cursor_up(0, n);
+                    }
+                    CSI::Cursor(Cursor::TabulationClear(
+                        TabulationClear::ClearCharacterTabStopAtActivePosition,
+                    )) => {
+                        self.tab_stops &= !(1 << self.cursor_x);
+                    }
+                    CSI::Cursor(Cursor::TabulationClear(
+                        TabulationClear::ClearAllCharacterTabStops,
+                    )) => {
+                        self.tab_stops = 0;
+                    }
+                    CSI::Cursor(Cursor::RequestActivePositionReport) => {
+                        // CPR: report the 1-based cursor position, which
+                        // is relative to the scroll region in origin mode
+                        let top = if self.origin_mode {
+                            self.scroll_region().0
+                        } else {
+                            0
+                        };
+                        let line = self.cursor_y.0.saturating_sub(top) as u32 + 1;
+                        let col = self.cursor_x as u32 + 1;
+                        self.respond(alloc::format!("\u{1b}[{line};{col}R").as_bytes());
+                    }
+                    CSI::Mode(Mode::SetMode(TerminalMode::Code(TerminalModeCode::Insert))) => {
+                        self.insert_mode = true;
+                    }
+                    CSI::Mode(Mode::ResetMode(TerminalMode::Code(TerminalModeCode::Insert))) => {
+                        self.insert_mode = false;
+                    }
+                    CSI::Mode(Mode::SetDecPrivateMode(DecPrivateMode::Code(
+                        DecPrivateModeCode::AutoWrap,
+                    ))) => {
+                        self.auto_wrap = true;
+                    }
+                    CSI::Mode(Mode::ResetDecPrivateMode(DecPrivateMode::Code(
+                        DecPrivateModeCode::AutoWrap,
+                    ))) => {
+                        self.auto_wrap = false;
+                    }
+                    CSI::Mode(Mode::SetDecPrivateMode(DecPrivateMode::Code(
+                        DecPrivateModeCode::ReverseWraparound,
+                    ))) => {
+                        self.reverse_wrap = true;
+                    }
+                    CSI::Mode(Mode::ResetDecPrivateMode(DecPrivateMode::Code(
+                        DecPrivateModeCode::ReverseWraparound,
+                    ))) => {
+                        self.reverse_wrap = false;
+                    }
+                    CSI::Mode(Mode::SetDecPrivateMode(DecPrivateMode::Code(
+                        DecPrivateModeCode::OriginMode,
+                    ))) => {
+                        self.set_origin_mode(true);
+                    }
+                    CSI::Mode(Mode::ResetDecPrivateMode(DecPrivateMode::Code(
+                        DecPrivateModeCode::OriginMode,
+                    ))) => {
+                        self.set_origin_mode(false);
+                    }
+                    CSI::Mode(Mode::SetDecPrivateMode(DecPrivateMode::Code(
+                        DecPrivateModeCode::ApplicationCursorKeys,
+                    ))) => {
+                        self.application_cursor_keys = true;
+                    }
+                    CSI::Mode(Mode::ResetDecPrivateMode(DecPrivateMode::Code(
+                        DecPrivateModeCode::ApplicationCursorKeys,
+                    ))) => {
+                        self.application_cursor_keys = false;
+                    }
+                    CSI::Mode(Mode::SetDecPrivateMode(DecPrivateMode::Code(
+                        DecPrivateModeCode::BracketedPaste,
+                    ))) => {
+                        self.bracketed_paste = true;
+                    }
+                    CSI::Mode(Mode::ResetDecPrivateMode(DecPrivateMode::Code(
+                        DecPrivateModeCode::BracketedPaste,
+                    ))) => {
+                        self.bracketed_paste = false;
+                    }
+                    CSI::Mode(Mode::SetDecPrivateMode(DecPrivateMode::Code(
+                        DecPrivateModeCode::StartBlinkingCursor,
+                    ))) => {
+                        self.set_cursor_blink(true);
+                    }
+                    CSI::Mode(Mode::ResetDecPrivateMode(DecPrivateMode::Code(
+                        DecPrivateModeCode::StartBlinkingCursor,
+                    ))) => {
+                        self.set_cursor_blink(false);
+                    }
+                    CSI::Device(device) if matches!(*device, Device::SoftReset) => {
+                        self.soft_reset();
+                    }
+                    CSI::Cursor(Cursor::CursorStyle(style)) => {
+                        // DECSCUSR; the default is a blinking block
+                        self.set_cursor_blink(matches!(
+                            style,
+                            CursorStyle::Default
+                                | CursorStyle::BlinkingBlock
+                                | CursorStyle::BlinkingUnderline
+                                | CursorStyle::BlinkingBar
+                        ));
+                        self.cursor_shape = match style {
+                            CursorStyle::Default
+                            | CursorStyle::BlinkingBlock
+                            | CursorStyle::SteadyBlock => CursorShape::Block,
+                            CursorStyle::BlinkingUnderline | CursorStyle::SteadyUnderline => {
+                                CursorShape::Underline
+                            }
+                            CursorStyle::BlinkingBar | CursorStyle::SteadyBar => CursorShape::Bar,
+                        };
+                        self.line_log_mut(self.cursor_y).unwrap().needs_paint = true;
+                    }
+                    // CHA and HPA
+                    CSI::Cursor(Cursor::CharacterAbsolute(col))
+                    | CSI::Cursor(Cursor::CharacterPositionAbsolute(col)) => {
+                        let x = col.as_zero_based().min(u8::MAX as u32) as u8;
+                        self.move_cursor(x, self.cursor_y.0);
+                    }
+                    CSI::Edit(Edit::EraseInDisplay(EraseInDisplay::EraseDisplay)) => {
+                        // Erase in display
+                        for y in 0..self.height {
+                            if let Some(line) = self.line_log_mut(LogicalY(y)) {
+                                line.clear();
+                            }
+                        }
+                    }
+                    CSI::Sgr(Sgr::Intensity(Intensity::Bold)) => {
+                        self.current_attributes.set(Attributes::BOLD, true);
+                        self.current_attributes.set(Attributes::HALF_BRIGHT, false);
+                    }
+                    CSI::Sgr(Sgr::Intensity(Intensity::Normal)) => {
+                        self.current_attributes.set(Attributes::BOLD, false);
+                        self.current_attributes.set(Attributes::HALF_BRIGHT, false);
+                    }
+                    CSI::Sgr(Sgr::Intensity(Intensity::Half)) => {
+                        self.current_attributes.set(Attributes::BOLD, false);
+                        self.current_attributes.set(Attributes::HALF_BRIGHT, true);
+                    }
+                    CSI::Sgr(Sgr::StrikeThrough(enable)) => {
+                        self.current_attributes
+                            .set(Attributes::STRIKE_THROUGH, enable);
+                    }
+                    CSI::Sgr(Sgr::Inverse(enable)) => {
+                        self.current_attributes.set(Attributes::REVERSE, enable);
+                    }
+                    CSI::Sgr(Sgr::Italic(_enable)) => {}
+                    CSI::Sgr(Sgr::Blink(_)) => {}
+                    CSI::Sgr(Sgr::Underline(Underline::None)) => {
+                        self.current_attributes.set(Attributes::UNDERLINE, false);
+                    }
+                    CSI::Sgr(Sgr::Underline(_)) => {
+                        self.current_attributes.set(Attributes::UNDERLINE, true);
+                    }
+                    CSI::Sgr(Sgr::Reset) => {
+                        self.current_attributes = Attributes::NONE;
+                        self.current_color = CellColors::default();
+                    }
+                    // The parser maps 39 and 49 to ColorSpec::Default,
+                    // 30-37/90-97 and 40-47/100-107 to palette indices
+                    // 0-7/8-15, `38;5;N` to index N and `38;2;R;G;B`
+                    // to a true color
+                    CSI::Sgr(Sgr::Foreground(spec)) => {
+                        self.current_color.fg = Color::from_spec(spec);
+                    }
+                    CSI::Sgr(Sgr::Background(spec)) => {
+                        self.current_color.bg = Color::from_spec(spec);
+                    }
+                    unhandled => {
+                        log::info!("csi: unhandled {unhandled:?}");
+                    }
+                }
+            }
+            Action::OperatingSystemCommand(osc) => {
+                log::info!("osc: unhandled {osc:?}");
+            }
+            Action::DeviceControl(ctrl) => {
+                log::info!("unhandled {ctrl:?}");
+            }
+            Action::Sixel(sixel) => {
+                let (max_width, max_height) = self.prepare_for_image();
+                if let Some(image) = crate::sixel::decode(&sixel, max_width, max_height) {
+                    self.place_image(image);
+                }
+            }
+            Action::XtGetTcap(_tcap) => {}
+            Action::KittyImage(command) => {
+                let (max_width, max_height) = self.prepare_for_image();
+                if let Some(image) = self.kitty.receive(*command, max_width, max_height) {
+                    self.place_image(image);
+                }
+            }
+        }
+    }
+
+    /// Moves the cursor to `x`, `y`, limited to the screen, and
+    /// marks the rows that it left and arrived at for painting
+    fn move_cursor(&mut self, x: u8, y: u8) {
+        self.line_log_mut(self.cursor_y).unwrap().needs_paint = true;
+        self.cursor_x = x.min(self.width - 1);
+        self.cursor_y = LogicalY(y.min(self.height - 1));
+        self.line_log_mut(self.cursor_y).unwrap().needs_paint = true;
+    }
+
+    /// Moves the cursor to column `x`, `n` rows up, for CUU and CPL.
+    /// It stops at the top margin, unless it is already above it;
+    /// in origin mode, it can't be.
+    fn move_cursor_up(&mut self, x: u8, n: u32) {
+        let (top, _) = self.scroll_region();
+        let limit = if self.cursor_y.0 >= top { top } else { 0 };
+        let y = self.cursor_y.0.saturating_sub(movement_count(n));
+        self.move_cursor(x, y.max(limit));
+    }
+
+    /// Moves the cursor to column `x`, `n` rows down, for CUD and
+    /// CNL. It stops at the bottom margin, unless it is already
+    /// below it.
+    fn move_cursor_down(&mut self, x: u8, n: u32) {
+        let (_, bottom) = self.scroll_region();
+        let limit = if self.cursor_y.0 <= bottom {
+            bottom
+        } else {
+            self.height - 1
+        };
+        let y = self.cursor_y.0.saturating_add(movement_count(n));
+        self.move_cursor(x, y.min(limit));
+    }
+
+    /// Moves the cursor to the 0-based column `x` and row `y`, which
+    /// are relative to the scroll region in origin mode, clamping
+    /// them to the screen or region
+    fn set_cursor_position(&mut self, x: u32, y: u32) {
+        let x = x.min(u8::MAX as u32) as u8;
+        let y = y.min(u8::MAX as u32) as u8;
+        if self.origin_mode {
+            let (top, bottom) = self.scroll_region();
+            self.move_cursor(x, top.saturating_add(y).min(bottom));
+        } else {
+            self.move_cursor(x, y);
+        }
+    }
+
+    /// Returns the top and bottom rows of the scroll region
+    fn scroll_region(&self) -> (u8, u8) {
+        (self.scroll_top, self.scroll_bottom)
+    }
+
+    /// Returns true if the scroll region is the whole screen, so
+    /// that scrolling can move the display and feed scrollback
+    fn scroll_region_is_screen(&self) -> bool {
+        self.scroll_top == 0 && self.scroll_bottom == self.height - 1
+    }
+
+    /// Moves the cursor down a row. On the bottom row of the scroll
+    /// region, the region scrolls up instead. Below the region, the
+    /// cursor stops at the bottom of the screen.
+    fn line_feed(&mut self) {
+        self.line_log_mut(self.cursor_y).unwrap().needs_paint = true;
+        if self.scroll_region_is_screen() {
+            self.cursor_y.0 += 1;
+            self.check_scroll();
+            return;
+        }
+        if self.cursor_y.0 == self.scroll_bottom {
+            self.scroll_region_up();
+        } else if self.cursor_y.0 + 1 < self.height {
+            self.cursor_y.0 += 1;
+        }
+        self.line_log_mut(self.cursor_y).unwrap().needs_paint = true;
+    }
+
+    /// RI: moves the cursor up a row. On the top row of the scroll
+    /// region, the region scrolls down instead.
+    fn reverse_line_feed(&mut self) {
+        self.line_log_mut(self.cursor_y).unwrap().needs_paint = true;
+        if self.cursor_y.0 == self.scroll_top {
+            self.scroll_region_down();
+        } else {
+            self.cursor_y.0 = self.cursor_y.0.saturating_sub(1);
+        }
+        self.line_log_mut(self.cursor_y).unwrap().needs_paint = true;
+    }
+
+    /// Moves the lines of the scroll region up by one, leaving a
+    /// blank line at the bottom of it. Unlike scrolling the whole
+    /// screen, this redraws the lines in place, and the line that
+    /// leaves the region is not kept in scrollback.
+    fn scroll_region_up(&mut self) {
+        let (top, bottom) = self.scroll_region();
+        for y in top..bottom {
+            let below = *self.line_log(LogicalY(y + 1)).unwrap();
+            *self.line_log_mut(LogicalY(y)).unwrap() = below;
+        }
+        self.line_log_mut(LogicalY(bottom)).unwrap().clear();
+        self.mark_region_for_paint();
+    }
+
+    /// Moves the lines of the scroll region down by one, leaving a
+    /// blank line at the top of it
+    fn scroll_region_down(&mut self) {
+        let (top, bottom) = self.scroll_region();
+        for y in (top..bottom).rev() {
+            let above = *self.line_log(LogicalY(y)).unwrap();
+            *self.line_log_mut(LogicalY(y + 1)).unwrap() = above;
+        }
+        self.line_log_mut(LogicalY(top)).unwrap().clear();
+        self.mark_region_for_paint();
+    }
+
+    fn mark_region_for_paint(&mut self) {
+        let (top, bottom) = self.scroll_region();
+        self.mark_rows_for_paint(top, bottom);
+    }
+
+    fn mark_rows_for_paint(&mut self, top: u8, bottom: u8) {
+        for y in top..=bottom {
+            self.line_log_mut(LogicalY(y)).unwrap().needs_paint = true;
+        }
+    }
+
+    /// DECOM: switches cursor addressing between the screen and
+    /// the scroll region, and homes the cursor to the top left of
+    /// whichever is now in use, as a VT100 does
+    /// DECSTR: returns the modes, margins, SGR attributes and cursor
+    /// style to their defaults, leaving what is on the screen, and
+    /// where the cursor is, as they are
+    pub fn soft_reset(&mut self) {
+        self.current_attributes = Attributes::NONE;
+        self.current_color = CellColors::default();
+        self.insert_mode = false;
+        self.auto_wrap = true;
+        self.reverse_wrap = false;
+        self.origin_mode = false;
+        self.bracketed_paste = false;
+        self.application_cursor_keys = false;
+        self.scroll_top = 0;
+        self.scroll_bottom = self.height - 1;
+        self.cursor_shape = CursorShape::default();
+        self.set_cursor_blink(true);
+    }
+
+    fn set_origin_mode(&mut self, enable: bool) {
+        self.origin_mode = enable;
+        let top = if enable { self.scroll_region().0 } else { 0 };
+        self.move_cursor(0, top);
+    }
+
+    /// Frees the images that are no longer shown, to make room for
+    /// a new one, and returns the largest size, in pixels, that an
+    /// image can be shown at the cursor
+    fn prepare_for_image(&mut self) -> (usize, usize) {
+        let lines = &self.lines;
+        self.images.retain(|image| {
+            lines
+                .iter()
+                .any(|line| line.image.is_some_and(|slice| slice.id == image.id))
+        });
+
+        let cell_width = self.font.character_size.width + self.font.character_spacing;
+        let col = self.cursor_x.min(self.width - 1) as u32;
+        let max_width = SCREEN_WIDTH as u32 - col * cell_width;
+        (max_width as usize, SCREEN_HEIGHT as usize)
+    }
+
+    /// Shows `image` at the cursor, leaving the cursor at the
+    /// start of the line below it, as xterm does for sixel
+    fn place_image(&mut self, mut image: Image) {
+        let cell_height = self.font.character_size.height as usize;
+        self.last_image_id = self.last_image_id.wrapping_add(1);
+        let id = self.last_image_id;
+        image.id = id;
+        image.col = self.cursor_x.min(self.width - 1);
+        let rows = image.height.div_ceil(cell_height);
+        self.images.push(image);
+
+        for row in 0..rows {
+            let line = self.line_log_mut(self.cursor_y).unwrap();
+            line.image = Some(ImageSlice {
+                id,
+                top: (row * cell_height) as u16,
+            });
+            line.needs_paint = true;
+            self.line_feed();
+        }
+        self.cursor_x = 0;
+    }
+
+    /// Paints the part of an image that is shown on the line
+    /// whose top is at pixel row `row_y` in video ram
+    fn paint_image(&self, surface: &mut impl CellSurface, slice: ImageSlice, row_y: u32) {
+        let Some(image) = self.images.iter().find(|image| image.id == slice.id) else {
+            return;
+        };
+        let cell_width = self.font.character_size.width + self.font.character_spacing;
+        let x = image.col as u32 * cell_width;
+        for dy in 0..self.font.character_size.height {
+            let Some(pixels) = image.row(slice.top as usize + dy as usize) else {
+                break;
+            };
+            surface.draw_pixels(x, (row_y + dy) % VIDEO_RAM_HEIGHT, pixels);
+        }
+    }
+
+    /// Turns blinking of the cursor on or off. The cursor is
+    /// shown straight away either way, so that it doesn't seem
+    /// to vanish when a program changes the setting.
+    fn set_cursor_blink(&mut self, blink: bool) {
+        self.cursor_blink = blink;
+        self.cursor_blink_epoch = Instant::now();
+        self.line_log_mut(self.cursor_y).unwrap().needs_paint = true;
+    }
+
+    /// Returns whether the cursor should be shown right now,
+    /// given the blink setting
+    fn cursor_visible(&self) -> bool {
+        !self.cursor_blink
+            || (self.cursor_blink_epoch.elapsed().as_millis() / CURSOR_BLINK_MS) % 2 == 0
+    }
+
+    /// Draws the bar cursor down the left edge of column `x`
+    /// of the line whose top is at pixel row `row_y` in video ram
+    fn paint_bar_cursor(&self, surface: &mut impl CellSurface, x: u8, row_y: u32) {
+        let cell_width = self.font.character_size.width + self.font.character_spacing;
+        let bar = [BAR_CURSOR_COLOR; BAR_CURSOR_WIDTH];
+        for dy in 0..self.font.character_size.height {
+            surface.draw_pixels(x as u32 * cell_width, (row_y + dy) % VIDEO_RAM_HEIGHT, &bar);
+        }
+    }
+
+    /// Blanks `cells` of the cursor row, giving them the current
+    /// attributes and colors, as erasing the line does
+    fn blank_cells(&mut self, cells: Range<usize>) {
+        let attributes = self.current_attributes;
+        let color = self.current_color;
+        let line = self.line_log_mut(self.cursor_y).unwrap();
+        line.ascii[cells.clone()].fill(0x20);
+        line.attributes[cells.clone()].fill(attributes);
+        line.colors[cells].fill(color);
+        line.needs_paint = true;
+    }
+
+    /// ICH: moves the cells from the cursor onward `n` to the
+    /// right, losing those that pass the right edge, and blanks
+    /// the cells that they leave
+    fn insert_characters(&mut self, n: u8) {
+        let x = self.cursor_x as usize;
+        let width = self.width as usize;
+        let n = (n as usize).min(width - x);
+        let line = self.line_log_mut(self.cursor_y).unwrap();
+        line.ascii.copy_within(x..width - n, x + n);
+        line.attributes.copy_within(x..width - n, x + n);
+        line.colors.copy_within(x..width - n, x + n);
+        if line.content_end as usize > x {
+            line.content_end = (line.content_end as usize + n).min(width) as u8;
+        }
+        self.blank_cells(x..x + n);
+    }
+
+    /// DCH: removes `n` cells at the cursor, moving the rest of
+    /// the line left, and blanks the cells left at the right edge
+    fn delete_characters(&mut self, n: u8) {
+        let x = self.cursor_x as usize;
+        let width = self.width as usize;
+        let n = (n as usize).min(width - x);
+        let line = self.line_log_mut(self.cursor_y).unwrap();
+        line.ascii.copy_within(x + n..width, x);
+        line.attributes.copy_within(x + n..width, x);
+        line.colors.copy_within(x + n..width, x);
+        if line.content_end as usize > x {
+            line.content_end = (line.content_end as usize).saturating_sub(n).max(x) as u8;
+        }
+        self.blank_cells(width - n..width);
+    }
+
+    /// ECH: blanks `n` cells from the cursor, without moving it
+    fn erase_characters(&mut self, n: u8) {
+        let x = self.cursor_x as usize;
+        let n = (n as usize).min(self.width as usize - x);
+        self.blank_cells(x..x + n);
+    }
+
+    /// IL: inserts `n` blank lines at the cursor row, moving the
+    /// rows below it down, and losing those that pass the bottom
+    /// of the scroll region. The cursor moves to the left edge.
+    /// Outside the scroll region, this does nothing.
+    fn insert_lines(&mut self, n: u8) {
+        let (top, bottom) = self.scroll_region();
+        let y = self.cursor_y.0;
+        if y < top || y > bottom {
+            return;
+        }
+        let n = n.min(bottom - y + 1);
+        for dst in (y + n..=bottom).rev() {
+            let src = *self.line_log(LogicalY(dst - n)).unwrap();
+            *self.line_log_mut(LogicalY(dst)).unwrap() = src;
+        }
+        for row in y..y + n {
+            self.line_log_mut(LogicalY(row)).unwrap().clear();
+        }
+        self.mark_rows_for_paint(y, bottom);
+        self.move_cursor(0, y);
+    }
+
+    /// DL: removes `n` lines at the cursor row, moving the rows
+    /// below it up, and blanks the rows that they leave at the
+    /// bottom of the scroll region. The cursor moves to the left
+    /// edge. Outside the scroll region, this does nothing.
+    fn delete_lines(&mut self, n: u8) {
+        let (top, bottom) = self.scroll_region();
+        let y = self.cursor_y.0;
+        if y < top || y > bottom {
+            return;
+        }
+        let n = n.min(bottom - y + 1);
+        // Empty when every row from the cursor down is deleted
+        for dst in y..bottom + 1 - n {
+            let src = *self.line_log(LogicalY(dst + n)).unwrap();
+            *self.line_log_mut(LogicalY(dst)).unwrap() = src;
+        }
+        for row in bottom + 1 - n..=bottom {
+            self.line_log_mut(LogicalY(row)).unwrap().clear();
+        }
+        self.mark_rows_for_paint(y, bottom);
+        self.move_cursor(0, y);
+    }
+
+    fn erase_to_end_of_line(&mut self) {
+        let x = self.cursor_x;
+        let current_attributes = self.current_attributes;
+        let current_color = self.current_color;
+        let line = self.line_log_mut(self.cursor_y).unwrap();
+        for (ascii, (attr, color)) in line
+            .ascii
+            .iter_mut()
+            .zip(line.attributes.iter_mut().zip(line.colors.iter_mut()))
+            .skip(x as usize)
+        {
+            *ascii = 0x20;
+            *attr = current_attributes;
+            *color = current_color;
+        }
+        line.content_end = line.content_end.min(x);
+        line.needs_paint = true;
+    }
+
+    fn print(&mut self, c: char) {
+        self.last_char = Some(c);
+        let ascii = if c.is_ascii() {
+            c as u32 as u8
+        } else {
+            ascii_fallback(c)
+        };
+
+        let cursor_x = self.cursor_x as usize;
+        let width = self.width as usize;
+        debug_assert!(cursor_x < width && width <= MAX_COLS);
+        let attributes = self.current_attributes;
+        let color = self.current_color;
+        let insert_mode = self.insert_mode;
+        let line = self.line_log_mut(self.cursor_y).unwrap();
+        line.needs_paint = true;
+        if insert_mode && cursor_x + 1 < width {
+            // Make room by shifting the rest of the line to the
+            // right, losing whatever is in the rightmost column
+            line.ascii.copy_within(cursor_x..width - 1, cursor_x + 1);
+            line.attributes
+                .copy_within(cursor_x..width - 1, cursor_x + 1);
+            line.colors.copy_within(cursor_x..width - 1, cursor_x + 1);
+            line.content_end = (line.content_end + 1).min(width as u8);
+        }
+        line.ascii[cursor_x] = ascii;
+        line.attributes[cursor_x] = attributes;
+        line.colors[cursor_x] = color;
+        line.content_end = line.content_end.max(cursor_x as u8 + 1);
+        self.cursor_x += 1;
+        if self.cursor_x >= self.width {
+            if !self.auto_wrap {
+                // Further characters overwrite the rightmost column
+                self.cursor_x = self.width - 1;
+                return;
+            }
+            self.cursor_x = 0;
+            self.line_feed();
+        }
+    }
+}
+
+const MAX_LINES: usize = 60;
+
+pub struct ScreenModel {
+    lines: [Line; MAX_LINES],
+    /// cursor x,y in logical coordinates
+    cursor_x: u8,
+    cursor_y: LogicalY,
+    cursor_shape: CursorShape,
+    /// Whether the cursor blinks, set by DECSCUSR and by
+    /// DECSET/DECRST 12
+    cursor_blink: bool,
+    /// Blinking is timed from here, so that the cursor
+    /// shows as soon as blinking is turned on
+    cursor_blink_epoch: Instant,
+    /// Whether the cursor was shown by the last paint
+    cursor_painted: bool,
+    /// IRM: whether printing shifts the rest of the line
+    /// to the right, rather than replacing the cell
+    insert_mode: bool,
+    /// DECAWM: whether printing in the rightmost column
+    /// moves the cursor to the start of the next line
+    auto_wrap: bool,
+    /// DECSET 45: whether backspace in the leftmost column moves
+    /// to the rightmost column of the line above
+    reverse_wrap: bool,
+    /// DECOM: whether cursor positions are relative to the top
+    /// of the scroll region, rather than of the screen
+    origin_mode: bool,
+    /// DECSET 2004: whether pasted text is to be sent between
+    /// `ESC [ 200 ~` and `ESC [ 201 ~`
+    bracketed_paste: bool,
+    /// DECCKM: whether the cursor keys are to be sent as
+    /// `ESC O A` rather than `ESC [ A`
+    application_cursor_keys: bool,
+    /// The most recently printed character, for REP
+    last_char: Option<char>,
+    /// DECSTBM: the top and bottom rows of the scroll region
+    scroll_top: u8,
+    scroll_bottom: u8,
+    /// One bit per column, set where there is a tab stop
+    tab_stops: u128,
+    current_attributes: Attributes,
+    current_color: CellColors,
+    pub width: u8,
+    pub height: u8,
+    font: &'static MonoFont<'static>,
+    full_repaint: bool,
+    /// physical offset to logical row 0
+    first_line_idx: u8,
+    /// addressing to video ram for logical row 0
+    pixel_offset_first_line: u16,
+    /// set when BEL is received, until taken by take_bell
+    bell: bool,
+    /// Lines that have scrolled off the top of the screen,
+    /// oldest first
+    scrollback: VecDeque<Line>,
+    /// Images that are shown on the lines of the screen
+    images: Vec<Image>,
+    /// The id given to the most recent image
+    last_image_id: u16,
+    /// Collects kitty images that arrive in several chunks
+    kitty: KittyReceiver,
+    /// The maximum number of lines to keep in scrollback
+    scrollback_limit: usize,
+    /// How many lines back into scrollback the view is scrolled;
+    /// 0 shows the screen itself
+    view_offset: usize,
+    /// Set when the view of scrollback needs to be painted again
+    view_changed: bool,
+    /// Replies to queries, such as a cursor position report,
+    /// waiting to be sent back to whatever produced the output
+    responses: Vec<u8>,
+    /// A notification that is drawn over the top line
+    status_line: Option<String>,
+}
+
+impl ScreenModel {
+    pub fn clear(&mut self) {
+        for line in &mut self.lines {
+            line.clear();
+        }
+        self.cursor_x = 0;
+        self.cursor_y = LogicalY(0);
+        self.current_attributes = Attributes::NONE;
+        self.current_color = CellColors::default();
+        self.first_line_idx = 0;
+        self.full_repaint = true;
+        self.pixel_offset_first_line = 0;
+        self.scroll_top = 0;
+        self.scroll_bottom = self.height - 1;
+        self.images.clear();
+        self.view_offset = 0;
+    }
+
+    /// Adds the rows of `other`, down to its last non-blank row,
+    /// below what is already shown, so that they stay on screen
+    /// after `other` is gone
+    pub fn append_screen(&mut self, other: &ScreenModel) {
+        let Some(last_row) = (0..other.height)
+            .rev()
+            .find(|&y| other.line_log(LogicalY(y)).unwrap().content_end > 0)
+        else {
+            return;
+        };
+        if self.cursor_x > 0 {
+            self.cursor_x = 0;
+            self.line_feed();
+        }
+        for y in 0..=last_row {
+            let mut line = *other.line_log(LogicalY(y)).unwrap();
+            line.image = None;
+            line.needs_paint = true;
+            *self.line_log_mut(self.cursor_y).unwrap() = line;
+            self.line_feed();
+        }
+    }
+
+    /// Sets the maximum number of lines retained in scrollback,
+    /// discarding the oldest lines if there are now too many
+    pub fn set_scrollback_limit(&mut self, limit: usize) {
+        self.scrollback_limit = limit;
+        if self.scrollback.len() > limit {
+            self.scrollback.drain(..self.scrollback.len() - limit);
+        }
+        self.scrollback.shrink_to_fit();
+        self.set_view_offset(self.view_offset.min(self.scrollback.len()));
+    }
+
+    /// Scrolls the view `lines` further back into scrollback
+    pub fn scroll_view_up(&mut self, lines: usize) {
+        let offset = self.view_offset.saturating_add(lines);
+        self.set_view_offset(offset.min(self.scrollback.len()));
+    }
+
+    /// Scrolls the view `lines` back toward the screen
+    pub fn scroll_view_down(&mut self, lines: usize) {
+        self.set_view_offset(self.view_offset.saturating_sub(lines));
+    }
+
+    /// Returns the view from scrollback to the screen
+    pub fn reset_view(&mut self) {
+        self.set_view_offset(0);
+    }
+
+    fn set_view_offset(&mut self, offset: usize) {
+        if offset == self.view_offset {
+            return;
+        }
+        self.view_offset = offset;
+        self.view_changed = true;
+        if offset == 0 {
+            // The view was drawn over the screen's contents
+            self.full_repaint = true;
+        }
+    }
+
+    pub fn scrollback_limit(&self) -> usize {
+        self.scrollback_limit
+    }
+
+    /// Returns the number of lines in scrollback, and the number
+    /// of bytes of heap that they occupy
+    pub fn scrollback_usage(&self) -> (usize, usize) {
+        (
+            self.scrollback.len(),
+            self.scrollback.capacity() * core::mem::size_of::<Line>(),
+        )
+    }
+
+    fn push_scrollback(&mut self, mut line: Line) {
+        // Images are too large to keep for long, so scrollback
+        // only keeps the text
+        line.image = None;
+        if self.scrollback_limit == 0 {
+            return;
+        }
+        while self.scrollback.len() >= self.scrollback_limit {
+            self.scrollback.pop_front();
+        }
+        // Growing the deque may need more memory than is free;
+        // if so, keep fewer lines rather than failing
+        while self.scrollback.try_reserve(1).is_err() {
+            if self.scrollback.pop_front().is_none() {
+                return;
+            }
+        }
+        self.scrollback.push_back(line);
+        if self.view_offset > 0 {
+            // Keep the same lines in view as the screen scrolls
+            self.set_view_offset((self.view_offset + 1).min(self.scrollback.len()));
+        }
+    }
+
+    /// Queues a reply to be sent back to the producer of the output.
+    /// If there isn't room for all of it, it is dropped entirely,
+    /// as a truncated escape sequence would confuse the receiver.
+    fn respond(&mut self, bytes: &[u8]) {
+        if self.responses.len() + bytes.len() > MAX_RESPONSE_BYTES {
+            log::warn!("response queue is full; dropping {bytes:x?}");
+            return;
+        }
+        self.responses.extend_from_slice(bytes);
+    }
+
+    /// Takes the queued responses, leaving the queue empty
+    pub fn take_responses(&mut self) -> Vec<u8> {
+        core::mem::take(&mut self.responses)
+    }
+
+    /// Returns true if the application asked for bracketed paste
+    pub fn application_cursor_keys(&self) -> bool {
+        self.application_cursor_keys
+    }
+
+    pub fn bracketed_paste(&self) -> bool {
+        self.bracketed_paste
+    }
+
+    /// Returns true if BEL was received since the last call
+    pub fn take_bell(&mut self) -> bool {
+        core::mem::take(&mut self.bell)
+    }
+
+    fn check_scroll(&mut self) {
+        log::trace!(
+            "consider scroll, y={:?}, height={} first_line_idx={} pixel={}",
+            self.cursor_y,
+            self.height,
+            self.first_line_idx,
+            self.pixel_offset_first_line,
+        );
+        let mut cursor_y = self.cursor_y;
+        while cursor_y.0 >= self.height {
+            let top = *self.line_log(LogicalY(0)).unwrap();
+            self.push_scrollback(top);
+            self.line_log_mut(cursor_y).unwrap().clear();
+            self.first_line_idx += 1;
+            self.pixel_offset_first_line += self.font.character_size.height as u16;
+            cursor_y.0 -= 1;
+        }
+
+        self.pixel_offset_first_line %= VIDEO_RAM_HEIGHT as u16;
+        self.cursor_y = cursor_y;
+        self.line_log_mut(self.cursor_y).unwrap().needs_paint = true;
+        log::trace!(
+            "done scroll -> y={:?}, cell_height={} height={} first_line_idx={} pixel={}",
+            self.cursor_y,
+            self.font.character_size.height,
+            self.height,
+            self.first_line_idx,
+            self.pixel_offset_first_line,
+        );
+    }
+
+    fn line_phys(&self, phys: PhysicalY) -> Option<&Line> {
+        self.lines.get(phys.0 as usize)
+    }
+    fn line_phys_mut(&mut self, phys: PhysicalY) -> Option<&mut Line> {
+        self.lines.get_mut(phys.0 as usize)
+    }
+
+    fn log_to_phys(&self, log: LogicalY) -> Option<PhysicalY> {
+        let idx = (self.first_line_idx + log.0) % MAX_LINES as u8;
+        Some(PhysicalY(idx))
+    }
+
+    fn line_log(&self, log: LogicalY) -> Option<&Line> {
+        self.line_phys(self.log_to_phys(log)?)
+    }
+    fn line_log_mut(&mut self, log: LogicalY) -> Option<&mut Line> {
+        self.line_phys_mut(self.log_to_phys(log)?)
+    }
+
+    /// The index into `fonts()` of the current font
+    pub fn font_index(&self) -> Option<usize> {
+        FONTS.iter().position(|&(_, f)| f == self.font)
+    }
+
+    /// Switches to the font at `idx` in `fonts()`, returning
+    /// false if there is no such font
+    pub fn set_font(&mut self, idx: usize) -> bool {
+        let Some(&(_, font)) = FONTS.get(idx) else {
+            return false;
+        };
+        if font != self.font {
+            self.change_font(font);
+        }
+        true
+    }
+
+    pub fn increase_font(&mut self) {
+        let Some(idx) = self.font_index() else {
+            return;
+        };
+        self.set_font(idx + 1);
+    }
+
+    pub fn decrease_font(&mut self) {
+        let Some(idx) = self.font_index() else {
+            return;
+        };
+        self.set_font(idx.saturating_sub(1));
+    }
+
+    /// Shows `text` in place of the top line, or puts the line back
+    /// when None. The contents of the screen are left as they are,
+    /// so that this doesn't disturb whatever is running.
+    pub fn set_status_line(&mut self, text: Option<&str>) {
+        if self.status_line.as_deref() == text {
+            return;
+        }
+        self.status_line = text.map(String::from);
+        self.line_log_mut(LogicalY(0)).unwrap().needs_paint = true;
+    }
+
+    /// Causes the next paint to redraw everything, for use
+    /// after something else has drawn on the display
+    pub fn request_full_repaint(&mut self) {
+        self.full_repaint = true;
+    }
+
+    /// Switches to `font`, which changes the number of rows and
+    /// columns. Lines are not re-flowed. The content that was on
+    /// screen stays at the top, up to and including the cursor
+    /// row or the last non-blank row, whichever is lower. If that
+    /// no longer fits, the oldest rows move into scrollback so that
+    /// the most recent rows remain visible. All other rows are
+    /// cleared, so that nothing stale is revealed by scrolling.
+    fn change_font(&mut self, font: &'static MonoFont) {
+        let old_height = self.height;
+
+        // Images are placed in terms of the cells of the old font
+        self.images.clear();
+        for line in &mut self.lines {
+            line.image = None;
+        }
+
+        self.font = font;
+        self.full_repaint = true;
+        self.view_offset = 0;
+        self.width = columns(font);
+        self.height = rows(font);
+        // The margins were in terms of the old rows
+        self.scroll_top = 0;
+        self.scroll_bottom = self.height - 1;
+
+        let last_non_blank = (0..old_height).rev().find(|&y| {
+            self.line_log(LogicalY(y))
+                .is_some_and(|l| l.content_end > 0)
+        });
+        let used = last_non_blank.unwrap_or(0).max(self.cursor_y.0) + 1;
+
+        let excess = used.saturating_sub(self.height);
+        for _ in 0..excess {
+            let top = *self.line_log(LogicalY(0)).unwrap();
+            self.push_scrollback(top);
+            self.first_line_idx = (self.first_line_idx + 1) % MAX_LINES as u8;
+        }
+        // The cursor may be above content that stays, such as when
+        // a full screen program has homed it
+        self.cursor_y.0 = self.cursor_y.0.saturating_sub(excess);
+        self.cursor_x = self.cursor_x.min(self.width - 1);
+
+        for y in used - excess..MAX_LINES as u8 {
+            self.line_log_mut(LogicalY(y)).unwrap().clear();
+        }
+    }
+
+    /// Paints the view of scrollback, which is `view_offset` lines
+    /// back from the screen, whenever it or the screen changes. It
+    /// is drawn from the top of video ram, without scrolling, as it
+    /// is painted as a whole each time.
+    fn paint_scrollback(&mut self, surface: &mut impl CellSurface) {
+        // Output that arrives while scrolled back may change the
+        // rows of the screen that are in view
+        for line in &mut self.lines {
+            if core::mem::take(&mut line.needs_paint) {
+                self.view_changed = true;
+            }
+        }
+        if !core::mem::take(&mut self.view_changed) {
+            return;
+        }
+
+        surface.erase();
+        surface.set_scroll_offset(0);
+        let font = self.font;
+        let history = self.scrollback.len();
+        let first = history - self.view_offset;
+        for row in 0..self.height as usize {
+            let row_y = row as u32 * font.character_size.height;
+            if row == 0 {
+                let text =
+                    alloc::format!("Scrollback: {} of {history} lines back", self.view_offset);
+                let banner = Cluster {
+                    text: &text,
+                    start_col: 0,
+                    end_col: MAX_COLS,
+                    attributes: Attributes::REVERSE,
+                    colors: CellColors::default(),
+                };
+                surface.draw_cluster(&banner, font, row_y);
+                continue;
+            }
+            let idx = first + row;
+            let line = match self.scrollback.get(idx) {
+                Some(line) => line,
+                None => self.line_log(LogicalY((idx - history) as u8)).unwrap(),
+            };
+            for cluster in line.cluster(None, self.cursor_shape) {
+                surface.draw_cluster(&cluster, font, row_y);
+            }
+        }
+    }
+
+    /// Paints the lines that have changed since the last call
+    /// into `surface`, or all of them if a full repaint is needed.
+    pub fn paint(&mut self, surface: &mut impl CellSurface) {
+        if self.view_offset > 0 {
+            self.paint_scrollback(surface);
+            return;
+        }
+        let start = Instant::now();
+        let is_full_repaint = self.full_repaint;
+        if is_full_repaint {
+            surface.erase();
+            self.full_repaint = false;
+            self.pixel_offset_first_line = 0;
+        }
+
+        let font = self.font;
+
+        let pixel_offset = self.pixel_offset_first_line;
+
+        let boundary_y =
+            (VIDEO_RAM_HEIGHT / font.character_size.height) * font.character_size.height;
+        let boundary_height = VIDEO_RAM_HEIGHT - boundary_y;
+
+        let mut num_changed = 0;
+        let mut row_y = pixel_offset as u32;
+
+        let cursor_x = self.cursor_x;
+        let cursor_y = self.cursor_y;
+        let cursor_shape = self.cursor_shape;
+        let cursor_visible = self.cursor_visible();
+        if cursor_visible != self.cursor_painted {
+            // Blink: redraw the line with the cursor shown or hidden
+            self.cursor_painted = cursor_visible;
+            self.line_log_mut(cursor_y).unwrap().needs_paint = true;
+        }
+        let width = self.width as usize;
+        let status_line = self.status_line.take();
+
+        for idx in 0..self.height {
+            let y = LogicalY(idx);
+            let phys_y = self.log_to_phys(y).unwrap();
+            let line = self.line_phys_mut(phys_y).unwrap();
+
+            if idx == 0 && status_line.is_some() {
+                // Scrolling moves the line that it was drawn over,
+                // so it is simplest to always draw it again
+                line.needs_paint = true;
+            }
+            if !line.needs_paint && !is_full_repaint {
+                row_y = (row_y + font.character_size.height) % VIDEO_RAM_HEIGHT;
+                continue;
+            }
+            line.needs_paint = false;
+            num_changed += 1;
+
+            if let (0, Some(text)) = (idx, status_line.as_deref()) {
+                let end = text
+                    .char_indices()
+                    .nth(width)
+                    .map_or(text.len(), |(i, _)| i);
+                let status_cluster = Cluster {
+                    text: &text[..end],
+                    start_col: 0,
+                    end_col: MAX_COLS,
+                    attributes: Attributes::REVERSE,
+                    colors: CellColors::default(),
+                };
+                surface.draw_cluster(&status_cluster, font, row_y);
+                row_y = (row_y + font.character_size.height) % VIDEO_RAM_HEIGHT;
+                continue;
+            }
+
+            let image = line.image;
+            let line_cursor_x = if y == cursor_y && cursor_visible {
+                Some(cursor_x)
+            } else {
+                None
+            };
+            for cluster in line.cluster(line_cursor_x, cursor_shape) {
+                //log::info!("line {idx} cluster {cluster:?}");
+                surface.draw_cluster(&cluster, font, row_y);
+            }
+            if let Some(slice) = image {
+                self.paint_image(surface, slice, row_y);
+            }
+            if line_cursor_x.is_some() && cursor_shape == CursorShape::Bar {
+                self.paint_bar_cursor(surface, cursor_x, row_y);
+            }
+
+            row_y = (row_y + font.character_size.height) % VIDEO_RAM_HEIGHT;
+        }
+        self.status_line = status_line;
+
+        if num_changed > 0 {
+            //log::info!("clear next row @ {row_y}");
+
+            let blank_cluster = Cluster {
+                text: "",
+                start_col: 0,
+                end_col: MAX_COLS,
+                attributes: Attributes::NONE,
+                colors: CellColors::default(),
+            };
+            surface.draw_cluster(&blank_cluster, font, row_y);
+            if boundary_height > 0 {
+                //log::info!("clear EXTRA row @ {}", row_y + font.character_size.height);
+                surface.draw_cluster(&blank_cluster, font, row_y + font.character_size.height);
+            }
+
+            log::trace!(
+                "render of {num_changed} lines took {}ms. boundary_y={boundary_y} h={boundary_height} baseline={} pixel_offset={pixel_offset}",
+                start.elapsed().as_millis(),
+                font.baseline
+            );
+
+            surface.set_scroll_offset(pixel_offset % VIDEO_RAM_HEIGHT as u16);
+        }
+    }
+}
+
+impl Default for ScreenModel {
+    fn default() -> ScreenModel {
+        let font = FONTS[2].1;
+        ScreenModel {
+            cursor_x: 0,
+            cursor_y: LogicalY(0),
+            cursor_shape: CursorShape::default(),
+            cursor_blink: true,
+            cursor_blink_epoch: Instant::now(),
+            cursor_painted: false,
+            auto_wrap: true,
+            insert_mode: false,
+            reverse_wrap: false,
+            origin_mode: false,
+            bracketed_paste: false,
+            application_cursor_keys: false,
+            last_char: None,
+            tab_stops: DEFAULT_TAB_STOPS,
+            scroll_top: 0,
+            scroll_bottom: rows(font) - 1,
+            width: columns(font),
+            height: rows(font),
+            font,
+
+            lines: [Line::default(); MAX_LINES],
+            full_repaint: true,
+            first_line_idx: 0,
+            pixel_offset_first_line: 0,
+            bell: false,
+            scrollback: VecDeque::new(),
+            scrollback_limit: 0,
+            view_offset: 0,
+            view_changed: false,
+            images: Vec::new(),
+            last_image_id: 0,
+            kitty: KittyReceiver::default(),
+            responses: Vec::new(),
+            status_line: None,
+            current_attributes: Attributes::NONE,
+            current_color: CellColors::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wezterm_escape_parser::parser::Parser;
+
+    /// Feeds `bytes` through the escape parser into `model`,
+    /// as Screen::parse_bytes does
+    fn parse_bytes(model: &mut ScreenModel, bytes: &[u8]) {
+        Parser::new().parse(bytes, |action| model.apply_action(action));
+    }
+
+    /// Returns the text of row `y`, without trailing spaces
+    fn row_text(model: &ScreenModel, y: u8) -> String {
+        let line = model.line_log(LogicalY(y)).unwrap();
+        let text = core::str::from_utf8(&line.ascii[..model.width as usize]).unwrap();
+        String::from(text.trim_end())
+    }
+
+    /// Fills every row of the screen with "line N", leaving the
+    /// cursor at the end of the last row
+    fn fill_screen(model: &mut ScreenModel) {
+        for y in 0..model.height {
+            if y > 0 {
+                parse_bytes(model, b"\r\n");
+            }
+            parse_bytes(model, alloc::format!("line {y}").as_bytes());
+        }
+    }
+
+    #[test]
+    fn font_change_to_larger_keeps_most_recent_rows() {
+        let mut model = ScreenModel::default();
+        fill_screen(&mut model);
+        let old_height = model.height;
+
+        assert!(model.set_font(FONTS.len() - 1));
+        let excess = old_height - model.height;
+        for y in 0..model.height {
+            assert_eq!(row_text(&model, y), alloc::format!("line {}", excess + y));
+        }
+        assert_eq!(model.cursor_y, LogicalY(model.height - 1));
+        for y in model.height..MAX_LINES as u8 {
+            assert_eq!(row_text(&model, y), "");
+        }
+    }
+
+    #[test]
+    fn font_change_to_smaller_keeps_rows_at_top() {
+        let mut model = ScreenModel::default();
+        assert!(model.set_font(FONTS.len() - 1));
+        fill_screen(&mut model);
+        let old_height = model.height;
+
+        assert!(model.set_font(0));
+        for y in 0..old_height {
+            assert_eq!(row_text(&model, y), alloc::format!("line {y}"));
+        }
+        for y in old_height..model.height {
+            assert_eq!(row_text(&model, y), "");
+        }
+        assert_eq!(model.cursor_y, LogicalY(old_height - 1));
+    }
+
+    #[test]
+    fn font_change_with_cursor_above_content() {
+        let mut model = ScreenModel::default();
+        fill_screen(&mut model);
+        parse_bytes(&mut model, b"\x1b[H");
+
+        assert!(model.set_font(FONTS.len() - 1));
+        assert_eq!(model.cursor_y, LogicalY(0));
+        assert!(model.cursor_y.0 < model.height);
+    }
+
+    #[test]
+    fn font_change_keeps_cursor_within_the_width() {
+        let mut model = ScreenModel::default();
+        assert!(model.set_font(0));
+        parse_bytes(&mut model, b"\x1b[1;999H");
+        assert_eq!(model.cursor_x, model.width - 1);
+
+        assert!(model.set_font(FONTS.len() - 1));
+        assert_eq!(model.cursor_x, model.width - 1);
+        parse_bytes(&mut model, b"x");
+    }
+
+    #[test]
+    fn cup_moves_to_one_based_position() {
+        let mut model = ScreenModel::default();
+        parse_bytes(&mut model, b"\x1b[5;10H");
+        assert_eq!((model.cursor_x, model.cursor_y), (9, LogicalY(4)));
+
+        // HVP is the same
+        parse_bytes(&mut model, b"\x1b[2;3f");
+        assert_eq!((model.cursor_x, model.cursor_y), (2, LogicalY(1)));
+
+        // and positions are relative to the scroll region in origin mode
+        parse_bytes(&mut model, b"\x1b[3;8r\x1b[?6h\x1b[5;10H");
+        assert_eq!((model.cursor_x, model.cursor_y), (9, LogicalY(6)));
+        parse_bytes(&mut model, b"\x1b[99;1H");
+        assert_eq!(model.cursor_y, LogicalY(7));
+    }
+
+    #[test]
+    fn cnl_and_cpl_stop_at_the_margins() {
+        let mut model = ScreenModel::default();
+        parse_bytes(&mut model, b"\x1b[3;8r\x1b[5;10H\x1b[9E");
+        assert_eq!((model.cursor_x, model.cursor_y), (0, LogicalY(7)));
+        parse_bytes(&mut model, b"\x1b[5;10H\x1b[9F");
+        assert_eq!((model.cursor_x, model.cursor_y), (0, LogicalY(2)));
+
+        // Outside the region, they stop at the edge of the screen
+        parse_bytes(&mut model, b"\x1b[1;10H\x1b[F");
+        assert_eq!((model.cursor_x, model.cursor_y), (0, LogicalY(0)));
+        parse_bytes(&mut model, b"\x1b[10;10H\x1b[99E");
+        assert_eq!(model.cursor_y, LogicalY(model.height - 1));
+
+        // In origin mode, the cursor can't leave the region
+        parse_bytes(&mut model, b"\x1b[?6h\x1b[2E\x1b[99F");
+        assert_eq!(model.cursor_y, LogicalY(2));
+    }
+
+    #[test]
+    fn sgr_39_and_49_reset_to_the_default_colors() {
+        let mut model = ScreenModel::default();
+        parse_bytes(&mut model, b"\x1b[31;42mA\x1b[39mB\x1b[49mC");
+        let line = model.line_log(LogicalY(0)).unwrap();
+        assert_eq!(line.colors[0].fg, Color::Indexed(1));
+        assert_eq!(line.colors[0].bg, Color::Indexed(2));
+        assert_eq!(line.colors[1].fg, Color::Default);
+        assert_eq!(line.colors[1].bg, Color::Indexed(2));
+        assert_eq!(line.colors[2], CellColors::default());
+    }
+
+    #[test]
+    fn sgr_bright_colors() {
+        let mut model = ScreenModel::default();
+        parse_bytes(&mut model, b"\x1b[97;100mA\x1b[90;107mB");
+        let line = model.line_log(LogicalY(0)).unwrap();
+        assert_eq!(line.colors[0].fg, Color::Indexed(15));
+        assert_eq!(line.colors[0].bg, Color::Indexed(8));
+        assert_eq!(line.colors[1].fg, Color::Indexed(8));
+        assert_eq!(line.colors[1].bg, Color::Indexed(15));
+    }
+
+    #[test]
+    fn decscusr_sets_shape_and_blink() {
+        let mut model = ScreenModel::default();
+        assert_eq!(
+            (model.cursor_shape, model.cursor_blink),
+            (CursorShape::Block, true)
+        );
+
+        for (param, shape, blink) in [
+            (0, CursorShape::Block, true),
+            (1, CursorShape::Block, true),
+            (2, CursorShape::Block, false),
+            (3, CursorShape::Underline, true),
+            (4, CursorShape::Underline, false),
+            (5, CursorShape::Bar, true),
+            (6, CursorShape::Bar, false),
+        ] {
+            parse_bytes(&mut model, alloc::format!("\x1b[{param} q").as_bytes());
+            assert_eq!(
+                (model.cursor_shape, model.cursor_blink),
+                (shape, blink),
+                "DECSCUSR {param}"
+            );
+        }
+
+        // A missing parameter is the same as 0
+        parse_bytes(&mut model, b"\x1b[ q");
+        assert_eq!(
+            (model.cursor_shape, model.cursor_blink),
+            (CursorShape::Block, true)
+        );
+    }
+
+    #[test]
+    fn decset_12_controls_blink_only() {
+        let mut model = ScreenModel::default();
+        parse_bytes(&mut model, b"\x1b[6 q\x1b[?12h");
+        assert_eq!(
+            (model.cursor_shape, model.cursor_blink),
+            (CursorShape::Bar, true)
+        );
+        parse_bytes(&mut model, b"\x1b[?12l");
+        assert_eq!(
+            (model.cursor_shape, model.cursor_blink),
+            (CursorShape::Bar, false)
+        );
+    }
+
+    #[test]
+    fn cursor_movement_counts() {
+        let mut model = ScreenModel::default();
+        parse_bytes(&mut model, b"\x1b[10;10H");
+        assert_eq!((model.cursor_x, model.cursor_y), (9, LogicalY(9)));
+
+        parse_bytes(&mut model, b"\x1b[A");
+        assert_eq!(model.cursor_y, LogicalY(8));
+        parse_bytes(&mut model, b"\x1b[3A");
+        assert_eq!(model.cursor_y, LogicalY(5));
+        parse_bytes(&mut model, b"\x1b[0A");
+        assert_eq!(model.cursor_y, LogicalY(4));
+
+        parse_bytes(&mut model, b"\x1b[B");
+        assert_eq!(model.cursor_y, LogicalY(5));
+        parse_bytes(&mut model, b"\x1b[2B");
+        assert_eq!(model.cursor_y, LogicalY(7));
+        parse_bytes(&mut model, b"\x1b[0B");
+        assert_eq!(model.cursor_y, LogicalY(8));
+
+        parse_bytes(&mut model, b"\x1b[C");
+        assert_eq!(model.cursor_x, 10);
+        parse_bytes(&mut model, b"\x1b[4C");
+        assert_eq!(model.cursor_x, 14);
+        parse_bytes(&mut model, b"\x1b[0C");
+        assert_eq!(model.cursor_x, 15);
+
+        parse_bytes(&mut model, b"\x1b[D");
+        assert_eq!(model.cursor_x, 14);
+        parse_bytes(&mut model, b"\x1b[4D");
+        assert_eq!(model.cursor_x, 10);
+        parse_bytes(&mut model, b"\x1b[0D");
+        assert_eq!(model.cursor_x, 9);
+
+        parse_bytes(&mut model, b"\x1b[H");
+        assert_eq!((model.cursor_x, model.cursor_y), (0, LogicalY(0)));
+        parse_bytes(&mut model, b"\x1b[3;4H\x1b[0;0H");
+        assert_eq!((model.cursor_x, model.cursor_y), (0, LogicalY(0)));
+
+        // Counts larger than the screen stop at the edges
+        parse_bytes(&mut model, b"\x1b[999B\x1b[999C");
+        assert_eq!(
+            (model.cursor_x, model.cursor_y),
+            (model.width - 1, LogicalY(model.height - 1))
+        );
+        parse_bytes(&mut model, b"\x1b[999A\x1b[999D");
+        assert_eq!((model.cursor_x, model.cursor_y), (0, LogicalY(0)));
+    }
+
+    #[test]
+    fn insert_characters() {
+        let mut model = ScreenModel::default();
+        parse_bytes(&mut model, b"abcdef\x1b[1;3H\x1b[@");
+        assert_eq!(row_text(&model, 0), "ab cdef");
+        parse_bytes(&mut model, b"\x1b[2@");
+        assert_eq!(row_text(&model, 0), "ab   cdef");
+        parse_bytes(&mut model, b"\x1b[0@");
+        assert_eq!(row_text(&model, 0), "ab    cdef");
+        assert_eq!(model.cursor_x, 2);
+
+        // Cells pushed past the right edge are lost
+        parse_bytes(&mut model, b"\x1b[999@");
+        assert_eq!(row_text(&model, 0), "ab");
+    }
+
+    #[test]
+    fn delete_characters() {
+        let mut model = ScreenModel::default();
+        parse_bytes(&mut model, b"abcdefgh\x1b[1;2H\x1b[P");
+        assert_eq!(row_text(&model, 0), "acdefgh");
+        parse_bytes(&mut model, b"\x1b[2P");
+        assert_eq!(row_text(&model, 0), "aefgh");
+        parse_bytes(&mut model, b"\x1b[0P");
+        assert_eq!(row_text(&model, 0), "afgh");
+        assert_eq!(model.cursor_x, 1);
+        parse_bytes(&mut model, b"\x1b[999P");
+        assert_eq!(row_text(&model, 0), "a");
+    }
+
+    #[test]
+    fn erase_characters() {
+        let mut model = ScreenModel::default();
+        parse_bytes(&mut model, b"abcdefgh\x1b[1;2H\x1b[X");
+        assert_eq!(row_text(&model, 0), "a cdefgh");
+        parse_bytes(&mut model, b"\x1b[3X");
+        assert_eq!(row_text(&model, 0), "a   efgh");
+        parse_bytes(&mut model, b"\x1b[1;6H\x1b[0X");
+        assert_eq!(row_text(&model, 0), "a   e gh");
+        assert_eq!(model.cursor_x, 5);
+    }
+
+    #[test]
+    fn insert_lines() {
+        let mut model = ScreenModel::default();
+        fill_screen(&mut model);
+        parse_bytes(&mut model, b"\x1b[2;5H\x1b[L");
+        assert_eq!(row_text(&model, 0), "line 0");
+        assert_eq!(row_text(&model, 1), "");
+        assert_eq!(row_text(&model, 2), "line 1");
+        assert_eq!((model.cursor_x, model.cursor_y), (0, LogicalY(1)));
+
+        parse_bytes(&mut model, b"\x1b[2L");
+        assert_eq!(row_text(&model, 3), "");
+        assert_eq!(row_text(&model, 4), "line 1");
+        parse_bytes(&mut model, b"\x1b[0L");
+        assert_eq!(row_text(&model, 5), "line 1");
+
+        // Within a scroll region, rows below it are untouched
+        parse_bytes(&mut model, b"\x1b[H\x1b[2J");
+        fill_screen(&mut model);
+        parse_bytes(&mut model, b"\x1b[2;4r\x1b[2H\x1b[L");
+        assert_eq!(row_text(&model, 1), "");
+        assert_eq!(row_text(&model, 2), "line 1");
+        assert_eq!(row_text(&model, 3), "line 2");
+        assert_eq!(row_text(&model, 4), "line 4");
+    }
+
+    #[test]
+    fn delete_lines() {
+        let mut model = ScreenModel::default();
+        fill_screen(&mut model);
+        let last = model.height - 1;
+        parse_bytes(&mut model, b"\x1b[2;5H\x1b[M");
+        assert_eq!(row_text(&model, 0), "line 0");
+        assert_eq!(row_text(&model, 1), "line 2");
+        assert_eq!(row_text(&model, last), "");
+        assert_eq!((model.cursor_x, model.cursor_y), (0, LogicalY(1)));
+
+        parse_bytes(&mut model, b"\x1b[2M");
+        assert_eq!(row_text(&model, 1), "line 4");
+        parse_bytes(&mut model, b"\x1b[0M");
+        assert_eq!(row_text(&model, 1), "line 5");
+
+        // Within a scroll region, rows below it are untouched
+        parse_bytes(&mut model, b"\x1b[H\x1b[2J");
+        fill_screen(&mut model);
+        parse_bytes(&mut model, b"\x1b[2;4r\x1b[2H\x1b[M");
+        assert_eq!(row_text(&model, 1), "line 2");
+        assert_eq!(row_text(&model, 2), "line 3");
+        assert_eq!(row_text(&model, 3), "");
+        assert_eq!(row_text(&model, 4), "line 4");
+    }
+
+    /// Returns the columns and attributes of each cluster of `line`
+    fn clusters(line: &Line, cursor_x: Option<u8>) -> Vec<(usize, usize, Attributes)> {
+        line.cluster(cursor_x, CursorShape::Block)
+            .map(|cluster| (cluster.start_col, cluster.end_col, cluster.attributes))
+            .collect()
+    }
+
+    #[test]
+    fn clusters_split_when_only_underline_changes() {
+        let mut line = Line::default();
+        line.attributes[2..4].fill(Attributes::UNDERLINE);
+        assert_eq!(
+            clusters(&line, None),
+            [
+                (0, 2, Attributes::NONE),
+                (2, 4, Attributes::UNDERLINE),
+                (4, MAX_COLS - 1, Attributes::NONE),
+            ]
+        );
+    }
+
+    #[test]
+    fn clusters_split_when_only_strike_through_changes() {
+        let mut line = Line::default();
+        line.attributes[..3].fill(Attributes::BOLD);
+        line.attributes[3..5].fill(Attributes::BOLD | Attributes::STRIKE_THROUGH);
+        assert_eq!(
+            clusters(&line, None),
+            [
+                (0, 3, Attributes::BOLD),
+                (3, 5, Attributes::BOLD | Attributes::STRIKE_THROUGH),
+                (5, MAX_COLS - 1, Attributes::NONE),
+            ]
+        );
+    }
+
+    #[test]
+    fn cursor_has_a_cluster_of_its_own() {
+        let mut line = Line::default();
+        line.attributes[..4].fill(Attributes::UNDERLINE);
+        assert_eq!(
+            clusters(&line, Some(1)),
+            [
+                (0, 1, Attributes::UNDERLINE),
+                (1, 2, Attributes::UNDERLINE | Attributes::REVERSE),
+                (2, 4, Attributes::UNDERLINE),
+                (4, MAX_COLS - 1, Attributes::NONE),
+            ]
+        );
+    }
+
+    #[test]
+    fn backspace_at_column_0_of_the_top_line() {
+        let mut model = ScreenModel::default();
+        parse_bytes(&mut model, b"\x08");
+        assert_eq!((model.cursor_x, model.cursor_y), (0, LogicalY(0)));
+
+        // With reverse-wraparound there is no line above to go to
+        parse_bytes(&mut model, b"\x1b[?45h\x08");
+        assert_eq!((model.cursor_x, model.cursor_y), (0, LogicalY(0)));
+    }
+
+    #[test]
+    fn backspace_after_a_wrap() {
+        let mut model = ScreenModel::default();
+        let full_row = "x".repeat(model.width as usize);
+        parse_bytes(&mut model, full_row.as_bytes());
+        assert_eq!((model.cursor_x, model.cursor_y), (0, LogicalY(1)));
+
+        // Backspace stops at the left edge, as it does in xterm
+        parse_bytes(&mut model, b"\x08");
+        assert_eq!((model.cursor_x, model.cursor_y), (0, LogicalY(1)));
+
+        // unless reverse-wraparound is on, when it goes to the last
+        // column of the row above, which can then be overwritten
+        parse_bytes(&mut model, b"\x1b[?45h\x08");
+        assert_eq!(
+            (model.cursor_x, model.cursor_y),
+            (model.width - 1, LogicalY(0))
+        );
+        parse_bytes(&mut model, b"Z");
+        let expected = alloc::format!("{}Z", &full_row[1..]);
+        assert_eq!(row_text(&model, 0), expected);
+        assert_eq!((model.cursor_x, model.cursor_y), (0, LogicalY(1)));
+    }
+
+    #[test]
+    fn delete_and_insert_lines_of_the_whole_region() {
+        let mut model = ScreenModel::default();
+        fill_screen(&mut model);
+        parse_bytes(&mut model, b"\x1b[H\x1b[99M");
+        for y in 0..model.height {
+            assert_eq!(row_text(&model, y), "");
+        }
+        assert_eq!((model.cursor_x, model.cursor_y), (0, LogicalY(0)));
+
+        fill_screen(&mut model);
+        parse_bytes(&mut model, b"\x1b[H\x1b[99L");
+        for y in 0..model.height {
+            assert_eq!(row_text(&model, y), "");
+        }
+
+        // and from the top of a scroll region
+        fill_screen(&mut model);
+        parse_bytes(&mut model, b"\x1b[2;4r\x1b[2H\x1b[99M");
+        assert_eq!(row_text(&model, 0), "line 0");
+        for y in 1..4 {
+            assert_eq!(row_text(&model, y), "");
+        }
+        assert_eq!(row_text(&model, 4), "line 4");
+    }
+
+    #[test]
+    fn append_screen_keeps_the_rows_with_text() {
+        let mut shell = ScreenModel::default();
+        parse_bytes(&mut shell, b"$ ssh host ls");
+        let mut session = ScreenModel::default();
+        parse_bytes(&mut session, b"a\r\nb\r\n\r\n");
+
+        shell.append_screen(&session);
+        assert_eq!(row_text(&shell, 0), "$ ssh host ls");
+        assert_eq!(row_text(&shell, 1), "a");
+        assert_eq!(row_text(&shell, 2), "b");
+        assert_eq!((shell.cursor_x, shell.cursor_y), (0, LogicalY(3)));
+    }
+
+    #[test]
+    fn box_drawing_is_shown_as_ascii() {
+        let mut model = ScreenModel::default();
+        parse_bytes(&mut model, "├── a\r\n│   └── b\r\n╔═╗ é".as_bytes());
+        assert_eq!(row_text(&model, 0), "+-- a");
+        assert_eq!(row_text(&model, 1), "|   `-- b");
+        assert_eq!(row_text(&model, 2), "+-+");
+    }
+
+    /// Returns the text of the line shown at the top of the view
+    /// of scrollback
+    fn top_of_view(model: &ScreenModel) -> String {
+        let line = &model.scrollback[model.scrollback.len() - model.view_offset];
+        let text = core::str::from_utf8(&line.ascii[..model.width as usize]).unwrap();
+        String::from(text.trim_end())
+    }
+
+    #[test]
+    fn scroll_view_is_clamped_to_scrollback() {
+        let mut model = ScreenModel::default();
+        model.set_scrollback_limit(100);
+        model.scroll_view_up(5);
+        assert_eq!(model.view_offset, 0);
+
+        fill_screen(&mut model);
+        parse_bytes(&mut model, b"\r\nmore\r\nmore");
+        assert_eq!(model.scrollback_usage().0, 2);
+
+        model.scroll_view_up(1);
+        assert_eq!(model.view_offset, 1);
+        model.scroll_view_up(10);
+        assert_eq!(model.view_offset, 2);
+        model.scroll_view_up(usize::MAX);
+        assert_eq!(model.view_offset, 2);
+        model.scroll_view_down(1);
+        assert_eq!(model.view_offset, 1);
+        model.scroll_view_down(10);
+        assert_eq!(model.view_offset, 0);
+
+        model.scroll_view_up(2);
+        model.reset_view();
+        assert_eq!(model.view_offset, 0);
+    }
+
+    #[test]
+    fn scroll_view_keeps_its_place_as_output_arrives() {
+        let mut model = ScreenModel::default();
+        model.set_scrollback_limit(100);
+        fill_screen(&mut model);
+        parse_bytes(&mut model, b"\r\nmore\r\nmore");
+        model.scroll_view_up(2);
+        assert_eq!(top_of_view(&model), "line 0");
+
+        parse_bytes(&mut model, b"\r\nmore\r\nmore\r\nmore");
+        assert_eq!(model.view_offset, 5);
+        assert_eq!(top_of_view(&model), "line 0");
+
+        // Once the view reaches the oldest line, which is then
+        // discarded, it stays on the oldest that remains
+        model.set_scrollback_limit(5);
+        parse_bytes(&mut model, b"\r\nmore");
+        assert_eq!(model.view_offset, 5);
+        assert_eq!(top_of_view(&model), "line 1");
+    }
+
+    #[test]
+    fn decstr_resets_modes_but_not_the_screen() {
+        let mut model = ScreenModel::default();
+        parse_bytes(
+            &mut model,
+            b"\x1b[2;5r\x1b[?6h\x1b[?7l\x1b[4h\x1b[?2004h\x1b[?1h\x1b[1;31m\x1b[6 qtext",
+        );
+        parse_bytes(&mut model, b"\x1b[!p");
+        assert_eq!(model.current_attributes, Attributes::NONE);
+        assert_eq!(model.current_color, CellColors::default());
+        assert!(model.auto_wrap && !model.insert_mode && !model.origin_mode);
+        assert!(!model.bracketed_paste);
+        assert_eq!(
+            (model.scroll_top, model.scroll_bottom),
+            (0, model.height - 1)
+        );
+        assert_eq!(model.cursor_shape, CursorShape::default());
+        assert!(model.cursor_blink);
+        assert_eq!(row_text(&model, 1), "text");
+        assert_eq!((model.cursor_x, model.cursor_y), (4, LogicalY(1)));
+    }
+
+    #[test]
+    fn decckm_sets_application_cursor_keys() {
+        let mut model = ScreenModel::default();
+        assert!(!model.application_cursor_keys());
+        parse_bytes(&mut model, b"\x1b[?1h");
+        assert!(model.application_cursor_keys());
+        parse_bytes(&mut model, b"\x1b[?1l");
+        assert!(!model.application_cursor_keys());
+    }
+}
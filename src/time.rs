@@ -1,12 +1,19 @@
 use chrono::{DateTime, Datelike, Timelike, Utc};
+#[cfg(feature = "net")]
 use core::net::{IpAddr, SocketAddr};
+#[cfg(feature = "net")]
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "net")]
 use embassy_net::Stack;
-use embassy_net::dns::DnsQueryType;
+#[cfg(feature = "net")]
 use embassy_net::udp::{PacketMetadata, UdpSocket};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::lazy_lock::LazyLock;
 use embassy_sync::mutex::Mutex;
-use embassy_time::{Duration, Instant, Timer};
+#[cfg(feature = "net")]
+use embassy_time::Timer;
+use embassy_time::{Duration, Instant};
+#[cfg(feature = "net")]
 use sntpc::{NtpContext, NtpResult, NtpTimestampGenerator, get_time};
 
 // This module keeps track of the wall clock time.
@@ -24,6 +31,9 @@ use sntpc::{NtpContext, NtpResult, NtpTimestampGenerator, get_time};
 //
 // That allows us to provide a UnixTime type and associated
 // UnixTime::now() method to return the current unix time.
+//
+// Without the `net` feature there is no NTP, so the time
+// counts up from the Unix Epoch from when the device boots.
 
 /// This type is used to expose the current time to the
 /// embedded_sdmmc crate
@@ -123,6 +133,8 @@ impl core::fmt::Display for Rfc3339 {
 struct TheTime {
     unix: UnixTime,
     instant: Instant,
+    /// When we last heard from the NTP server
+    last_sync: Option<Instant>,
 }
 
 impl TheTime {
@@ -130,11 +142,14 @@ impl TheTime {
         Self {
             unix: UnixTime::default(),
             instant: Instant::now(),
+            last_sync: None,
         }
     }
 
+    #[cfg(feature = "net")]
     pub fn update_from_ntp(&mut self, now: Instant, ntp: NtpResult) {
         self.instant = now;
+        self.last_sync = Some(now);
         self.unix.seconds = ntp.sec() as u64;
         self.unix.useconds = ntp.sec_fraction() * 1_000_000 / u32::MAX;
     }
@@ -143,12 +158,32 @@ impl TheTime {
 static TIME: LazyLock<Mutex<CriticalSectionRawMutex, TheTime>> =
     LazyLock::new(|| Mutex::new(TheTime::new()));
 
+/// Set once the time has been synchronized with NTP. It is kept
+/// apart from TIME so that it can be checked without waiting.
+#[cfg(feature = "net")]
+static CLOCK_SET: AtomicBool = AtomicBool::new(false);
+
+/// Returns true once the time has been synchronized, before which
+/// it is counting up from the Unix Epoch
+#[cfg(feature = "net")]
+pub fn clock_is_set() -> bool {
+    CLOCK_SET.load(Ordering::Relaxed)
+}
+
+/// Returns the Instant at which the time was last synchronized
+/// with NTP, or None if it has never been synchronized.
+pub async fn last_sync() -> Option<Instant> {
+    TIME.get().lock().await.last_sync
+}
+
 /// Enables sntpc to get our idea of the current time
+#[cfg(feature = "net")]
 #[derive(Copy, Clone, Default)]
 struct Timestamp {
     now: UnixTime,
 }
 
+#[cfg(feature = "net")]
 impl NtpTimestampGenerator for Timestamp {
     fn init(&mut self) {
         self.now = UnixTime::now();
@@ -163,6 +198,7 @@ impl NtpTimestampGenerator for Timestamp {
     }
 }
 
+#[cfg(feature = "net")]
 #[embassy_executor::task]
 pub async fn time_sync(stack: Stack<'static>) {
     let mut rx_meta = [PacketMetadata::EMPTY; 8];
@@ -186,10 +222,10 @@ pub async fn time_sync(stack: Stack<'static>) {
     let mut first = true;
 
     loop {
-        let ntp_addrs = match stack.dns_query(NTP_SERVER, DnsQueryType::A).await {
+        let ntp_addrs = match crate::net::resolve(stack, NTP_SERVER).await {
             Ok(ntp_addrs) => ntp_addrs,
             Err(err) => {
-                log::error!("dns_query {NTP_SERVER} failed: {err:?}");
+                log::error!("{err}");
                 Timer::after(Duration::from_secs(15)).await;
                 continue;
             }
@@ -213,6 +249,7 @@ pub async fn time_sync(stack: Stack<'static>) {
                     Ok(time) => {
                         let now = Instant::now();
                         TIME.get().lock().await.update_from_ntp(now, time);
+                        CLOCK_SET.store(true, Ordering::Relaxed);
 
                         let now_ts = UnixTime::now();
                         let rfc3339 = Rfc3339(now_ts.as_chrono());
@@ -221,6 +258,7 @@ pub async fn time_sync(stack: Stack<'static>) {
                         if first {
                             first = false;
                             print!("The time is {rfc3339}\r\n");
+                            crate::storage::fix_timestamps().await;
                         }
 
                         log::info!("{rfc3339} drift={}us", offset.as_micros());
@@ -251,7 +289,16 @@ pub async fn time_sync(stack: Stack<'static>) {
     }
 }
 
-pub async fn time_command(_args: &[&str]) {
+/// `time` shows the time, while `time COMMAND [ARGS...]` runs
+/// the command and shows how long it took
+pub async fn time_command(args: &[&str]) {
+    if args.len() > 1 {
+        let elapsed = crate::process::timed_dispatch(&crate::process::quote_args(&args[1..])).await;
+        let ms = elapsed.as_millis();
+        print!("took {}.{:03}s\r\n", ms / 1000, ms % 1000);
+        return;
+    }
+
     let now_ts = UnixTime::now();
     let rfc3339 = Rfc3339(now_ts.as_chrono());
     print!("The time is {rfc3339}\r\n");
@@ -0,0 +1,66 @@
+use crate::storage::write_sd_file;
+use alloc::vec::Vec;
+use embassy_time::{Duration, Instant};
+
+extern crate alloc;
+
+// This module records the output of an ssh session to a file on the
+// SD card, so that it can be reviewed later. Output is collected in
+// memory and appended to the file every so often, as the SD card is
+// shared with everything else and can't be held open by a session.
+
+/// Transcripts of every session are appended to this file
+pub const TRANSCRIPT_PATH: &str = "SSHLOG.TXT";
+/// Pending output is written once there is this much of it
+const FLUSH_BYTES: usize = 2048;
+/// or once it has been waiting this long
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct Transcript {
+    pending: Vec<u8>,
+    last_flush: Instant,
+    /// Set when writing fails, such as when the card has been
+    /// removed, after which output is no longer recorded
+    failed: bool,
+}
+
+impl Transcript {
+    /// Starts recording a session with `host`
+    pub async fn begin(host: &str) -> Self {
+        let mut transcript = Self {
+            pending: Vec::new(),
+            last_flush: Instant::now(),
+            failed: false,
+        };
+        transcript
+            .record(alloc::format!("\r\n--- ssh {host} ---\r\n").as_bytes())
+            .await;
+        transcript.flush().await;
+        transcript
+    }
+
+    /// Adds output from the session to the transcript
+    pub async fn record(&mut self, bytes: &[u8]) {
+        if self.failed {
+            return;
+        }
+        self.pending.extend_from_slice(bytes);
+        if self.pending.len() >= FLUSH_BYTES || self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush().await;
+        }
+    }
+
+    /// Appends any pending output to the file
+    pub async fn flush(&mut self) {
+        self.last_flush = Instant::now();
+        if self.failed || self.pending.is_empty() {
+            return;
+        }
+        let result = write_sd_file(TRANSCRIPT_PATH, &self.pending, true).await;
+        self.pending.clear();
+        if let Err(err) = result {
+            log::error!("transcript: {err}; no longer recording");
+            self.failed = true;
+        }
+    }
+}